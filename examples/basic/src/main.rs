@@ -49,6 +49,7 @@ fn main() {
                 certificate_path: "./tls.crt".to_string(),
                 private_key_path: "./tls.key".to_string(),
             },
+            ..Default::default()
         }
     );
 
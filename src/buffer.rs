@@ -0,0 +1,138 @@
+use crate::{
+    SessionBuffers,
+    DataPipeSender,
+    DataPipe,
+    RelayConfig,
+    Sender,
+    Arc,
+    AtomicBool,
+    AtomicUsize,
+    Ordering,
+    Duration,
+    mpsc,
+};
+
+impl SessionBuffers {
+
+    pub fn new(config: &RelayConfig) -> Self {
+
+        let high_watermark = config.high_watermark.max(1);
+
+        SessionBuffers {
+            ds_pending: Arc::new(AtomicUsize::new(0)),
+            us_pending: Arc::new(AtomicUsize::new(0)),
+            ds_unprocessed: AtomicUsize::new(0),
+            us_unprocessed: AtomicUsize::new(0),
+            ds_scheduled: AtomicUsize::new(0),
+            us_scheduled: AtomicUsize::new(0),
+            ds_framing: AtomicUsize::new(0),
+            us_framing: AtomicUsize::new(0),
+            ds_read_paused: AtomicBool::new(false),
+            us_read_paused: AtomicBool::new(false),
+            ds_write_closed: AtomicBool::new(false),
+            us_write_closed: AtomicBool::new(false),
+            high_watermark,
+            low_watermark: config.low_watermark.min(high_watermark - 1),
+        }
+    }
+
+    /// Upper bound for a single read, so one read can not overshoot the high watermark by much.
+    pub fn read_limit(&self) -> usize {
+        self.high_watermark
+    }
+
+    /// Whether the DownStream thread may read more data.
+    /// Data read from DownStream is either waiting on the master thread, held back by the framing,
+    /// delayed by a callback or queued for UpStream.
+    pub fn can_read_downstream(&self, paused: &mut bool) -> bool {
+        let queued = self.ds_unprocessed.load(Ordering::Relaxed)
+            + self.ds_framing.load(Ordering::Relaxed)
+            + self.us_scheduled.load(Ordering::Relaxed)
+            + self.us_pending.load(Ordering::Relaxed);
+        let can_read = self.can_read(queued, paused);
+        self.ds_read_paused.store(*paused, Ordering::Relaxed);
+        can_read
+    }
+
+    /// Whether the UpStream thread may read more data.
+    pub fn can_read_upstream(&self, paused: &mut bool) -> bool {
+        let queued = self.us_unprocessed.load(Ordering::Relaxed)
+            + self.us_framing.load(Ordering::Relaxed)
+            + self.ds_scheduled.load(Ordering::Relaxed)
+            + self.ds_pending.load(Ordering::Relaxed);
+        let can_read = self.can_read(queued, paused);
+        self.us_read_paused.store(*paused, Ordering::Relaxed);
+        can_read
+    }
+
+    /// Socket read timeout for the DownStream thread. While UpStream is paused waiting on
+    /// DownStream to drain, the DownStream thread must not sit in a long read.
+    pub fn downstream_read_timeout(&self) -> Duration {
+        Self::read_timeout(self.us_read_paused.load(Ordering::Relaxed))
+    }
+
+    /// Socket read timeout for the UpStream thread.
+    pub fn upstream_read_timeout(&self) -> Duration {
+        Self::read_timeout(self.ds_read_paused.load(Ordering::Relaxed))
+    }
+
+    fn read_timeout(other_side_paused: bool) -> Duration {
+        if other_side_paused {
+            Duration::from_millis(1)
+        } else {
+            Duration::from_millis(50)
+        }
+    }
+
+    fn can_read(&self, queued: usize, paused: &mut bool) -> bool {
+
+        // Stop at the high watermark and only start again once drained to the low watermark
+        if *paused {
+            if queued <= self.low_watermark {
+                *paused = false;
+            }
+        } else if queued >= self.high_watermark {
+            *paused = true;
+        }
+        !*paused
+    }
+
+    /// Bytes the session is currently holding in both directions.
+    pub fn total(&self) -> usize {
+        self.ds_pending.load(Ordering::Relaxed)
+            + self.us_pending.load(Ordering::Relaxed)
+            + self.ds_unprocessed.load(Ordering::Relaxed)
+            + self.us_unprocessed.load(Ordering::Relaxed)
+            + self.ds_scheduled.load(Ordering::Relaxed)
+            + self.us_scheduled.load(Ordering::Relaxed)
+            + self.ds_framing.load(Ordering::Relaxed)
+            + self.us_framing.load(Ordering::Relaxed)
+    }
+}
+
+impl DataPipeSender {
+
+    pub fn new(sender: Sender<DataPipe>, pending: Arc<AtomicUsize>) -> Self {
+        DataPipeSender {
+            sender,
+            pending,
+        }
+    }
+
+    /// Sends into the DataPipe, counting written data as pending until the stream thread wrote it.
+    pub fn send(&self, data_pipe: DataPipe) -> Result<(), mpsc::SendError<DataPipe>> {
+
+        let data_length = match &data_pipe {
+            DataPipe::DataWrite(data) | DataPipe::StartTLS(data) => data.len(),
+            _ => 0,
+        };
+
+        self.pending.fetch_add(data_length, Ordering::Relaxed);
+
+        let result = self.sender.send(data_pipe);
+        if result.is_err() {
+            self.pending.fetch_sub(data_length, Ordering::Relaxed);
+        }
+        result
+    }
+}
@@ -0,0 +1,118 @@
+use crate::{
+    CallbackQueue,
+    InnerHandlers,
+    CallbackQueueState,
+    NbCallbackJob,
+    HandlerCallbacks,
+    OverflowPolicy,
+    VecDeque,
+    Arc,
+    Mutex,
+    Condvar,
+    thread,
+};
+
+impl CallbackQueue {
+
+    pub fn new<H: HandlerCallbacks + std::marker::Send + 'static>(inner_handlers: InnerHandlers<H>, backlog: usize, overflow: OverflowPolicy) -> Self {
+
+        let shared = Arc::new((
+            Mutex::new(CallbackQueueState {
+                jobs: VecDeque::new(),
+                backlog: backlog.max(1),
+                overflow,
+                closed: false,
+            }),
+            Condvar::new(),
+        ));
+
+        let worker_shared = shared.clone();
+        let span = tracing::info_span!("nb_callbacks");
+        thread::spawn(move || {
+            let _entered = span.enter();
+            Self::run(worker_shared, inner_handlers);
+        });
+
+        CallbackQueue {
+            shared,
+        }
+    }
+
+    /// Queues a non blocking callback behind every one queued before it.
+    pub fn push(&self, job: NbCallbackJob) {
+
+        let (state, condvar) = &*self.shared;
+
+        let mut state = match state.lock() {
+            Ok(state) => state,
+            Err(_e) => return,
+        };
+
+        while state.jobs.len() >= state.backlog && !state.closed {
+            match state.overflow {
+                OverflowPolicy::Block => {
+                    state = match condvar.wait(state) {
+                        Ok(state) => state,
+                        Err(_e) => return,
+                    };
+                },
+                OverflowPolicy::DropOldest => {
+                    state.jobs.pop_front();
+                },
+                OverflowPolicy::DropNewest => return,
+            }
+        }
+
+        state.jobs.push_back(job);
+        condvar.notify_all();
+    }
+
+    fn run<H: HandlerCallbacks + std::marker::Send + 'static>(shared: Arc<(Mutex<CallbackQueueState>, Condvar)>, inner_handlers: InnerHandlers<H>) {
+
+        let (state, condvar) = &*shared;
+
+        loop {
+
+            let job = {
+                let mut state = match state.lock() {
+                    Ok(state) => state,
+                    Err(_e) => return,
+                };
+
+                while state.jobs.is_empty() && !state.closed {
+                    state = match condvar.wait(state) {
+                        Ok(state) => state,
+                        Err(_e) => return,
+                    };
+                }
+
+                match state.jobs.pop_front() {
+                    Some(job) => job,
+                    // Closed and every queued callback has run
+                    None => return,
+                }
+            };
+
+            // Wake up a push() blocked on a full backlog
+            condvar.notify_all();
+
+            match job {
+                NbCallbackJob::DownStream(data) => inner_handlers.lock_nb().ds_nb_callback(data),
+                NbCallbackJob::UpStream(data) => inner_handlers.lock_nb().us_nb_callback(data),
+            }
+        }
+    }
+}
+
+impl Drop for CallbackQueue {
+
+    fn drop(&mut self) {
+
+        // The worker finishes the callbacks still queued and exits
+        let (state, condvar) = &*self.shared;
+        if let Ok(mut state) = state.lock() {
+            state.closed = true;
+        }
+        condvar.notify_all();
+    }
+}
@@ -0,0 +1,440 @@
+use crate::{
+    PcapngCapture,
+    CaptureConnection,
+    DataStreamType,
+    StreamSide,
+    SocketAddr,
+    SystemTime,
+    Arc,
+    Mutex,
+    AtomicU64,
+    Ordering,
+    Path,
+    Write,
+    io,
+};
+use std::net::{
+    IpAddr,
+    Ipv6Addr,
+};
+
+// LINKTYPE_RAW, packets start with their IP header
+const LINK_TYPE_RAW: u16 = 101;
+
+// Payload of one synthesized segment, larger chunks are split
+const MAX_SEGMENT: usize = 32 * 1024;
+
+const TCP_FIN: u8 = 0x01;
+const TCP_SYN: u8 = 0x02;
+const TCP_PSH: u8 = 0x08;
+const TCP_ACK: u8 = 0x10;
+
+impl PcapngCapture {
+
+    /// Creates (or truncates) a pcapng file at path.
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::new(io::BufWriter::new(std::fs::File::create(path)?))
+    }
+
+    /// Writes pcapng to any writer, a pipe into Wireshark for example.
+    pub fn new<W: Write + std::marker::Send + 'static>(mut writer: W) -> io::Result<Self> {
+
+        // Section header block, little endian
+        let mut options = Vec::new();
+        write_option(&mut options, 4, b"sslrelay");// shb_userappl
+        write_block(&mut writer, 0x0a0d0d0a, &[
+            &0x1a2b3c4du32.to_le_bytes()[..],
+            &1u16.to_le_bytes(),
+            &0u16.to_le_bytes(),
+            &(-1i64).to_le_bytes(),// Section length not known
+            &options,
+        ].concat())?;
+
+        // Interface description block, timestamps in microseconds (the default resolution)
+        write_block(&mut writer, 0x00000001, &[
+            &LINK_TYPE_RAW.to_le_bytes()[..],
+            &0u16.to_le_bytes(),
+            &0u32.to_le_bytes(),// No snap length
+        ].concat())?;
+        writer.flush()?;
+
+        Ok(PcapngCapture {
+            writer: Mutex::new(Box::new(writer)),
+            connections: AtomicU64::new(0),
+        })
+    }
+
+    /// Writes the handshake of a synthesized connection, with comment on its SYN.
+    pub(crate) fn open_connection(capture: &Arc<PcapngCapture>, client: SocketAddr, server: SocketAddr, comment: String) -> CaptureConnection {
+
+        // Both addresses have to be of one family, IPv4 goes into IPv6 as a mapped address
+        let (client, server) = match (client.ip(), server.ip()) {
+            (IpAddr::V4(_), IpAddr::V6(_)) => (mapped(client), server),
+            (IpAddr::V6(_), IpAddr::V4(_)) => (client, mapped(server)),
+            _ => (client, server),
+        };
+
+        // Initial sequence numbers only need to differ between connections
+        let number = capture.connections.fetch_add(1, Ordering::Relaxed);
+        let isn = (number as u32).wrapping_mul(0x9e37_79b9);
+
+        let mut connection = CaptureConnection {
+            capture: capture.clone(),
+            client,
+            server,
+            client_seq: isn,
+            server_seq: isn ^ 0x5555_5555,
+            comment: None,
+        };
+
+        connection.segment(true, TCP_SYN, &[], Some(&comment));
+        connection.client_seq = connection.client_seq.wrapping_add(1);
+        connection.segment(false, TCP_SYN | TCP_ACK, &[], None);
+        connection.server_seq = connection.server_seq.wrapping_add(1);
+        connection.segment(true, TCP_ACK, &[], None);
+        connection
+    }
+
+    fn write_packet(&self, packet: &[u8], comment: Option<&str>) {
+
+        let micros = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|since| since.as_micros() as u64)
+            .unwrap_or(0);
+
+        let mut body = Vec::with_capacity(packet.len() + 32);
+        body.extend_from_slice(&0u32.to_le_bytes());// Interface 0
+        body.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+        body.extend_from_slice(&(micros as u32).to_le_bytes());
+        body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        body.extend_from_slice(packet);
+        pad(&mut body);
+        if let Some(comment) = comment {
+            write_option(&mut body, 1, comment.as_bytes());// opt_comment
+        }
+
+        // A capture that can not be written to is not worth ending sessions over
+        let mut writer = match self.writer.lock() {
+            Ok(writer) => writer,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let _ = write_block(&mut *writer, 0x00000006, &body).and_then(|_| writer.flush());
+    }
+}
+
+impl CaptureConnection {
+
+    /// Notes that a leg switched to TLS, on the next packet written.
+    pub(crate) fn start_tls(&mut self, side: StreamSide, details: String) {
+        let side = match side {
+            StreamSide::DownStream => "DownStream",
+            StreamSide::UpStream => "UpStream",
+        };
+        let note = format!("{} switched to TLS: {}", side, details);
+        self.comment = Some(match self.comment.take() {
+            Some(comment) => format!("{}, {}", comment, note),
+            None => note,
+        });
+    }
+
+    /// Writes data one side sent as segments of the synthesized connection.
+    pub(crate) fn record(&mut self, from_client: bool, data: &[u8]) {
+        for chunk in data.chunks(MAX_SEGMENT) {
+            let comment = self.comment.take();
+            self.segment(from_client, TCP_PSH | TCP_ACK, chunk, comment.as_deref());
+            let seq = if from_client { &mut self.client_seq } else { &mut self.server_seq };
+            *seq = seq.wrapping_add(chunk.len() as u32);
+        }
+    }
+
+    fn segment(&self, from_client: bool, flags: u8, payload: &[u8], comment: Option<&str>) {
+
+        let (source, destination, seq, ack) = if from_client {
+            (self.client, self.server, self.client_seq, self.server_seq)
+        } else {
+            (self.server, self.client, self.server_seq, self.client_seq)
+        };
+        // The SYN acknowledges nothing
+        let ack = if flags & TCP_ACK != 0 { ack } else { 0 };
+
+        let mut tcp = Vec::with_capacity(20 + payload.len());
+        tcp.extend_from_slice(&source.port().to_be_bytes());
+        tcp.extend_from_slice(&destination.port().to_be_bytes());
+        tcp.extend_from_slice(&seq.to_be_bytes());
+        tcp.extend_from_slice(&ack.to_be_bytes());
+        tcp.push(5 << 4);// Header length in 32 bit words
+        tcp.push(flags);
+        tcp.extend_from_slice(&0xffffu16.to_be_bytes());// Window
+        tcp.extend_from_slice(&[0, 0, 0, 0]);// Checksum and urgent pointer
+        tcp.extend_from_slice(payload);
+
+        let packet = match (source.ip(), destination.ip()) {
+            (IpAddr::V4(source), IpAddr::V4(destination)) => {
+                let mut pseudo_header = Vec::with_capacity(12);
+                pseudo_header.extend_from_slice(&source.octets());
+                pseudo_header.extend_from_slice(&destination.octets());
+                pseudo_header.extend_from_slice(&[0, 6]);
+                pseudo_header.extend_from_slice(&(tcp.len() as u16).to_be_bytes());
+                let tcp_checksum = checksum(&[&pseudo_header, &tcp]);
+                tcp[16..18].copy_from_slice(&tcp_checksum.to_be_bytes());
+
+                let mut ip = Vec::with_capacity(20 + tcp.len());
+                ip.extend_from_slice(&[0x45, 0]);
+                ip.extend_from_slice(&((20 + tcp.len()) as u16).to_be_bytes());
+                ip.extend_from_slice(&[0, 0, 0x40, 0]);// No id, don't fragment
+                ip.extend_from_slice(&[64, 6, 0, 0]);// TTL, TCP and the checksum
+                ip.extend_from_slice(&source.octets());
+                ip.extend_from_slice(&destination.octets());
+                let ip_checksum = checksum(&[&ip]);
+                ip[10..12].copy_from_slice(&ip_checksum.to_be_bytes());
+                ip.extend(tcp);
+                ip
+            },
+            (source, destination) => {
+                let (source, destination) = (v6(source), v6(destination));
+                let mut pseudo_header = Vec::with_capacity(40);
+                pseudo_header.extend_from_slice(&source.octets());
+                pseudo_header.extend_from_slice(&destination.octets());
+                pseudo_header.extend_from_slice(&(tcp.len() as u32).to_be_bytes());
+                pseudo_header.extend_from_slice(&[0, 0, 0, 6]);
+                let tcp_checksum = checksum(&[&pseudo_header, &tcp]);
+                tcp[16..18].copy_from_slice(&tcp_checksum.to_be_bytes());
+
+                let mut ip = Vec::with_capacity(40 + tcp.len());
+                ip.extend_from_slice(&[0x60, 0, 0, 0]);
+                ip.extend_from_slice(&(tcp.len() as u16).to_be_bytes());
+                ip.extend_from_slice(&[6, 64]);// TCP and the hop limit
+                ip.extend_from_slice(&source.octets());
+                ip.extend_from_slice(&destination.octets());
+                ip.extend(tcp);
+                ip
+            },
+        };
+
+        self.capture.write_packet(&packet, comment);
+    }
+}
+
+impl Drop for CaptureConnection {
+
+    /// Closes the synthesized connection from both sides.
+    fn drop(&mut self) {
+        let comment = self.comment.take();
+        self.segment(true, TCP_FIN | TCP_ACK, &[], comment.as_deref());
+        self.client_seq = self.client_seq.wrapping_add(1);
+        self.segment(false, TCP_FIN | TCP_ACK, &[], None);
+        self.server_seq = self.server_seq.wrapping_add(1);
+        self.segment(true, TCP_ACK, &[], None);
+    }
+}
+
+/// How a leg was set up, for the comment on a captured connection.
+pub fn stream_details(stream: &DataStreamType) -> String {
+    match stream {
+        DataStreamType::RAW(_) => "RAW".to_string(),
+        DataStreamType::TLS(stream) => {
+            let ssl = stream.ssl();
+            let mut details = format!("{} {}", ssl.version_str(), ssl.current_cipher().map(|cipher| cipher.name()).unwrap_or("no cipher"));
+            if let Some(server_name) = ssl.servername(openssl::ssl::NameType::HOST_NAME) {
+                details.push_str(&format!(", SNI {}", server_name));
+            }
+            if let Some(protocol) = ssl.selected_alpn_protocol() {
+                details.push_str(&format!(", ALPN {}", String::from_utf8_lossy(protocol)));
+            }
+            details
+        },
+    }
+}
+
+fn mapped(address: SocketAddr) -> SocketAddr {
+    SocketAddr::new(IpAddr::V6(v6(address.ip())), address.port())
+}
+
+fn v6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+/// The internet checksum over parts laid end to end. Every part but the last has an even length.
+fn checksum(parts: &[&[u8]]) -> u16 {
+    let mut sum = 0u32;
+    for part in parts {
+        for pair in part.chunks(2) {
+            let word = match pair {
+                [high, low] => u16::from_be_bytes([*high, *low]),
+                [high] => u16::from_be_bytes([*high, 0]),
+                _ => 0,
+            };
+            sum += word as u32;
+        }
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// A block with its type and total length on both ends.
+fn write_block<W: Write + ?Sized>(writer: &mut W, block_type: u32, body: &[u8]) -> io::Result<()> {
+    let length = (12 + body.len()) as u32;
+    writer.write_all(&block_type.to_le_bytes())?;
+    writer.write_all(&length.to_le_bytes())?;
+    writer.write_all(body)?;
+    writer.write_all(&length.to_le_bytes())
+}
+
+/// An option followed by the end of options.
+fn write_option(out: &mut Vec<u8>, code: u16, value: &[u8]) {
+    let value = &value[..value.len().min(u16::MAX as usize - 3)];
+    out.extend_from_slice(&code.to_le_bytes());
+    out.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    out.extend_from_slice(value);
+    pad(out);
+    out.extend_from_slice(&[0, 0, 0, 0]);
+}
+
+/// Pads to 32 bits.
+fn pad(out: &mut Vec<u8>) {
+    while out.len() % 4 != 0 {
+        out.push(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::convert::TryInto;
+    use std::net::Ipv4Addr;
+
+    /// A writer the test can still read once the capture has it.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(data);
+            Ok(data.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// The blocks of a capture by type and body, checking the length on both ends.
+    fn blocks(mut bytes: &[u8]) -> Vec<(u32, Vec<u8>)> {
+        let mut blocks = Vec::new();
+        while !bytes.is_empty() {
+            let block_type = u32::from_le_bytes(bytes[0..4].try_into().unwrap());
+            let length = u32::from_le_bytes(bytes[4..8].try_into().unwrap()) as usize;
+            assert_eq!(length % 4, 0, "block of {} bytes", length);
+            assert_eq!(u32::from_le_bytes(bytes[length - 4..length].try_into().unwrap()) as usize, length);
+            blocks.push((block_type, bytes[8..length - 4].to_vec()));
+            bytes = &bytes[length..];
+        }
+        blocks
+    }
+
+    /// The packet and the comment of an enhanced packet block.
+    fn packet(body: &[u8]) -> (Vec<u8>, Option<String>) {
+        let captured = u32::from_le_bytes(body[12..16].try_into().unwrap()) as usize;
+        assert_eq!(u32::from_le_bytes(body[16..20].try_into().unwrap()) as usize, captured);
+        let padded = 20 + (captured + 3) / 4 * 4;
+        assert!(body[20 + captured..padded].iter().all(|byte| *byte == 0));
+        let comment = match &body[padded..] {
+            [] => None,
+            [1, 0, low, high, rest @ ..] => Some(String::from_utf8(rest[..u16::from_le_bytes([*low, *high]) as usize].to_vec()).unwrap()),
+            options => panic!("unexpected options {:?}", options),
+        };
+        (body[20..20 + captured].to_vec(), comment)
+    }
+
+    fn capture(client: SocketAddr, server: SocketAddr, session: impl FnOnce(&mut CaptureConnection)) -> Vec<(u32, Vec<u8>)> {
+        let buffer = SharedBuffer::default();
+        let capture = Arc::new(PcapngCapture::new(buffer.clone()).unwrap());
+        let mut connection = PcapngCapture::open_connection(&capture, client, server, "DownStream: RAW, UpStream: RAW".to_string());
+        session(&mut connection);
+        drop(connection);
+        let bytes = buffer.0.lock().unwrap().clone();
+        blocks(&bytes)
+    }
+
+    #[test]
+    fn sessions_are_written_as_tcp_segments() {
+        let client = SocketAddr::from(([10, 0, 0, 1], 50000));
+        let server = SocketAddr::from(([10, 0, 0, 2], 443));
+        let blocks = capture(client, server, |connection| {
+            connection.record(true, b"hello");
+            connection.record(false, b"world!");
+            connection.start_tls(StreamSide::UpStream, "TLSv1.3".to_string());
+            connection.record(true, b"again");
+        });
+
+        assert_eq!(blocks[0].0, 0x0a0d0d0a);
+        assert_eq!(&blocks[0].1[..4], &0x1a2b3c4du32.to_le_bytes());
+        assert_eq!(blocks[1], (1, [&LINK_TYPE_RAW.to_le_bytes()[..], &[0; 6]].concat()));
+
+        // Handshake, three data segments and the close
+        let packets: Vec<_> = blocks[2..].iter().map(|(block_type, body)| {
+            assert_eq!(*block_type, 6);
+            packet(body)
+        }).collect();
+        assert_eq!(packets.len(), 9);
+        assert_eq!(packets[0].1.as_deref(), Some("DownStream: RAW, UpStream: RAW"));
+        assert_eq!(packets[5].1.as_deref(), Some("UpStream switched to TLS: TLSv1.3"));
+
+        let mut client_seq = None;
+        let mut server_seq = None;
+        for (packet, _) in &packets {
+            // IPv4 header and TCP checksums come out as 0 over what they cover
+            assert_eq!(packet[0], 0x45);
+            assert_eq!(u16::from_be_bytes([packet[2], packet[3]]) as usize, packet.len());
+            assert_eq!(checksum(&[&packet[..20]]), 0);
+            let tcp = &packet[20..];
+            let pseudo_header = [&packet[12..20], &[0, 6], &(tcp.len() as u16).to_be_bytes()].concat();
+            assert_eq!(checksum(&[&pseudo_header, tcp]), 0);
+
+            // Every segment starts where the last one of its side ended
+            let from_client = u16::from_be_bytes([tcp[0], tcp[1]]) == client.port();
+            let seq = u32::from_be_bytes(tcp[4..8].try_into().unwrap());
+            let flags = tcp[13];
+            let next = seq.wrapping_add((tcp.len() - 20) as u32 + (flags & (TCP_SYN | TCP_FIN) != 0) as u32);
+            let expected = if from_client { &mut client_seq } else { &mut server_seq };
+            if let Some(expected) = expected {
+                assert_eq!(seq, *expected);
+            }
+            *expected = Some(next);
+        }
+        assert_eq!(&packets[3].0[40..], b"hello");
+        assert_eq!(&packets[4].0[40..], b"world!");
+        assert_eq!(&packets[5].0[40..], b"again");
+    }
+
+    #[test]
+    fn mixed_families_use_mapped_addresses() {
+        let client = SocketAddr::from(([192, 168, 1, 5], 50000));
+        let server = SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 443);
+        let blocks = capture(client, server, |connection| connection.record(true, b"x"));
+
+        let (syn, _) = packet(&blocks[2].1);
+        assert_eq!(syn[0] >> 4, 6);
+        assert_eq!(&syn[8..24], &Ipv4Addr::new(192, 168, 1, 5).to_ipv6_mapped().octets());
+        assert_eq!(&syn[24..40], &Ipv6Addr::LOCALHOST.octets());
+        let tcp = &syn[40..];
+        let pseudo_header = [&syn[8..40], &(tcp.len() as u32).to_be_bytes(), &[0, 0, 0, 6]].concat();
+        assert_eq!(checksum(&[&pseudo_header, tcp]), 0);
+    }
+
+    #[test]
+    fn options_are_padded() {
+        for length in 0..8 {
+            let mut out = Vec::new();
+            write_option(&mut out, 1, &vec![b'a'; length]);
+            assert_eq!(out.len(), 4 + (length + 3) / 4 * 4 + 4);
+            assert_eq!(&out[out.len() - 4..], &[0, 0, 0, 0]);
+        }
+    }
+}
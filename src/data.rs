@@ -11,15 +11,16 @@ use crate::{
     Duration,
     Read,
     Write,
+    Ordering,
     io,
+    CloseReason,
+    StreamSide,
+    tls,
+    capture,
 };
 
 impl DownStreamInner {
 
-    fn handle_error(error_description: &str) {
-        println!("[SSLRelay DownStream Thread Error]: {}", error_description);
-    }
-
     pub fn ds_handler(self, data_out: Sender<FullDuplexTcpState>, data_in: Receiver<DataPipe>) {
 
         match &self.ds_stream {
@@ -35,9 +36,18 @@ impl DownStreamInner {
             _ => return,
         };
 
+        let mut read_closed = false;
+        let mut read_paused = false;
+        let mut read_full = false;
+        let mut read_timeout = Duration::from_millis(50);
+
         loop {
 
-            match data_in.recv_timeout(Duration::from_millis(50)) {
+            // A paused reader checks back often so it notices the other side draining,
+            // a reader that stopped at the read limit goes straight back to the socket
+            let poll_timeout = if read_paused || read_full { Duration::from_millis(1) } else { Duration::from_millis(50) };
+
+            match data_in.recv_timeout(poll_timeout) {
 
                 // DataPipe Received
                 Ok(data_received) => {
@@ -45,10 +55,13 @@ impl DownStreamInner {
                     match data_received {
                         DataPipe::DataWrite(data) => {
 
-                            match raw_stream.write_all(&data) {
+                            let write_result = raw_stream.write_all(&data);
+                            self.buffers.ds_pending.fetch_sub(data.len(), Ordering::Relaxed);
+
+                            match write_result {
                                 Ok(()) => {},
-                                Err(_e) => {
-                                    Self::handle_error("Failed to write data to DownStream tcp stream!");
+                                Err(e) => {
+                                    tracing::warn!(error = %e, "failed to write to the DownStream stream");
                                     let _ = data_out.send(FullDuplexTcpState::DownStreamShutDown);
                                     let _ = raw_stream.shutdown(Shutdown::Both);
                                     return;
@@ -57,6 +70,25 @@ impl DownStreamInner {
                             let _ = raw_stream.flush();
 
                         },
+                        DataPipe::HalfClose => {
+                            let _ = raw_stream.shutdown(Shutdown::Write);
+                        },
+                        DataPipe::StartTLS(data) => {
+
+                            let write_result = raw_stream.write_all(&data);
+                            self.buffers.ds_pending.fetch_sub(data.len(), Ordering::Relaxed);
+
+                            if let Err(e) = write_result {
+                                tracing::warn!(error = %e, "failed to write to the DownStream stream");
+                                let _ = data_out.send(FullDuplexTcpState::DownStreamShutDown);
+                                let _ = raw_stream.shutdown(Shutdown::Both);
+                                return;
+                            }
+                            let _ = raw_stream.flush();
+                            break;
+                        },
+                        // Only expected while switching to TLS
+                        DataPipe::Handshake(_) => {},
                         DataPipe::Shutdown => {
                             let _ = raw_stream.shutdown(Shutdown::Both);
                             return;
@@ -67,26 +99,56 @@ impl DownStreamInner {
                     match _e {
                         mpsc::RecvTimeoutError::Timeout => {},
                         mpsc::RecvTimeoutError::Disconnected => {
-                            Self::handle_error("DownStream data_in channel is disconnected!");
+                            tracing::error!("DownStream data_in channel is disconnected");
                             return;
                         }
                     }
                 }
             }// End of data_in receive
 
+            // Peer half closed its side, only writes are left to relay
+            if read_closed {
+                continue;
+            }
+
+            // Write out everything queued for this side before blocking on a read
+            if self.buffers.ds_pending.load(Ordering::Relaxed) > 0 {
+                continue;
+            }
+
+            // Other side is still draining what was already read
+            if !self.buffers.can_read_downstream(&mut read_paused) {
+                continue;
+            }
+
+            // Keep reads short while the other side waits on this one to drain
+            if read_timeout != self.buffers.downstream_read_timeout() {
+                read_timeout = self.buffers.downstream_read_timeout();
+                let _ = raw_stream.set_read_timeout(Some(read_timeout));
+            }
+
             // If received data
-            if let Some(byte_count) = Self::get_data_stream(&mut raw_stream, &mut self.internal_data_buffer) {
+            if let Some(byte_count) = Self::get_data_stream(&mut raw_stream, &mut self.internal_data_buffer, self.buffers.read_limit()) {
+
+                read_full = byte_count >= self.buffers.read_limit() as i64;
+
                 if byte_count > 0 {
 
-                    if let Err(_e) = data_out.send(FullDuplexTcpState::UpStreamWrite(self.internal_data_buffer.clone())) {
-                        
-                        //Self::handle_error(format!("Failed to send UpStreamWrite to main thread: {}", e).as_str());
+                    self.buffers.ds_unprocessed.fetch_add(self.internal_data_buffer.len(), Ordering::Relaxed);
+
+                    if let Err(e) = data_out.send(FullDuplexTcpState::UpStreamWrite(self.internal_data_buffer.clone())) {
+                        tracing::debug!(error = %e, "failed to send UpStreamWrite to the master thread");
                         let _ = raw_stream.shutdown(Shutdown::Both);
                         return;
                     }
 
                     self.internal_data_buffer.clear();
 
+                } else if byte_count == 0 && self.half_close {
+
+                    let _ = data_out.send(FullDuplexTcpState::DownStreamHalfClose);
+                    read_closed = true;
+
                 } else if byte_count == 0 || byte_count == -2 {
 
                     let _ = data_out.send(FullDuplexTcpState::DownStreamShutDown);
@@ -96,8 +158,55 @@ impl DownStreamInner {
                 } else if byte_count == -1 {
                     continue;
                 }
-            } else {
+            }
+        }
+
+        // Only a StartTLS gets here
+        self.start_tls(data_out, data_in);
+    }
+
+    /// Switches the stream to TLS, as the server of the handshake, and goes on relaying over it.
+    fn start_tls(mut self, data_out: Sender<FullDuplexTcpState>, data_in: Receiver<DataPipe>) {
+
+        let raw_stream = match self.ds_stream {
+            DataStreamType::RAW(s) => s,
+            DataStreamType::TLS(_) => return,
+        };
 
+        let acceptor = match self.tls_acceptor.clone() {
+            Some(acceptor) => acceptor,
+            None => {
+                tracing::error!("can't switch DownStream to TLS without a tls_config");
+                let _ = raw_stream.shutdown(Shutdown::Both);
+                let _ = data_out.send(FullDuplexTcpState::StartTLSFailed(CloseReason::DownStreamHandshakeFailed));
+                return;
+            }
+        };
+
+        let (read_ahead, writes, half_close) = match wait_for_handshake_data(&data_out, &data_in, FullDuplexTcpState::DownStreamStartTLS) {
+            Some(handshake_data) => handshake_data,
+            None => {
+                let _ = raw_stream.shutdown(Shutdown::Both);
+                return;
+            }
+        };
+
+        match tls::tls_accept(&acceptor, raw_stream, read_ahead, self.handshake_timeout) {
+            Ok(mut tls_stream) => {
+                let _ = tls_stream.get_ref().set_read_timeout(Some(Duration::from_millis(50)));
+                for data in writes {
+                    let _ = tls_stream.write_all(&data);
+                    self.buffers.ds_pending.fetch_sub(data.len(), Ordering::Relaxed);
+                }
+                if half_close {
+                    let _ = tls_stream.shutdown();
+                }
+                self.ds_stream = DataStreamType::TLS(tls_stream);
+                let _ = data_out.send(FullDuplexTcpState::StartTLSDone(StreamSide::DownStream, capture::stream_details(&self.ds_stream)));
+                self.handle_tls(data_out, data_in);
+            },
+            Err(reason) => {
+                let _ = data_out.send(FullDuplexTcpState::StartTLSFailed(reason));
             }
         }
     }
@@ -109,20 +218,33 @@ impl DownStreamInner {
             _ => return,
         };
 
+        let mut read_closed = false;
+        let mut read_paused = false;
+        let mut read_full = false;
+        let mut read_timeout = Duration::from_millis(50);
+
         loop {
 
-            match data_in.recv_timeout(Duration::from_millis(50)) {
+            // A paused reader checks back often so it notices the other side draining,
+            // a reader that stopped at the read limit goes straight back to the socket
+            let poll_timeout = if read_paused || read_full { Duration::from_millis(1) } else { Duration::from_millis(50) };
+
+            match data_in.recv_timeout(poll_timeout) {
 
                 // DataPipe Received
                 Ok(data_received) => {
 
                     match data_received {
-                        DataPipe::DataWrite(data) => {
+                        // Already TLS, there is nothing to switch
+                        DataPipe::DataWrite(data) | DataPipe::StartTLS(data) => {
+
+                            let write_result = tls_stream.write_all(&data);
+                            self.buffers.ds_pending.fetch_sub(data.len(), Ordering::Relaxed);
 
-                            match tls_stream.write_all(&data) {
+                            match write_result {
                                 Ok(()) => {},
-                                Err(_e) => {
-                                    Self::handle_error("Failed to write data to DownStream tcp stream!");
+                                Err(e) => {
+                                    tracing::warn!(error = %e, "failed to write to the DownStream stream");
                                     let _ = data_out.send(FullDuplexTcpState::DownStreamShutDown);
                                     let _ = tls_stream.shutdown();
                                     return;
@@ -130,6 +252,12 @@ impl DownStreamInner {
                             }
                             let _ = tls_stream.flush();
                         },
+                        DataPipe::HalfClose => {
+                            // close_notify, reading goes on until the peer sends its own
+                            let _ = tls_stream.shutdown();
+                        },
+                        // Only expected while switching to TLS
+                        DataPipe::Handshake(_) => {},
                         DataPipe::Shutdown => {
                             let _ = tls_stream.shutdown();
                             return;
@@ -140,26 +268,56 @@ impl DownStreamInner {
                     match _e {
                         mpsc::RecvTimeoutError::Timeout => {},
                         mpsc::RecvTimeoutError::Disconnected => {
-                            Self::handle_error("DownStream data_in channel is disconnected!");
+                            tracing::error!("DownStream data_in channel is disconnected");
                             return;
                         }
                     }
                 }
             }// End of data_in receive
 
+            // Peer half closed its side, only writes are left to relay
+            if read_closed {
+                continue;
+            }
+
+            // Write out everything queued for this side before blocking on a read
+            if self.buffers.ds_pending.load(Ordering::Relaxed) > 0 {
+                continue;
+            }
+
+            // Other side is still draining what was already read
+            if !self.buffers.can_read_downstream(&mut read_paused) {
+                continue;
+            }
+
+            // Keep reads short while the other side waits on this one to drain
+            if read_timeout != self.buffers.downstream_read_timeout() {
+                read_timeout = self.buffers.downstream_read_timeout();
+                let _ = tls_stream.get_ref().set_read_timeout(Some(read_timeout));
+            }
+
             // If received data
-            if let Some(byte_count) = Self::get_data_stream(&mut tls_stream, &mut self.internal_data_buffer) {
+            if let Some(byte_count) = Self::get_data_stream(&mut tls_stream, &mut self.internal_data_buffer, self.buffers.read_limit()) {
+
+                read_full = byte_count >= self.buffers.read_limit() as i64;
+
                 if byte_count > 0 {
 
-                    if let Err(_e) = data_out.send(FullDuplexTcpState::UpStreamWrite(self.internal_data_buffer.clone())) {
-                        
-                        //Self::handle_error(format!("Failed to send UpStreamWrite to main thread: {}", e).as_str());
+                    self.buffers.ds_unprocessed.fetch_add(self.internal_data_buffer.len(), Ordering::Relaxed);
+
+                    if let Err(e) = data_out.send(FullDuplexTcpState::UpStreamWrite(self.internal_data_buffer.clone())) {
+                        tracing::debug!(error = %e, "failed to send UpStreamWrite to the master thread");
                         let _ = tls_stream.shutdown();
                         return;
                     }
 
                     self.internal_data_buffer.clear();
 
+                } else if byte_count == 0 && self.half_close {
+
+                    let _ = data_out.send(FullDuplexTcpState::DownStreamHalfClose);
+                    read_closed = true;
+
                 } else if byte_count == 0 || byte_count == -2 {
 
                     let _ = data_out.send(FullDuplexTcpState::DownStreamShutDown);
@@ -169,17 +327,16 @@ impl DownStreamInner {
                 } else if byte_count == -1 {
                     continue;
                 }
-            } else {
-
             }
         }
     }
 
-    fn get_data_stream<S: Read>(stream: &mut S, internal_data_buffer: &mut Vec<u8>) -> Option<i64> {
+    fn get_data_stream<S: Read>(stream: &mut S, internal_data_buffer: &mut Vec<u8>, read_limit: usize) -> Option<i64> {
 
         let mut data_length: i64 = 0;
 
-        loop {
+        // Leave the rest in the socket once read_limit is reached
+        while (data_length as usize) < read_limit {
 
             let mut r_buf = [0; 1024];
 
@@ -204,7 +361,7 @@ impl DownStreamInner {
                         data_length += bytes_read as i64;
 
                     } else {
-                        println!("[+] Else hit!!!!!!!!!!!!!!!!!!!!!!");
+                        tracing::error!(bytes_read, "read returned more than the buffer holds");
                     }
                 },
                 Err(e) => {
@@ -222,22 +379,18 @@ impl DownStreamInner {
                             data_length = -2;
                             break;
                         },
-                        _ => {println!("[!!!] Got error: {}",e);}
+                        _ => tracing::debug!(error = %e, kind = ?e.kind(), "read failed"),
                     }
                 },
             }
         }
-        return Some(data_length);
+        Some(data_length)
     }
 }
 
 
 impl UpStreamInner {
 
-    fn handle_error(error_description: &str) {
-        println!("[SSLRelay UpStream Thread Error]: {}", error_description);
-    }
-
     pub fn us_handler(self, data_out: Sender<FullDuplexTcpState>, data_in: Receiver<DataPipe>) {
 
         match &self.us_stream {
@@ -254,19 +407,31 @@ impl UpStreamInner {
             _ => return,
         };
 
+        let mut read_closed = false;
+        let mut read_paused = false;
+        let mut read_full = false;
+        let mut read_timeout = Duration::from_millis(50);
+
         loop {
 
-            match data_in.recv_timeout(Duration::from_millis(50)) {
+            // A paused reader checks back often so it notices the other side draining,
+            // a reader that stopped at the read limit goes straight back to the socket
+            let poll_timeout = if read_paused || read_full { Duration::from_millis(1) } else { Duration::from_millis(50) };
+
+            match data_in.recv_timeout(poll_timeout) {
 
                 Ok(data_received) => {
 
                     match data_received {
                         DataPipe::DataWrite(data) => {
 
-                            match raw_stream.write_all(&data) {
+                            let write_result = raw_stream.write_all(&data);
+                            self.buffers.us_pending.fetch_sub(data.len(), Ordering::Relaxed);
+
+                            match write_result {
                                 Ok(()) => {},
-                                Err(_e) => {
-                                    Self::handle_error("Failed to write data to UpStream tcp stream!");
+                                Err(e) => {
+                                    tracing::warn!(error = %e, "failed to write to the UpStream stream");
                                     let _ = data_out.send(FullDuplexTcpState::UpStreamShutDown);
                                     let _ = raw_stream.shutdown(Shutdown::Both);
                                     return;
@@ -274,6 +439,25 @@ impl UpStreamInner {
                             }
                             let _ = raw_stream.flush();
                         },
+                        DataPipe::HalfClose => {
+                            let _ = raw_stream.shutdown(Shutdown::Write);
+                        },
+                        DataPipe::StartTLS(data) => {
+
+                            let write_result = raw_stream.write_all(&data);
+                            self.buffers.us_pending.fetch_sub(data.len(), Ordering::Relaxed);
+
+                            if let Err(e) = write_result {
+                                tracing::warn!(error = %e, "failed to write to the UpStream stream");
+                                let _ = data_out.send(FullDuplexTcpState::UpStreamShutDown);
+                                let _ = raw_stream.shutdown(Shutdown::Both);
+                                return;
+                            }
+                            let _ = raw_stream.flush();
+                            break;
+                        },
+                        // Only expected while switching to TLS
+                        DataPipe::Handshake(_) => {},
                         DataPipe::Shutdown => {
                             let _ = raw_stream.shutdown(Shutdown::Both);
                             return;
@@ -284,25 +468,55 @@ impl UpStreamInner {
                     match e {
                         mpsc::RecvTimeoutError::Timeout => {},
                         mpsc::RecvTimeoutError::Disconnected => {
-                            Self::handle_error("UpStream data_in channel is disconnected!");
+                            tracing::error!("UpStream data_in channel is disconnected");
                             return;
                         }
                     }
                 }
             }// End of data_in receive
 
-            if let Some(byte_count) = Self::get_data_stream(&mut raw_stream, &mut self.internal_data_buffer) {
+            // Peer half closed its side, only writes are left to relay
+            if read_closed {
+                continue;
+            }
+
+            // Write out everything queued for this side before blocking on a read
+            if self.buffers.us_pending.load(Ordering::Relaxed) > 0 {
+                continue;
+            }
+
+            // Other side is still draining what was already read
+            if !self.buffers.can_read_upstream(&mut read_paused) {
+                continue;
+            }
+
+            // Keep reads short while the other side waits on this one to drain
+            if read_timeout != self.buffers.upstream_read_timeout() {
+                read_timeout = self.buffers.upstream_read_timeout();
+                let _ = raw_stream.set_read_timeout(Some(read_timeout));
+            }
+
+            if let Some(byte_count) = Self::get_data_stream(&mut raw_stream, &mut self.internal_data_buffer, self.buffers.read_limit()) {
+
+                read_full = byte_count >= self.buffers.read_limit() as i64;
+
                 if byte_count > 0 {
 
-                    if let Err(_e) = data_out.send(FullDuplexTcpState::DownStreamWrite(self.internal_data_buffer.clone())) {
-                        
-                        //Self::handle_error(format!("Failed to send DownStreamWrite to main thread: {}", e).as_str());
+                    self.buffers.us_unprocessed.fetch_add(self.internal_data_buffer.len(), Ordering::Relaxed);
+
+                    if let Err(e) = data_out.send(FullDuplexTcpState::DownStreamWrite(self.internal_data_buffer.clone())) {
+                        tracing::debug!(error = %e, "failed to send DownStreamWrite to the master thread");
                         let _ = raw_stream.shutdown(Shutdown::Both);
                         return;
                     }
 
                     self.internal_data_buffer.clear();
 
+                } else if byte_count == 0 && self.half_close {
+
+                    let _ = data_out.send(FullDuplexTcpState::UpStreamHalfClose);
+                    read_closed = true;
+
                 } else if byte_count == 0 || byte_count == -2 {
 
                     let _ = data_out.send(FullDuplexTcpState::UpStreamShutDown);
@@ -311,7 +525,45 @@ impl UpStreamInner {
                 } else if byte_count == -1 {
                     continue;
                 }
-            } else {
+            }
+        }
+
+        // Only a StartTLS gets here
+        self.start_tls(data_out, data_in);
+    }
+
+    /// Switches the stream to TLS, as the client of the handshake, and goes on relaying over it.
+    fn start_tls(mut self, data_out: Sender<FullDuplexTcpState>, data_in: Receiver<DataPipe>) {
+
+        let raw_stream = match self.us_stream {
+            DataStreamType::RAW(s) => s,
+            DataStreamType::TLS(_) => return,
+        };
+
+        let (read_ahead, writes, half_close) = match wait_for_handshake_data(&data_out, &data_in, FullDuplexTcpState::UpStreamStartTLS) {
+            Some(handshake_data) => handshake_data,
+            None => {
+                let _ = raw_stream.shutdown(Shutdown::Both);
+                return;
+            }
+        };
+
+        match tls::tls_connect(raw_stream, read_ahead, &self.remote_host, self.handshake_timeout, &self.alpn_protocols) {
+            Ok(mut tls_stream) => {
+                let _ = tls_stream.get_ref().set_read_timeout(Some(Duration::from_millis(50)));
+                for data in writes {
+                    let _ = tls_stream.write_all(&data);
+                    self.buffers.us_pending.fetch_sub(data.len(), Ordering::Relaxed);
+                }
+                if half_close {
+                    let _ = tls_stream.shutdown();
+                }
+                self.us_stream = DataStreamType::TLS(tls_stream);
+                let _ = data_out.send(FullDuplexTcpState::StartTLSDone(StreamSide::UpStream, capture::stream_details(&self.us_stream)));
+                self.handle_tls(data_out, data_in);
+            },
+            Err(reason) => {
+                let _ = data_out.send(FullDuplexTcpState::StartTLSFailed(reason));
             }
         }
     }
@@ -323,19 +575,32 @@ impl UpStreamInner {
             _ => return,
         };
 
+        let mut read_closed = false;
+        let mut read_paused = false;
+        let mut read_full = false;
+        let mut read_timeout = Duration::from_millis(50);
+
         loop {
 
-            match data_in.recv_timeout(Duration::from_millis(50)) {
+            // A paused reader checks back often so it notices the other side draining,
+            // a reader that stopped at the read limit goes straight back to the socket
+            let poll_timeout = if read_paused || read_full { Duration::from_millis(1) } else { Duration::from_millis(50) };
+
+            match data_in.recv_timeout(poll_timeout) {
 
                 Ok(data_received) => {
 
                     match data_received {
-                        DataPipe::DataWrite(data) => {
+                        // Already TLS, there is nothing to switch
+                        DataPipe::DataWrite(data) | DataPipe::StartTLS(data) => {
 
-                            match tls_stream.write_all(&data) {
+                            let write_result = tls_stream.write_all(&data);
+                            self.buffers.us_pending.fetch_sub(data.len(), Ordering::Relaxed);
+
+                            match write_result {
                                 Ok(()) => {},
-                                Err(_e) => {
-                                    Self::handle_error("Failed to write data to UpStream tcp stream!");
+                                Err(e) => {
+                                    tracing::warn!(error = %e, "failed to write to the UpStream stream");
                                     let _ = data_out.send(FullDuplexTcpState::UpStreamShutDown);
                                     let _ = tls_stream.shutdown();
                                     return;
@@ -343,6 +608,12 @@ impl UpStreamInner {
                             }
                             let _ = tls_stream.flush();
                         },
+                        DataPipe::HalfClose => {
+                            // close_notify, reading goes on until the peer sends its own
+                            let _ = tls_stream.shutdown();
+                        },
+                        // Only expected while switching to TLS
+                        DataPipe::Handshake(_) => {},
                         DataPipe::Shutdown => {
                             let _ = tls_stream.shutdown();
                             return;
@@ -353,25 +624,55 @@ impl UpStreamInner {
                     match e {
                         mpsc::RecvTimeoutError::Timeout => {},
                         mpsc::RecvTimeoutError::Disconnected => {
-                            Self::handle_error("UpStream data_in channel is disconnected!");
+                            tracing::error!("UpStream data_in channel is disconnected");
                             return;
                         }
                     }
                 }
             }// End of data_in receive
 
-            if let Some(byte_count) = Self::get_data_stream(&mut tls_stream, &mut self.internal_data_buffer) {
+            // Peer half closed its side, only writes are left to relay
+            if read_closed {
+                continue;
+            }
+
+            // Write out everything queued for this side before blocking on a read
+            if self.buffers.us_pending.load(Ordering::Relaxed) > 0 {
+                continue;
+            }
+
+            // Other side is still draining what was already read
+            if !self.buffers.can_read_upstream(&mut read_paused) {
+                continue;
+            }
+
+            // Keep reads short while the other side waits on this one to drain
+            if read_timeout != self.buffers.upstream_read_timeout() {
+                read_timeout = self.buffers.upstream_read_timeout();
+                let _ = tls_stream.get_ref().set_read_timeout(Some(read_timeout));
+            }
+
+            if let Some(byte_count) = Self::get_data_stream(&mut tls_stream, &mut self.internal_data_buffer, self.buffers.read_limit()) {
+
+                read_full = byte_count >= self.buffers.read_limit() as i64;
+
                 if byte_count > 0 {
 
-                    if let Err(_e) = data_out.send(FullDuplexTcpState::DownStreamWrite(self.internal_data_buffer.clone())) {
+                    self.buffers.us_unprocessed.fetch_add(self.internal_data_buffer.len(), Ordering::Relaxed);
 
-                        //Self::handle_error(format!("Failed to send DownStreamWrite to main thread: {}", e).as_str());
+                    if let Err(e) = data_out.send(FullDuplexTcpState::DownStreamWrite(self.internal_data_buffer.clone())) {
+                        tracing::debug!(error = %e, "failed to send DownStreamWrite to the master thread");
                         let _ = tls_stream.shutdown();
                         return;
                     }
 
                     self.internal_data_buffer.clear();
 
+                } else if byte_count == 0 && self.half_close {
+
+                    let _ = data_out.send(FullDuplexTcpState::UpStreamHalfClose);
+                    read_closed = true;
+
                 } else if byte_count == 0 || byte_count == -2 {
 
                     let _ = data_out.send(FullDuplexTcpState::UpStreamShutDown);
@@ -380,16 +681,16 @@ impl UpStreamInner {
                 } else if byte_count == -1 {
                     continue;
                 }
-            } else {
             }
         }
     }
 
-    fn get_data_stream<S: Read>(stream: &mut S, internal_data_buffer: &mut Vec<u8>) -> Option<i64> {
+    fn get_data_stream<S: Read>(stream: &mut S, internal_data_buffer: &mut Vec<u8>, read_limit: usize) -> Option<i64> {
 
         let mut data_length: i64 = 0;
 
-        loop {
+        // Leave the rest in the socket once read_limit is reached
+        while (data_length as usize) < read_limit {
 
             let mut r_buf = [0; 1024];
 
@@ -415,7 +716,7 @@ impl UpStreamInner {
                         data_length += bytes_read as i64;
 
                     } else {
-                        println!("[+] Else hit!!!!!!!!!!!!!!!!!!!!!!");
+                        tracing::error!(bytes_read, "read returned more than the buffer holds");
                     }
                 },
                 Err(e) => {
@@ -431,11 +732,33 @@ impl UpStreamInner {
                             data_length = -2;
                             break;
                         },
-                        _ => {println!("[!!!] Got error: {}",e);}
+                        _ => tracing::debug!(error = %e, kind = ?e.kind(), "read failed"),
                     }
                 },
             }
         }
-        return Some(data_length);
+        Some(data_length)
     }
-}
\ No newline at end of file
+}
+
+/// Tells the master that the stream stopped reading in the clear and waits for the handshake data
+/// that was read before that. Writes that come in meanwhile go out once the handshake is done,
+/// followed by a half close if one came in as well. None when the session ends first.
+fn wait_for_handshake_data(data_out: &Sender<FullDuplexTcpState>, data_in: &Receiver<DataPipe>, ready: FullDuplexTcpState) -> Option<(Vec<u8>, Vec<Vec<u8>>, bool)> {
+
+    if data_out.send(ready).is_err() {
+        return None;
+    }
+
+    let mut writes = Vec::new();
+    let mut half_close = false;
+    loop {
+        match data_in.recv() {
+            Ok(DataPipe::Handshake(read_ahead)) => return Some((read_ahead, writes, half_close)),
+            // The stream is already switching, a StartTLS is only its data by now
+            Ok(DataPipe::DataWrite(data)) | Ok(DataPipe::StartTLS(data)) => writes.push(data),
+            Ok(DataPipe::HalfClose) => half_close = true,
+            Ok(DataPipe::Shutdown) | Err(_) => return None,
+        }
+    }
+}
@@ -0,0 +1,836 @@
+//! DNS aware callbacks.
+//!
+//! DnsRelay wraps a DnsHandler and implements HandlerCallbacks for it. It splits DNS over TCP and
+//! DNS over TLS (port 853) into the two byte length prefixed messages both use, parses them into
+//! DnsMessages and matches every response to the query it answers.
+//! ```ignore
+//! use sslrelay::dns::{DnsRelay, DnsHandler, DnsRet, DnsMessage, DnsRecord, DnsRData, TYPE_A};
+//!
+//! struct Handler;
+//!
+//! impl DnsHandler for Handler {
+//!     fn on_query(&mut self, query: &mut DnsMessage) -> DnsRet {
+//!         match query.questions.first() {
+//!             Some(question) if question.name == "update.example.com" && question.qtype == TYPE_A => {
+//!                 let record = DnsRecord::new(&question.name, 60, DnsRData::A([10, 0, 0, 5].into()));
+//!                 DnsRet::Reply(query.response(vec![record]))
+//!             },
+//!             _ => DnsRet::Relay,
+//!         }
+//!     }
+//!
+//!     fn on_response(&mut self, _query: Option<&DnsMessage>, response: &mut DnsMessage) -> DnsRet {
+//!         for record in &mut response.answers {
+//!             record.ttl = record.ttl.min(30);
+//!         }
+//!         DnsRet::Relay
+//!     }
+//! }
+//!
+//! let mut relay = sslrelay::SSLRelay::new(DnsRelay::new(Handler), config);
+//! ```
+//! Messages the handler left alone are relayed byte for byte. Changed ones are written out with
+//! uncompressed names, and a session where that no longer fits into a message ends instead of sending
+//! a cut off one. Messages that do not parse are relayed as they are.
+//! DnsMessage::parse and DnsMessage::to_bytes work on single messages (without the length prefix)
+//! for plain HandlerCallbacks, which Framing::LengthPrefixedU16BE hands one message at a time.
+
+use crate::{
+    HandlerCallbacks,
+    CallbackRet,
+    CloseReason,
+    SessionHandle,
+    HashMap,
+    http::callback_ret,
+};
+use std::net::{
+    Ipv4Addr,
+    Ipv6Addr,
+};
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_NS: u16 = 2;
+pub const TYPE_CNAME: u16 = 5;
+pub const TYPE_SOA: u16 = 6;
+pub const TYPE_PTR: u16 = 12;
+pub const TYPE_MX: u16 = 15;
+pub const TYPE_TXT: u16 = 16;
+pub const TYPE_AAAA: u16 = 28;
+pub const TYPE_SRV: u16 = 33;
+pub const TYPE_OPT: u16 = 41;
+pub const TYPE_HTTPS: u16 = 65;
+pub const TYPE_ANY: u16 = 255;
+
+pub const CLASS_IN: u16 = 1;
+
+pub const RCODE_NOERROR: u8 = 0;
+pub const RCODE_FORMERR: u8 = 1;
+pub const RCODE_SERVFAIL: u8 = 2;
+pub const RCODE_NXDOMAIN: u8 = 3;
+pub const RCODE_NOTIMP: u8 = 4;
+pub const RCODE_REFUSED: u8 = 5;
+
+/// A DNS query or response.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DnsMessage {
+    pub id: u16,
+    pub response: bool,// QR
+    pub opcode: u8,
+    pub authoritative: bool,// AA
+    pub truncated: bool,// TC
+    pub recursion_desired: bool,// RD
+    pub recursion_available: bool,// RA
+    pub authentic_data: bool,// AD
+    pub checking_disabled: bool,// CD
+    pub rcode: u8,
+    pub questions: Vec<DnsQuestion>,
+    pub answers: Vec<DnsRecord>,
+    pub authorities: Vec<DnsRecord>,
+    pub additionals: Vec<DnsRecord>,// The EDNS OPT record goes here
+}
+
+/// An entry of the question section.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DnsQuestion {
+    pub name: String,
+    pub qtype: u16,
+    pub qclass: u16,
+}
+
+/// A resource record. The type follows from data.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DnsRecord {
+    pub name: String,
+    pub class: u16,// The UDP payload size for OPT
+    pub ttl: u32,// Extended rcode and flags for OPT
+    pub data: DnsRData,
+}
+
+/// The data of a resource record.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DnsRData {
+    A(Ipv4Addr),
+    AAAA(Ipv6Addr),
+    NS(String),
+    CNAME(String),
+    PTR(String),
+    MX{preference: u16, exchange: String},
+    TXT(Vec<Vec<u8>>),// The character strings of the record
+    SOA{mname: String, rname: String, serial: u32, refresh: u32, retry: u32, expire: u32, minimum: u32},
+    SRV{priority: u16, weight: u16, port: u16, target: String},
+    Other(u16, Vec<u8>),// Any other type, with its data as sent
+}
+
+/// What DnsRelay does with a query or response after the handler has seen it.
+#[derive(Debug)]
+pub enum DnsRet {
+    Relay,// Relay the (possibly modified) message
+    Reply(DnsMessage),// Queries: answer the client with this instead of relaying. Responses: relay this instead
+    Drop,// Dont relay the message. A dropped query is never answered
+    Shutdown,// Shutdown TCP connection
+}
+
+/// Callbacks for DNS traffic, used through DnsRelay.
+pub trait DnsHandler {
+    fn on_query(&mut self, _query: &mut DnsMessage) -> DnsRet {DnsRet::Relay}
+    /// Called with the query the response answers, if the relay saw it.
+    fn on_response(&mut self, _query: Option<&DnsMessage>, _response: &mut DnsMessage) -> DnsRet {DnsRet::Relay}
+    fn open_callback(&mut self, _session: SessionHandle){}
+    fn close_callback(&mut self, _reason: CloseReason){}
+}
+
+/// Turns a DnsHandler into HandlerCallbacks.
+pub struct DnsRelay<T: DnsHandler> {
+    handler: T,
+    ds_buffer: Vec<u8>,
+    us_buffer: Vec<u8>,
+    // Relayed queries waiting for their response, by message id. Responses may come in any order
+    pending: HashMap<u16, DnsMessage>,
+    // Zone transfers under way, by message id: the serial of their first SOA and the SOA records seen so far
+    transfers: HashMap<u16, (u32, usize)>,
+}
+
+// Compression pointers followed for one name before it is taken as a loop
+const MAX_POINTERS: usize = 64;
+
+impl<T: DnsHandler> DnsRelay<T> {
+
+    pub fn new(handler: T) -> Self {
+        DnsRelay {
+            handler,
+            ds_buffer: Vec::new(),
+            us_buffer: Vec::new(),
+            pending: HashMap::new(),
+            transfers: HashMap::new(),
+        }
+    }
+
+    /// Follows a zone transfer through one of its responses, true once that was its last.
+    /// AXFR and IXFR both end with the SOA they started with, an IXFR that has nothing to send with that SOA alone.
+    fn transfer_done(&mut self, query: &DnsMessage, response: &DnsMessage) -> bool {
+
+        if response.rcode != RCODE_NOERROR {
+            self.transfers.remove(&response.id);
+            return true;
+        }
+
+        let (first_serial, soa_seen) = self.transfers.entry(response.id).or_insert((0, 0));
+        let mut done = false;
+
+        for record in &response.answers {
+            done = false;
+            if let DnsRData::SOA{serial, ..} = record.data {
+                *soa_seen += 1;
+                if *soa_seen == 1 {
+                    *first_serial = serial;
+                    done = query.questions.iter().any(|question| question.qtype == 251) && response.answers.len() == 1;
+                } else {
+                    done = serial == *first_serial;
+                }
+            }
+        }
+
+        if done {
+            self.transfers.remove(&response.id);
+        }
+        done
+    }
+}
+
+impl<T: DnsHandler + Clone> Clone for DnsRelay<T> {
+
+    /// Clones the handler into a relay that has not seen any traffic.
+    fn clone(&self) -> Self {
+        DnsRelay::new(self.handler.clone())
+    }
+}
+
+impl<T: DnsHandler> HandlerCallbacks for DnsRelay<T> {
+
+    fn ds_b_callback(&mut self, in_data: Vec<u8>) -> CallbackRet {
+
+        self.ds_buffer.extend(in_data);
+
+        let mut relay_data = Vec::new();
+        let mut spoof_data = Vec::new();
+
+        while let Some(message) = next_message(&mut self.ds_buffer) {
+
+            let mut query = match DnsMessage::parse(&message) {
+                Some(query) => query,
+                None => {
+                    write_message(&mut relay_data, &message);
+                    continue;
+                },
+            };
+            let original = query.clone();
+
+            let written = match self.handler.on_query(&mut query) {
+                DnsRet::Relay => {
+                    let written = match query == original {
+                        true => write_message(&mut relay_data, &message),
+                        false => write_message(&mut relay_data, &query.to_bytes()),
+                    };
+                    self.pending.insert(query.id, query);
+                    written
+                },
+                DnsRet::Reply(response) => write_message(&mut spoof_data, &response.to_bytes()),
+                DnsRet::Drop => true,
+                DnsRet::Shutdown => return CallbackRet::Shutdown,
+            };
+            if !written {
+                return CallbackRet::Shutdown;
+            }
+        }
+
+        callback_ret(relay_data, spoof_data)
+    }
+
+    fn us_b_callback(&mut self, in_data: Vec<u8>) -> CallbackRet {
+
+        self.us_buffer.extend(in_data);
+
+        let mut relay_data = Vec::new();
+
+        while let Some(message) = next_message(&mut self.us_buffer) {
+
+            let mut response = match DnsMessage::parse(&message) {
+                Some(response) => response,
+                None => {
+                    write_message(&mut relay_data, &message);
+                    continue;
+                },
+            };
+            let original = response.clone();
+
+            // Zone transfers answer one query with many messages
+            let query = match self.pending.get(&response.id).cloned() {
+                Some(query) if query.is_zone_transfer() => {
+                    if self.transfer_done(&query, &response) {
+                        self.pending.remove(&response.id);
+                    }
+                    Some(query)
+                },
+                _ => self.pending.remove(&response.id),
+            };
+
+            let written = match self.handler.on_response(query.as_ref(), &mut response) {
+                DnsRet::Relay if response == original => write_message(&mut relay_data, &message),
+                DnsRet::Relay => write_message(&mut relay_data, &response.to_bytes()),
+                DnsRet::Reply(replacement) => write_message(&mut relay_data, &replacement.to_bytes()),
+                DnsRet::Drop => true,
+                DnsRet::Shutdown => return CallbackRet::Shutdown,
+            };
+            if !written {
+                return CallbackRet::Shutdown;
+            }
+        }
+
+        callback_ret(relay_data, Vec::new())
+    }
+
+    fn open_callback(&mut self, session: SessionHandle) {
+        self.handler.open_callback(session);
+    }
+
+    fn close_callback(&mut self, reason: CloseReason) {
+        self.handler.close_callback(reason);
+    }
+}
+
+impl DnsMessage {
+
+    /// A response to this query with the given answers.
+    pub fn response(&self, answers: Vec<DnsRecord>) -> DnsMessage {
+        DnsMessage {
+            id: self.id,
+            response: true,
+            opcode: self.opcode,
+            authoritative: false,
+            truncated: false,
+            recursion_desired: self.recursion_desired,
+            recursion_available: true,
+            authentic_data: false,
+            checking_disabled: self.checking_disabled,
+            rcode: RCODE_NOERROR,
+            questions: self.questions.clone(),
+            answers,
+            authorities: Vec::new(),
+            additionals: Vec::new(),
+        }
+    }
+
+    /// A response to this query without answers, RCODE_NXDOMAIN or RCODE_REFUSED for example.
+    pub fn error_response(&self, rcode: u8) -> DnsMessage {
+        let mut response = self.response(Vec::new());
+        response.rcode = rcode;
+        response
+    }
+
+    /// Parses a DNS message, without the length prefix of TCP.
+    pub fn parse(message: &[u8]) -> Option<DnsMessage> {
+
+        let mut reader = Reader{message, position: 0};
+
+        let id = reader.u16()?;
+        let flags = reader.u16()?;
+        let counts = [reader.u16()?, reader.u16()?, reader.u16()?, reader.u16()?];
+
+        let mut questions = Vec::new();
+        for _ in 0..counts[0] {
+            questions.push(DnsQuestion {
+                name: reader.name()?,
+                qtype: reader.u16()?,
+                qclass: reader.u16()?,
+            });
+        }
+
+        let mut sections = [Vec::new(), Vec::new(), Vec::new()];
+        for (section, count) in sections.iter_mut().zip(&counts[1..]) {
+            for _ in 0..*count {
+                section.push(reader.record()?);
+            }
+        }
+        if reader.position != message.len() {
+            return None;
+        }
+        let [answers, authorities, additionals] = sections;
+
+        Some(DnsMessage {
+            id,
+            response: flags & 0x8000 != 0,
+            opcode: ((flags >> 11) & 0x0f) as u8,
+            authoritative: flags & 0x0400 != 0,
+            truncated: flags & 0x0200 != 0,
+            recursion_desired: flags & 0x0100 != 0,
+            recursion_available: flags & 0x0080 != 0,
+            authentic_data: flags & 0x0020 != 0,
+            checking_disabled: flags & 0x0010 != 0,
+            rcode: (flags & 0x0f) as u8,
+            questions,
+            answers,
+            authorities,
+            additionals,
+        })
+    }
+
+    /// The message as sent, without the length prefix of TCP.
+    pub fn to_bytes(&self) -> Vec<u8> {
+
+        let mut flags = ((self.opcode & 0x0f) as u16) << 11 | (self.rcode & 0x0f) as u16;
+        for (set, bit) in [
+            (self.response, 0x8000),
+            (self.authoritative, 0x0400),
+            (self.truncated, 0x0200),
+            (self.recursion_desired, 0x0100),
+            (self.recursion_available, 0x0080),
+            (self.authentic_data, 0x0020),
+            (self.checking_disabled, 0x0010),
+        ] {
+            if set {
+                flags |= bit;
+            }
+        }
+
+        let mut out = Vec::new();
+        out.extend_from_slice(&self.id.to_be_bytes());
+        out.extend_from_slice(&flags.to_be_bytes());
+        for count in [self.questions.len(), self.answers.len(), self.authorities.len(), self.additionals.len()] {
+            out.extend_from_slice(&(count as u16).to_be_bytes());
+        }
+
+        for question in &self.questions {
+            write_name(&mut out, &question.name);
+            out.extend_from_slice(&question.qtype.to_be_bytes());
+            out.extend_from_slice(&question.qclass.to_be_bytes());
+        }
+        for record in self.answers.iter().chain(&self.authorities).chain(&self.additionals) {
+            record.write(&mut out);
+        }
+        out
+    }
+
+    fn is_zone_transfer(&self) -> bool {
+        // AXFR and IXFR
+        self.questions.iter().any(|question| question.qtype == 252 || question.qtype == 251)
+    }
+}
+
+impl DnsRecord {
+
+    /// A record of class IN.
+    pub fn new(name: &str, ttl: u32, data: DnsRData) -> Self {
+        DnsRecord {
+            name: name.to_string(),
+            class: CLASS_IN,
+            ttl,
+            data,
+        }
+    }
+
+    pub fn rtype(&self) -> u16 {
+        match &self.data {
+            DnsRData::A(_) => TYPE_A,
+            DnsRData::AAAA(_) => TYPE_AAAA,
+            DnsRData::NS(_) => TYPE_NS,
+            DnsRData::CNAME(_) => TYPE_CNAME,
+            DnsRData::PTR(_) => TYPE_PTR,
+            DnsRData::MX{..} => TYPE_MX,
+            DnsRData::TXT(_) => TYPE_TXT,
+            DnsRData::SOA{..} => TYPE_SOA,
+            DnsRData::SRV{..} => TYPE_SRV,
+            DnsRData::Other(rtype, _) => *rtype,
+        }
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+
+        write_name(out, &self.name);
+        out.extend_from_slice(&self.rtype().to_be_bytes());
+        out.extend_from_slice(&self.class.to_be_bytes());
+        out.extend_from_slice(&self.ttl.to_be_bytes());
+
+        let mut data = Vec::new();
+        match &self.data {
+            DnsRData::A(address) => data.extend_from_slice(&address.octets()),
+            DnsRData::AAAA(address) => data.extend_from_slice(&address.octets()),
+            DnsRData::NS(name) | DnsRData::CNAME(name) | DnsRData::PTR(name) => write_name(&mut data, name),
+            DnsRData::MX{preference, exchange} => {
+                data.extend_from_slice(&preference.to_be_bytes());
+                write_name(&mut data, exchange);
+            },
+            DnsRData::TXT(strings) => {
+                for string in strings {
+                    // Character strings hold at most 255 bytes
+                    for chunk in string.chunks(255) {
+                        data.push(chunk.len() as u8);
+                        data.extend_from_slice(chunk);
+                    }
+                }
+            },
+            DnsRData::SOA{mname, rname, serial, refresh, retry, expire, minimum} => {
+                write_name(&mut data, mname);
+                write_name(&mut data, rname);
+                for value in [serial, refresh, retry, expire, minimum] {
+                    data.extend_from_slice(&value.to_be_bytes());
+                }
+            },
+            DnsRData::SRV{priority, weight, port, target} => {
+                data.extend_from_slice(&priority.to_be_bytes());
+                data.extend_from_slice(&weight.to_be_bytes());
+                data.extend_from_slice(&port.to_be_bytes());
+                write_name(&mut data, target);
+            },
+            DnsRData::Other(_, raw) => data.extend_from_slice(raw),
+        }
+
+        out.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        out.extend(data);
+    }
+}
+
+/// Takes the first complete length prefixed message out of buffer, without its prefix.
+fn next_message(buffer: &mut Vec<u8>) -> Option<Vec<u8>> {
+    if buffer.len() < 2 {
+        return None;
+    }
+    let end = 2 + u16::from_be_bytes([buffer[0], buffer[1]]) as usize;
+    if buffer.len() < end {
+        return None;
+    }
+    let message = buffer[2..end].to_vec();
+    buffer.drain(..end);
+    Some(message)
+}
+
+/// Writes a message with its length prefix. False if it is longer than a u16 length allows.
+fn write_message(out: &mut Vec<u8>, message: &[u8]) -> bool {
+    if message.len() > u16::MAX as usize {
+        tracing::warn!(length = message.len(), "DNS message too long to be sent over TCP");
+        return false;
+    }
+    out.extend_from_slice(&(message.len() as u16).to_be_bytes());
+    out.extend_from_slice(message);
+    true
+}
+
+/// Writes a name in presentation form ("www.example.com", "." for the root) as labels.
+/// "\." and "\DDD" escape bytes that are not printable or are part of a label.
+fn write_name(out: &mut Vec<u8>, name: &str) {
+
+    let name = name.as_bytes();
+    let mut label = Vec::new();
+    let mut position = 0;
+
+    while position < name.len() {
+        match name[position] {
+            b'.' => {
+                write_label(out, &label);
+                label.clear();
+            },
+            b'\\' => {
+                let decimal = name.get(position + 1..position + 4)
+                    .and_then(|digits| std::str::from_utf8(digits).ok())
+                    .and_then(|digits| digits.parse::<u8>().ok());
+                match decimal {
+                    Some(value) => {
+                        label.push(value);
+                        position += 3;
+                    },
+                    None => if let Some(escaped) = name.get(position + 1) {
+                        label.push(*escaped);
+                        position += 1;
+                    },
+                }
+            },
+            byte => label.push(byte),
+        }
+        position += 1;
+    }
+    write_label(out, &label);
+    out.push(0);
+}
+
+/// Empty labels (a trailing dot, the root) are left out.
+fn write_label(out: &mut Vec<u8>, label: &[u8]) {
+    if label.is_empty() {
+        return;
+    }
+    let label = &label[..label.len().min(63)];
+    out.push(label.len() as u8);
+    out.extend_from_slice(label);
+}
+
+/// Reads the fields of a message front to back. Names may point anywhere in the message.
+struct Reader<'a> {
+    message: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+
+    fn bytes(&mut self, length: usize) -> Option<&'a [u8]> {
+        let bytes = self.message.get(self.position..self.position.checked_add(length)?)?;
+        self.position += length;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|bytes| bytes[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes(2).map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.bytes(4).map(|bytes| u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// A possibly compressed name, in presentation form.
+    fn name(&mut self) -> Option<String> {
+
+        let mut name = String::new();
+        let mut position = self.position;
+        // Where reading goes on once the name is read, set at the first pointer
+        let mut resume = None;
+        let mut pointers = 0;
+
+        loop {
+            let length = *self.message.get(position)? as usize;
+            match length {
+                0 => {
+                    position += 1;
+                    break;
+                },
+                length if length & 0xc0 == 0xc0 => {
+                    let target = (length & 0x3f) << 8 | *self.message.get(position + 1)? as usize;
+                    resume.get_or_insert(position + 2);
+                    pointers += 1;
+                    if pointers > MAX_POINTERS {
+                        return None;
+                    }
+                    position = target;
+                },
+                length if length <= 63 => {
+                    let label = self.message.get(position + 1..position + 1 + length)?;
+                    if !name.is_empty() {
+                        name.push('.');
+                    }
+                    for byte in label {
+                        match byte {
+                            b'.' | b'\\' => {
+                                name.push('\\');
+                                name.push(*byte as char);
+                            },
+                            0x21..=0x7e => name.push(*byte as char),
+                            _ => name.push_str(&format!("\\{:03}", byte)),
+                        }
+                    }
+                    position += 1 + length;
+                },
+                _ => return None,
+            }
+        }
+
+        self.position = resume.unwrap_or(position);
+        if name.is_empty() {
+            name.push('.');
+        }
+        Some(name)
+    }
+
+    fn record(&mut self) -> Option<DnsRecord> {
+
+        let name = self.name()?;
+        let rtype = self.u16()?;
+        let class = self.u16()?;
+        let ttl = self.u32()?;
+        let length = self.u16()? as usize;
+        let end = self.position + length;
+        if end > self.message.len() {
+            return None;
+        }
+
+        let data = match rtype {
+            TYPE_A if length == 4 => {
+                let octets = self.bytes(4)?;
+                DnsRData::A(Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3]))
+            },
+            TYPE_AAAA if length == 16 => {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(self.bytes(16)?);
+                DnsRData::AAAA(Ipv6Addr::from(octets))
+            },
+            TYPE_NS => DnsRData::NS(self.name()?),
+            TYPE_CNAME => DnsRData::CNAME(self.name()?),
+            TYPE_PTR => DnsRData::PTR(self.name()?),
+            TYPE_MX => DnsRData::MX{preference: self.u16()?, exchange: self.name()?},
+            TYPE_TXT => {
+                let mut strings = Vec::new();
+                while self.position < end {
+                    let length = self.u8()? as usize;
+                    strings.push(self.bytes(length)?.to_vec());
+                }
+                DnsRData::TXT(strings)
+            },
+            TYPE_SOA => DnsRData::SOA {
+                mname: self.name()?,
+                rname: self.name()?,
+                serial: self.u32()?,
+                refresh: self.u32()?,
+                retry: self.u32()?,
+                expire: self.u32()?,
+                minimum: self.u32()?,
+            },
+            TYPE_SRV => DnsRData::SRV {
+                priority: self.u16()?,
+                weight: self.u16()?,
+                port: self.u16()?,
+                target: self.name()?,
+            },
+            _ => DnsRData::Other(rtype, self.bytes(length)?.to_vec()),
+        };
+
+        // The data has to take exactly the length it was given
+        if self.position != end {
+            return None;
+        }
+        Some(DnsRecord{name, class, ttl, data})
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    struct Passive;
+
+    impl DnsHandler for Passive {}
+
+    struct Growing;
+
+    impl DnsHandler for Growing {
+        fn on_response(&mut self, _query: Option<&DnsMessage>, response: &mut DnsMessage) -> DnsRet {
+            response.answers.push(DnsRecord::new("grown.example.com", 60, DnsRData::TXT(vec![vec![b'x'; 60000]])));
+            DnsRet::Relay
+        }
+    }
+
+    fn framed(message: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        assert!(write_message(&mut out, message));
+        out
+    }
+
+    fn relayed(callback_ret: CallbackRet) -> Vec<u8> {
+        match callback_ret {
+            CallbackRet::Relay(data) => data,
+            other => panic!("not relayed: {:?}", other),
+        }
+    }
+
+    fn query(id: u16, name: &str, qtype: u16) -> DnsMessage {
+        DnsMessage {
+            id,
+            response: false,
+            opcode: 0,
+            authoritative: false,
+            truncated: false,
+            recursion_desired: true,
+            recursion_available: false,
+            authentic_data: false,
+            checking_disabled: false,
+            rcode: RCODE_NOERROR,
+            questions: vec![DnsQuestion{name: name.to_string(), qtype, qclass: CLASS_IN}],
+            answers: Vec::new(),
+            authorities: Vec::new(),
+            additionals: Vec::new(),
+        }
+    }
+
+    fn soa(serial: u32) -> DnsRecord {
+        DnsRecord::new("example.com", 60, DnsRData::SOA{mname: "ns.example.com".to_string(), rname: "admin.example.com".to_string(), serial, refresh: 1, retry: 2, expire: 3, minimum: 4})
+    }
+
+    // A response to "example.com A" whose answer points back at the question name
+    const COMPRESSED: &[u8] = &[
+        0x12, 0x34, 0x81, 0x80, 0, 1, 0, 1, 0, 0, 0, 0,
+        7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm', 0, 0, 1, 0, 1,
+        0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 10, 0, 0, 1,
+    ];
+
+    #[test]
+    fn message_round_trips() {
+        let mut message = query(7, "www.example.com", TYPE_A).response(vec![
+            DnsRecord::new("www.example.com", 60, DnsRData::A([10, 0, 0, 1].into())),
+            DnsRecord::new("www.example.com", 60, DnsRData::MX{preference: 10, exchange: "mail.example.com".to_string()}),
+            DnsRecord::new("www.example.com", 60, DnsRData::TXT(vec![b"hello".to_vec()])),
+        ]);
+        message.authorities.push(soa(1));
+        assert_eq!(DnsMessage::parse(&message.to_bytes()), Some(message));
+    }
+
+    #[test]
+    fn compressed_names_are_followed() {
+        let message = DnsMessage::parse(COMPRESSED).unwrap();
+        assert_eq!(message.answers[0].name, "example.com");
+        assert_eq!(message.answers[0].data, DnsRData::A([10, 0, 0, 1].into()));
+    }
+
+    #[test]
+    fn unchanged_messages_keep_their_bytes() {
+        let mut relay = DnsRelay::new(Passive);
+        assert_eq!(relayed(relay.us_b_callback(framed(COMPRESSED))), framed(COMPRESSED));
+    }
+
+    #[test]
+    fn oversized_messages_end_the_session() {
+        let mut relay = DnsRelay::new(Growing);
+        let mut response = DnsMessage::parse(COMPRESSED).unwrap();
+        response.answers.push(DnsRecord::new("big.example.com", 60, DnsRData::TXT(vec![vec![b'y'; 10000]])));
+        assert!(matches!(relay.us_b_callback(framed(&response.to_bytes())), CallbackRet::Shutdown));
+    }
+
+    #[test]
+    fn zone_transfers_are_forgotten_once_done() {
+        let mut relay = DnsRelay::new(Passive);
+        let axfr = query(9, "example.com", 252);
+        relay.ds_b_callback(framed(&axfr.to_bytes()));
+        assert!(relay.pending.contains_key(&9));
+
+        let first = axfr.response(vec![soa(5), DnsRecord::new("a.example.com", 60, DnsRData::A([10, 0, 0, 1].into()))]);
+        relay.us_b_callback(framed(&first.to_bytes()));
+        assert!(relay.pending.contains_key(&9));
+
+        let last = axfr.response(vec![DnsRecord::new("b.example.com", 60, DnsRData::A([10, 0, 0, 2].into())), soa(5)]);
+        relay.us_b_callback(framed(&last.to_bytes()));
+        assert!(relay.pending.is_empty());
+        assert!(relay.transfers.is_empty());
+
+        let ixfr = query(10, "example.com", 251);
+        relay.ds_b_callback(framed(&ixfr.to_bytes()));
+        relay.us_b_callback(framed(&ixfr.response(vec![soa(5)]).to_bytes()));
+        assert!(relay.pending.is_empty());
+    }
+
+    #[test]
+    fn malformed_messages_do_not_parse() {
+        // Truncated header
+        assert_eq!(DnsMessage::parse(&COMPRESSED[..10]), None);
+        // Answer cut short
+        assert_eq!(DnsMessage::parse(&COMPRESSED[..COMPRESSED.len() - 2]), None);
+        // A name pointing at itself
+        let mut looped = COMPRESSED[..12].to_vec();
+        looped[5] = 1;
+        looped.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1]);
+        assert_eq!(DnsMessage::parse(&looped), None);
+        // Trailing bytes
+        assert_eq!(DnsMessage::parse(&[COMPRESSED, &[0]].concat()), None);
+
+        // Relayed as they are
+        let mut relay = DnsRelay::new(Passive);
+        assert_eq!(relayed(relay.us_b_callback(framed(&looped))), framed(&looped));
+    }
+}
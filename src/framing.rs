@@ -0,0 +1,296 @@
+use crate::{
+    Framer,
+    Framing,
+    redis,
+    mqtt,
+};
+
+/// Data of one direction waiting to become a complete message.
+pub struct FrameBuffer {
+    framer: Option<Box<dyn Framer>>,
+    buffer: Vec<u8>,
+    max_frame_size: usize,
+}
+
+impl FrameBuffer {
+
+    pub fn new(framing: &Framing, max_frame_size: usize) -> Self {
+
+        let framer: Option<Box<dyn Framer>> = match framing {
+            Framing::NONE => None,
+            Framing::Lines => Some(Box::new(DelimiterFramer::new(b"\n".to_vec()))),
+            Framing::FixedLength(length) => Some(Box::new(FixedLengthFramer((*length).max(1)))),
+            Framing::LengthPrefixedU16BE => Some(Box::new(LengthPrefixFramer{width: 2, big_endian: true})),
+            Framing::LengthPrefixedU16LE => Some(Box::new(LengthPrefixFramer{width: 2, big_endian: false})),
+            Framing::LengthPrefixedU32BE => Some(Box::new(LengthPrefixFramer{width: 4, big_endian: true})),
+            Framing::LengthPrefixedU32LE => Some(Box::new(LengthPrefixFramer{width: 4, big_endian: false})),
+            Framing::Delimiter(delimiter) => Some(Box::new(DelimiterFramer::new(delimiter.clone()))),
+            Framing::TLSRecord => Some(Box::new(TlsRecordFramer)),
+            Framing::RESP => Some(Box::new(RespFramer)),
+            Framing::MQTT => Some(Box::new(MqttFramer)),
+            Framing::Custom(new_framer) => Some(new_framer()),
+        };
+
+        FrameBuffer {
+            framer,
+            buffer: Vec::new(),
+            max_frame_size,
+        }
+    }
+
+    /// Adds data read from the stream and returns every message it completed.
+    pub fn push(&mut self, data: Vec<u8>) -> Vec<Vec<u8>> {
+
+        let framer = match self.framer.as_mut() {
+            Some(framer) => framer,
+            None => return vec![data],
+        };
+
+        self.buffer.extend(data);
+
+        let mut frames = Vec::new();
+        while let Some(frame) = framer.next_frame(&mut self.buffer) {
+            frames.push(frame);
+        }
+
+        // There is no waiting for the end of a message this long, the rest of the stream goes on unframed
+        if self.buffer.len() > self.max_frame_size {
+            tracing::debug!(buffered = self.buffer.len(), "message longer than max_frame_size, framing stopped");
+            self.framer = None;
+            frames.push(std::mem::take(&mut self.buffer));
+        }
+        frames
+    }
+
+    /// Takes whatever is left of an incomplete message, for when the stream stopped sending.
+    pub fn take_remainder(&mut self) -> Option<Vec<u8>> {
+        if self.buffer.is_empty() {
+            None
+        } else {
+            Some(std::mem::take(&mut self.buffer))
+        }
+    }
+
+    /// Bytes held back waiting for the rest of a message.
+    pub fn buffered(&self) -> usize {
+        self.buffer.len()
+    }
+}
+
+struct DelimiterFramer {
+    delimiter: Vec<u8>,
+    // How much of the buffer was searched for the delimiter already
+    searched: usize,
+}
+
+impl DelimiterFramer {
+
+    fn new(delimiter: Vec<u8>) -> Self {
+        DelimiterFramer {
+            delimiter,
+            searched: 0,
+        }
+    }
+}
+
+impl Framer for DelimiterFramer {
+
+    fn next_frame(&mut self, buffer: &mut Vec<u8>) -> Option<Vec<u8>> {
+
+        if buffer.is_empty() {
+            return None;
+        }
+        // An empty delimiter frames nothing, every read is a message
+        if self.delimiter.is_empty() {
+            return Some(std::mem::take(buffer));
+        }
+
+        // The delimiter may have started in the last bytes searched
+        let start = self.searched.saturating_sub(self.delimiter.len() - 1).min(buffer.len());
+        match buffer[start..].windows(self.delimiter.len()).position(|window| window == self.delimiter.as_slice()) {
+            Some(position) => {
+                self.searched = 0;
+                Some(buffer.drain(..start + position + self.delimiter.len()).collect())
+            },
+            None => {
+                self.searched = buffer.len();
+                None
+            },
+        }
+    }
+}
+
+struct FixedLengthFramer(usize);
+
+impl Framer for FixedLengthFramer {
+
+    fn next_frame(&mut self, buffer: &mut Vec<u8>) -> Option<Vec<u8>> {
+        if buffer.len() < self.0 {
+            return None;
+        }
+        Some(buffer.drain(..self.0).collect())
+    }
+}
+
+struct LengthPrefixFramer {
+    width: usize,
+    big_endian: bool,
+}
+
+impl Framer for LengthPrefixFramer {
+
+    fn next_frame(&mut self, buffer: &mut Vec<u8>) -> Option<Vec<u8>> {
+
+        if buffer.len() < self.width {
+            return None;
+        }
+
+        let prefix = &buffer[..self.width];
+        let length = if self.big_endian {
+            prefix.iter().fold(0usize, |length, byte| (length << 8) | *byte as usize)
+        } else {
+            prefix.iter().rev().fold(0usize, |length, byte| (length << 8) | *byte as usize)
+        };
+
+        let end = self.width + length;
+        if buffer.len() < end {
+            return None;
+        }
+        Some(buffer.drain(..end).collect())
+    }
+}
+
+struct TlsRecordFramer;
+
+impl Framer for TlsRecordFramer {
+
+    fn next_frame(&mut self, buffer: &mut Vec<u8>) -> Option<Vec<u8>> {
+
+        // Content type, protocol version and a big endian u16 length
+        if buffer.len() < 5 {
+            return None;
+        }
+
+        let end = 5 + u16::from_be_bytes([buffer[3], buffer[4]]) as usize;
+        if buffer.len() < end {
+            return None;
+        }
+        Some(buffer.drain(..end).collect())
+    }
+}
+
+struct RespFramer;
+
+impl Framer for RespFramer {
+
+    fn next_frame(&mut self, buffer: &mut Vec<u8>) -> Option<Vec<u8>> {
+        match redis::value_length(buffer) {
+            redis::Parsed::Complete(_, length) => Some(buffer.drain(..length).collect()),
+            redis::Parsed::Incomplete(_) => None,
+            // Not RESP, there is no telling where it ends
+            redis::Parsed::Invalid => Some(std::mem::take(buffer)),
+        }
+    }
+}
+
+struct MqttFramer;
+
+impl Framer for MqttFramer {
+
+    fn next_frame(&mut self, buffer: &mut Vec<u8>) -> Option<Vec<u8>> {
+        match mqtt::packet_length(buffer) {
+            mqtt::Parsed::Complete(_, length) => Some(buffer.drain(..length).collect()),
+            mqtt::Parsed::Incomplete => None,
+            // A remaining length longer than four bytes, there is no telling where it ends
+            mqtt::Parsed::Invalid => Some(std::mem::take(buffer)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn frames(framing: Framing, reads: &[&[u8]]) -> (Vec<Vec<u8>>, Option<Vec<u8>>) {
+        let mut frame_buffer = FrameBuffer::new(&framing, 1024);
+        let frames = reads.iter().flat_map(|read| frame_buffer.push(read.to_vec())).collect();
+        (frames, frame_buffer.take_remainder())
+    }
+
+    #[test]
+    fn lines_are_framed() {
+        let (lines, remainder) = frames(Framing::Lines, &[b"first\nsec", b"ond\nthird\n", b"fou"]);
+        assert_eq!(lines, vec![b"first\n".to_vec(), b"second\n".to_vec(), b"third\n".to_vec()]);
+        assert_eq!(remainder, Some(b"fou".to_vec()));
+    }
+
+    #[test]
+    fn delimiters_split_across_reads_are_found() {
+        let (messages, remainder) = frames(Framing::Delimiter(b"\r\n\r\n".to_vec()), &[b"GET / HTTP/1.1\r\n", b"\r", b"\n\r\nrest"]);
+        assert_eq!(messages, vec![b"GET / HTTP/1.1\r\n\r\n".to_vec()]);
+        assert_eq!(remainder, Some(b"\r\nrest".to_vec()));
+
+        let (messages, remainder) = frames(Framing::Delimiter(Vec::new()), &[b"ab", b"cd"]);
+        assert_eq!(messages, vec![b"ab".to_vec(), b"cd".to_vec()]);
+        assert_eq!(remainder, None);
+    }
+
+    #[test]
+    fn fixed_lengths_are_framed() {
+        let (messages, remainder) = frames(Framing::FixedLength(3), &[b"ab", b"cdefghi", b"j"]);
+        assert_eq!(messages, vec![b"abc".to_vec(), b"def".to_vec(), b"ghi".to_vec()]);
+        assert_eq!(remainder, Some(b"j".to_vec()));
+    }
+
+    #[test]
+    fn length_prefixes_are_framed() {
+        let cases: [(Framing, &[u8]); 4] = [
+            (Framing::LengthPrefixedU16BE, b"\x00\x02"),
+            (Framing::LengthPrefixedU16LE, b"\x02\x00"),
+            (Framing::LengthPrefixedU32BE, b"\x00\x00\x00\x02"),
+            (Framing::LengthPrefixedU32LE, b"\x02\x00\x00\x00"),
+        ];
+        for (framing, prefix) in cases {
+            let message = [prefix, b"hi"].concat();
+            let empty = vec![0; prefix.len()];
+            // The prefix is split, then the rest comes with an empty message and the start of another
+            let second_read = [&message[1..], &empty, prefix, b"h"].concat();
+            let (messages, remainder) = frames(framing, &[&message[..1], &second_read]);
+            assert_eq!(messages, vec![message.clone(), empty]);
+            assert_eq!(remainder, Some([prefix, b"h"].concat()));
+        }
+    }
+
+    #[test]
+    fn tls_records_are_framed() {
+        let handshake = [&[0x16, 0x03, 0x01, 0x00, 0x04][..], &[1, 2, 3, 4]].concat();
+        let alert = vec![0x15, 0x03, 0x03, 0x00, 0x02, 0x01, 0x00];
+        let data = [handshake.clone(), alert.clone(), vec![0x17, 0x03]].concat();
+        let (records, remainder) = frames(Framing::TLSRecord, &[&data[..3], &data[3..]]);
+        assert_eq!(records, vec![handshake, alert]);
+        assert_eq!(remainder, Some(vec![0x17, 0x03]));
+    }
+
+    #[test]
+    fn unframed_reads_pass_as_they_are() {
+        let (messages, remainder) = frames(Framing::NONE, &[b"a", b"bc"]);
+        assert_eq!(messages, vec![b"a".to_vec(), b"bc".to_vec()]);
+        assert_eq!(remainder, None);
+    }
+
+    #[test]
+    fn messages_past_the_maximum_end_the_framing() {
+        let mut frame_buffer = FrameBuffer::new(&Framing::LengthPrefixedU32BE, 8);
+        assert_eq!(frame_buffer.push(b"\x00\x00\x00\x01a\xff\xff".to_vec()), vec![b"\x00\x00\x00\x01a".to_vec()]);
+        assert_eq!(frame_buffer.buffered(), 2);
+        assert_eq!(frame_buffer.push(b"\xff\xff\x00\x00\x00\x00\x00".to_vec()), vec![b"\xff\xff\xff\xff\x00\x00\x00\x00\x00".to_vec()]);
+        // From here on reads pass as they are
+        assert_eq!(frame_buffer.push(b"\x00\x00\x00\x01a".to_vec()), vec![b"\x00\x00\x00\x01a".to_vec()]);
+        assert_eq!(frame_buffer.take_remainder(), None);
+
+        let mut frame_buffer = FrameBuffer::new(&Framing::Lines, 4);
+        assert_eq!(frame_buffer.push(b"ab\ncdefg".to_vec()), vec![b"ab\n".to_vec(), b"cdefg".to_vec()]);
+        assert_eq!(frame_buffer.buffered(), 0);
+    }
+}
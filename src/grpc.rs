@@ -0,0 +1,543 @@
+//! gRPC aware callbacks.
+//!
+//! GrpcRelay wraps a GrpcHandler and implements HandlerCallbacks for it. It runs on top of
+//! http2::Http2Relay and, for every stream with a gRPC content-type, hands the handler the
+//! called service and method, the metadata, every single length-prefixed message and the trailers.
+//! Messages compressed with gzip or deflate are decompressed before the handler sees them and
+//! compressed the same way again afterwards.
+//! ```ignore
+//! use sslrelay::grpc::{GrpcRelay, GrpcHandler, GrpcCall, GrpcMessage, GrpcOptions};
+//! use sslrelay::protobuf::{DescriptorSet, ProtoValue};
+//!
+//! struct Handler;
+//!
+//! impl GrpcHandler for Handler {
+//!     fn on_request_message(&mut self, call: &GrpcCall, message: &mut GrpcMessage) {
+//!         println!("{}/{}: {:?}", call.service, call.method, message.decoded);
+//!         if let Some(ProtoValue::String(name)) = message.decoded.as_mut().and_then(|decoded| decoded.get_mut("name")) {
+//!             *name = name.to_uppercase();
+//!         }
+//!     }
+//! }
+//!
+//! let options = GrpcOptions {
+//!     descriptors: Some(Arc::new(DescriptorSet::from_file("services.pb").unwrap())),
+//!     ..Default::default()
+//! };
+//! let mut relay = sslrelay::SSLRelay::new(GrpcRelay::with_options(Handler, options), config);
+//! ```
+//! With a DescriptorSet (see the protobuf module) messages of known methods are decoded into
+//! GrpcMessage::decoded as well. Streams that are not gRPC pass through untouched.
+
+use std::io::{
+    Read,
+    Write,
+};
+
+use flate2::{
+    Compression,
+    read::{
+        GzDecoder,
+        ZlibDecoder,
+    },
+    write::{
+        GzEncoder,
+        ZlibEncoder,
+    },
+};
+
+use crate::{
+    HandlerCallbacks,
+    CallbackRet,
+    CloseReason,
+    SessionHandle,
+    HashMap,
+    Arc,
+    http2::{
+        Http2Handler,
+        Http2Relay,
+    },
+    protobuf::{
+        DescriptorSet,
+        ProtoMessage,
+    },
+};
+
+/// The call a stream carries, from its :path of "/package.Service/Method".
+#[derive(Clone, Debug)]
+pub struct GrpcCall {
+    pub stream_id: u32,
+    pub service: String,
+    pub method: String,
+}
+
+/// A single request or response message.
+#[derive(Clone, Debug)]
+pub struct GrpcMessage {
+    /// The message, already decompressed.
+    pub data: Vec<u8>,
+    /// Whether the message was compressed on the wire. It is compressed again with the
+    /// stream's grpc-encoding, set it to false to send it uncompressed.
+    pub compressed: bool,
+    /// The message decoded with GrpcOptions::descriptors. If it is changed it is encoded
+    /// again and replaces data.
+    pub decoded: Option<ProtoMessage>,
+}
+
+/// Callbacks for gRPC traffic, used through GrpcRelay.
+/// The grpc-encoding metadata should be left as it is, messages are compressed again with the original encoding.
+pub trait GrpcHandler {
+    fn on_request_metadata(&mut self, _call: &GrpcCall, _metadata: &mut Vec<(String, String)>){}
+    fn on_request_message(&mut self, _call: &GrpcCall, _message: &mut GrpcMessage){}
+    fn on_response_metadata(&mut self, _call: &GrpcCall, _metadata: &mut Vec<(String, String)>){}
+    fn on_response_message(&mut self, _call: &GrpcCall, _message: &mut GrpcMessage){}
+    /// Called with the trailers (grpc-status, grpc-message) ending a call,
+    /// also for responses made of trailers only.
+    fn on_trailers(&mut self, _call: &GrpcCall, _trailers: &mut Vec<(String, String)>){}
+    fn open_callback(&mut self, _session: SessionHandle){}
+    fn close_callback(&mut self, _reason: CloseReason){}
+}
+
+#[derive(Clone)]
+pub struct GrpcOptions {
+    /// Message definitions to decode messages into GrpcMessage::decoded.
+    pub descriptors: Option<Arc<DescriptorSet>>,
+    /// A message larger than this is relayed untouched along with the rest of its stream.
+    pub max_message_size: usize,
+}
+
+impl Default for GrpcOptions {
+    fn default() -> Self {
+        GrpcOptions {
+            descriptors: None,
+            max_message_size: 16 * 1024 * 1024,
+        }
+    }
+}
+
+/// Turns a GrpcHandler into HandlerCallbacks.
+pub struct GrpcRelay<T: GrpcHandler> {
+    http2: Http2Relay<GrpcStreams<T>>,
+}
+
+/// The HTTP/2 side of GrpcRelay, following the gRPC calls of one connection.
+struct GrpcStreams<T: GrpcHandler> {
+    handler: T,
+    options: GrpcOptions,
+    calls: HashMap<u32, GrpcStream>,
+}
+
+struct GrpcStream {
+    call: GrpcCall,
+    // Message names from the descriptors
+    input_type: Option<String>,
+    output_type: Option<String>,
+    request: GrpcDirection,
+    response: GrpcDirection,
+    response_started: bool,
+}
+
+/// Messages flowing one way on a stream.
+struct GrpcDirection {
+    buffer: Vec<u8>,
+    encoding: Option<String>,
+    passthrough: bool,
+}
+
+impl<T: GrpcHandler> GrpcRelay<T> {
+
+    pub fn new(handler: T) -> Self {
+        GrpcRelay::with_options(handler, GrpcOptions::default())
+    }
+
+    pub fn with_options(handler: T, options: GrpcOptions) -> Self {
+        GrpcRelay {
+            http2: Http2Relay::new(GrpcStreams {
+                handler,
+                options,
+                calls: HashMap::new(),
+            }),
+        }
+    }
+}
+
+impl<T: GrpcHandler + Clone> Clone for GrpcRelay<T> {
+
+    /// Clones the handler into a relay that has not seen any traffic.
+    fn clone(&self) -> Self {
+        let streams = self.http2.handler();
+        GrpcRelay::with_options(streams.handler.clone(), streams.options.clone())
+    }
+}
+
+impl<T: GrpcHandler> HandlerCallbacks for GrpcRelay<T> {
+
+    fn ds_b_callback(&mut self, in_data: Vec<u8>) -> CallbackRet {
+        self.http2.ds_b_callback(in_data)
+    }
+
+    fn us_b_callback(&mut self, in_data: Vec<u8>) -> CallbackRet {
+        self.http2.us_b_callback(in_data)
+    }
+
+    fn open_callback(&mut self, session: SessionHandle) {
+        self.http2.open_callback(session);
+    }
+
+    fn close_callback(&mut self, reason: CloseReason) {
+        self.http2.close_callback(reason);
+    }
+}
+
+impl<T: GrpcHandler> GrpcStreams<T> {
+
+    /// Replaces `data` with the complete messages it finishes, as the handler left them.
+    /// Bytes of a message that is not complete yet stay buffered.
+    fn messages(&mut self, stream_id: u32, from_client: bool, data: &mut Vec<u8>) {
+
+        let stream = match self.calls.get_mut(&stream_id) {
+            Some(stream) => stream,
+            None => return,
+        };
+        let (direction, type_name) = match from_client {
+            true => (&mut stream.request, &stream.input_type),
+            false => (&mut stream.response, &stream.output_type),
+        };
+        if direction.passthrough {
+            return;
+        }
+        direction.buffer.append(data);
+
+        while direction.buffer.len() >= 5 {
+
+            let length = u32::from_be_bytes([direction.buffer[1], direction.buffer[2], direction.buffer[3], direction.buffer[4]]) as usize;
+            if length > self.options.max_message_size {
+                direction.passthrough = true;
+                data.append(&mut direction.buffer);
+                return;
+            }
+            if direction.buffer.len() < 5 + length {
+                break;
+            }
+
+            let compressed = direction.buffer[0] & 0x1 != 0;
+            let payload: Vec<u8> = direction.buffer.drain(..5 + length).skip(5).collect();
+
+            let raw = match compressed {
+                true => decompress(direction.encoding.as_deref(), &payload, self.options.max_message_size),
+                false => Some(payload.clone()),
+            };
+            let raw = match raw {
+                Some(raw) => raw,
+                // Unknown encoding, the handler could not read it anyway
+                None => {
+                    write_message(data, compressed, &payload);
+                    continue;
+                },
+            };
+
+            let decoded = match (&self.options.descriptors, type_name) {
+                (Some(descriptors), Some(type_name)) => descriptors.decode(type_name, &raw),
+                _ => None,
+            };
+            let mut message = GrpcMessage {
+                data: raw,
+                compressed,
+                decoded: decoded.clone(),
+            };
+
+            match from_client {
+                true => self.handler.on_request_message(&stream.call, &mut message),
+                false => self.handler.on_response_message(&stream.call, &mut message),
+            }
+
+            if message.decoded != decoded {
+                if let Some(decoded) = &message.decoded {
+                    message.data = decoded.encode();
+                }
+            }
+
+            match message.compressed.then(|| compress(direction.encoding.as_deref(), &message.data)).flatten() {
+                Some(compressed) => write_message(data, true, &compressed),
+                None => write_message(data, false, &message.data),
+            }
+        }
+    }
+}
+
+impl<T: GrpcHandler> Http2Handler for GrpcStreams<T> {
+
+    fn on_request_headers(&mut self, stream_id: u32, headers: &mut Vec<(String, String)>) {
+
+        if let Some(stream) = self.calls.get(&stream_id) {
+            // Trailers from the client, not part of gRPC but metadata all the same
+            self.handler.on_request_metadata(&stream.call, headers);
+            return;
+        }
+
+        let is_grpc = header(headers, "content-type").is_some_and(|content_type| content_type.starts_with("application/grpc"));
+        let path = header(headers, ":path").and_then(|path| path.trim_start_matches('/').split_once('/'));
+        let (service, method) = match (is_grpc, path) {
+            (true, Some((service, method))) => (service.to_string(), method.to_string()),
+            _ => return,
+        };
+
+        let (input_type, output_type) = match self.options.descriptors.as_ref().and_then(|descriptors| descriptors.method(&service, &method)) {
+            Some((input, output)) => (Some(input.to_string()), Some(output.to_string())),
+            None => (None, None),
+        };
+
+        let stream = GrpcStream {
+            call: GrpcCall {stream_id, service, method},
+            input_type,
+            output_type,
+            request: GrpcDirection::new(header(headers, "grpc-encoding")),
+            response: GrpcDirection::new(None),
+            response_started: false,
+        };
+        self.handler.on_request_metadata(&stream.call, headers);
+        remove_content_length(headers);
+        self.calls.insert(stream_id, stream);
+    }
+
+    fn on_request_data(&mut self, stream_id: u32, data: &mut Vec<u8>, _end_stream: bool) {
+        self.messages(stream_id, true, data);
+    }
+
+    fn on_response_headers(&mut self, stream_id: u32, headers: &mut Vec<(String, String)>) {
+
+        let stream = match self.calls.get_mut(&stream_id) {
+            Some(stream) => stream,
+            None => return,
+        };
+
+        if stream.response_started || header(headers, "grpc-status").is_some() {
+            self.handler.on_trailers(&stream.call, headers);
+            self.calls.remove(&stream_id);
+            return;
+        }
+
+        stream.response_started = true;
+        stream.response = GrpcDirection::new(header(headers, "grpc-encoding"));
+        self.handler.on_response_metadata(&stream.call, headers);
+        remove_content_length(headers);
+    }
+
+    fn on_response_data(&mut self, stream_id: u32, data: &mut Vec<u8>, end_stream: bool) {
+        self.messages(stream_id, false, data);
+        if end_stream {
+            self.calls.remove(&stream_id);
+        }
+    }
+
+    fn open_callback(&mut self, session: SessionHandle) {
+        self.handler.open_callback(session);
+    }
+
+    fn close_callback(&mut self, reason: CloseReason) {
+        self.handler.close_callback(reason);
+    }
+}
+
+impl GrpcDirection {
+
+    fn new(encoding: Option<&str>) -> Self {
+        GrpcDirection {
+            buffer: Vec::new(),
+            encoding: encoding.map(|encoding| encoding.trim().to_ascii_lowercase()),
+            passthrough: false,
+        }
+    }
+}
+
+fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers.iter().find(|(header_name, _)| header_name.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str())
+}
+
+/// Messages that are changed or compressed again rarely keep their length, so gRPC bodies go without one.
+fn remove_content_length(headers: &mut Vec<(String, String)>) {
+    headers.retain(|(name, _)| !name.eq_ignore_ascii_case("content-length"));
+}
+
+fn write_message(out: &mut Vec<u8>, compressed: bool, message: &[u8]) {
+    out.push(compressed as u8);
+    out.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    out.extend_from_slice(message);
+}
+
+fn decompress(encoding: Option<&str>, data: &[u8], max_size: usize) -> Option<Vec<u8>> {
+
+    let mut out = Vec::new();
+    // One byte over the limit tells a message that is too large from one that just fits
+    let limit = max_size as u64 + 1;
+    match encoding? {
+        "gzip" => GzDecoder::new(data).take(limit).read_to_end(&mut out).ok()?,
+        "deflate" => ZlibDecoder::new(data).take(limit).read_to_end(&mut out).ok()?,
+        _ => return None,
+    };
+    if out.len() > max_size {
+        return None;
+    }
+    Some(out)
+}
+
+fn compress(encoding: Option<&str>, data: &[u8]) -> Option<Vec<u8>> {
+    match encoding? {
+        "gzip" => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(data).ok()?;
+            encoder.finish().ok()
+        },
+        "deflate" => {
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(data).ok()?;
+            encoder.finish().ok()
+        },
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protobuf::ProtoValue;
+
+    #[derive(Default)]
+    struct Uppercasing {
+        requests: Vec<GrpcMessage>,
+    }
+
+    impl GrpcHandler for Uppercasing {
+        fn on_request_message(&mut self, _call: &GrpcCall, message: &mut GrpcMessage) {
+            self.requests.push(message.clone());
+            message.data.make_ascii_uppercase();
+        }
+
+        fn on_response_message(&mut self, _call: &GrpcCall, message: &mut GrpcMessage) {
+            if let Some(ProtoValue::String(name)) = message.decoded.as_mut().and_then(|decoded| decoded.get_mut("name")) {
+                *name = name.to_uppercase();
+            }
+        }
+    }
+
+    // Short fields only, the length has to fit in a single byte varint
+    fn length_field(number: u8, data: &[u8]) -> Vec<u8> {
+        [&[number << 3 | 2, data.len() as u8][..], data].concat()
+    }
+
+    /// package test; message Hello {string name = 1;} service Greeter {rpc Hello(Hello) returns (Hello);}
+    fn descriptors() -> DescriptorSet {
+        let field = [length_field(1, b"name"), vec![0x18, 0x01, 0x28, 0x09]].concat();
+        let message = [length_field(1, b"Hello"), length_field(2, &field)].concat();
+        let method = [length_field(1, b"Hello"), length_field(2, b".test.Hello"), length_field(3, b".test.Hello")].concat();
+        let service = [length_field(1, b"Greeter"), length_field(2, &method)].concat();
+        let file = [length_field(2, b"test"), length_field(4, &message), length_field(6, &service)].concat();
+        DescriptorSet::from_bytes(&length_field(1, &file)).unwrap()
+    }
+
+    fn streams(options: GrpcOptions, encoding: Option<&str>) -> GrpcStreams<Uppercasing> {
+        let mut streams = GrpcStreams {handler: Uppercasing::default(), options, calls: HashMap::new()};
+        let mut headers = vec![
+            (":path".to_string(), "/test.Greeter/Hello".to_string()),
+            ("content-type".to_string(), "application/grpc+proto".to_string()),
+            ("content-length".to_string(), "12".to_string()),
+        ];
+        if let Some(encoding) = encoding {
+            headers.push(("grpc-encoding".to_string(), encoding.to_string()));
+        }
+        streams.on_request_headers(1, &mut headers);
+        assert!(header(&headers, "content-length").is_none());
+        streams
+    }
+
+    fn message(compressed: bool, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        write_message(&mut out, compressed, data);
+        out
+    }
+
+    #[test]
+    fn messages_in_pieces_are_put_together() {
+        let mut streams = streams(GrpcOptions::default(), None);
+        let mut messages = [message(false, b"one"), message(false, b"two")].concat();
+        let mut rest = messages.split_off(6);
+
+        streams.on_request_data(1, &mut messages, false);
+        assert!(messages.is_empty());
+        streams.on_request_data(1, &mut rest, true);
+        assert_eq!(rest, [message(false, b"ONE"), message(false, b"TWO")].concat());
+        assert_eq!(streams.handler.requests.len(), 2);
+        assert!(!streams.handler.requests[0].compressed);
+    }
+
+    #[test]
+    fn compressed_messages_are_compressed_again() {
+        for encoding in ["gzip", "deflate"] {
+            let mut streams = streams(GrpcOptions::default(), Some(encoding));
+            let mut data = message(true, &compress(Some(encoding), b"hello").unwrap());
+            streams.on_request_data(1, &mut data, false);
+
+            assert_eq!(streams.handler.requests[0].data, b"hello");
+            assert!(streams.handler.requests[0].compressed);
+            assert_eq!(data[0], 1);
+            assert_eq!(decompress(Some(encoding), &data[5..], 64).unwrap(), b"HELLO");
+        }
+    }
+
+    #[test]
+    fn unknown_encodings_are_passed_on() {
+        let mut streams = streams(GrpcOptions::default(), Some("snappy"));
+        let mut data = message(true, b"not gzip");
+        streams.on_request_data(1, &mut data, false);
+        assert_eq!(data, message(true, b"not gzip"));
+        assert!(streams.handler.requests.is_empty());
+    }
+
+    #[test]
+    fn decompression_is_bounded() {
+        let compressed = compress(Some("gzip"), &[0; 1000]).unwrap();
+        assert_eq!(decompress(Some("gzip"), &compressed, 1000).map(|data| data.len()), Some(1000));
+        assert_eq!(decompress(Some("gzip"), &compressed, 999), None);
+        assert_eq!(decompress(Some("gzip"), b"not gzip", 1000), None);
+        assert_eq!(decompress(None, &compressed, 1000), None);
+    }
+
+    #[test]
+    fn oversized_messages_end_the_interpretation() {
+        let mut streams = streams(GrpcOptions {max_message_size: 8, ..Default::default()}, None);
+        let mut data = message(false, b"far too long");
+        streams.on_request_data(1, &mut data, false);
+        assert_eq!(data, message(false, b"far too long"));
+
+        let mut data = message(false, b"short");
+        streams.on_request_data(1, &mut data, false);
+        assert_eq!(data, message(false, b"short"));
+        assert!(streams.handler.requests.is_empty());
+    }
+
+    #[test]
+    fn changed_messages_are_encoded_again() {
+        let options = GrpcOptions {descriptors: Some(Arc::new(descriptors())), ..Default::default()};
+        let mut streams = streams(options, None);
+        streams.on_response_headers(1, &mut vec![(":status".to_string(), "200".to_string())]);
+
+        let mut data = message(false, &length_field(1, b"world"));
+        streams.on_response_data(1, &mut data, false);
+        assert_eq!(data, message(false, &length_field(1, b"WORLD")));
+
+        // Not a Hello, so nothing was decoded to change
+        let mut data = message(false, &[0xff]);
+        streams.on_response_data(1, &mut data, true);
+        assert_eq!(data, message(false, &[0xff]));
+    }
+
+    #[test]
+    fn other_streams_are_left_alone() {
+        let mut streams = GrpcStreams {handler: Uppercasing::default(), options: GrpcOptions::default(), calls: HashMap::new()};
+        streams.on_request_headers(3, &mut vec![(":path".to_string(), "/index.html".to_string())]);
+        let mut data = message(false, b"one");
+        streams.on_request_data(3, &mut data, true);
+        assert_eq!(data, message(false, b"one"));
+        assert!(streams.handler.requests.is_empty());
+    }
+}
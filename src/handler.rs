@@ -0,0 +1,100 @@
+use crate::{
+    HandlerCallbacks,
+    HandlerFactory,
+    ConnectionInfo,
+    SharedState,
+    InnerHandlers,
+    Arc,
+    Mutex,
+    MutexGuard,
+};
+
+impl<H: HandlerCallbacks + Clone + std::marker::Send + 'static> HandlerFactory for H {
+
+    type Handler = H;
+
+    fn create(&self, _info: &ConnectionInfo) -> H {
+        self.clone()
+    }
+
+    fn create_nb(&self, _info: &ConnectionInfo) -> Option<H> {
+        Some(self.clone())
+    }
+}
+
+impl<T> SharedState<T> {
+    /// Wraps a value so it can be shared by every connection.
+    pub fn new(value: T) -> Self {
+        SharedState {
+            inner: Arc::new(Mutex::new(value)),
+        }
+    }
+    /// Locks the shared value. A handler that panicked while holding the lock does not poison it for the others.
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        match self.inner.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+    /// Runs f with the shared value locked and returns what f returns.
+    pub fn with<R, F: FnOnce(&mut T) -> R>(&self, f: F) -> R {
+        f(&mut self.lock())
+    }
+}
+
+impl<T> Clone for SharedState<T> {
+
+    fn clone(&self) -> Self {
+        SharedState {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T: Default> Default for SharedState<T> {
+
+    fn default() -> Self {
+        SharedState::new(T::default())
+    }
+}
+
+impl<H: HandlerCallbacks + std::marker::Send + 'static> InnerHandlers<H> {
+
+    pub fn new(handler: H, nb_handler: Option<H>) -> Self {
+        let cb = Arc::new(Mutex::new(handler));
+        let nb = match nb_handler {
+            Some(nb_handler) => Arc::new(Mutex::new(nb_handler)),
+            None => cb.clone(),
+        };
+        InnerHandlers {
+            cb,
+            nb,
+        }
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, H> {
+        Self::lock_handler(&self.cb)
+    }
+
+    /// The handler for the non blocking callbacks.
+    pub fn lock_nb(&self) -> MutexGuard<'_, H> {
+        Self::lock_handler(&self.nb)
+    }
+
+    fn lock_handler(handler: &Mutex<H>) -> MutexGuard<'_, H> {
+        match handler.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+impl<H: HandlerCallbacks + std::marker::Send + 'static> Clone for InnerHandlers<H> {
+
+    fn clone(&self) -> Self {
+        InnerHandlers {
+            cb: self.cb.clone(),
+            nb: self.nb.clone(),
+        }
+    }
+}
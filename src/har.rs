@@ -0,0 +1,719 @@
+//! HAR 1.2 export of relayed HTTP/1.1 traffic.
+//!
+//! A HarRecorder set as RelayConfig::har follows every session and pairs the requests the client sent
+//! with the responses upstream sent back, as they were before any callback changed them. Timings are
+//! the relay's own: connect and ssl are what setting up the upstream connection took (on the first
+//! entry of a connection only), send is how long the request took to arrive, wait the time until
+//! the first byte of the response and receive the time until its last byte.
+//! ```ignore
+//! use sslrelay::har::HarRecorder;
+//!
+//! let recorder = Arc::new(HarRecorder::with_path("session.har"));
+//! let config = RelayConfig { har: Some(recorder.clone()), ..Default::default() };
+//! // Written to session.har when the relay shuts down, or any time before:
+//! recorder.save("snapshot.har")?;
+//! ```
+//! The recorder keeps the latest 10000 entries or 256 MiB of them, whichever is less, and forgets
+//! the oldest ones to make room. HarRecorder::with_limits changes that.
+//!
+//! Sessions that are not HTTP/1.x are left out. Recording of a connection stops where it is upgraded
+//! (CONNECT, WebSocket).
+
+use std::io::{
+    Read,
+    Write,
+};
+
+use std::net::IpAddr;
+
+use std::path::PathBuf;
+
+use flate2::read::{
+    GzDecoder,
+    ZlibDecoder,
+};
+
+use crate::{
+    ConnectTimings,
+    Arc,
+    Mutex,
+    AtomicBool,
+    Ordering,
+    VecDeque,
+    Duration,
+    Instant,
+    SystemTime,
+    Path,
+    io,
+    http::{
+        self,
+        HttpRequest,
+        HttpResponse,
+        HttpOptions,
+        Parsed,
+    },
+};
+
+/// Collects the HTTP requests and responses of every session it is set for and writes them as a HAR log.
+pub struct HarRecorder {
+    entries: Mutex<RecordedEntries>,
+    path: Option<PathBuf>,
+    max_entries: usize,
+    max_bytes: usize,
+    // Entries were recorded since the log was last written to path
+    unsaved: AtomicBool,
+}
+
+/// The entries with how many bytes their URLs, headers and bodies take.
+#[derive(Default)]
+struct RecordedEntries {
+    entries: VecDeque<HarEntry>,
+    bytes: usize,
+}
+
+/// One request with its response, if one came.
+struct HarEntry {
+    started: SystemTime,
+    url: String,
+    request: HttpRequest,
+    request_head_size: usize,
+    request_body_size: usize,
+    response: Option<RecordedResponse>,
+    timings: HarTimings,
+    server_ip: Option<IpAddr>,
+    connection: String,
+}
+
+struct RecordedResponse {
+    response: HttpResponse,
+    head_size: usize,
+    body_size: usize,
+}
+
+struct HarTimings {
+    connect: Option<Duration>,
+    ssl: Option<Duration>,
+    send: Duration,
+    wait: Option<Duration>,
+    receive: Option<Duration>,
+}
+
+/// The HTTP traffic of one session on its way into a HarRecorder.
+pub(crate) struct HarSession {
+    recorder: Arc<HarRecorder>,
+    options: HttpOptions,
+    scheme: &'static str,
+    // Host and port of the upstream, for requests without a Host header
+    authority: String,
+    server_ip: Option<IpAddr>,
+    connection: String,
+    // Only the first entry of a connection had to wait for it to be set up
+    connect_timings: Option<ConnectTimings>,
+    client_buffer: Vec<u8>,
+    server_buffer: Vec<u8>,
+    // Not HTTP, or not anymore
+    passthrough: bool,
+    // When the first byte of the request being read came in
+    request_started: Option<(SystemTime, Instant)>,
+    pending: VecDeque<PendingEntry>,
+    // A response whose body runs until the connection closes
+    until_close: Option<PendingEntry>,
+}
+
+/// A request waiting for its response.
+struct PendingEntry {
+    entry: HarEntry,
+    sent: Instant,
+    first_byte: Option<Instant>,
+}
+
+impl HarRecorder {
+
+    /// A recorder that writes its log only when asked to.
+    pub fn new() -> Self {
+        HarRecorder {
+            entries: Mutex::new(RecordedEntries::default()),
+            path: None,
+            max_entries: 10_000,
+            max_bytes: 256 * 1024 * 1024,
+            unsaved: AtomicBool::new(false),
+        }
+    }
+
+    /// A recorder that also writes its log to path when the relay shuts down,
+    /// and once more when it is dropped if sessions were still finishing.
+    pub fn with_path<P: Into<PathBuf>>(path: P) -> Self {
+        let mut recorder = Self::new();
+        recorder.path = Some(path.into());
+        recorder
+    }
+
+    /// Keeps at most max_entries entries taking at most max_bytes, the oldest ones are forgotten first.
+    pub fn with_limits(mut self, max_entries: usize, max_bytes: usize) -> Self {
+        self.max_entries = max_entries;
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    /// Number of recorded entries.
+    pub fn len(&self) -> usize {
+        self.lock().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lock().entries.is_empty()
+    }
+
+    /// Forgets every recorded entry.
+    pub fn clear(&self) {
+        let mut entries = self.lock();
+        entries.entries.clear();
+        entries.bytes = 0;
+    }
+
+    /// The HAR log of everything recorded so far.
+    pub fn to_json(&self) -> String {
+
+        let entries = self.lock();
+        let mut json = String::new();
+
+        json.push_str("{\"log\":{\"version\":\"1.2\",\"creator\":{\"name\":\"sslrelay\",\"version\":");
+        json.push_str(&json_string(env!("CARGO_PKG_VERSION")));
+        json.push_str("},\"entries\":[");
+        for (index, entry) in entries.entries.iter().enumerate() {
+            if index > 0 {
+                json.push(',');
+            }
+            entry.write_json(&mut json);
+        }
+        json.push_str("]}}");
+        json
+    }
+
+    /// Writes the HAR log of everything recorded so far.
+    pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writer.write_all(self.to_json().as_bytes())?;
+        writer.flush()
+    }
+
+    /// Writes the HAR log of everything recorded so far to a file.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        self.write(io::BufWriter::new(std::fs::File::create(path)?))
+    }
+
+    /// Writes the log to the path given to with_path, if anything new was recorded.
+    pub(crate) fn save_on_shutdown(&self) {
+
+        let path = match &self.path {
+            Some(path) => path,
+            None => return,
+        };
+        if !self.unsaved.swap(false, Ordering::Relaxed) {
+            return;
+        }
+        if let Err(e) = self.save(path) {
+            tracing::error!(path = %path.display(), error = %e, "failed to write HAR log");
+        }
+    }
+
+    fn push(&self, entry: HarEntry) {
+        let mut entries = self.lock();
+        entries.bytes += entry.size();
+        entries.entries.push_back(entry);
+        // An entry larger than max_bytes on its own is not kept either
+        while entries.entries.len() > self.max_entries || entries.bytes > self.max_bytes {
+            match entries.entries.pop_front() {
+                Some(oldest) => entries.bytes -= oldest.size(),
+                None => break,
+            }
+        }
+        self.unsaved.store(true, Ordering::Relaxed);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, RecordedEntries> {
+        match self.entries.lock() {
+            Ok(entries) => entries,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+impl Default for HarRecorder {
+
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for HarRecorder {
+
+    fn drop(&mut self) {
+        self.save_on_shutdown();
+    }
+}
+
+impl HarSession {
+
+    pub(crate) fn new(recorder: Arc<HarRecorder>, tls: bool, authority: String, server_ip: Option<IpAddr>, connection: String, connect_timings: ConnectTimings) -> Self {
+        HarSession {
+            recorder,
+            options: HttpOptions::default(),
+            scheme: if tls { "https" } else { "http" },
+            authority,
+            server_ip,
+            connection,
+            connect_timings: Some(connect_timings),
+            client_buffer: Vec::new(),
+            server_buffer: Vec::new(),
+            passthrough: false,
+            request_started: None,
+            pending: VecDeque::new(),
+            until_close: None,
+        }
+    }
+
+    /// Takes data as it was read from the client or from upstream.
+    pub(crate) fn record(&mut self, from_client: bool, data: &[u8]) {
+
+        if let Some(pending) = self.until_close.as_mut() {
+            if !from_client {
+                let body = &mut pending.entry.response.as_mut().expect("until close entries have a response").response.body;
+                let room = self.options.max_body_size.saturating_sub(body.len());
+                body.extend_from_slice(&data[..data.len().min(room)]);
+                pending.entry.response.as_mut().expect("until close entries have a response").body_size += data.len();
+            }
+            return;
+        }
+        if self.passthrough || data.is_empty() {
+            return;
+        }
+
+        if from_client {
+            self.client_data(data);
+        } else {
+            self.server_data(data);
+        }
+    }
+
+    fn client_data(&mut self, data: &[u8]) {
+
+        let now = Instant::now();
+        if self.request_started.is_none() {
+            self.request_started = Some((SystemTime::now(), now));
+        }
+        self.client_buffer.extend_from_slice(data);
+
+        loop {
+            match http::parse_request(&self.client_buffer, &self.options) {
+                Parsed::Complete(request, length) => {
+
+                    let head_size = head_size(&self.client_buffer);
+                    self.client_buffer.drain(..length);
+
+                    let (started, started_instant) = self.request_started.take().unwrap_or((SystemTime::now(), now));
+                    if !self.client_buffer.is_empty() {
+                        self.request_started = Some((SystemTime::now(), now));
+                    }
+
+                    let connect_timings = self.connect_timings.take();
+                    let entry = HarEntry {
+                        started,
+                        url: self.url(&request),
+                        request_head_size: head_size,
+                        request_body_size: length - head_size,
+                        request,
+                        response: None,
+                        timings: HarTimings {
+                            connect: connect_timings.map(|timings| timings.connect),
+                            ssl: connect_timings.and_then(|timings| timings.tls_handshake),
+                            send: now.duration_since(started_instant),
+                            wait: None,
+                            receive: None,
+                        },
+                        server_ip: self.server_ip,
+                        connection: self.connection.clone(),
+                    };
+                    self.pending.push_back(PendingEntry{entry, sent: now, first_byte: None});
+                },
+                Parsed::Incomplete => break,
+                Parsed::Invalid => {
+                    self.passthrough = true;
+                    break;
+                },
+            }
+        }
+    }
+
+    fn server_data(&mut self, data: &[u8]) {
+
+        let now = Instant::now();
+        if let Some(pending) = self.pending.front_mut() {
+            pending.first_byte.get_or_insert(now);
+        }
+        self.server_buffer.extend_from_slice(data);
+
+        // Nothing to pair with when upstream speaks first or out of turn
+        while let Some(request_method) = self.pending.front().map(|pending| pending.entry.request.method.clone()) {
+
+            match http::parse_response(&self.server_buffer, Some(&request_method), &self.options) {
+                Parsed::Complete(response, length) => {
+
+                    let head_size = head_size(&self.server_buffer);
+                    self.server_buffer.drain(..length);
+
+                    // Interim responses come before the final response to the same request
+                    let status = response.status;
+                    if (100..200).contains(&status) && status != 101 {
+                        continue;
+                    }
+
+                    let until_close = http::response_has_body(Some(&request_method), status)
+                        && !http::is_chunked(&response.headers)
+                        && http::content_length(&response.headers).is_none();
+                    let tunnel = status == 101 || (request_method.eq_ignore_ascii_case("CONNECT") && (200..300).contains(&status));
+
+                    let mut pending = self.pending.pop_front().expect("a response has a pending request");
+                    pending.entry.response = Some(RecordedResponse{response, head_size, body_size: length - head_size});
+
+                    if until_close && !tunnel {
+                        self.until_close = Some(pending);
+                        let rest = std::mem::take(&mut self.server_buffer);
+                        self.record(false, &rest);
+                        return;
+                    }
+
+                    self.finish(pending, now);
+
+                    if tunnel {
+                        self.passthrough = true;
+                        return;
+                    }
+                    if let Some(next) = self.pending.front_mut() {
+                        if !self.server_buffer.is_empty() {
+                            next.first_byte = Some(now);
+                        }
+                    }
+                },
+                Parsed::Incomplete => break,
+                Parsed::Invalid => {
+                    self.passthrough = true;
+                    break;
+                },
+            }
+        }
+    }
+
+    fn finish(&self, mut pending: PendingEntry, now: Instant) {
+        if let Some(first_byte) = pending.first_byte {
+            pending.entry.timings.wait = Some(first_byte.duration_since(pending.sent));
+            pending.entry.timings.receive = Some(now.duration_since(first_byte));
+        }
+        self.recorder.push(pending.entry);
+    }
+
+    fn url(&self, request: &HttpRequest) -> String {
+        if request.uri.starts_with("http://") || request.uri.starts_with("https://") {
+            return request.uri.clone();
+        }
+        let host = http::header(&request.headers, "Host").unwrap_or(&self.authority);
+        // CONNECT names the authority itself, OPTIONS may be for the whole server
+        match request.uri.as_str() {
+            uri if request.method.eq_ignore_ascii_case("CONNECT") => format!("{}://{}", self.scheme, uri),
+            "*" => format!("{}://{}", self.scheme, host),
+            uri => format!("{}://{}{}", self.scheme, host, uri),
+        }
+    }
+}
+
+impl Drop for HarSession {
+
+    /// The connection closed, which ends a response that runs until then.
+    /// Requests that never got a response are recorded without one.
+    fn drop(&mut self) {
+        let now = Instant::now();
+        if let Some(pending) = self.until_close.take() {
+            self.finish(pending, now);
+        }
+        while let Some(pending) = self.pending.pop_front() {
+            self.recorder.push(pending.entry);
+        }
+    }
+}
+
+impl HarEntry {
+
+    /// Roughly the memory the entry holds on to.
+    fn size(&self) -> usize {
+        let headers = |headers: &[(String, String)]| headers.iter().map(|(name, value)| name.len() + value.len()).sum::<usize>();
+        let response = self.response.as_ref().map_or(0, |recorded| headers(&recorded.response.headers) + recorded.response.body.len());
+        self.url.len() + self.request.uri.len() + headers(&self.request.headers) + self.request.body.len() + response
+    }
+
+    fn write_json(&self, json: &mut String) {
+
+        let timings = &self.timings;
+        let wait = timings.wait.map(milliseconds).unwrap_or(-1.0);
+        let receive = timings.receive.map(milliseconds).unwrap_or(-1.0);
+        let connect = timings.connect.map(milliseconds).unwrap_or(-1.0);
+        let ssl = timings.ssl.map(milliseconds).unwrap_or(-1.0);
+        let send = milliseconds(timings.send);
+        // ssl is part of connect already
+        let time: f64 = [connect, send, wait, receive].iter().filter(|timing| **timing >= 0.0).sum();
+
+        json.push_str("{\"startedDateTime\":");
+        json.push_str(&json_string(&iso_8601(self.started)));
+        json.push_str(&format!(",\"time\":{:.3}", time));
+
+        // Request
+        let request = &self.request;
+        json.push_str(",\"request\":{\"method\":");
+        json.push_str(&json_string(&request.method));
+        json.push_str(",\"url\":");
+        json.push_str(&json_string(&self.url));
+        json.push_str(",\"httpVersion\":");
+        json.push_str(&json_string(&request.version));
+        json.push_str(",\"cookies\":");
+        write_cookies(json, http::header(&request.headers, "Cookie").into_iter()
+            .flat_map(|value| value.split(';'))
+            .filter_map(|cookie| cookie.trim().split_once('='))
+            .map(|(name, value)| vec![("name", name), ("value", value)]));
+        json.push_str(",\"headers\":");
+        write_name_values(json, request.headers.iter().map(|(name, value)| (name.as_str(), value.as_str())));
+        json.push_str(",\"queryString\":");
+        let query = request.uri.split_once('?').map(|(_, query)| query.split('#').next().unwrap_or("")).unwrap_or("");
+        write_name_values(json, query.split('&')
+            .filter(|parameter| !parameter.is_empty())
+            .map(|parameter| parameter.split_once('=').unwrap_or((parameter, ""))));
+        if !request.body.is_empty() {
+            json.push_str(",\"postData\":{\"mimeType\":");
+            json.push_str(&json_string(http::header(&request.headers, "Content-Type").unwrap_or("")));
+            json.push_str(",\"params\":[],\"text\":");
+            json.push_str(&json_string(&String::from_utf8_lossy(&request.body)));
+            json.push('}');
+        }
+        json.push_str(&format!(",\"headersSize\":{},\"bodySize\":{}}}", self.request_head_size, self.request_body_size));
+
+        // Response, status 0 when none came
+        json.push_str(",\"response\":");
+        match &self.response {
+            Some(recorded) => {
+                let response = &recorded.response;
+                json.push_str(&format!("{{\"status\":{},\"statusText\":", response.status));
+                json.push_str(&json_string(&response.reason));
+                json.push_str(",\"httpVersion\":");
+                json.push_str(&json_string(&response.version));
+                json.push_str(",\"cookies\":");
+                write_cookies(json, response.headers.iter()
+                    .filter(|(name, _)| name.eq_ignore_ascii_case("Set-Cookie"))
+                    .filter_map(|(_, value)| set_cookie(value)));
+                json.push_str(",\"headers\":");
+                write_name_values(json, response.headers.iter().map(|(name, value)| (name.as_str(), value.as_str())));
+
+                let content = decode_content(response, HttpOptions::default().max_body_size);
+                json.push_str(&format!(",\"content\":{{\"size\":{},\"compression\":{}", content.len(), content.len() as i64 - response.body.len() as i64));
+                json.push_str(",\"mimeType\":");
+                json.push_str(&json_string(http::header(&response.headers, "Content-Type").unwrap_or("")));
+                if !content.is_empty() {
+                    match std::str::from_utf8(&content) {
+                        Ok(text) => {
+                            json.push_str(",\"text\":");
+                            json.push_str(&json_string(text));
+                        },
+                        Err(_e) => {
+                            json.push_str(",\"text\":");
+                            json.push_str(&json_string(&openssl::base64::encode_block(&content)));
+                            json.push_str(",\"encoding\":\"base64\"");
+                        },
+                    }
+                }
+                json.push_str("},\"redirectURL\":");
+                json.push_str(&json_string(http::header(&response.headers, "Location").unwrap_or("")));
+                json.push_str(&format!(",\"headersSize\":{},\"bodySize\":{}}}", recorded.head_size, recorded.body_size));
+            },
+            None => {
+                json.push_str("{\"status\":0,\"statusText\":\"\",\"httpVersion\":\"\",\"cookies\":[],\"headers\":[],");
+                json.push_str("\"content\":{\"size\":0,\"mimeType\":\"\"},\"redirectURL\":\"\",\"headersSize\":-1,\"bodySize\":-1,");
+                json.push_str("\"comment\":\"No response before the connection closed\"}");
+            },
+        }
+
+        json.push_str(",\"cache\":{}");
+        json.push_str(&format!(
+            ",\"timings\":{{\"blocked\":-1,\"dns\":-1,\"connect\":{:.3},\"send\":{:.3},\"wait\":{:.3},\"receive\":{:.3},\"ssl\":{:.3}}}",
+            connect, send, wait, receive, ssl,
+        ));
+        if let Some(server_ip) = self.server_ip {
+            json.push_str(",\"serverIPAddress\":");
+            json.push_str(&json_string(&server_ip.to_string()));
+        }
+        json.push_str(",\"connection\":");
+        json.push_str(&json_string(&self.connection));
+        json.push('}');
+    }
+}
+
+/// The body without its Content-Encoding, or as it is when that is not gzip or deflate.
+fn decode_content(response: &HttpResponse, limit: usize) -> Vec<u8> {
+
+    let encoding = http::header(&response.headers, "Content-Encoding").unwrap_or("").trim().to_ascii_lowercase();
+    let mut decoded = Vec::new();
+    let result = match encoding.as_str() {
+        "gzip" | "x-gzip" => GzDecoder::new(&response.body[..]).take(limit as u64).read_to_end(&mut decoded),
+        "deflate" => ZlibDecoder::new(&response.body[..]).take(limit as u64).read_to_end(&mut decoded),
+        _ => return response.body.clone(),
+    };
+    match result {
+        Ok(_) => decoded,
+        Err(_e) => response.body.clone(),
+    }
+}
+
+/// Name and value of a Set-Cookie header, with the attributes HAR knows about.
+fn set_cookie(value: &str) -> Option<Vec<(&'static str, &str)>> {
+
+    let mut parts = value.split(';');
+    let (name, value) = parts.next()?.trim().split_once('=')?;
+    let mut fields = vec![("name", name), ("value", value)];
+
+    for attribute in parts {
+        let (key, value) = attribute.trim().split_once('=').unwrap_or((attribute.trim(), ""));
+        match key.to_ascii_lowercase().as_str() {
+            "path" => fields.push(("path", value)),
+            "domain" => fields.push(("domain", value)),
+            "expires" => fields.push(("expires", value)),
+            "httponly" => fields.push(("httpOnly", "")),
+            "secure" => fields.push(("secure", "")),
+            _ => {},
+        }
+    }
+    Some(fields)
+}
+
+fn write_cookies<'a, I: Iterator<Item = Vec<(&'static str, &'a str)>>>(json: &mut String, cookies: I) {
+    json.push('[');
+    for (index, cookie) in cookies.enumerate() {
+        if index > 0 {
+            json.push(',');
+        }
+        json.push('{');
+        for (field_index, (field, value)) in cookie.iter().enumerate() {
+            if field_index > 0 {
+                json.push(',');
+            }
+            json.push_str(&json_string(field));
+            json.push(':');
+            // Flags are booleans, everything else a string
+            if matches!(*field, "httpOnly" | "secure") {
+                json.push_str("true");
+            } else {
+                json.push_str(&json_string(value));
+            }
+        }
+        json.push('}');
+    }
+    json.push(']');
+}
+
+fn write_name_values<'a, I: Iterator<Item = (&'a str, &'a str)>>(json: &mut String, pairs: I) {
+    json.push('[');
+    for (index, (name, value)) in pairs.enumerate() {
+        if index > 0 {
+            json.push(',');
+        }
+        json.push_str("{\"name\":");
+        json.push_str(&json_string(name));
+        json.push_str(",\"value\":");
+        json.push_str(&json_string(value));
+        json.push('}');
+    }
+    json.push(']');
+}
+
+/// Size of the head at the start of a buffer that holds a complete message.
+fn head_size(buffer: &[u8]) -> usize {
+    buffer.windows(4).position(|window| window == b"\r\n\r\n").map(|position| position + 4).unwrap_or(buffer.len())
+}
+
+fn milliseconds(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+fn json_string(value: &str) -> String {
+    let mut json = String::with_capacity(value.len() + 2);
+    json.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+/// UTC with milliseconds, 2009-07-24T19:20:30.450Z.
+fn iso_8601(time: SystemTime) -> String {
+
+    let since_epoch = time.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+    let seconds = since_epoch.as_secs();
+    let (days, second_of_day) = ((seconds / 86400) as i64, seconds % 86400);
+
+    // Days since 1970-01-01 to a proleptic Gregorian date
+    let shifted = days + 719468;
+    let era = shifted.div_euclid(146097);
+    let day_of_era = shifted.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year, month, day,
+        second_of_day / 3600, second_of_day / 60 % 60, second_of_day % 60,
+        since_epoch.subsec_millis(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn exchange(recorder: &Arc<HarRecorder>, body: &[u8]) {
+        let timings = ConnectTimings { connect: Duration::from_millis(1), tls_handshake: None };
+        let mut session = HarSession::new(recorder.clone(), false, "example.com:80".to_string(), None, "1".to_string(), timings);
+        session.record(true, b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n");
+        let response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len());
+        session.record(false, &[response.as_bytes(), body].concat());
+    }
+
+    #[test]
+    fn oldest_entries_make_room() {
+        let recorder = Arc::new(HarRecorder::new().with_limits(2, usize::MAX));
+        for body in [&b"first"[..], b"second", b"third"] {
+            exchange(&recorder, body);
+        }
+        assert_eq!(recorder.len(), 2);
+        let json = recorder.to_json();
+        assert!(!json.contains("first") && json.contains("second") && json.contains("third"));
+    }
+
+    #[test]
+    fn entries_stay_within_max_bytes() {
+        let recorder = Arc::new(HarRecorder::new().with_limits(usize::MAX, 2048));
+        for _ in 0..4 {
+            exchange(&recorder, &[b'x'; 800]);
+        }
+        assert_eq!(recorder.len(), 2);
+        exchange(&recorder, &[b'x'; 4096]);
+        assert!(recorder.is_empty());
+    }
+}
@@ -0,0 +1,471 @@
+//! HPACK header compression (RFC 7541) for the http2 module.
+//! The Decoder follows the peer's dynamic table. The Encoder never adds to the dynamic table,
+//! so whatever table size the peer allows, the header blocks it writes stay valid.
+
+use crate::OnceLock;
+
+/// (name, value) pairs in the order they appear in a header block.
+pub type HeaderList = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// Decoding error, the connection can not be followed any further.
+#[derive(Debug)]
+pub struct HpackError;
+
+pub struct Decoder {
+    dynamic_table: std::collections::VecDeque<(Vec<u8>, Vec<u8>)>,
+    table_size: usize,
+    max_table_size: usize,
+}
+
+pub struct Encoder;
+
+impl Decoder {
+
+    pub fn new() -> Self {
+        Decoder {
+            dynamic_table: std::collections::VecDeque::new(),
+            table_size: 0,
+            max_table_size: 4096,
+        }
+    }
+
+    pub fn decode(&mut self, block: &[u8]) -> Result<HeaderList, HpackError> {
+
+        let mut headers = Vec::new();
+        let mut position = 0;
+
+        while position < block.len() {
+
+            let first = block[position];
+
+            if first & 0x80 != 0 {
+                // Indexed header field
+                let index = decode_integer(block, &mut position, 7)?;
+                headers.push(self.entry(index)?);
+            } else if first & 0x40 != 0 {
+                // Literal with incremental indexing
+                let header = self.literal(block, &mut position, 6)?;
+                self.insert(header.clone());
+                headers.push(header);
+            } else if first & 0x20 != 0 {
+                // Dynamic table size update
+                let size = decode_integer(block, &mut position, 5)?;
+                self.max_table_size = size;
+                self.evict(0);
+            } else {
+                // Literal without indexing or never indexed
+                headers.push(self.literal(block, &mut position, 4)?);
+            }
+        }
+        Ok(headers)
+    }
+
+    fn literal(&self, block: &[u8], position: &mut usize, prefix_bits: u8) -> Result<(Vec<u8>, Vec<u8>), HpackError> {
+
+        let index = decode_integer(block, position, prefix_bits)?;
+        let name = if index == 0 {
+            decode_string(block, position)?
+        } else {
+            self.entry(index)?.0
+        };
+        let value = decode_string(block, position)?;
+        Ok((name, value))
+    }
+
+    fn entry(&self, index: usize) -> Result<(Vec<u8>, Vec<u8>), HpackError> {
+
+        if index == 0 {
+            return Err(HpackError);
+        }
+        if index <= STATIC_TABLE.len() {
+            let (name, value) = STATIC_TABLE[index - 1];
+            return Ok((name.as_bytes().to_vec(), value.as_bytes().to_vec()));
+        }
+        self.dynamic_table.get(index - STATIC_TABLE.len() - 1).cloned().ok_or(HpackError)
+    }
+
+    fn insert(&mut self, header: (Vec<u8>, Vec<u8>)) {
+
+        let size = entry_size(&header);
+        self.evict(size);
+        // An entry larger than the whole table empties it and is not added
+        if size <= self.max_table_size {
+            self.table_size += size;
+            self.dynamic_table.push_front(header);
+        }
+    }
+
+    fn evict(&mut self, room: usize) {
+        while self.table_size + room > self.max_table_size {
+            match self.dynamic_table.pop_back() {
+                Some(header) => self.table_size -= entry_size(&header),
+                None => {
+                    self.table_size = 0;
+                    break;
+                },
+            }
+        }
+    }
+}
+
+impl Encoder {
+
+    pub fn encode(headers: &[(Vec<u8>, Vec<u8>)]) -> Vec<u8> {
+
+        let mut block = Vec::new();
+
+        for (name, value) in headers {
+
+            let exact = STATIC_TABLE.iter().position(|(n, v)| n.as_bytes() == name.as_slice() && v.as_bytes() == value.as_slice());
+            if let Some(index) = exact {
+                encode_integer(&mut block, index + 1, 7, 0x80);
+                continue;
+            }
+
+            // Literal without indexing, reusing a static table name where there is one
+            match STATIC_TABLE.iter().position(|(n, _)| n.as_bytes() == name.as_slice()) {
+                Some(index) => encode_integer(&mut block, index + 1, 4, 0x00),
+                None => {
+                    block.push(0x00);
+                    encode_string(&mut block, name);
+                },
+            }
+            encode_string(&mut block, value);
+        }
+        block
+    }
+}
+
+fn entry_size(header: &(Vec<u8>, Vec<u8>)) -> usize {
+    header.0.len() + header.1.len() + 32
+}
+
+fn decode_integer(block: &[u8], position: &mut usize, prefix_bits: u8) -> Result<usize, HpackError> {
+
+    let mask = (1u16 << prefix_bits) as usize - 1;
+    let mut value = *block.get(*position).ok_or(HpackError)? as usize & mask;
+    *position += 1;
+
+    if value < mask {
+        return Ok(value);
+    }
+
+    let mut shift = 0;
+    loop {
+        let byte = *block.get(*position).ok_or(HpackError)?;
+        *position += 1;
+        if shift > 28 {
+            return Err(HpackError);
+        }
+        value += ((byte & 0x7f) as usize) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+}
+
+fn encode_integer(block: &mut Vec<u8>, value: usize, prefix_bits: u8, flags: u8) {
+
+    let mask = (1u16 << prefix_bits) as usize - 1;
+    if value < mask {
+        block.push(flags | value as u8);
+        return;
+    }
+
+    block.push(flags | mask as u8);
+    let mut value = value - mask;
+    while value >= 0x80 {
+        block.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    block.push(value as u8);
+}
+
+fn decode_string(block: &[u8], position: &mut usize) -> Result<Vec<u8>, HpackError> {
+
+    let huffman = *block.get(*position).ok_or(HpackError)? & 0x80 != 0;
+    let length = decode_integer(block, position, 7)?;
+
+    let end = position.checked_add(length).ok_or(HpackError)?;
+    let data = block.get(*position..end).ok_or(HpackError)?;
+    *position = end;
+
+    if huffman {
+        huffman_decode(data)
+    } else {
+        Ok(data.to_vec())
+    }
+}
+
+fn encode_string(block: &mut Vec<u8>, data: &[u8]) {
+    encode_integer(block, data.len(), 7, 0x00);
+    block.extend_from_slice(data);
+}
+
+/// Binary tree over the Huffman codes, children of node i are at tree[i].0 (bit 0) and tree[i].1 (bit 1).
+/// Leaves hold their symbol.
+enum HuffmanNode {
+    Branch(usize, usize),
+    Leaf(u16),
+}
+
+fn huffman_tree() -> &'static Vec<HuffmanNode> {
+
+    static TREE: OnceLock<Vec<HuffmanNode>> = OnceLock::new();
+
+    TREE.get_or_init(|| {
+        let mut tree = vec![HuffmanNode::Branch(0, 0)];
+
+        for (symbol, (code, length)) in HUFFMAN_CODES.iter().enumerate() {
+            let mut node = 0;
+            for bit in (0..*length).rev() {
+                let one = (code >> bit) & 1 == 1;
+                let next = match tree[node] {
+                    HuffmanNode::Branch(zero, one_child) => if one { one_child } else { zero },
+                    HuffmanNode::Leaf(_) => unreachable!("Huffman codes are prefix free"),
+                };
+                node = if next != 0 {
+                    next
+                } else {
+                    let child = tree.len();
+                    tree.push(if bit == 0 { HuffmanNode::Leaf(symbol as u16) } else { HuffmanNode::Branch(0, 0) });
+                    if let HuffmanNode::Branch(zero, one_child) = &mut tree[node] {
+                        if one { *one_child = child } else { *zero = child }
+                    }
+                    child
+                };
+            }
+        }
+        tree
+    })
+}
+
+fn huffman_decode(data: &[u8]) -> Result<Vec<u8>, HpackError> {
+
+    let tree = huffman_tree();
+    let mut decoded = Vec::with_capacity(data.len() * 8 / 5);
+
+    let mut node = 0;
+    // Bits read since the last complete symbol, all of them ones so far
+    let mut padding_bits = 0;
+    let mut padding_ones = true;
+
+    for byte in data {
+        for bit in (0..8).rev() {
+            let one = (byte >> bit) & 1 == 1;
+            node = match tree[node] {
+                HuffmanNode::Branch(zero, one_child) => if one { one_child } else { zero },
+                HuffmanNode::Leaf(_) => return Err(HpackError),
+            };
+            padding_bits += 1;
+            padding_ones &= one;
+
+            if let HuffmanNode::Leaf(symbol) = tree[node] {
+                // The end of string symbol must not show up in the data
+                if symbol == 256 {
+                    return Err(HpackError);
+                }
+                decoded.push(symbol as u8);
+                node = 0;
+                padding_bits = 0;
+                padding_ones = true;
+            }
+        }
+    }
+
+    // What is left must be a prefix of the end of string symbol, at most 7 bits of ones
+    if padding_bits > 7 || !padding_ones {
+        return Err(HpackError);
+    }
+    Ok(decoded)
+}
+
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+/// (code, bit length) for every byte value and the end of string symbol (256).
+const HUFFMAN_CODES: [(u32, u8); 257] = [
+    (0x1ff8, 13), (0x7fffd8, 23), (0xfffffe2, 28), (0xfffffe3, 28), (0xfffffe4, 28), (0xfffffe5, 28),
+    (0xfffffe6, 28), (0xfffffe7, 28), (0xfffffe8, 28), (0xffffea, 24), (0x3ffffffc, 30), (0xfffffe9, 28),
+    (0xfffffea, 28), (0x3ffffffd, 30), (0xfffffeb, 28), (0xfffffec, 28), (0xfffffed, 28), (0xfffffee, 28),
+    (0xfffffef, 28), (0xffffff0, 28), (0xffffff1, 28), (0xffffff2, 28), (0x3ffffffe, 30), (0xffffff3, 28),
+    (0xffffff4, 28), (0xffffff5, 28), (0xffffff6, 28), (0xffffff7, 28), (0xffffff8, 28), (0xffffff9, 28),
+    (0xffffffa, 28), (0xffffffb, 28), (0x14, 6), (0x3f8, 10), (0x3f9, 10), (0xffa, 12),
+    (0x1ff9, 13), (0x15, 6), (0xf8, 8), (0x7fa, 11), (0x3fa, 10), (0x3fb, 10),
+    (0xf9, 8), (0x7fb, 11), (0xfa, 8), (0x16, 6), (0x17, 6), (0x18, 6),
+    (0x0, 5), (0x1, 5), (0x2, 5), (0x19, 6), (0x1a, 6), (0x1b, 6),
+    (0x1c, 6), (0x1d, 6), (0x1e, 6), (0x1f, 6), (0x5c, 7), (0xfb, 8),
+    (0x7ffc, 15), (0x20, 6), (0xffb, 12), (0x3fc, 10), (0x1ffa, 13), (0x21, 6),
+    (0x5d, 7), (0x5e, 7), (0x5f, 7), (0x60, 7), (0x61, 7), (0x62, 7),
+    (0x63, 7), (0x64, 7), (0x65, 7), (0x66, 7), (0x67, 7), (0x68, 7),
+    (0x69, 7), (0x6a, 7), (0x6b, 7), (0x6c, 7), (0x6d, 7), (0x6e, 7),
+    (0x6f, 7), (0x70, 7), (0x71, 7), (0x72, 7), (0xfc, 8), (0x73, 7),
+    (0xfd, 8), (0x1ffb, 13), (0x7fff0, 19), (0x1ffc, 13), (0x3ffc, 14), (0x22, 6),
+    (0x7ffd, 15), (0x3, 5), (0x23, 6), (0x4, 5), (0x24, 6), (0x5, 5),
+    (0x25, 6), (0x26, 6), (0x27, 6), (0x6, 5), (0x74, 7), (0x75, 7),
+    (0x28, 6), (0x29, 6), (0x2a, 6), (0x7, 5), (0x2b, 6), (0x76, 7),
+    (0x2c, 6), (0x8, 5), (0x9, 5), (0x2d, 6), (0x77, 7), (0x78, 7),
+    (0x79, 7), (0x7a, 7), (0x7b, 7), (0x7ffe, 15), (0x7fc, 11), (0x3ffd, 14),
+    (0x1ffd, 13), (0xffffffc, 28), (0xfffe6, 20), (0x3fffd2, 22), (0xfffe7, 20), (0xfffe8, 20),
+    (0x3fffd3, 22), (0x3fffd4, 22), (0x3fffd5, 22), (0x7fffd9, 23), (0x3fffd6, 22), (0x7fffda, 23),
+    (0x7fffdb, 23), (0x7fffdc, 23), (0x7fffdd, 23), (0x7fffde, 23), (0xffffeb, 24), (0x7fffdf, 23),
+    (0xffffec, 24), (0xffffed, 24), (0x3fffd7, 22), (0x7fffe0, 23), (0xffffee, 24), (0x7fffe1, 23),
+    (0x7fffe2, 23), (0x7fffe3, 23), (0x7fffe4, 23), (0x1fffdc, 21), (0x3fffd8, 22), (0x7fffe5, 23),
+    (0x3fffd9, 22), (0x7fffe6, 23), (0x7fffe7, 23), (0xffffef, 24), (0x3fffda, 22), (0x1fffdd, 21),
+    (0xfffe9, 20), (0x3fffdb, 22), (0x3fffdc, 22), (0x7fffe8, 23), (0x7fffe9, 23), (0x1fffde, 21),
+    (0x7fffea, 23), (0x3fffdd, 22), (0x3fffde, 22), (0xfffff0, 24), (0x1fffdf, 21), (0x3fffdf, 22),
+    (0x7fffeb, 23), (0x7fffec, 23), (0x1fffe0, 21), (0x1fffe1, 21), (0x3fffe0, 22), (0x1fffe2, 21),
+    (0x7fffed, 23), (0x3fffe1, 22), (0x7fffee, 23), (0x7fffef, 23), (0xfffea, 20), (0x3fffe2, 22),
+    (0x3fffe3, 22), (0x3fffe4, 22), (0x7ffff0, 23), (0x3fffe5, 22), (0x3fffe6, 22), (0x7ffff1, 23),
+    (0x3ffffe0, 26), (0x3ffffe1, 26), (0xfffeb, 20), (0x7fff1, 19), (0x3fffe7, 22), (0x7ffff2, 23),
+    (0x3fffe8, 22), (0x1ffffec, 25), (0x3ffffe2, 26), (0x3ffffe3, 26), (0x3ffffe4, 26), (0x7ffffde, 27),
+    (0x7ffffdf, 27), (0x3ffffe5, 26), (0xfffff1, 24), (0x1ffffed, 25), (0x7fff2, 19), (0x1fffe3, 21),
+    (0x3ffffe6, 26), (0x7ffffe0, 27), (0x7ffffe1, 27), (0x3ffffe7, 26), (0x7ffffe2, 27), (0xfffff2, 24),
+    (0x1fffe4, 21), (0x1fffe5, 21), (0x3ffffe8, 26), (0x3ffffe9, 26), (0xffffffd, 28), (0x7ffffe3, 27),
+    (0x7ffffe4, 27), (0x7ffffe5, 27), (0xfffec, 20), (0xfffff3, 24), (0xfffed, 20), (0x1fffe6, 21),
+    (0x3fffe9, 22), (0x1fffe7, 21), (0x1fffe8, 21), (0x7ffff3, 23), (0x3fffea, 22), (0x3fffeb, 22),
+    (0x1ffffee, 25), (0x1ffffef, 25), (0xfffff4, 24), (0xfffff5, 24), (0x3ffffea, 26), (0x7ffff4, 23),
+    (0x3ffffeb, 26), (0x7ffffe6, 27), (0x3ffffec, 26), (0x3ffffed, 26), (0x7ffffe7, 27), (0x7ffffe8, 27),
+    (0x7ffffe9, 27), (0x7ffffea, 27), (0x7ffffeb, 27), (0xffffffe, 28), (0x7ffffec, 27), (0x7ffffed, 27),
+    (0x7ffffee, 27), (0x7ffffef, 27), (0x7fffff0, 27), (0x3ffffee, 26), (0x3fffffff, 30),
+];
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn hex(text: &str) -> Vec<u8> {
+        (0..text.len()).step_by(2).map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap()).collect()
+    }
+
+    fn header(name: &str, value: &str) -> (Vec<u8>, Vec<u8>) {
+        (name.as_bytes().to_vec(), value.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn integers_round_trip() {
+        // RFC 7541 C.1.2
+        let mut block = Vec::new();
+        encode_integer(&mut block, 1337, 5, 0x00);
+        assert_eq!(block, [0x1f, 0x9a, 0x0a]);
+        assert_eq!(decode_integer(&block, &mut 0, 5).unwrap(), 1337);
+
+        for value in [0, 30, 31, 127, 128, 16_383, 1 << 20] {
+            let mut block = Vec::new();
+            encode_integer(&mut block, value, 7, 0x80);
+            assert_eq!(decode_integer(&block, &mut 0, 7).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn requests_follow_the_dynamic_table() {
+        // RFC 7541 C.3, the same requests Huffman coded in C.4
+        for (first, second) in [
+            ("828684410f7777772e6578616d706c652e636f6d", "828684be58086e6f2d6361636865"),
+            ("828684418cf1e3c2e5f23a6ba0ab90f4ff", "828684be5886a8eb10649cbf"),
+        ] {
+            let mut decoder = Decoder::new();
+            let request = vec![header(":method", "GET"), header(":scheme", "http"), header(":path", "/"), header(":authority", "www.example.com")];
+            assert_eq!(decoder.decode(&hex(first)).unwrap(), request);
+
+            let mut request = request;
+            request.push(header("cache-control", "no-cache"));
+            assert_eq!(decoder.decode(&hex(second)).unwrap(), request);
+            assert_eq!(decoder.table_size, 110);
+        }
+    }
+
+    #[test]
+    fn encoded_headers_decode_to_the_same() {
+        let headers = vec![header(":status", "200"), header("content-type", "text/plain"), header("x-custom", "value"), header("empty", "")];
+        assert_eq!(Decoder::new().decode(&Encoder::encode(&headers)).unwrap(), headers);
+    }
+
+    #[test]
+    fn table_size_updates_evict() {
+        let mut decoder = Decoder::new();
+        decoder.decode(&hex("400a637573746f6d2d6b65790d637573746f6d2d686561646572")).unwrap();
+        assert_eq!(decoder.dynamic_table.len(), 1);
+        decoder.decode(&[0x20]).unwrap();
+        assert!(decoder.dynamic_table.is_empty());
+        assert!(decoder.decode(&[0xbe]).is_err());
+    }
+
+    #[test]
+    fn malformed_blocks_are_errors() {
+        let blocks: [&[u8]; 6] = [
+            &[0x80],// Index 0
+            &[0xbe],// Past the end of an empty dynamic table
+            &[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f],// Integer too large
+            &[0x04, 0x05, b'a', b'b'],// String cut short
+            &[0x00, 0x81, 0x00],// Huffman padding of zeros
+            &[0x00, 0x84, 0xff, 0xff, 0xff, 0xff],// End of string symbol in the data
+        ];
+        for block in blocks {
+            assert!(Decoder::new().decode(block).is_err(), "{:02x?}", block);
+        }
+    }
+}
@@ -0,0 +1,819 @@
+//! HTTP/1.1 aware callbacks.
+//!
+//! HttpRelay wraps an HttpHandler and implements HandlerCallbacks for it. It collects the
+//! relayed bytes into complete requests and responses (pipelined, chunked or with a
+//! Content-Length), hands them to the handler as mutable objects and writes them out again.
+//! ```ignore
+//! use sslrelay::http::{HttpRelay, HttpHandler, HttpRequest, HttpResponse, HttpRet};
+//!
+//! struct Handler;
+//!
+//! impl HttpHandler for Handler {
+//!     fn on_request(&mut self, request: &mut HttpRequest) -> HttpRet {
+//!         request.set_header("User-Agent", "sslrelay");
+//!         HttpRet::Relay
+//!     }
+//!     fn on_response(&mut self, response: &mut HttpResponse) -> HttpRet {
+//!         response.body = response.body.to_ascii_uppercase();
+//!         HttpRet::Relay
+//!     }
+//! }
+//!
+//! let mut relay = sslrelay::SSLRelay::new(HttpRelay::new(Handler), config);
+//! ```
+//! Once a connection is upgraded (CONNECT, Upgrade, 101 Switching Protocols), or data that is
+//! not HTTP/1.x shows up, the rest of that connection is relayed untouched. Upgrades to WebSocket
+//! are the exception, their messages go to HttpHandler::on_websocket_message (see the websocket module).
+
+use crate::{
+    HandlerCallbacks,
+    CallbackRet,
+    CloseReason,
+    SessionHandle,
+    VecDeque,
+    websocket::{
+        WebSocketSession,
+        WebSocketMessage,
+        WebSocketInjector,
+        WebSocketRet,
+    },
+};
+
+/// A parsed HTTP request.
+#[derive(Clone, Debug)]
+pub struct HttpRequest {
+    pub method: String,
+    pub uri: String,
+    pub version: String,
+    pub headers: Vec<(String, String)>,
+    /// The body with any chunked transfer coding already removed.
+    pub body: Vec<u8>,
+}
+
+/// A parsed HTTP response.
+#[derive(Clone, Debug)]
+pub struct HttpResponse {
+    pub version: String,
+    pub status: u16,
+    pub reason: String,
+    pub headers: Vec<(String, String)>,
+    /// The body with any chunked transfer coding already removed.
+    pub body: Vec<u8>,
+}
+
+/// What HttpRelay does with a request or response after the handler has seen it.
+#[derive(Debug)]
+pub enum HttpRet {
+    Relay,// Relay the (possibly modified) message
+    Respond(HttpResponse),// Send this response to the client instead, after the responses to earlier requests
+    Shutdown,// Shutdown TCP connection
+}
+
+/// Callbacks for HTTP traffic, used through HttpRelay.
+pub trait HttpHandler {
+    fn on_request(&mut self, _request: &mut HttpRequest) -> HttpRet {HttpRet::Relay}
+    fn on_response(&mut self, _response: &mut HttpResponse) -> HttpRet {HttpRet::Relay}
+    fn on_websocket_message(&mut self, _message: &mut WebSocketMessage, _inject: &mut WebSocketInjector) -> WebSocketRet {WebSocketRet::Relay}
+    fn open_callback(&mut self, _session: SessionHandle){}
+    fn close_callback(&mut self, _reason: CloseReason){}
+}
+
+/// Options for HttpRelay.
+#[derive(Clone, Debug)]
+pub struct HttpOptions {
+    /// Send chunked messages on with a Content-Length instead of re-chunking them.
+    pub dechunk: bool,
+    /// A request or response head larger than this is relayed untouched along with the rest of the connection.
+    pub max_header_size: usize,
+    /// A body larger than this is relayed untouched along with the rest of the connection.
+    /// Also the largest WebSocket message, a larger one ends the session.
+    pub max_body_size: usize,
+}
+
+impl Default for HttpOptions {
+
+    fn default() -> Self {
+        HttpOptions {
+            dechunk: false,
+            max_header_size: 64 * 1024,
+            max_body_size: 16 * 1024 * 1024,
+        }
+    }
+}
+
+/// Turns an HttpHandler into HandlerCallbacks.
+pub struct HttpRelay<T: HttpHandler> {
+    handler: T,
+    options: HttpOptions,
+    ds_buffer: Vec<u8>,
+    us_buffer: Vec<u8>,
+    ds_passthrough: bool,
+    us_passthrough: bool,
+    // Requests still waiting for their response, in the order the client sent them
+    pending: VecDeque<Pending>,
+    // The client asked for a WebSocket upgrade, what it sends next waits for the server's answer
+    websocket_requested: bool,
+    websocket: Option<WebSocketSession>,
+}
+
+enum Pending {
+    Relayed(String),// Sent to the server with this method
+    Answered(Vec<u8>),// Answered by the handler, goes out after the responses before it
+}
+
+enum BodyLength {
+    Empty,
+    Length(usize),
+    Chunked,
+    UntilClose,
+}
+
+pub(crate) enum Parsed<T> {
+    Complete(T, usize),// The message and how many bytes of the buffer it took
+    Incomplete,
+    Invalid,
+}
+
+impl<T: HttpHandler> HttpRelay<T> {
+
+    pub fn new(handler: T) -> Self {
+        Self::with_options(handler, HttpOptions::default())
+    }
+
+    pub fn with_options(handler: T, options: HttpOptions) -> Self {
+        HttpRelay {
+            handler,
+            options,
+            ds_buffer: Vec::new(),
+            us_buffer: Vec::new(),
+            ds_passthrough: false,
+            us_passthrough: false,
+            pending: VecDeque::new(),
+            websocket_requested: false,
+            websocket: None,
+        }
+    }
+
+    /// Runs WebSocket frames from one side through the handler.
+    fn websocket_messages(&mut self, from_client: bool, in_data: Vec<u8>) -> Option<(Vec<u8>, Vec<u8>)> {
+        let handler = &mut self.handler;
+        self.websocket.as_mut()?.process(from_client, in_data, &mut |message, inject| handler.on_websocket_message(message, inject))
+    }
+
+    fn websocket_frames(&mut self, from_client: bool, in_data: Vec<u8>) -> CallbackRet {
+        match self.websocket_messages(from_client, in_data) {
+            Some((to_server, to_client)) if from_client => callback_ret(to_server, to_client),
+            Some((to_server, to_client)) => callback_ret(to_client, to_server),
+            None => CallbackRet::Shutdown,
+        }
+    }
+
+    /// Sends on the handler's answers that were only waiting for the responses before them.
+    fn flush_answered(&mut self, relay_data: &mut Vec<u8>) {
+        while let Some(Pending::Answered(_)) = self.pending.front() {
+            if let Some(Pending::Answered(answer)) = self.pending.pop_front() {
+                relay_data.extend(answer);
+            }
+        }
+    }
+
+    /// Drops the chunked transfer coding from a message that is sent on with a Content-Length.
+    fn dechunk(&self, headers: &mut Vec<(String, String)>, body_length: usize) {
+
+        if !self.options.dechunk || !is_chunked(headers) {
+            return;
+        }
+        let codings: Vec<String> = header_values(headers, "Transfer-Encoding")
+            .flat_map(|value| value.split(',').map(|coding| coding.trim().to_string()).collect::<Vec<_>>())
+            .filter(|coding| !coding.eq_ignore_ascii_case("chunked"))
+            .collect();
+
+        remove_header(headers, "Transfer-Encoding");
+        if !codings.is_empty() {
+            headers.push(("Transfer-Encoding".to_string(), codings.join(", ")));
+        }
+        set_header(headers, "Content-Length", &body_length.to_string());
+    }
+}
+
+impl<T: HttpHandler + Clone> Clone for HttpRelay<T> {
+
+    /// Clones the handler into a relay that has not seen any traffic.
+    fn clone(&self) -> Self {
+        HttpRelay::with_options(self.handler.clone(), self.options.clone())
+    }
+}
+
+impl<T: HttpHandler> HandlerCallbacks for HttpRelay<T> {
+
+    fn ds_b_callback(&mut self, in_data: Vec<u8>) -> CallbackRet {
+
+        if self.websocket.is_some() {
+            return self.websocket_frames(true, in_data);
+        }
+        if self.ds_passthrough {
+            return CallbackRet::Relay(in_data);
+        }
+        self.ds_buffer.extend(in_data);
+        if self.websocket_requested {
+            return CallbackRet::Freeze;
+        }
+
+        let mut relay_data = Vec::new();
+        let mut spoof_data = Vec::new();
+
+        loop {
+            match parse_request(&self.ds_buffer, &self.options) {
+                Parsed::Complete(mut request, length) => {
+
+                    self.ds_buffer.drain(..length);
+                    self.dechunk(&mut request.headers, request.body.len());
+
+                    match self.handler.on_request(&mut request) {
+                        HttpRet::Relay => {
+                            // Whatever follows a CONNECT or Upgrade request is not HTTP anymore
+                            let upgrade = request.method.eq_ignore_ascii_case("CONNECT") || header(&request.headers, "Upgrade").is_some();
+
+                            let websocket = !request.method.eq_ignore_ascii_case("CONNECT") && is_websocket_upgrade(&request.headers);
+
+                            relay_data.extend(request.to_bytes());
+                            self.pending.push_back(Pending::Relayed(request.method));
+
+                            if websocket {
+                                self.websocket_requested = true;
+                                break;
+                            }
+                            if upgrade {
+                                self.ds_passthrough = true;
+                                relay_data.append(&mut self.ds_buffer);
+                                break;
+                            }
+                        },
+                        HttpRet::Respond(response) => {
+                            // A pipelining client gets its responses in the order it asked
+                            let answer = response.to_bytes(Some(&request.method));
+                            if self.pending.is_empty() {
+                                spoof_data.extend(answer);
+                            } else {
+                                self.pending.push_back(Pending::Answered(answer));
+                            }
+                        },
+                        HttpRet::Shutdown => return CallbackRet::Shutdown,
+                    }
+                },
+                Parsed::Incomplete => break,
+                Parsed::Invalid => {
+                    self.ds_passthrough = true;
+                    relay_data.append(&mut self.ds_buffer);
+                    break;
+                },
+            }
+        }
+
+        callback_ret(relay_data, spoof_data)
+    }
+
+    fn us_b_callback(&mut self, in_data: Vec<u8>) -> CallbackRet {
+
+        if self.websocket.is_some() {
+            return self.websocket_frames(false, in_data);
+        }
+        if self.us_passthrough {
+            return CallbackRet::Relay(in_data);
+        }
+        self.us_buffer.extend(in_data);
+
+        let mut relay_data = Vec::new();
+        let mut spoof_data = Vec::new();
+
+        loop {
+            let request_method = match self.pending.front() {
+                Some(Pending::Relayed(method)) => Some(method.clone()),
+                _ => None,
+            };
+
+            match parse_response(&self.us_buffer, request_method.as_deref(), &self.options) {
+                Parsed::Complete(mut response, length) => {
+
+                    self.us_buffer.drain(..length);
+
+                    let status = response.status;
+                    let until_close = response_has_body(request_method.as_deref(), status)
+                        && !is_chunked(&response.headers)
+                        && content_length(&response.headers).is_none();
+
+                    self.dechunk(&mut response.headers, response.body.len());
+
+                    // Interim responses come before the final response to the same request
+                    let final_response = !(100..200).contains(&status) || status == 101;
+                    if final_response {
+                        self.pending.pop_front();
+                    }
+
+                    let response = match self.handler.on_response(&mut response) {
+                        HttpRet::Relay => response,
+                        HttpRet::Respond(replacement) => replacement,
+                        HttpRet::Shutdown => return CallbackRet::Shutdown,
+                    };
+                    relay_data.extend(response.to_bytes(request_method.as_deref()));
+
+                    if final_response {
+                        self.flush_answered(&mut relay_data);
+                    }
+
+                    if self.websocket_requested && (status == 101 || status >= 200) {
+
+                        self.websocket_requested = false;
+
+                        if status == 101 && is_websocket_upgrade(&response.headers) {
+                            self.websocket = Some(WebSocketSession::new(response.header("Sec-WebSocket-Extensions"), self.options.max_body_size));
+
+                            // Frames that came along with the response, and the ones the client sent early
+                            let server_frames = std::mem::take(&mut self.us_buffer);
+                            let client_frames = std::mem::take(&mut self.ds_buffer);
+                            for (from_client, frames) in [(false, server_frames), (true, client_frames)] {
+                                match self.websocket_messages(from_client, frames) {
+                                    Some((to_server, to_client)) => {
+                                        spoof_data.extend(to_server);
+                                        relay_data.extend(to_client);
+                                    },
+                                    None => return CallbackRet::Shutdown,
+                                }
+                            }
+                            break;
+                        }
+
+                        // The upgrade was turned down, let what the client sent meanwhile through as it is
+                        self.ds_passthrough = true;
+                        spoof_data.append(&mut self.ds_buffer);
+                    }
+
+                    let tunnel = status == 101 || (request_method.as_deref().is_some_and(|method| method.eq_ignore_ascii_case("CONNECT")) && (200..300).contains(&status));
+
+                    // The body of this response or the tunnel runs until the connection closes
+                    if until_close || tunnel {
+                        self.us_passthrough = true;
+                        relay_data.append(&mut self.us_buffer);
+                        break;
+                    }
+                },
+                Parsed::Incomplete => break,
+                Parsed::Invalid => {
+                    self.us_passthrough = true;
+                    relay_data.append(&mut self.us_buffer);
+                    break;
+                },
+            }
+        }
+
+        callback_ret(relay_data, spoof_data)
+    }
+
+    fn open_callback(&mut self, session: SessionHandle) {
+        self.handler.open_callback(session);
+    }
+
+    fn close_callback(&mut self, reason: CloseReason) {
+        self.handler.close_callback(reason);
+    }
+}
+
+impl HttpRequest {
+    /// First value of the header with this name, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        header(&self.headers, name)
+    }
+    /// Replaces every header with this name by a single one.
+    pub fn set_header(&mut self, name: &str, value: &str) {
+        set_header(&mut self.headers, name, value);
+    }
+    /// Removes every header with this name.
+    pub fn remove_header(&mut self, name: &str) {
+        remove_header(&mut self.headers, name);
+    }
+    /// Serializes the request, with the Content-Length matching the body.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let start_line = format!("{} {} {}", self.method, self.uri, self.version);
+        serialize(&start_line, &self.headers, &self.body, true)
+    }
+}
+
+impl HttpResponse {
+    /// Builds a response with a body, for answering a request without relaying it.
+    pub fn new(status: u16, reason: &str, body: Vec<u8>) -> Self {
+        HttpResponse {
+            version: "HTTP/1.1".to_string(),
+            status,
+            reason: reason.to_string(),
+            headers: vec![("Content-Length".to_string(), body.len().to_string())],
+            body,
+        }
+    }
+    /// First value of the header with this name, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        header(&self.headers, name)
+    }
+    /// Replaces every header with this name by a single one.
+    pub fn set_header(&mut self, name: &str, value: &str) {
+        set_header(&mut self.headers, name, value);
+    }
+    /// Removes every header with this name.
+    pub fn remove_header(&mut self, name: &str) {
+        remove_header(&mut self.headers, name);
+    }
+    /// Serializes the response to a request with the given method.
+    /// The Content-Length is left alone where the response carries no body (HEAD, 1xx, 204, 304).
+    pub fn to_bytes(&self, request_method: Option<&str>) -> Vec<u8> {
+        let start_line = format!("{} {} {}", self.version, self.status, self.reason);
+        serialize(&start_line, &self.headers, &self.body, response_has_body(request_method, self.status))
+    }
+}
+
+/// A complete request at the start of buffer.
+pub(crate) fn parse_request(buffer: &[u8], options: &HttpOptions) -> Parsed<HttpRequest> {
+
+    let (head, head_length) = match parse_head(buffer, options.max_header_size) {
+        Parsed::Complete(head, head_length) => (head, head_length),
+        Parsed::Incomplete => return Parsed::Incomplete,
+        Parsed::Invalid => return Parsed::Invalid,
+    };
+
+    let mut parts = head.start_line.splitn(3, ' ');
+    let (method, uri, version) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(uri), Some(version)) if version.starts_with("HTTP/1.") => (method, uri, version),
+        _ => return Parsed::Invalid,
+    };
+
+    let body_length = if is_chunked(&head.headers) {
+        BodyLength::Chunked
+    } else {
+        match content_length(&head.headers) {
+            Some(Some(length)) => BodyLength::Length(length),
+            Some(None) => return Parsed::Invalid,
+            None => BodyLength::Empty,
+        }
+    };
+
+    let (body, body_length) = match parse_body(&buffer[head_length..], body_length, options) {
+        Parsed::Complete(body, body_length) => (body, body_length),
+        Parsed::Incomplete => return Parsed::Incomplete,
+        Parsed::Invalid => return Parsed::Invalid,
+    };
+
+    let request = HttpRequest {
+        method: method.to_string(),
+        uri: uri.to_string(),
+        version: version.to_string(),
+        headers: head.headers,
+        body,
+    };
+    Parsed::Complete(request, head_length + body_length)
+}
+
+/// A complete response at the start of buffer. One whose body runs until the connection closes comes without its body.
+pub(crate) fn parse_response(buffer: &[u8], request_method: Option<&str>, options: &HttpOptions) -> Parsed<HttpResponse> {
+
+    let (head, head_length) = match parse_head(buffer, options.max_header_size) {
+        Parsed::Complete(head, head_length) => (head, head_length),
+        Parsed::Incomplete => return Parsed::Incomplete,
+        Parsed::Invalid => return Parsed::Invalid,
+    };
+
+    let mut parts = head.start_line.splitn(3, ' ');
+    let (version, status, reason) = match (parts.next(), parts.next().map(|status| status.parse::<u16>())) {
+        (Some(version), Some(Ok(status))) if version.starts_with("HTTP/1.") => (version, status, parts.next().unwrap_or("")),
+        _ => return Parsed::Invalid,
+    };
+
+    let body_length = if !response_has_body(request_method, status) {
+        BodyLength::Empty
+    } else if is_chunked(&head.headers) {
+        BodyLength::Chunked
+    } else {
+        match content_length(&head.headers) {
+            Some(Some(length)) => BodyLength::Length(length),
+            Some(None) => return Parsed::Invalid,
+            None => BodyLength::UntilClose,
+        }
+    };
+
+    let (body, body_length) = match parse_body(&buffer[head_length..], body_length, options) {
+        Parsed::Complete(body, body_length) => (body, body_length),
+        Parsed::Incomplete => return Parsed::Incomplete,
+        Parsed::Invalid => return Parsed::Invalid,
+    };
+
+    let response = HttpResponse {
+        version: version.to_string(),
+        status,
+        reason: reason.to_string(),
+        headers: head.headers,
+        body,
+    };
+    Parsed::Complete(response, head_length + body_length)
+}
+
+fn parse_body(buffer: &[u8], body_length: BodyLength, options: &HttpOptions) -> Parsed<Vec<u8>> {
+    match body_length {
+        BodyLength::Empty | BodyLength::UntilClose => Parsed::Complete(Vec::new(), 0),
+        BodyLength::Length(length) => {
+            if length > options.max_body_size {
+                Parsed::Invalid
+            } else if buffer.len() < length {
+                Parsed::Incomplete
+            } else {
+                Parsed::Complete(buffer[..length].to_vec(), length)
+            }
+        },
+        BodyLength::Chunked => decode_chunked(buffer, options.max_body_size),
+    }
+}
+
+struct Head {
+    start_line: String,
+    headers: Vec<(String, String)>,
+}
+
+fn parse_head(buffer: &[u8], max_header_size: usize) -> Parsed<Head> {
+
+    let head_length = match buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+        Some(position) => position + 4,
+        None if buffer.len() > max_header_size => return Parsed::Invalid,
+        None => return Parsed::Incomplete,
+    };
+    if head_length > max_header_size {
+        return Parsed::Invalid;
+    }
+
+    let head = match std::str::from_utf8(&buffer[..head_length - 4]) {
+        Ok(head) => head,
+        Err(_e) => return Parsed::Invalid,
+    };
+
+    let mut lines = head.split("\r\n");
+    let start_line = match lines.next() {
+        Some(start_line) if !start_line.is_empty() => start_line.to_string(),
+        _ => return Parsed::Invalid,
+    };
+
+    let mut headers = Vec::new();
+    for line in lines {
+        match line.split_once(':') {
+            Some((name, value)) if !name.is_empty() && !name.ends_with(' ') => headers.push((name.to_string(), value.trim().to_string())),
+            _ => return Parsed::Invalid,
+        }
+    }
+
+    Parsed::Complete(Head{start_line, headers}, head_length)
+}
+
+fn decode_chunked(buffer: &[u8], max_body_size: usize) -> Parsed<Vec<u8>> {
+
+    let mut body = Vec::new();
+    let mut position = 0;
+
+    loop {
+        let line_end = match find_crlf(&buffer[position..]) {
+            Some(line_end) => position + line_end,
+            None => return Parsed::Incomplete,
+        };
+
+        let size_line = match std::str::from_utf8(&buffer[position..line_end]) {
+            Ok(size_line) => size_line,
+            Err(_e) => return Parsed::Invalid,
+        };
+        // Chunk extensions follow the size after a ';'
+        let size = match usize::from_str_radix(size_line.split(';').next().unwrap_or("").trim(), 16) {
+            Ok(size) => size,
+            Err(_e) => return Parsed::Invalid,
+        };
+        position = line_end + 2;
+
+        if size == 0 {
+            // Skip the trailer section up to the empty line
+            loop {
+                let line_end = match find_crlf(&buffer[position..]) {
+                    Some(line_end) => position + line_end,
+                    None => return Parsed::Incomplete,
+                };
+                let empty = line_end == position;
+                position = line_end + 2;
+                if empty {
+                    return Parsed::Complete(body, position);
+                }
+            }
+        }
+
+        // The size comes from the peer, so nothing it adds up to is trusted not to overflow
+        match body.len().checked_add(size) {
+            Some(body_length) if body_length <= max_body_size => {},
+            _ => return Parsed::Invalid,
+        }
+        let chunk_end = match position.checked_add(size).and_then(|end| end.checked_add(2)) {
+            Some(chunk_end) => chunk_end,
+            None => return Parsed::Invalid,
+        };
+        if buffer.len() < chunk_end {
+            return Parsed::Incomplete;
+        }
+        if &buffer[chunk_end - 2..chunk_end] != b"\r\n" {
+            return Parsed::Invalid;
+        }
+        body.extend_from_slice(&buffer[position..chunk_end - 2]);
+        position = chunk_end;
+    }
+}
+
+fn find_crlf(buffer: &[u8]) -> Option<usize> {
+    buffer.windows(2).position(|window| window == b"\r\n")
+}
+
+fn serialize(start_line: &str, headers: &[(String, String)], body: &[u8], has_body: bool) -> Vec<u8> {
+
+    let chunked = has_body && is_chunked(headers);
+    // A message that had a Content-Length or gained a body gets one that matches the body
+    let mut content_length = if has_body && !chunked && (!body.is_empty() || header(headers, "Content-Length").is_some()) {
+        Some(body.len())
+    } else {
+        None
+    };
+
+    let mut bytes = Vec::with_capacity(start_line.len() + body.len() + 256);
+
+    bytes.extend_from_slice(start_line.as_bytes());
+    bytes.extend_from_slice(b"\r\n");
+
+    for (name, value) in headers {
+        if has_body && name.eq_ignore_ascii_case("Content-Length") {
+            // Rewritten in place, duplicates and a Content-Length next to chunked are dropped
+            if let Some(length) = content_length.take() {
+                bytes.extend_from_slice(format!("{}: {}\r\n", name, length).as_bytes());
+            }
+            continue;
+        }
+        bytes.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+    }
+    if let Some(length) = content_length {
+        bytes.extend_from_slice(format!("Content-Length: {}\r\n", length).as_bytes());
+    }
+    bytes.extend_from_slice(b"\r\n");
+
+    if chunked {
+        if !body.is_empty() {
+            bytes.extend_from_slice(format!("{:x}\r\n", body.len()).as_bytes());
+            bytes.extend_from_slice(body);
+            bytes.extend_from_slice(b"\r\n");
+        }
+        bytes.extend_from_slice(b"0\r\n\r\n");
+    } else if has_body {
+        bytes.extend_from_slice(body);
+    }
+    bytes
+}
+
+pub(crate) fn callback_ret(relay_data: Vec<u8>, spoof_data: Vec<u8>) -> CallbackRet {
+    match (relay_data.is_empty(), spoof_data.is_empty()) {
+        (true, true) => CallbackRet::Freeze,
+        (false, true) => CallbackRet::Relay(relay_data),
+        (true, false) => CallbackRet::Spoof(spoof_data),
+        (false, false) => CallbackRet::RelayAndSpoof(relay_data, spoof_data),
+    }
+}
+
+pub(crate) fn response_has_body(request_method: Option<&str>, status: u16) -> bool {
+    let head_request = request_method.is_some_and(|method| method.eq_ignore_ascii_case("HEAD"));
+    !(head_request || (100..200).contains(&status) || status == 204 || status == 304)
+}
+
+pub(crate) fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers.iter()
+        .find(|(header_name, _)| header_name.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+fn header_values<'a>(headers: &'a [(String, String)], name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+    headers.iter()
+        .filter(move |(header_name, _)| header_name.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+fn set_header(headers: &mut Vec<(String, String)>, name: &str, value: &str) {
+    remove_header(headers, name);
+    headers.push((name.to_string(), value.to_string()));
+}
+
+fn remove_header(headers: &mut Vec<(String, String)>, name: &str) {
+    headers.retain(|(header_name, _)| !header_name.eq_ignore_ascii_case(name));
+}
+
+fn is_websocket_upgrade(headers: &[(String, String)]) -> bool {
+    header_values(headers, "Upgrade")
+        .flat_map(|value| value.split(','))
+        .any(|protocol| protocol.trim().eq_ignore_ascii_case("websocket"))
+}
+
+pub(crate) fn is_chunked(headers: &[(String, String)]) -> bool {
+    header_values(headers, "Transfer-Encoding")
+        .flat_map(|value| value.split(','))
+        .any(|coding| coding.trim().eq_ignore_ascii_case("chunked"))
+}
+
+/// None without a Content-Length header, Some(None) when it is not a valid length.
+pub(crate) fn content_length(headers: &[(String, String)]) -> Option<Option<usize>> {
+    header(headers, "Content-Length").map(|value| value.trim().parse::<usize>().ok())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    struct Answering;
+
+    impl HttpHandler for Answering {
+        fn on_request(&mut self, request: &mut HttpRequest) -> HttpRet {
+            match request.uri.as_str() {
+                "/answered" => HttpRet::Respond(HttpResponse::new(200, "OK", b"answered".to_vec())),
+                _ => HttpRet::Relay,
+            }
+        }
+    }
+
+    fn relayed(callback_ret: CallbackRet) -> Vec<u8> {
+        match callback_ret {
+            CallbackRet::Relay(data) | CallbackRet::RelayAndSpoof(data, _) => data,
+            _ => Vec::new(),
+        }
+    }
+
+    fn spoofed(callback_ret: CallbackRet) -> Vec<u8> {
+        match callback_ret {
+            CallbackRet::Spoof(data) | CallbackRet::RelayAndSpoof(_, data) => data,
+            _ => Vec::new(),
+        }
+    }
+
+    #[test]
+    fn chunked_body_is_decoded() {
+        let message = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5;ext=1\r\nhello\r\n6\r\n world\r\n0\r\nTrailer: x\r\n\r\n";
+        let buffer = [&message[..], b"GET"].concat();
+        match parse_request(&buffer, &HttpOptions::default()) {
+            Parsed::Complete(request, length) => {
+                assert_eq!(request.body, b"hello world");
+                assert_eq!(length, message.len());
+            },
+            _ => panic!("chunked request not parsed"),
+        }
+    }
+
+    #[test]
+    fn chunked_body_waits_for_more() {
+        assert!(matches!(decode_chunked(b"5\r\nhel", 1024), Parsed::Incomplete));
+        assert!(matches!(decode_chunked(b"5\r\nhello\r\n", 1024), Parsed::Incomplete));
+    }
+
+    #[test]
+    fn oversized_chunk_size_is_invalid() {
+        assert!(matches!(decode_chunked(b"1\r\na\r\nffffffffffffffff\r\n", usize::MAX), Parsed::Invalid));
+        assert!(matches!(decode_chunked(b"ffffffffffffffff\r\n", usize::MAX), Parsed::Invalid));
+        assert!(matches!(decode_chunked(b"fffffffffffffffff\r\n", usize::MAX), Parsed::Invalid));
+        assert!(matches!(decode_chunked(b"1\r\na\r\n10\r\n", 8), Parsed::Invalid));
+    }
+
+    #[test]
+    fn malformed_chunks_are_invalid() {
+        assert!(matches!(decode_chunked(b"zz\r\n", 1024), Parsed::Invalid));
+        assert!(matches!(decode_chunked(b"2\r\nabcd\r\n", 1024), Parsed::Invalid));
+    }
+
+    #[test]
+    fn request_round_trips() {
+        let options = HttpOptions::default();
+        let bytes = b"POST /path HTTP/1.1\r\nHost: example\r\nContent-Length: 4\r\n\r\nbody";
+        match parse_request(bytes, &options) {
+            Parsed::Complete(request, length) => {
+                assert_eq!(length, bytes.len());
+                assert_eq!(request.method, "POST");
+                assert_eq!(request.header("host"), Some("example"));
+                assert_eq!(request.to_bytes(), bytes.to_vec());
+            },
+            _ => panic!("request not parsed"),
+        }
+    }
+
+    #[test]
+    fn answers_wait_for_earlier_responses() {
+        let mut relay = HttpRelay::new(Answering);
+
+        let ret = relay.ds_b_callback(b"GET /relayed HTTP/1.1\r\n\r\nGET /answered HTTP/1.1\r\n\r\n".to_vec());
+        assert_eq!(relayed(ret), b"GET /relayed HTTP/1.1\r\n\r\n");
+
+        let ret = relay.us_b_callback(b"HTTP/1.1 200 OK\r\nContent-Length: 7\r\n\r\nrelayed".to_vec());
+        let to_client = relayed(ret);
+        assert_eq!(to_client, [&b"HTTP/1.1 200 OK\r\nContent-Length: 7\r\n\r\nrelayed"[..], b"HTTP/1.1 200 OK\r\nContent-Length: 8\r\n\r\nanswered"].concat());
+
+        // Nothing outstanding, answered right away
+        let ret = relay.ds_b_callback(b"GET /answered HTTP/1.1\r\n\r\n".to_vec());
+        assert_eq!(spoofed(ret), b"HTTP/1.1 200 OK\r\nContent-Length: 8\r\n\r\nanswered");
+    }
+}
@@ -0,0 +1,541 @@
+//! HTTP/2 aware callbacks.
+//!
+//! Http2Relay wraps an Http2Handler and implements HandlerCallbacks for it. It follows the
+//! HTTP/2 frames of both legs, decodes the HPACK compressed header blocks and hands every
+//! stream's headers and data to the handler before encoding them again for the other side.
+//! For TLS legs offer h2 through ALPN in RelayConfig:
+//! ```ignore
+//! use sslrelay::http2::{Http2Relay, Http2Handler};
+//!
+//! struct Handler;
+//!
+//! impl Http2Handler for Handler {
+//!     fn on_request_headers(&mut self, _stream_id: u32, headers: &mut Vec<(String, String)>) {
+//!         headers.push(("x-relayed".to_string(), "1".to_string()));
+//!     }
+//! }
+//!
+//! let config = RelayConfig {
+//!     alpn_protocols: vec!["h2".to_string()],
+//!     ..Default::default()
+//! };
+//! let mut relay = sslrelay::SSLRelay::new(Http2Relay::new(Handler), config);
+//! ```
+//! Flow control is kept in step when the handler shrinks a body. A body that grows must still
+//! fit into the window the receiving side advertised. Connections that do not start with the
+//! HTTP/2 client preface are relayed untouched.
+//!
+//! Frames are relayed stream for stream rather than terminated on each leg, so both legs have to
+//! speak HTTP/2. Over TLS the relay makes sure of that: upstream is only offered the protocol the
+//! client negotiated and a session where upstream turns h2 down is closed (CloseReason::AlpnMismatch).
+
+use crate::{
+    HandlerCallbacks,
+    CallbackRet,
+    CloseReason,
+    SessionHandle,
+    HashMap,
+    hpack::{
+        Decoder,
+        Encoder,
+        HeaderList,
+    },
+};
+
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+const FRAME_DATA: u8 = 0x0;
+const FRAME_HEADERS: u8 = 0x1;
+const FRAME_RST_STREAM: u8 = 0x3;
+const FRAME_PUSH_PROMISE: u8 = 0x5;
+const FRAME_WINDOW_UPDATE: u8 = 0x8;
+const FRAME_CONTINUATION: u8 = 0x9;
+
+const FLAG_END_STREAM: u8 = 0x1;
+const FLAG_END_HEADERS: u8 = 0x4;
+const FLAG_PADDED: u8 = 0x8;
+const FLAG_PRIORITY: u8 = 0x20;
+
+// Every HTTP/2 endpoint accepts frames of this size, whatever its settings say
+const MAX_FRAME_SIZE: usize = 16384;
+
+/// Callbacks for HTTP/2 traffic, used through Http2Relay.
+/// Header callbacks are called again for trailers.
+pub trait Http2Handler {
+    fn on_request_headers(&mut self, _stream_id: u32, _headers: &mut Vec<(String, String)>){}
+    fn on_request_data(&mut self, _stream_id: u32, _data: &mut Vec<u8>, _end_stream: bool){}
+    fn on_response_headers(&mut self, _stream_id: u32, _headers: &mut Vec<(String, String)>){}
+    fn on_response_data(&mut self, _stream_id: u32, _data: &mut Vec<u8>, _end_stream: bool){}
+    fn open_callback(&mut self, _session: SessionHandle){}
+    fn close_callback(&mut self, _reason: CloseReason){}
+}
+
+/// Turns an Http2Handler into HandlerCallbacks.
+pub struct Http2Relay<T: Http2Handler> {
+    handler: T,
+    passthrough: bool,
+    preface_done: bool,
+    ds: Http2Direction,
+    us: Http2Direction,
+}
+
+/// Frames flowing from one side of the connection.
+struct Http2Direction {
+    buffer: Vec<u8>,
+    decoder: Decoder,
+    pending_headers: Option<PendingHeaders>,
+    // Bytes this side sent that grew on the way, taken back out of the other side's WINDOW_UPDATEs
+    connection_debt: u64,
+    stream_debt: HashMap<u32, u64>,
+}
+
+/// A header block waiting for its CONTINUATION frames.
+struct PendingHeaders {
+    frame_type: u8,
+    stream_id: u32,
+    flags: u8,
+    // Priority fields of HEADERS or the promised stream id of PUSH_PROMISE
+    prefix: Vec<u8>,
+    block: Vec<u8>,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Side {
+    DownStream,
+    UpStream,
+}
+
+struct Frame {
+    frame_type: u8,
+    flags: u8,
+    stream_id: u32,
+    payload: Vec<u8>,
+}
+
+impl<T: Http2Handler> Http2Relay<T> {
+
+    pub fn new(handler: T) -> Self {
+        Http2Relay {
+            handler,
+            passthrough: false,
+            preface_done: false,
+            ds: Http2Direction::new(),
+            us: Http2Direction::new(),
+        }
+    }
+
+    pub(crate) fn handler(&self) -> &T {
+        &self.handler
+    }
+
+    /// Processes every complete frame received from the side `from`.
+    /// Returns the bytes for the other side and the bytes to send back, or None if the session can not go on.
+    fn process(&mut self, from: Side, in_data: Vec<u8>) -> Option<(Vec<u8>, Vec<u8>)> {
+
+        let mut relay_data = Vec::new();
+        let mut spoof_data = Vec::new();
+
+        self.direction(from).buffer.extend(in_data);
+
+        if from == Side::DownStream && !self.preface_done {
+            let buffer = &self.ds.buffer;
+            let compared = buffer.len().min(PREFACE.len());
+            if buffer[..compared] != PREFACE[..compared] {
+                self.passthrough = true;
+                return Some((std::mem::take(&mut self.ds.buffer), spoof_data));
+            }
+            if buffer.len() < PREFACE.len() {
+                return Some((relay_data, spoof_data));
+            }
+            relay_data.extend(self.ds.buffer.drain(..PREFACE.len()));
+            self.preface_done = true;
+        }
+
+        while let Some(frame) = next_frame(&mut self.direction(from).buffer) {
+
+            match frame.frame_type {
+                FRAME_DATA => {
+
+                    let received = frame.payload.len() as u64;
+                    let end_stream = frame.flags & FLAG_END_STREAM != 0;
+                    let mut data = strip_padding(frame.flags, frame.payload)?;
+
+                    match from {
+                        Side::DownStream => self.handler.on_request_data(frame.stream_id, &mut data, end_stream),
+                        Side::UpStream => self.handler.on_response_data(frame.stream_id, &mut data, end_stream),
+                    }
+
+                    let sent = data.len() as u64;
+                    write_data_frames(&mut relay_data, frame.stream_id, &data, end_stream);
+
+                    if sent < received {
+                        // Hand the sender back the window the receiver will never see
+                        let credit = received - sent;
+                        write_window_update(&mut spoof_data, 0, credit);
+                        if !end_stream {
+                            write_window_update(&mut spoof_data, frame.stream_id, credit);
+                        }
+                    } else if sent > received {
+                        let direction = self.direction(from);
+                        direction.connection_debt += sent - received;
+                        *direction.stream_debt.entry(frame.stream_id).or_insert(0) += sent - received;
+                    }
+                    if end_stream {
+                        self.direction(from).stream_debt.remove(&frame.stream_id);
+                    }
+                },
+                FRAME_HEADERS | FRAME_PUSH_PROMISE => {
+
+                    // Trailers end the stream, nothing it still owes matters after that
+                    if frame.frame_type == FRAME_HEADERS && frame.flags & FLAG_END_STREAM != 0 {
+                        self.direction(from).stream_debt.remove(&frame.stream_id);
+                    }
+
+                    let mut payload = strip_padding(frame.flags, frame.payload)?;
+                    let prefix_length = match frame.frame_type {
+                        FRAME_PUSH_PROMISE => 4,
+                        _ if frame.flags & FLAG_PRIORITY != 0 => 5,
+                        _ => 0,
+                    };
+                    if payload.len() < prefix_length {
+                        return None;
+                    }
+                    let block = payload.split_off(prefix_length);
+
+                    let pending = PendingHeaders {
+                        frame_type: frame.frame_type,
+                        stream_id: frame.stream_id,
+                        flags: frame.flags,
+                        prefix: payload,
+                        block,
+                    };
+
+                    if frame.flags & FLAG_END_HEADERS != 0 {
+                        self.header_block(from, pending, &mut relay_data)?;
+                    } else {
+                        self.direction(from).pending_headers = Some(pending);
+                    }
+                },
+                FRAME_CONTINUATION => {
+
+                    let mut pending = self.direction(from).pending_headers.take()?;
+                    if pending.stream_id != frame.stream_id {
+                        return None;
+                    }
+                    pending.block.extend(frame.payload);
+
+                    if frame.flags & FLAG_END_HEADERS != 0 {
+                        self.header_block(from, pending, &mut relay_data)?;
+                    } else {
+                        self.direction(from).pending_headers = Some(pending);
+                    }
+                },
+                FRAME_WINDOW_UPDATE => {
+
+                    // Window the other side's grown data already used up
+                    let to = self.direction(match from {
+                        Side::DownStream => Side::UpStream,
+                        Side::UpStream => Side::DownStream,
+                    });
+                    let debt = match frame.stream_id {
+                        0 => &mut to.connection_debt,
+                        stream_id => match to.stream_debt.get_mut(&stream_id) {
+                            Some(debt) => debt,
+                            None => {
+                                write_frame(&mut relay_data, frame.frame_type, frame.flags, frame.stream_id, &frame.payload);
+                                continue;
+                            },
+                        },
+                    };
+
+                    if frame.payload.len() != 4 || *debt == 0 {
+                        write_frame(&mut relay_data, frame.frame_type, frame.flags, frame.stream_id, &frame.payload);
+                        continue;
+                    }
+
+                    let increment = (u32::from_be_bytes([frame.payload[0], frame.payload[1], frame.payload[2], frame.payload[3]]) & 0x7fff_ffff) as u64;
+                    let paid = increment.min(*debt);
+                    *debt -= paid;
+                    // A zero increment is a protocol error, so a fully used up update is dropped
+                    if increment > paid {
+                        write_window_update(&mut relay_data, frame.stream_id, increment - paid);
+                    }
+                },
+                FRAME_RST_STREAM => {
+                    self.ds.stream_debt.remove(&frame.stream_id);
+                    self.us.stream_debt.remove(&frame.stream_id);
+                    write_frame(&mut relay_data, frame.frame_type, frame.flags, frame.stream_id, &frame.payload);
+                },
+                _ => write_frame(&mut relay_data, frame.frame_type, frame.flags, frame.stream_id, &frame.payload),
+            }
+        }
+
+        Some((relay_data, spoof_data))
+    }
+
+    /// Decodes a complete header block, lets the handler change it and writes it out encoded for the other side.
+    fn header_block(&mut self, from: Side, pending: PendingHeaders, relay_data: &mut Vec<u8>) -> Option<()> {
+
+        let decoded = self.direction(from).decoder.decode(&pending.block).ok()?;
+
+        let headers = if pending.frame_type == FRAME_PUSH_PROMISE {
+            decoded
+        } else {
+            let mut headers: Vec<(String, String)> = decoded.into_iter()
+                .map(|(name, value)| (String::from_utf8_lossy(&name).into_owned(), String::from_utf8_lossy(&value).into_owned()))
+                .collect();
+
+            match from {
+                Side::DownStream => self.handler.on_request_headers(pending.stream_id, &mut headers),
+                Side::UpStream => self.handler.on_response_headers(pending.stream_id, &mut headers),
+            }
+
+            headers.into_iter()
+                .map(|(name, value)| (name.into_bytes(), value.into_bytes()))
+                .collect::<HeaderList>()
+        };
+
+        let block = Encoder::encode(&headers);
+
+        // Padding is dropped, the block is split into CONTINUATION frames where it does not fit
+        let first_length = block.len().min(MAX_FRAME_SIZE - pending.prefix.len());
+        let (first, mut rest) = block.split_at(first_length);
+
+        let mut flags = pending.flags & (FLAG_END_STREAM | FLAG_PRIORITY);
+        if rest.is_empty() {
+            flags |= FLAG_END_HEADERS;
+        }
+
+        let mut payload = pending.prefix;
+        payload.extend_from_slice(first);
+        write_frame(relay_data, pending.frame_type, flags, pending.stream_id, &payload);
+
+        while !rest.is_empty() {
+            let (chunk, remaining) = rest.split_at(rest.len().min(MAX_FRAME_SIZE));
+            rest = remaining;
+            let flags = if rest.is_empty() { FLAG_END_HEADERS } else { 0 };
+            write_frame(relay_data, FRAME_CONTINUATION, flags, pending.stream_id, chunk);
+        }
+        Some(())
+    }
+
+    fn direction(&mut self, side: Side) -> &mut Http2Direction {
+        match side {
+            Side::DownStream => &mut self.ds,
+            Side::UpStream => &mut self.us,
+        }
+    }
+
+    fn callback(&mut self, from: Side, in_data: Vec<u8>) -> CallbackRet {
+
+        if self.passthrough {
+            return CallbackRet::Relay(in_data);
+        }
+
+        match self.process(from, in_data) {
+            Some((relay_data, spoof_data)) => match (relay_data.is_empty(), spoof_data.is_empty()) {
+                (true, true) => CallbackRet::Freeze,
+                (false, true) => CallbackRet::Relay(relay_data),
+                (true, false) => CallbackRet::Spoof(spoof_data),
+                (false, false) => CallbackRet::RelayAndSpoof(relay_data, spoof_data),
+            },
+            // Broken framing or header compression, the connection state is lost
+            None => CallbackRet::Shutdown,
+        }
+    }
+}
+
+impl<T: Http2Handler + Clone> Clone for Http2Relay<T> {
+
+    /// Clones the handler into a relay that has not seen any traffic.
+    fn clone(&self) -> Self {
+        Http2Relay::new(self.handler.clone())
+    }
+}
+
+impl<T: Http2Handler> HandlerCallbacks for Http2Relay<T> {
+
+    fn ds_b_callback(&mut self, in_data: Vec<u8>) -> CallbackRet {
+        self.callback(Side::DownStream, in_data)
+    }
+
+    fn us_b_callback(&mut self, in_data: Vec<u8>) -> CallbackRet {
+        self.callback(Side::UpStream, in_data)
+    }
+
+    fn open_callback(&mut self, session: SessionHandle) {
+        self.handler.open_callback(session);
+    }
+
+    fn close_callback(&mut self, reason: CloseReason) {
+        self.handler.close_callback(reason);
+    }
+}
+
+impl Http2Direction {
+
+    fn new() -> Self {
+        Http2Direction {
+            buffer: Vec::new(),
+            decoder: Decoder::new(),
+            pending_headers: None,
+            connection_debt: 0,
+            stream_debt: HashMap::new(),
+        }
+    }
+}
+
+fn next_frame(buffer: &mut Vec<u8>) -> Option<Frame> {
+
+    if buffer.len() < 9 {
+        return None;
+    }
+    let length = ((buffer[0] as usize) << 16) | ((buffer[1] as usize) << 8) | buffer[2] as usize;
+    if buffer.len() < 9 + length {
+        return None;
+    }
+
+    let frame = Frame {
+        frame_type: buffer[3],
+        flags: buffer[4],
+        stream_id: u32::from_be_bytes([buffer[5], buffer[6], buffer[7], buffer[8]]) & 0x7fff_ffff,
+        payload: buffer[9..9 + length].to_vec(),
+    };
+    buffer.drain(..9 + length);
+    Some(frame)
+}
+
+fn strip_padding(flags: u8, mut payload: Vec<u8>) -> Option<Vec<u8>> {
+
+    if flags & FLAG_PADDED == 0 {
+        return Some(payload);
+    }
+    let padding = *payload.first()? as usize;
+    if padding + 1 > payload.len() {
+        return None;
+    }
+    payload.truncate(payload.len() - padding);
+    payload.remove(0);
+    Some(payload)
+}
+
+fn write_frame(out: &mut Vec<u8>, frame_type: u8, flags: u8, stream_id: u32, payload: &[u8]) {
+    let length = payload.len() as u32;
+    out.extend_from_slice(&length.to_be_bytes()[1..]);
+    out.push(frame_type);
+    out.push(flags);
+    out.extend_from_slice(&stream_id.to_be_bytes());
+    out.extend_from_slice(payload);
+}
+
+fn write_data_frames(out: &mut Vec<u8>, stream_id: u32, data: &[u8], end_stream: bool) {
+
+    let mut chunks = data.chunks(MAX_FRAME_SIZE).peekable();
+    if chunks.peek().is_none() {
+        write_frame(out, FRAME_DATA, if end_stream { FLAG_END_STREAM } else { 0 }, stream_id, &[]);
+        return;
+    }
+    while let Some(chunk) = chunks.next() {
+        let flags = if end_stream && chunks.peek().is_none() { FLAG_END_STREAM } else { 0 };
+        write_frame(out, FRAME_DATA, flags, stream_id, chunk);
+    }
+}
+
+fn write_window_update(out: &mut Vec<u8>, stream_id: u32, increment: u64) {
+
+    // Increments are at most 2^31-1
+    let mut increment = increment;
+    while increment > 0 {
+        let step = increment.min(0x7fff_ffff);
+        write_frame(out, FRAME_WINDOW_UPDATE, 0, stream_id, &(step as u32).to_be_bytes());
+        increment -= step;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    struct Growing;
+
+    impl Http2Handler for Growing {
+        fn on_request_headers(&mut self, _stream_id: u32, headers: &mut Vec<(String, String)>) {
+            headers.push(("x-relayed".to_string(), "1".to_string()));
+        }
+        fn on_request_data(&mut self, _stream_id: u32, data: &mut Vec<u8>, _end_stream: bool) {
+            data.extend_from_slice(b"!!");
+        }
+    }
+
+    fn frame(frame_type: u8, flags: u8, stream_id: u32, payload: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        write_frame(&mut out, frame_type, flags, stream_id, payload);
+        out
+    }
+
+    fn started() -> Http2Relay<Growing> {
+        let mut relay = Http2Relay::new(Growing);
+        let (relayed, _) = relay.process(Side::DownStream, PREFACE.to_vec()).unwrap();
+        assert_eq!(relayed, PREFACE);
+        relay
+    }
+
+    #[test]
+    fn headers_are_decoded_and_encoded_again() {
+        let mut relay = started();
+        let block = Encoder::encode(&[(b":method".to_vec(), b"GET".to_vec()), (b":path".to_vec(), b"/".to_vec())]);
+        let (mut relayed, _) = relay.process(Side::DownStream, frame(FRAME_HEADERS, FLAG_END_HEADERS | FLAG_END_STREAM, 1, &block)).unwrap();
+
+        let relayed_frame = next_frame(&mut relayed).unwrap();
+        assert_eq!(relayed_frame.flags, FLAG_END_HEADERS | FLAG_END_STREAM);
+        let headers = Decoder::new().decode(&relayed_frame.payload).unwrap();
+        assert_eq!(headers.last(), Some(&(b"x-relayed".to_vec(), b"1".to_vec())));
+    }
+
+    #[test]
+    fn stream_debt_ends_with_the_stream() {
+        let mut relay = started();
+
+        relay.process(Side::DownStream, frame(FRAME_DATA, 0, 1, b"abc")).unwrap();
+        assert_eq!(relay.ds.stream_debt.get(&1), Some(&2));
+        relay.process(Side::DownStream, frame(FRAME_DATA, FLAG_END_STREAM, 1, b"abc")).unwrap();
+        assert!(relay.ds.stream_debt.is_empty());
+
+        relay.process(Side::DownStream, frame(FRAME_DATA, 0, 3, b"abc")).unwrap();
+        relay.process(Side::UpStream, frame(FRAME_RST_STREAM, 0, 3, &8u32.to_be_bytes())).unwrap();
+        assert!(relay.ds.stream_debt.is_empty());
+    }
+
+    #[test]
+    fn window_updates_pay_off_debt() {
+        let mut relay = started();
+        relay.process(Side::DownStream, frame(FRAME_DATA, 0, 1, b"abc")).unwrap();
+
+        let (mut relayed, _) = relay.process(Side::UpStream, frame(FRAME_WINDOW_UPDATE, 0, 0, &5u32.to_be_bytes())).unwrap();
+        assert_eq!(next_frame(&mut relayed).unwrap().payload, 3u32.to_be_bytes());
+
+        // Fully used up, the update is dropped
+        let (relayed, _) = relay.process(Side::UpStream, frame(FRAME_WINDOW_UPDATE, 0, 1, &2u32.to_be_bytes())).unwrap();
+        assert!(relayed.is_empty());
+    }
+
+    #[test]
+    fn malformed_frames_end_the_session() {
+        let mut relay = started();
+        // Padding longer than the frame
+        assert!(relay.process(Side::DownStream, frame(FRAME_DATA, FLAG_PADDED, 1, &[9, 1])).is_none());
+
+        let mut relay = started();
+        // CONTINUATION without a header block before it
+        assert!(relay.process(Side::DownStream, frame(FRAME_CONTINUATION, FLAG_END_HEADERS, 1, &[0x82])).is_none());
+    }
+
+    #[test]
+    fn other_protocols_pass_through() {
+        let mut relay = Http2Relay::new(Growing);
+        let (relayed, _) = relay.process(Side::DownStream, b"GET / HTTP/1.1\r\n\r\n".to_vec()).unwrap();
+        assert_eq!(relayed, b"GET / HTTP/1.1\r\n\r\n");
+        assert!(relay.passthrough);
+    }
+}
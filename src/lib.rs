@@ -3,26 +3,41 @@
 //! Library for relaying TCP traffic as well as TLS encrypted TCP traffic.
 //! This library allows you to implement callback functions for upstream and downstream traffic.
 //! These callbacks can R/W the data from a stream(Blocking) or only R the data(Non-Blocking).
-//!```
+//!```ignore
 //!pub trait HandlerCallbacks {
 //!    fn ds_b_callback(&mut self, _in_data: Vec<u8>) -> CallbackRet {CallbackRet::Relay(_in_data)}
 //!    fn ds_nb_callback(&self, _in_data: Vec<u8>){}
 //!    fn us_b_callback(&mut self, _in_data: Vec<u8>) -> CallbackRet {CallbackRet::Relay(_in_data)}
 //!    fn us_nb_callback(&self, _in_data: Vec<u8>){}
+//!    fn open_callback(&mut self, _session: SessionHandle){}
+//!    fn close_callback(&mut self, _reason: CloseReason){}
 //!}
 //!```
-//! The blocking callbacks return an enum called CallbackRet with four different variants.
+//! The blocking callbacks return an enum called CallbackRet.
 //! The variants control the flow of the tcp stream.
 //!```
+//! # use std::time::Duration;
 //! pub enum CallbackRet {
 //!     Relay(Vec<u8>),// Relay data
 //!     Spoof(Vec<u8>),// Skip relaying and send data back
 //!     Shutdown,// Shutdown TCP connection
 //!     Freeze,// Dont send data (pretend as if stream never was recieved)
+//!     RelayAndSpoof(Vec<u8>, Vec<u8>),// Relay the first and send the second back
+//!     Split(Vec<Vec<u8>>, Duration),// Relay every chunk as its own write, pausing in between
+//!     Delay(Vec<u8>, Duration),// Relay data once the duration has passed
+//!     RelayAndHalfClose(Vec<u8>),// Relay data, then stop sending to that side
+//!     SpoofAndHalfClose(Vec<u8>),// Send data back, then stop sending to that side
+//!     RelayAndStartTLS(Vec<u8>, Vec<u8>),// Relay the first, then switch both sides to TLS (STARTTLS). The second is handshake data the callback already got
 //! }
 //! ```
+//! Callbacks get data in the chunks it was read in unless downstream_framing / upstream_framing
+//! in RelayConfig cut it into whole messages first (lines, length prefixed, TLS records, ...).
+//! A message that grows past max_frame_size, or past high_watermark, ends the framing of its direction
+//! and the rest of that direction is passed on as it is read.
+//! Delayed writes do not hold up the relay. Writes to a side wait for any delayed write queued
+//! before them, so data reaches each side in the order the callbacks returned it.
 //! ## Example (basic.rs)
-//! ```
+//! ```ignore
 //! use sslrelay::{self, RelayConfig, HandlerCallbacks, CallbackRet, TCPDataType, TLSConfig};
 //! 
 //! // Handler object
@@ -74,6 +89,7 @@
 //!                 certificate_path: "./tls.crt".to_string(),
 //!                 private_key_path: "./tls.key".to_string(),
 //!             },
+//!             ..Default::default()
 //!         }
 //!     );
 //! 
@@ -81,6 +97,151 @@
 //!     relay.start();
 //! }
 //! ```
+//! ## Multiple routes
+//! One SSLRelay object can serve several listeners at once. Every route has its own
+//! RelayConfig (bind address, remote host, data types and TLSConfig) and its own handler,
+//! while all of them share one worker pool and one RelayHandle for shutting down.
+//! ```ignore
+//! let mut relay = sslrelay::SSLRelay::new(Handler, web_config);
+//! relay.add_route(Handler, imap_config);
+//! relay.set_worker_threads(64);
+//!
+//! let relay_handle = relay.handle();
+//! std::thread::spawn(move || relay.start());
+//! // ...
+//! relay_handle.shutdown();
+//! ```
+//! ## Upstream load balancing
+//! A route can spread its connections over several upstream endpoints. When connecting to the
+//! picked endpoint fails the next one is tried, and endpoints that keep failing are ejected for a while.
+//! ```ignore
+//! RelayConfig {
+//!     remote_host: "10.0.0.1".to_string(),
+//!     remote_port: "443".to_string(),
+//!     extra_remotes: vec![
+//!         RemoteEndpoint{host: "10.0.0.2".to_string(), port: "443".to_string()},
+//!     ],
+//!     load_balancing: LoadBalancing::LeastConnections,
+//!     connect_timeout: Some(Duration::from_secs(3)),
+//!     max_fails: 3,
+//!     fail_timeout: Duration::from_secs(30),
+//!     ..Default::default()
+//! }
+//! ```
+//! ## Timeouts
+//! connect_timeout, handshake_timeout, idle_timeout and max_session_lifetime in RelayConfig
+//! bound how long a session may take to set up and how long it may live. Whichever one ends
+//! a session is reported to HandlerCallbacks::close_callback as a CloseReason.
+//! ## Half-close
+//! With half_close enabled a TCP FIN or TLS close_notify from one side is passed on to the other side as
+//! a write shutdown (FIN on RAW, close_notify on TLS) while data keeps flowing the opposite way.
+//! The session ends once both sides have stopped sending.
+//! ## STARTTLS
+//! A blocking callback that returns CallbackRet::RelayAndStartTLS switches a RAW session to TLS midway,
+//! like protocols with a STARTTLS step (SMTP, IMAP, PostgreSQL) do. The data is written in the clear,
+//! then the relay accepts TLS from the client with tls_config and starts TLS towards upstream, so a
+//! RAW downstream needs a tls_config for this too. Clients that start their handshake without waiting
+//! for an answer (MySQL) may have it read along with the request: whatever the callback got past the
+//! request goes in the second field, and data read from that side until the switch is not passed to
+//! the callbacks but to the handshake.
+//! ## Protocol detection
+//! A downstream_data_type of TCPDataType::AUTO lets one port take mixed clients. The first bytes of every
+//! connection are peeked at: a TLS ClientHello is accepted with tls_config, anything else is relayed as RAW.
+//! ConnectionInfo::protocol tells a HandlerFactory what was found (HTTP/1.x, the HTTP/2 preface, SSH, SOCKS).
+//! A PROXY protocol v1 or v2 header in front is read off the stream into ConnectionInfo::proxy_client_addr,
+//! if the client connected from one of RelayConfig::trusted_proxies. From anyone else it is relayed as RAW data.
+//! ## Capture
+//! Setting RelayConfig::capture to a PcapngCapture writes every session to a pcapng file as a TCP connection
+//! between the real client and upstream addresses, with the plaintext each side sent before the callbacks
+//! touched it. A comment on each connection's SYN notes how both legs were set up (TLS version, cipher, SNI, ALPN).
+//! A leg switched to TLS later (STARTTLS) gets the same note on the first packet after the switch.
+//! ## HAR
+//! RelayConfig::har takes a har::HarRecorder that turns the HTTP/1.1 traffic of every session into a HAR 1.2
+//! log, with connect, TLS handshake, wait and receive timings measured by the relay.
+//! ## Record and replay
+//! RelayConfig::session_recorder takes a replay::SessionRecorder that writes every chunk of every session to a file.
+//! The replay module plays a recorded session back as a fake upstream to a live client, or as a fake client
+//! to a live upstream, with the recorded timing kept, scaled or left out.
+//! ## Metrics
+//! A Metrics set in RelayConfig::metrics counts connections, bytes, TLS handshake and upstream connect failures,
+//! close reasons, blocking callback durations and the CallbackRet variants they return, labelled by route.
+//! Metrics::serve answers Prometheus scrapes over HTTP, Metrics::render gives the same text.
+//! ## Logging
+//! Diagnostics are tracing events and nothing is printed unless the application installs a subscriber.
+//! Every connection runs in a `session` span with its connection_id, route and peer address, and the stream
+//! threads of a session log in a `stream` span with their side beneath it.
+//! ## Backpressure
+//! Every direction stops reading once high_watermark bytes are waiting to be written to the other side
+//! and resumes when they drained to low_watermark. max_session_memory puts a hard cap on a whole session.
+//! ## Non blocking callbacks
+//! ds_nb_callback and us_nb_callback run on one worker thread per session, in the order the data was received.
+//! For a Clone handler they run on a clone of it taken when the session opens, so a slow observer never holds up
+//! the blocking callbacks. State both kinds of callbacks need to see goes in a SharedState.
+//! At most nb_callback_backlog chunks wait for that thread, nb_callback_overflow decides what happens past that.
+//! ## Per connection handlers
+//! A handler passed to SSLRelay::new is cloned for every connection. To build each connection's handler
+//! from its ConnectionInfo instead, pass a HandlerFactory. State every connection should see goes in a SharedState.
+//! ```ignore
+//! struct Factory {
+//!     connections: SharedState<u64>,
+//! }
+//!
+//! impl HandlerFactory for Factory {
+//!     type Handler = Handler;
+//!
+//!     fn create(&self, info: &ConnectionInfo) -> Handler {
+//!         self.connections.with(|count| *count += 1);
+//!         Handler { id: info.connection_id, client: info.client_addr, connections: self.connections.clone() }
+//!     }
+//! }
+//!
+//! let mut relay = sslrelay::SSLRelay::new(Factory { connections: SharedState::new(0) }, config);
+//! ```
+//! ## Injecting data
+//! open_callback hands every session a SessionHandle. It can be cloned and moved to other threads to send
+//! unsolicited data to either side (heartbeats, injected commands, delayed replies) or to close the session.
+//! ```ignore
+//! fn open_callback(&mut self, session: SessionHandle) {
+//!     std::thread::spawn(move || {
+//!         while session.send_upstream(b"PING\r\n".to_vec()) {
+//!             std::thread::sleep(Duration::from_secs(5));
+//!         }
+//!     });
+//! }
+//! ```
+//! ## HTTP
+//! The http module parses HTTP/1.1 traffic into requests and responses. Wrap an HttpHandler in
+//! http::HttpRelay and pass that to SSLRelay::new to work on whole messages instead of raw chunks.
+//! The http2 module does the same for HTTP/2 (negotiated with alpn_protocols on TLS legs), calling
+//! http2::Http2Handler once per stream for headers and data. Connections that HttpRelay sees upgraded
+//! to WebSocket are followed message by message through HttpHandler::on_websocket_message.
+//! On top of HTTP/2 the grpc module calls grpc::GrpcHandler for every gRPC message, decoded into
+//! protobuf::ProtoMessage when a descriptor set is given.
+//!
+//! ## Redis
+//! The redis module parses RESP2/RESP3 into redis::RespValue. redis::RedisRelay hands a RedisHandler
+//! every command and the reply that answers it, so keys can be rewritten or commands failed with an
+//! error reply of the handler's own. Framing::RESP frames raw callbacks one RESP value at a time.
+//!
+//! ## PostgreSQL
+//! postgres::PostgresRelay decodes the frontend/backend protocol for a PostgresHandler, which can rewrite
+//! queries or fail them with an ErrorResponse of its own. SSLRequest upgrades both legs to TLS.
+//!
+//! ## MySQL
+//! mysql::MySqlRelay does the same for the MySQL client/server protocol: handshake, COM_QUERY, prepared
+//! statements, result sets and OK/ERR packets reach a MySqlHandler as typed messages it can rewrite
+//! or answer itself. A client that asks for SSL gets both legs switched to TLS.
+//!
+//! ## MQTT
+//! mqtt::MqttRelay splits MQTT 3.1.1 and 5.0 traffic into control packets for an MqttHandler, which can
+//! rewrite topics, payloads and properties, drop publishes or inject packets to the client or the broker.
+//! Framing::MQTT frames raw callbacks one control packet at a time.
+//!
+//! ## DNS
+//! dns::DnsRelay parses DNS over TCP and DNS over TLS into dns::DnsMessages. A DnsHandler sees every query and
+//! the response that answers it, so queries can be answered locally and answer records rewritten.
+
+#![allow(clippy::upper_case_acronyms)]
 
 use openssl::{
     x509::X509,
@@ -90,29 +251,53 @@ use openssl::{
         SslConnector,
         SslAcceptor,
         SslStream,
+        HandshakeError,
         SslFiletype,
         SslMethod,
+        AlpnError,
     }
 };
 
 use std::net::{
+    IpAddr,
     TcpListener,
     TcpStream,
-    Shutdown
+    Shutdown,
+    SocketAddr,
+    ToSocketAddrs,
 };
 
 use std::sync::{
     Arc,
-    Mutex
+    Mutex,
+    MutexGuard,
+    Condvar,
+    OnceLock,
+    atomic::{
+        AtomicBool,
+        AtomicUsize,
+        AtomicU64,
+        Ordering,
+    }
 };
 
 use std::{
-    thread
+    thread,
+    collections::{
+        VecDeque,
+        HashMap,
+        HashSet,
+        BTreeMap,
+    },
 };
 
 use std::{
     path::Path,
-    time::Duration,
+    time::{
+        Duration,
+        Instant,
+        SystemTime,
+    },
 };
 
 use std::io::{
@@ -130,6 +315,35 @@ use std::sync::mpsc::{
 mod data;
 mod tcp;
 mod relay;
+mod pool;
+mod upstream;
+mod buffer;
+mod callback;
+mod handler;
+mod session;
+mod schedule;
+mod framing;
+mod tls;
+mod sniff;
+mod capture;
+mod metrics;
+pub mod http;
+pub mod http2;
+pub mod websocket;
+pub mod grpc;
+pub mod protobuf;
+pub mod redis;
+pub mod postgres;
+pub mod mysql;
+pub mod mqtt;
+pub mod dns;
+pub mod har;
+pub mod replay;
+mod hpack;
+
+use pool::WorkerPool;
+use framing::FrameBuffer;
+use tls::TlsTransport;
 
 #[derive(Debug)]
 enum FullDuplexTcpState {
@@ -137,17 +351,66 @@ enum FullDuplexTcpState {
     UpStreamWrite(Vec<u8>),
     DownStreamShutDown,
     UpStreamShutDown,
+    DownStreamHalfClose,
+    UpStreamHalfClose,
+    SessionHandleShutDown,
+    Inject(StreamSide, Vec<u8>),// Written through a SessionHandle, queued like a callback's write
+    StartTLSFailed(CloseReason),
+    StartTLSDone(StreamSide, String),// A side switched to TLS, with how it was set up
+    DownStreamStartTLS,// Stopped reading in the clear, waiting for the handshake data read so far
+    UpStreamStartTLS,
 }
 
 #[derive(Debug)]
 enum DataPipe {
     DataWrite(Vec<u8>),
+    HalfClose,
     Shutdown,
+    StartTLS(Vec<u8>),// Write the data in the clear, then run a TLS handshake on the stream
+    Handshake(Vec<u8>),// Handshake data that was read in the clear before the switch
+}
+
+#[derive(Clone)]
+struct DataPipeSender {
+    sender: Sender<DataPipe>,
+    pending: Arc<AtomicUsize>,
+}
+
+/// Byte counts shared by the master and stream threads of a session for backpressure.
+struct SessionBuffers {
+    ds_pending: Arc<AtomicUsize>,// Queued for writing to DownStream
+    us_pending: Arc<AtomicUsize>,// Queued for writing to UpStream
+    ds_unprocessed: AtomicUsize,// Read from DownStream, not yet handled by the master thread
+    us_unprocessed: AtomicUsize,// Read from UpStream, not yet handled by the master thread
+    ds_scheduled: AtomicUsize,// Delayed by a callback, not yet queued for writing to DownStream
+    us_scheduled: AtomicUsize,// Delayed by a callback, not yet queued for writing to UpStream
+    ds_framing: AtomicUsize,// Read from DownStream, waiting for the rest of its message
+    us_framing: AtomicUsize,// Read from UpStream, waiting for the rest of its message
+    ds_read_paused: AtomicBool,
+    us_read_paused: AtomicBool,
+    ds_write_closed: AtomicBool,// DownStream was half closed, nothing more is written to it
+    us_write_closed: AtomicBool,
+    high_watermark: usize,
+    low_watermark: usize,
+}
+
+#[derive(Copy, Clone, Debug)]
+enum StreamSide {
+    DownStream,
+    UpStream,
+}
+
+/// Writes the master thread holds back until they are due, kept in order per side.
+struct WriteSchedule {
+    ds_writes: VecDeque<(Instant, DataPipe)>,
+    us_writes: VecDeque<(Instant, DataPipe)>,
+    tls_started: bool,
+    buffers: Arc<SessionBuffers>,
 }
 
 enum DataStreamType {
     RAW(TcpStream),
-    TLS(SslStream<TcpStream>),
+    TLS(SslStream<TlsTransport>),
 }
 
 /// Specifies the upstream or downstream data type (TLS or RAW).
@@ -155,6 +418,19 @@ enum DataStreamType {
 pub enum TCPDataType {
     TLS,
     RAW,
+    AUTO,// Downstream only: TLS if the client starts with a ClientHello, RAW otherwise. Upstream takes it as RAW
+}
+
+/// What a client started with on a downstream_data_type of AUTO.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DetectedProtocol {
+    TLS,// A TLS ClientHello, accepted with tls_config if there is one
+    HTTP1,// An HTTP/1.x request line
+    HTTP2,// The HTTP/2 connection preface (h2c with prior knowledge)
+    SSH,
+    SOCKS4,
+    SOCKS5,
+    Unknown,// None of the above, or the client sent nothing within sniff_timeout
 }
 
 /// TLSConfig is used to specify TLS options.
@@ -169,6 +445,7 @@ pub enum TLSConfig {
 }
 
 /// Relay Config structure for passing into the SSLRelay::new() config parameter.
+/// Fields that are left out can be filled in with `..Default::default()`.
 #[derive(Clone)]
 pub struct RelayConfig {
     pub downstream_data_type: TCPDataType,
@@ -178,6 +455,198 @@ pub struct RelayConfig {
     pub remote_host: String,
     pub remote_port: String,
     pub tls_config: TLSConfig,
+    /// ALPN protocols offered to the client and to upstream on TLS legs, most preferred first (e.g. "h2", "http/1.1").
+    /// Empty leaves ALPN out of the handshakes. Behind a TLS downstream, upstream is only offered what the client
+    /// negotiated, and a session whose upstream settles on anything else ends with CloseReason::AlpnMismatch.
+    pub alpn_protocols: Vec<String>,
+    /// More upstream endpoints that share the traffic with remote_host:remote_port.
+    pub extra_remotes: Vec<RemoteEndpoint>,
+    /// How an upstream endpoint is picked for a new connection.
+    pub load_balancing: LoadBalancing,
+    /// Gives up on an upstream TCP connect after this long and tries the next endpoint.
+    /// None waits as long as the operating system does.
+    pub connect_timeout: Option<Duration>,
+    /// Consecutive failed connects after which an upstream endpoint is ejected.
+    pub max_fails: u32,
+    /// How long an ejected upstream endpoint is skipped before it is tried again.
+    pub fail_timeout: Duration,
+    /// Time limit for the TLS handshake on either leg.
+    pub handshake_timeout: Option<Duration>,
+    /// Ends the session when no data was received from either side for this long.
+    pub idle_timeout: Option<Duration>,
+    /// Ends the session once it has been open for this long, no matter the traffic.
+    pub max_session_lifetime: Option<Duration>,
+    /// When one side stops sending (TCP FIN or TLS close_notify) only shut down writing
+    /// towards the other side and keep relaying the opposite direction.
+    /// When false the whole session is torn down as soon as either side stops sending.
+    pub half_close: bool,
+    /// Bytes a direction may have read but not yet written to the other side before
+    /// reading from the sending side is paused.
+    pub high_watermark: usize,
+    /// A paused direction starts reading again once it has drained to this many bytes.
+    pub low_watermark: usize,
+    /// Ends the session when it holds more than this many bytes over both directions.
+    /// Callbacks that grow the data can push a session past its watermarks.
+    pub max_session_memory: Option<usize>,
+    /// Chunks that may wait for the non blocking callbacks of a session before nb_callback_overflow applies.
+    pub nb_callback_backlog: usize,
+    /// What to do with a chunk when the non blocking callback backlog is full.
+    pub nb_callback_overflow: OverflowPolicy,
+    /// How long a downstream_data_type of AUTO waits for the first bytes of a client.
+    /// Clients that send nothing in time (the server speaks first) are relayed as RAW.
+    pub sniff_timeout: Duration,
+    /// Addresses of the proxies whose PROXY protocol header is read, for a downstream_data_type of AUTO.
+    /// Empty trusts nobody, the header can be sent by any client that reaches the relay directly.
+    pub trusted_proxies: Vec<IpAddr>,
+    /// Writes the plaintext of every session to a pcapng file, None captures nothing.
+    /// One capture can be shared by several routes.
+    pub capture: Option<Arc<PcapngCapture>>,
+    /// Records the HTTP requests and responses of every session for a HAR log, None records nothing.
+    pub har: Option<Arc<har::HarRecorder>>,
+    /// Records every chunk of every session for replaying, None records nothing.
+    pub session_recorder: Option<Arc<replay::SessionRecorder>>,
+    /// Collects connection, traffic and callback statistics, None collects nothing.
+    /// One Metrics can be shared by several routes, they are told apart by their bind address.
+    pub metrics: Option<Arc<Metrics>>,
+    /// How data read from DownStream is cut into messages before it is passed to the callbacks.
+    pub downstream_framing: Framing,
+    /// How data read from UpStream is cut into messages before it is passed to the callbacks.
+    pub upstream_framing: Framing,
+    /// Longest incomplete message framing holds back, capped at high_watermark. Past it the direction
+    /// is no longer framed and the callbacks get its data as it was read.
+    pub max_frame_size: usize,
+}
+
+/// A pcapng file the plaintext of relayed sessions is written to as synthesized TCP/IP packets.
+/// Open it with Wireshark to look at decrypted traffic of TLS sessions.
+pub struct PcapngCapture {
+    writer: Mutex<Box<dyn Write + std::marker::Send>>,
+    connections: AtomicU64,
+}
+
+/// One session in a PcapngCapture, closed with FIN packets when dropped.
+struct CaptureConnection {
+    capture: Arc<PcapngCapture>,
+    client: SocketAddr,
+    server: SocketAddr,
+    client_seq: u32,
+    server_seq: u32,
+    // Goes on the next packet, how a leg was set up when it switched to TLS
+    comment: Option<String>,
+}
+
+/// Counters, gauges and histograms of a running relay, rendered in the Prometheus text format.
+pub struct Metrics {
+    state: Mutex<MetricsState>,
+    // Per route label, what is counted for every chunk
+    routes: Mutex<BTreeMap<String, Arc<RouteCounters>>>,
+}
+
+/// Metric name and rendered labels to value.
+#[derive(Clone)]
+struct MetricsState {
+    counters: BTreeMap<(&'static str, String), u64>,
+    gauges: BTreeMap<(&'static str, String), i64>,
+    histograms: BTreeMap<(&'static str, String), Histogram>,
+}
+
+#[derive(Clone)]
+struct Histogram {
+    // Per bucket, not cumulative
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+/// The counts of one route that change with every chunk, atomics so sessions never wait on each other.
+/// Folded into the rest when rendering.
+struct RouteCounters {
+    // Indexed by StreamSide
+    received: [AtomicU64; 2],
+    callbacks: [CallbackCounters; 2],
+}
+
+/// Duration histogram and returned variants of one blocking callback.
+struct CallbackCounters {
+    // Per bucket, not cumulative
+    buckets: Vec<AtomicU64>,
+    sum_nanos: AtomicU64,
+    count: AtomicU64,
+    // Indexed like the CallbackRet variants
+    returns: Vec<AtomicU64>,
+}
+
+/// The Metrics of one route, with its route label rendered once.
+#[derive(Clone)]
+struct SessionMetrics {
+    metrics: Arc<Metrics>,
+    route: String,
+    counters: Arc<RouteCounters>,
+}
+
+/// Keeps a connection counted as active.
+struct ActiveConnection {
+    metrics: SessionMetrics,
+}
+
+/// How long setting up the upstream leg of a session took.
+#[derive(Copy, Clone)]
+struct ConnectTimings {
+    /// The TCP connect and TLS handshake together.
+    connect: Duration,
+    tls_handshake: Option<Duration>,
+}
+
+/// What happens to a non blocking callback chunk when the session backlog is full.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    Block,// Wait for the callbacks to catch up, slowing down relaying
+    DropOldest,// Throw away the oldest waiting chunk
+    DropNewest,// Throw away the new chunk
+}
+
+/// Splits the data of one direction into messages, so every callback gets exactly one message.
+/// The relay keeps one Framer per direction per session.
+pub trait Framer: std::marker::Send {
+    /// Removes the first complete message from the front of buffer and returns it.
+    /// Returns None while buffer does not hold a complete message yet.
+    fn next_frame(&mut self, buffer: &mut Vec<u8>) -> Option<Vec<u8>>;
+}
+
+/// How a direction is framed into messages.
+/// Messages are passed to the callbacks whole, including delimiters and length prefixes.
+/// Length prefixes count the bytes after the prefix.
+#[derive(Clone)]
+pub enum Framing {
+    NONE,// Callbacks get the data as it was read
+    Lines,// Up to and including '\n'
+    FixedLength(usize),// Every message is this many bytes
+    LengthPrefixedU16BE,
+    LengthPrefixedU16LE,
+    LengthPrefixedU32BE,
+    LengthPrefixedU32LE,
+    Delimiter(Vec<u8>),// Up to and including the delimiter
+    TLSRecord,// One TLS record, header included
+    RESP,// One Redis RESP2/RESP3 value or inline command
+    MQTT,// One MQTT control packet
+    Custom(Arc<dyn Fn() -> Box<dyn Framer> + std::marker::Send + std::marker::Sync>),// Builds a Framer for every session
+}
+
+/// An upstream host and port.
+#[derive(Clone, Debug)]
+pub struct RemoteEndpoint {
+    pub host: String,
+    pub port: String,
+}
+
+/// Strategy for picking an upstream endpoint when a route has more than one.
+/// Whichever endpoint is picked first, the others are tried in turn if connecting fails.
+#[derive(Copy, Clone, Debug)]
+pub enum LoadBalancing {
+    RoundRobin,// Cycle through the endpoints
+    LeastConnections,// Endpoint with the fewest active sessions
+    Random,// Random endpoint
+    ClientIpHash,// Same client IP always starts with the same endpoint
 }
 
 /// CallbackRet for blocking callback functions
@@ -187,6 +656,34 @@ pub enum CallbackRet {
     Spoof(Vec<u8>),// Skip relaying and send data back
     Shutdown,// Shutdown TCP connection
     Freeze,// Dont send data (pretend as if stream never was recieved)
+    RelayAndSpoof(Vec<u8>, Vec<u8>),// Relay the first and send the second back
+    Split(Vec<Vec<u8>>, Duration),// Relay every chunk as its own write, pausing in between
+    Delay(Vec<u8>, Duration),// Relay data once the duration has passed
+    RelayAndHalfClose(Vec<u8>),// Relay data, then stop sending to that side
+    SpoofAndHalfClose(Vec<u8>),// Send data back, then stop sending to that side
+    RelayAndStartTLS(Vec<u8>, Vec<u8>),// Relay the first, then switch both sides to TLS (STARTTLS). The second is handshake data the callback already got
+}
+
+/// Why a TCP session ended. Passed to HandlerCallbacks::close_callback.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CloseReason {
+    DownStreamClosed,// DownStream closed or reset the connection
+    UpStreamClosed,// UpStream closed or reset the connection
+    CallbackShutdown,// A blocking callback returned CallbackRet::Shutdown
+    RelayShutdown,// RelayHandle::shutdown() was called
+    RelayError,// Internal relay communication failed
+    UpStreamConnectFailed,// No upstream endpoint accepted the connection
+    UpStreamConnectTimeout,// Connecting to upstream took longer than connect_timeout
+    DownStreamHandshakeFailed,// TLS handshake with the client failed
+    DownStreamHandshakeTimeout,// TLS handshake with the client took longer than handshake_timeout
+    UpStreamHandshakeFailed,// TLS handshake with upstream failed
+    UpStreamHandshakeTimeout,// TLS handshake with upstream took longer than handshake_timeout
+    IdleTimeout,// No data in either direction for idle_timeout
+    SessionLifetime,// Session was open longer than max_session_lifetime
+    MemoryLimit,// Session buffered more than max_session_memory
+    SessionHandleShutdown,// SessionHandle::shutdown() was called
+    InvalidProxyHeader,// The PROXY protocol header of the client did not parse
+    AlpnMismatch,// Upstream did not agree to the ALPN protocol the client negotiated
 }
 
 /// Callback functions a user may or may not implement.
@@ -195,46 +692,188 @@ pub trait HandlerCallbacks {
     fn ds_nb_callback(&self, _in_data: Vec<u8>){}
     fn us_b_callback(&mut self, _in_data: Vec<u8>) -> CallbackRet {CallbackRet::Relay(_in_data)}
     fn us_nb_callback(&self, _in_data: Vec<u8>){}
+    /// Called once both streams are connected, before any data is relayed.
+    /// The SessionHandle can be kept to write to either side at any time.
+    fn open_callback(&mut self, _session: SessionHandle){}
+    /// Called once when the session ends, including sessions that never reached the relaying stage.
+    fn close_callback(&mut self, _reason: CloseReason){}
+}
+
+/// Builds a fresh handler for every accepted connection.
+/// Every `HandlerCallbacks + Clone` type is a HandlerFactory that hands out clones of itself,
+/// so a plain handler can still be passed to SSLRelay::new().
+pub trait HandlerFactory {
+    type Handler: HandlerCallbacks + std::marker::Send + 'static;
+    fn create(&self, info: &ConnectionInfo) -> Self::Handler;
+    /// A second handler for the non blocking callbacks of the connection, so they never wait on the lock
+    /// the blocking callbacks hold. None runs them on the handler from create(), behind that same lock.
+    fn create_nb(&self, _info: &ConnectionInfo) -> Option<Self::Handler> {None}
+}
+
+/// Details about an accepted connection.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct ConnectionInfo {
+    /// Unique per SSLRelay instance, counting up from 0.
+    pub connection_id: u64,
+    /// Index of the route that accepted the connection, 0 being the one passed to SSLRelay::new().
+    pub route: usize,
+    pub client_addr: SocketAddr,
+    pub local_addr: Option<SocketAddr>,
+    /// What the client started with, for a downstream_data_type of AUTO.
+    pub protocol: Option<DetectedProtocol>,
+    /// Addresses from a PROXY protocol header the client started with, for a downstream_data_type of AUTO.
+    /// Only read from the RelayConfig::trusted_proxies.
+    pub proxy_client_addr: Option<SocketAddr>,
+    pub proxy_local_addr: Option<SocketAddr>,
+}
+
+/// Writes into a running session from any thread, outside of the blocking callbacks.
+/// Data sent through it is queued behind whatever the session already relays to that side, delayed writes included.
+#[derive(Clone)]
+pub struct SessionHandle {
+    ds_data_pipe_sender: DataPipeSender,
+    us_data_pipe_sender: DataPipeSender,
+    state_sender: Sender<FullDuplexTcpState>,
+    buffers: Arc<SessionBuffers>,
+}
+
+/// State shared by the handlers of every connection, such as counters or caches.
+/// Cloning a SharedState gives another reference to the same value.
+pub struct SharedState<T> {
+    inner: Arc<Mutex<T>>,
 }
 
 /// The main SSLRelay object.
 #[derive(Clone)]
 pub struct SSLRelay<H>
 where
-    H: HandlerCallbacks + std::marker::Sync + std::marker::Send + Clone + 'static,
+    H: HandlerFactory + std::marker::Sync + std::marker::Send + 'static,
+{
+    routes: Vec<RelayRoute<H>>,
+    worker_threads: Option<usize>,
+    shutdown: Arc<AtomicBool>,
+    next_connection_id: Arc<AtomicU64>,
+}
+
+/// Handle for controlling a running SSLRelay from another thread.
+#[derive(Clone)]
+pub struct RelayHandle {
+    shutdown: Arc<AtomicBool>,
+}
+
+/// One listener -> upstream pair served by an SSLRelay.
+#[derive(Clone)]
+struct RelayRoute<H>
+where
+    H: HandlerFactory + std::marker::Sync + std::marker::Send + 'static,
 {
     config: RelayConfig,
-    handlers: Option<InnerHandlers<H>>,
+    factory: Arc<H>,
 }
 
 #[allow(dead_code)]
 struct FullDuplexTcp<H>
 where
-    H: HandlerCallbacks + std::marker::Sync + std::marker::Send + Clone + 'static,
+    H: HandlerCallbacks + std::marker::Send + 'static,
 {
     remote_host: String,
     remote_port: String,
     ds_inner_m: Arc<Mutex<Option<DownStreamInner>>>,
     us_inner_m: Arc<Mutex<Option<UpStreamInner>>>,
     inner_handlers: InnerHandlers<H>,
+    // Whether DownStream was accepted without TLS, which AUTO decides per connection
+    ds_raw: bool,
+    capture: Option<CaptureConnection>,
+    har: Option<har::HarSession>,
+    recording: Option<replay::RecordedConnection>,
+    metrics: Option<SessionMetrics>,
+    config: Arc<RelayConfig>,
+    buffers: Arc<SessionBuffers>,
+    shutdown: Arc<AtomicBool>,
+    _upstream_lease: UpstreamLease,
 }
 
-#[derive(Clone)]
+/// The handler of one session, shared between the master thread and the non blocking callback thread.
 struct InnerHandlers<H>
 where
-    H: HandlerCallbacks + std::marker::Sync + std::marker::Send + Clone + 'static,
+    H: HandlerCallbacks + std::marker::Send + 'static,
+{
+    cb: Arc<Mutex<H>>,
+    // What the non blocking callbacks run on, cb itself when the factory gave no handler of their own
+    nb: Arc<Mutex<H>>,
+}
+
+struct UpstreamPool
+{
+    endpoints: Vec<UpstreamEndpointState>,
+    load_balancing: LoadBalancing,
+    connect_timeout: Option<Duration>,
+    max_fails: u32,
+    fail_timeout: Duration,
+    round_robin_next: AtomicUsize,
+    random_state: AtomicU64,
+}
+
+struct UpstreamEndpointState
+{
+    remote: RemoteEndpoint,
+    active_connections: AtomicUsize,
+    health: Mutex<UpstreamHealth>,
+}
+
+struct UpstreamHealth
+{
+    consecutive_fails: u32,
+    ejected_until: Option<Instant>,
+}
+
+/// Counts a session against its upstream endpoint until dropped.
+struct UpstreamLease
 {
-    cb: H
+    pool: Arc<UpstreamPool>,
+    index: usize,
+}
+
+/// Runs the non blocking callbacks of one session in order on a single thread.
+struct CallbackQueue
+{
+    shared: Arc<(Mutex<CallbackQueueState>, Condvar)>,
+}
+
+struct CallbackQueueState
+{
+    jobs: VecDeque<NbCallbackJob>,
+    backlog: usize,
+    overflow: OverflowPolicy,
+    closed: bool,
+}
+
+enum NbCallbackJob
+{
+    DownStream(Vec<u8>),// Data received from DownStream
+    UpStream(Vec<u8>),// Data received from UpStream
 }
 
 struct DownStreamInner
 {
     ds_stream: DataStreamType,
     internal_data_buffer: Vec<u8>,
+    half_close: bool,
+    buffers: Arc<SessionBuffers>,
+    // For a RAW stream that switches to TLS
+    tls_acceptor: Option<Arc<SslAcceptor>>,
+    handshake_timeout: Option<Duration>,
 }
 
 struct UpStreamInner
 {
     us_stream: DataStreamType,
-    internal_data_buffer: Vec<u8>
+    internal_data_buffer: Vec<u8>,
+    half_close: bool,
+    buffers: Arc<SessionBuffers>,
+    // For a RAW stream that switches to TLS
+    remote_host: String,
+    alpn_protocols: Vec<u8>,
+    handshake_timeout: Option<Duration>,
 }
\ No newline at end of file
@@ -0,0 +1,337 @@
+use crate::{
+    Metrics,
+    MetricsState,
+    SessionMetrics,
+    ActiveConnection,
+    Histogram,
+    RouteCounters,
+    CallbackCounters,
+    CallbackRet,
+    CloseReason,
+    RelayConfig,
+    StreamSide,
+    TcpListener,
+    TcpStream,
+    ToSocketAddrs,
+    BTreeMap,
+    Arc,
+    Mutex,
+    AtomicU64,
+    Ordering,
+    Duration,
+    Instant,
+    Read,
+    Write,
+    io,
+    thread,
+};
+
+// Upper bounds of the callback duration buckets in seconds
+const DURATION_BUCKETS: &[f64] = &[0.00001, 0.00005, 0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+// Every metric with its type and help text, in the order they are rendered
+const METRICS: &[(&str, &str, &str)] = &[
+    ("sslrelay_connections_total", "counter", "Connections accepted."),
+    ("sslrelay_connections_active", "gauge", "Connections accepted and not yet closed."),
+    ("sslrelay_received_bytes_total", "counter", "Bytes read from each side before the callbacks."),
+    ("sslrelay_tls_handshake_failures_total", "counter", "TLS handshakes that failed or timed out, STARTTLS included."),
+    ("sslrelay_upstream_connect_failures_total", "counter", "Upstream endpoints that could not be connected to."),
+    ("sslrelay_sessions_closed_total", "counter", "Sessions that ended, by close reason."),
+    ("sslrelay_callback_duration_seconds", "histogram", "Time spent in blocking callbacks."),
+    ("sslrelay_callback_returns_total", "counter", "CallbackRet variants returned by blocking callbacks."),
+];
+
+// Side and callback labels, indexed by side_index
+const SIDES: [(&str, &str); 2] = [("downstream", "ds_b_callback"), ("upstream", "us_b_callback")];
+
+// Every CallbackRet variant, indexed by variant_index
+const VARIANTS: &[&str] = &[
+    "Relay", "Spoof", "Shutdown", "Freeze", "RelayAndSpoof", "Split", "Delay", "RelayAndHalfClose", "SpoofAndHalfClose", "RelayAndStartTLS",
+];
+
+// A request line and headers larger than this are not read any further
+const MAX_REQUEST_HEAD: usize = 8 * 1024;
+// Time a scrape gets to send its request head, and for every write of the answer
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+impl Metrics {
+
+    pub fn new() -> Self {
+        Metrics {
+            state: Mutex::new(MetricsState {
+                counters: BTreeMap::new(),
+                gauges: BTreeMap::new(),
+                histograms: BTreeMap::new(),
+            }),
+            routes: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Everything collected so far in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+
+        let state = self.snapshot();
+        let mut text = String::new();
+
+        for (name, metric_type, help) in METRICS {
+            text.push_str(&format!("# HELP {} {}\n# TYPE {} {}\n", name, help, name, metric_type));
+
+            match *metric_type {
+                "counter" => for ((_, labels), value) in state.counters.range(range(name)) {
+                    text.push_str(&format!("{}{{{}}} {}\n", name, labels, value));
+                },
+                "gauge" => for ((_, labels), value) in state.gauges.range(range(name)) {
+                    text.push_str(&format!("{}{{{}}} {}\n", name, labels, value));
+                },
+                _ => for ((_, labels), histogram) in state.histograms.range(range(name)) {
+                    let mut cumulative = 0;
+                    for (bound, count) in DURATION_BUCKETS.iter().zip(&histogram.buckets) {
+                        cumulative += count;
+                        text.push_str(&format!("{}_bucket{{{},le=\"{}\"}} {}\n", name, labels, bound, cumulative));
+                    }
+                    text.push_str(&format!("{}_bucket{{{},le=\"+Inf\"}} {}\n", name, labels, histogram.count));
+                    text.push_str(&format!("{}_sum{{{}}} {}\n", name, labels, histogram.sum));
+                    text.push_str(&format!("{}_count{{{}}} {}\n", name, labels, histogram.count));
+                },
+            }
+        }
+        text
+    }
+
+    /// Serves render() over HTTP on a thread of its own, at /metrics and at /.
+    /// Every scrape is answered on a thread of its own, so a slow client does not hold up the others.
+    pub fn serve<A: ToSocketAddrs>(self: &Arc<Self>, address: A) -> io::Result<thread::JoinHandle<()>> {
+
+        let listener = TcpListener::bind(address)?;
+        let metrics = self.clone();
+
+        Ok(thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let metrics = metrics.clone();
+                        thread::spawn(move || {
+                            let _ = stream.set_write_timeout(Some(REQUEST_TIMEOUT));
+                            let _ = metrics.answer(stream);
+                        });
+                    },
+                    Err(e) => tracing::error!(error = %e, "metrics endpoint failed to accept"),
+                }
+            }
+        }))
+    }
+
+    fn answer(&self, mut stream: TcpStream) -> io::Result<()> {
+
+        // The whole request head has to arrive in time, not just every read of it
+        let deadline = Instant::now() + REQUEST_TIMEOUT;
+        let mut head = Vec::new();
+        let mut buffer = [0; 1024];
+        while !head.windows(4).any(|window| window == b"\r\n\r\n") && head.len() < MAX_REQUEST_HEAD {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            stream.set_read_timeout(Some(remaining))?;
+            match stream.read(&mut buffer)? {
+                0 => break,
+                length => head.extend_from_slice(&buffer[..length]),
+            }
+        }
+
+        let request_line = String::from_utf8_lossy(&head);
+        let mut parts = request_line.split_whitespace();
+        let (status, body) = match (parts.next(), parts.next()) {
+            (Some("GET"), Some(path)) if path == "/" || path == "/metrics" || path.starts_with("/metrics?") => ("200 OK", self.render()),
+            (Some("GET"), _) => ("404 Not Found", "Not Found\n".to_string()),
+            _ => ("405 Method Not Allowed", "Method Not Allowed\n".to_string()),
+        };
+
+        stream.write_all(format!(
+            "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            status, body.len(),
+        ).as_bytes())?;
+        stream.write_all(body.as_bytes())?;
+        stream.flush()
+    }
+
+    /// The collected values with the route counters folded in.
+    fn snapshot(&self) -> MetricsState {
+
+        let mut state = self.lock().clone();
+        let routes = match self.routes.lock() {
+            Ok(routes) => routes,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        for (route, counters) in routes.iter() {
+            for (index, (side, callback)) in SIDES.iter().enumerate() {
+                let received = counters.received[index].load(Ordering::Relaxed);
+                if received > 0 {
+                    state.counters.insert(("sslrelay_received_bytes_total", format!("{},side=\"{}\"", route, side)), received);
+                }
+
+                let callbacks = &counters.callbacks[index];
+                let count = callbacks.count.load(Ordering::Relaxed);
+                if count > 0 {
+                    state.histograms.insert(("sslrelay_callback_duration_seconds", format!("{},callback=\"{}\"", route, callback)), Histogram {
+                        buckets: callbacks.buckets.iter().map(|bucket| bucket.load(Ordering::Relaxed)).collect(),
+                        sum: callbacks.sum_nanos.load(Ordering::Relaxed) as f64 / 1e9,
+                        count,
+                    });
+                }
+                for (variant, returned) in VARIANTS.iter().zip(&callbacks.returns) {
+                    let returned = returned.load(Ordering::Relaxed);
+                    if returned > 0 {
+                        state.counters.insert(("sslrelay_callback_returns_total", format!("{},callback=\"{}\",variant=\"{}\"", route, callback, variant)), returned);
+                    }
+                }
+            }
+        }
+        state
+    }
+
+    /// The counters of route, shared by all its sessions.
+    fn route_counters(&self, route: &str) -> Arc<RouteCounters> {
+        let mut routes = match self.routes.lock() {
+            Ok(routes) => routes,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        routes.entry(route.to_string()).or_insert_with(|| Arc::new(RouteCounters {
+            received: Default::default(),
+            callbacks: [CallbackCounters::new(), CallbackCounters::new()],
+        })).clone()
+    }
+
+    fn add(&self, name: &'static str, labels: String, value: u64) {
+        *self.lock().counters.entry((name, labels)).or_insert(0) += value;
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, MetricsState> {
+        match self.state.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+impl Default for Metrics {
+
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SessionMetrics {
+
+    /// Counts for the route of config, None when it has no metrics.
+    pub fn new(config: &RelayConfig) -> Option<Self> {
+        config.metrics.as_ref().map(|metrics| {
+            let route = format!("route=\"{}\"", escape(&format!("{}:{}", config.bind_host, config.bind_port)));
+            SessionMetrics {
+                metrics: metrics.clone(),
+                counters: metrics.route_counters(&route),
+                route,
+            }
+        })
+    }
+
+    /// Counts an accepted connection as active until the returned guard is dropped.
+    pub fn connection_accepted(&self) -> ActiveConnection {
+        self.metrics.add("sslrelay_connections_total", self.route.clone(), 1);
+        self.gauge("sslrelay_connections_active", 1);
+        ActiveConnection {
+            metrics: self.clone(),
+        }
+    }
+
+    pub fn received(&self, from: StreamSide, length: usize) {
+        self.counters.received[side_index(from)].fetch_add(length as u64, Ordering::Relaxed);
+    }
+
+    pub fn handshake_failed(&self, reason: CloseReason) {
+        self.metrics.add("sslrelay_tls_handshake_failures_total", format!("{},reason=\"{:?}\"", self.route, reason), 1);
+    }
+
+    /// Counts a failed upstream connect, or a failed handshake when that is what it was.
+    pub fn upstream_failed(&self, upstream: &str, reason: CloseReason) {
+        match reason {
+            CloseReason::UpStreamHandshakeFailed | CloseReason::UpStreamHandshakeTimeout => self.handshake_failed(reason),
+            _ => self.metrics.add("sslrelay_upstream_connect_failures_total", format!("{},upstream=\"{}\",reason=\"{:?}\"", self.route, escape(upstream), reason), 1),
+        }
+    }
+
+    pub fn closed(&self, reason: CloseReason) {
+        self.metrics.add("sslrelay_sessions_closed_total", format!("{},reason=\"{:?}\"", self.route, reason), 1);
+    }
+
+    /// Records how long a blocking callback ran and what it returned.
+    pub fn callback(&self, from: StreamSide, duration: Duration, callback_ret: &CallbackRet) {
+
+        let callbacks = &self.counters.callbacks[side_index(from)];
+        let seconds = duration.as_secs_f64();
+
+        if let Some(bucket) = DURATION_BUCKETS.iter().position(|bound| seconds <= *bound) {
+            callbacks.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        callbacks.sum_nanos.fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+        callbacks.count.fetch_add(1, Ordering::Relaxed);
+        callbacks.returns[variant_index(callback_ret)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn gauge(&self, name: &'static str, change: i64) {
+        *self.metrics.lock().gauges.entry((name, self.route.clone())).or_insert(0) += change;
+    }
+}
+
+impl CallbackCounters {
+
+    fn new() -> Self {
+        CallbackCounters {
+            buckets: DURATION_BUCKETS.iter().map(|_| AtomicU64::new(0)).collect(),
+            sum_nanos: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+            returns: VARIANTS.iter().map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+}
+
+impl Drop for ActiveConnection {
+
+    fn drop(&mut self) {
+        self.metrics.gauge("sslrelay_connections_active", -1);
+    }
+}
+
+fn side_index(side: StreamSide) -> usize {
+    match side {
+        StreamSide::DownStream => 0,
+        StreamSide::UpStream => 1,
+    }
+}
+
+/// Position of the variant in VARIANTS.
+fn variant_index(callback_ret: &CallbackRet) -> usize {
+    match callback_ret {
+        CallbackRet::Relay(_) => 0,
+        CallbackRet::Spoof(_) => 1,
+        CallbackRet::Shutdown => 2,
+        CallbackRet::Freeze => 3,
+        CallbackRet::RelayAndSpoof(_, _) => 4,
+        CallbackRet::Split(_, _) => 5,
+        CallbackRet::Delay(_, _) => 6,
+        CallbackRet::RelayAndHalfClose(_) => 7,
+        CallbackRet::SpoofAndHalfClose(_) => 8,
+        CallbackRet::RelayAndStartTLS(_, _) => 9,
+    }
+}
+
+/// Every key of one metric, whatever its labels.
+fn range(name: &'static str) -> std::ops::RangeInclusive<(&'static str, String)> {
+    (name, String::new())..=(name, "\u{10ffff}".to_string())
+}
+
+/// A label value with backslashes, quotes and newlines escaped.
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
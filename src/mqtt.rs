@@ -0,0 +1,1322 @@
+//! MQTT aware callbacks.
+//!
+//! MqttRelay wraps an MqttHandler and implements HandlerCallbacks for it. It splits both legs into
+//! MQTT 3.1, 3.1.1 and 5.0 control packets, hands every packet to the handler and writes it out again.
+//! The version is the one the client asks for in its CONNECT packet.
+//! ```ignore
+//! use sslrelay::mqtt::{MqttRelay, MqttHandler, MqttRet, MqttPacket, MqttPublish, MqttInjector};
+//!
+//! struct Handler;
+//!
+//! impl MqttHandler for Handler {
+//!     fn on_client_packet(&mut self, packet: &mut MqttPacket, inject: &mut MqttInjector) -> MqttRet {
+//!         match packet {
+//!             MqttPacket::Publish(publish) if publish.topic.starts_with("firmware/") => MqttRet::Drop,
+//!             MqttPacket::Publish(publish) if publish.topic == "sensors/temperature" => {
+//!                 publish.payload = b"21.5".to_vec();
+//!                 inject.to_broker(MqttPacket::Publish(MqttPublish::new("audit/temperature", b"rewritten".to_vec())));
+//!                 MqttRet::Relay
+//!             },
+//!             _ => MqttRet::Relay,
+//!         }
+//!     }
+//! }
+//!
+//! let mut relay = sslrelay::SSLRelay::new(MqttRelay::new(Handler), config);
+//! ```
+//! The relay keeps the QoS 1 and 2 flows of both sides intact: publishes the handler drops are acknowledged
+//! to their sender by the relay, injected publishes and subscriptions get packet ids of their own and
+//! the acks that answer them never reach the other side. Topic aliases are taken out of the session so
+//! every publish carries its topic. Data that is not MQTT is relayed untouched from there on.
+//! Framing::MQTT frames raw callbacks one control packet at a time.
+
+use crate::{
+    HandlerCallbacks,
+    CallbackRet,
+    CloseReason,
+    SessionHandle,
+    HashMap,
+    HashSet,
+    http::callback_ret,
+};
+
+/// An MQTT control packet. Properties are only sent with MQTT 5.0 and are empty before it.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MqttPacket {
+    Connect(MqttConnect),
+    ConnAck{session_present: bool, reason_code: u8, properties: Vec<MqttProperty>},// reason_code is the return code before 5.0
+    Publish(MqttPublish),
+    PubAck(MqttAck),
+    PubRec(MqttAck),
+    PubRel(MqttAck),
+    PubComp(MqttAck),
+    Subscribe{packet_id: u16, properties: Vec<MqttProperty>, subscriptions: Vec<(String, u8)>},// Topic filters with their options, QoS in the low two bits
+    SubAck{packet_id: u16, properties: Vec<MqttProperty>, reason_codes: Vec<u8>},
+    Unsubscribe{packet_id: u16, properties: Vec<MqttProperty>, topics: Vec<String>},
+    UnsubAck{packet_id: u16, properties: Vec<MqttProperty>, reason_codes: Vec<u8>},// No reason codes before 5.0
+    PingReq,
+    PingResp,
+    Disconnect{reason_code: u8, properties: Vec<MqttProperty>},
+    Auth{reason_code: u8, properties: Vec<MqttProperty>},// 5.0 only
+}
+
+/// The CONNECT packet of the client.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MqttConnect {
+    pub protocol_name: String,// "MQTT", or "MQIsdp" for 3.1
+    pub protocol_level: u8,// 3 for 3.1, 4 for 3.1.1, 5 for 5.0
+    pub clean_start: bool,
+    pub keep_alive: u16,
+    pub properties: Vec<MqttProperty>,
+    pub client_id: String,
+    pub will: Option<MqttWill>,
+    pub username: Option<String>,
+    pub password: Option<Vec<u8>>,
+}
+
+/// The will message of a CONNECT packet.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MqttWill {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub qos: u8,
+    pub retain: bool,
+    pub properties: Vec<MqttProperty>,
+}
+
+/// A PUBLISH packet.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MqttPublish {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub qos: u8,
+    pub retain: bool,
+    pub dup: bool,
+    pub packet_id: Option<u16>,// Set for QoS 1 and 2, assigned by the relay for injected publishes
+    pub properties: Vec<MqttProperty>,
+}
+
+/// PUBACK, PUBREC, PUBREL and PUBCOMP.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MqttAck {
+    pub packet_id: u16,
+    pub reason_code: u8,// Always 0 before 5.0
+    pub properties: Vec<MqttProperty>,
+}
+
+/// An MQTT 5.0 property.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MqttProperty {
+    PayloadFormatIndicator(u8),// 0x01
+    MessageExpiryInterval(u32),// 0x02
+    ContentType(String),// 0x03
+    ResponseTopic(String),// 0x08
+    CorrelationData(Vec<u8>),// 0x09
+    SubscriptionIdentifier(u32),// 0x0B
+    SessionExpiryInterval(u32),// 0x11
+    AssignedClientIdentifier(String),// 0x12
+    ServerKeepAlive(u16),// 0x13
+    AuthenticationMethod(String),// 0x15
+    AuthenticationData(Vec<u8>),// 0x16
+    RequestProblemInformation(u8),// 0x17
+    WillDelayInterval(u32),// 0x18
+    RequestResponseInformation(u8),// 0x19
+    ResponseInformation(String),// 0x1A
+    ServerReference(String),// 0x1C
+    ReasonString(String),// 0x1F
+    ReceiveMaximum(u16),// 0x21
+    TopicAliasMaximum(u16),// 0x22
+    TopicAlias(u16),// 0x23
+    MaximumQoS(u8),// 0x24
+    RetainAvailable(u8),// 0x25
+    UserProperty(String, String),// 0x26
+    MaximumPacketSize(u32),// 0x27
+    WildcardSubscriptionAvailable(u8),// 0x28
+    SubscriptionIdentifierAvailable(u8),// 0x29
+    SharedSubscriptionAvailable(u8),// 0x2A
+}
+
+/// What MqttRelay does with a packet after the handler has seen it.
+#[derive(Debug)]
+pub enum MqttRet {
+    Relay,// Relay the (possibly modified) packet
+    Drop,// Dont relay the packet. Dropped QoS 1 and 2 publishes are acknowledged to their sender
+    Shutdown,// Shutdown TCP connection
+}
+
+/// Callbacks for MQTT traffic, used through MqttRelay.
+pub trait MqttHandler {
+    fn on_client_packet(&mut self, _packet: &mut MqttPacket, _inject: &mut MqttInjector) -> MqttRet {MqttRet::Relay}
+    fn on_broker_packet(&mut self, _packet: &mut MqttPacket, _inject: &mut MqttInjector) -> MqttRet {MqttRet::Relay}
+    fn open_callback(&mut self, _session: SessionHandle){}
+    fn close_callback(&mut self, _reason: CloseReason){}
+}
+
+/// Collects packets the handler wants to send in addition to the one it was given.
+/// They go out right after that packet. Publishes with QoS 1 or 2, SUBSCRIBE and UNSUBSCRIBE get a packet
+/// id from the relay, and the acks that answer them are handled by the relay.
+pub struct MqttInjector {
+    to_client: Vec<MqttPacket>,
+    to_broker: Vec<MqttPacket>,
+}
+
+/// Turns an MqttHandler into HandlerCallbacks.
+pub struct MqttRelay<T: MqttHandler> {
+    handler: T,
+    client: Side,
+    broker: Side,
+    // Protocol level of the CONNECT packet, nothing is parsed before it
+    version: Option<u8>,
+}
+
+/// What the relay keeps for one leg.
+#[derive(Default)]
+struct Side {
+    buffer: Vec<u8>,
+    passthrough: bool,
+    // Flows started towards this side, by the packet ids it sees
+    flows: PacketIds,
+    // QoS 2 publishes of this side that were dropped and acknowledged by the relay, waiting for their PUBREL
+    dropped: HashSet<u16>,
+    // Topic aliases this side set up
+    aliases: HashMap<u16, String>,
+}
+
+/// Packet ids of the flows (QoS 1 and 2 publishes, subscriptions) started towards one side.
+/// A flow of the other side keeps its packet id unless the relay already uses that id for a flow of its own.
+#[derive(Default)]
+struct PacketIds {
+    // Id sent -> the id the other side gave the flow, None for flows of the relay
+    flows: HashMap<u16, Option<u16>>,
+    // Id the other side gave a flow -> the id it was sent with, where they differ
+    renumbered: HashMap<u16, u16>,
+    next: u16,
+}
+
+pub(crate) enum Parsed<T> {
+    Complete(T, usize),// The value and how many bytes of the buffer it took
+    Incomplete,
+    Invalid,
+}
+
+const CONNECT: u8 = 1;
+const CONNACK: u8 = 2;
+const PUBLISH: u8 = 3;
+const PUBACK: u8 = 4;
+const PUBREC: u8 = 5;
+const PUBREL: u8 = 6;
+const PUBCOMP: u8 = 7;
+const SUBSCRIBE: u8 = 8;
+const SUBACK: u8 = 9;
+const UNSUBSCRIBE: u8 = 10;
+const UNSUBACK: u8 = 11;
+const PINGREQ: u8 = 12;
+const PINGRESP: u8 = 13;
+const DISCONNECT: u8 = 14;
+const AUTH: u8 = 15;
+
+impl<T: MqttHandler> MqttRelay<T> {
+
+    pub fn new(handler: T) -> Self {
+        MqttRelay {
+            handler,
+            client: Side::default(),
+            broker: Side::default(),
+            version: None,
+        }
+    }
+
+    /// Runs the packets read from one side through the handler.
+    /// Returns the bytes for the other side and the bytes for the side that sent them.
+    fn process(&mut self, from_client: bool, in_data: Vec<u8>) -> Option<(Vec<u8>, Vec<u8>)> {
+
+        let MqttRelay { handler, client, broker, version } = self;
+        let (from, to) = if from_client { (client, broker) } else { (broker, client) };
+
+        from.buffer.extend(in_data);
+
+        let mut forward = Vec::new();
+        let mut back = Vec::new();
+
+        loop {
+            let mut packet = match parse_packet(&from.buffer, *version) {
+                Parsed::Complete(packet, length) => {
+                    from.buffer.drain(..length);
+                    packet
+                },
+                Parsed::Incomplete => break,
+                Parsed::Invalid => {
+                    from.passthrough = true;
+                    forward.append(&mut from.buffer);
+                    break;
+                },
+            };
+
+            let is_pubrec = matches!(&packet, MqttPacket::PubRec(_));
+
+            match &mut packet {
+                MqttPacket::Connect(connect) => {
+                    *version = Some(connect.protocol_level);
+                    // Topic aliases are kept out of the session
+                    connect.properties.retain(|property| !matches!(property, MqttProperty::TopicAliasMaximum(_)));
+                },
+                MqttPacket::ConnAck{properties, ..} => {
+                    properties.retain(|property| !matches!(property, MqttProperty::TopicAliasMaximum(_)));
+                },
+                MqttPacket::Publish(publish) => {
+                    if let Some(alias) = publish.topic_alias() {
+                        if publish.topic.is_empty() {
+                            publish.topic = from.aliases.get(&alias).cloned().unwrap_or_default();
+                        } else {
+                            from.aliases.insert(alias, publish.topic.clone());
+                        }
+                        publish.properties.retain(|property| !matches!(property, MqttProperty::TopicAlias(_)));
+                    }
+                },
+                // Acks of flows started towards this side
+                MqttPacket::PubAck(ack) | MqttPacket::PubRec(ack) | MqttPacket::PubComp(ack) => {
+                    // A PUBREC that accepts the publish leaves the flow open for the release
+                    let released = is_pubrec && ack.reason_code < 0x80;
+                    match from.flows.finish(ack.packet_id, !released) {
+                        Some(packet_id) => ack.packet_id = packet_id,
+                        None => {
+                            // A publish of the relay, the release is the relay's to send too
+                            if released {
+                                MqttPacket::PubRel(MqttAck::new(ack.packet_id)).write(*version, &mut back);
+                            }
+                            continue;
+                        },
+                    }
+                },
+                MqttPacket::SubAck{packet_id, ..} | MqttPacket::UnsubAck{packet_id, ..} => {
+                    match from.flows.finish(*packet_id, true) {
+                        Some(id) => *packet_id = id,
+                        None => continue,
+                    }
+                },
+                // The release of a publish that was dropped
+                MqttPacket::PubRel(ack) if from.dropped.remove(&ack.packet_id) => {
+                    MqttPacket::PubComp(MqttAck::new(ack.packet_id)).write(*version, &mut back);
+                    continue;
+                },
+                _ => {},
+            }
+
+            let sent_qos = match &packet {
+                MqttPacket::Publish(publish) => publish.qos,
+                _ => 0,
+            };
+            let sent_packet_id = match &packet {
+                MqttPacket::Publish(publish) => publish.packet_id,
+                _ => None,
+            };
+
+            let mut injector = MqttInjector {
+                to_client: Vec::new(),
+                to_broker: Vec::new(),
+            };
+
+            let ret = if from_client {
+                handler.on_client_packet(&mut packet, &mut injector)
+            } else {
+                handler.on_broker_packet(&mut packet, &mut injector)
+            };
+
+            // With another QoS a publish is no longer the sender's flow
+            let requalified = matches!(&packet, MqttPacket::Publish(publish) if publish.qos != sent_qos);
+
+            match ret {
+                MqttRet::Relay if requalified => {
+                    if let Some(ack) = from.acknowledge(sent_qos, sent_packet_id) {
+                        ack.write(*version, &mut back);
+                    }
+                    to.flows.inject(&mut packet, *version, &mut forward);
+                },
+                MqttRet::Relay => match &mut packet {
+                    MqttPacket::Publish(MqttPublish{packet_id: Some(packet_id), ..})
+                    | MqttPacket::PubRel(MqttAck{packet_id, ..})
+                    | MqttPacket::Subscribe{packet_id, ..}
+                    | MqttPacket::Unsubscribe{packet_id, ..} => {
+                        *packet_id = to.flows.forward(*packet_id);
+                        packet.write(*version, &mut forward);
+                    },
+                    _ => packet.write(*version, &mut forward),
+                },
+                MqttRet::Drop => {
+                    if let Some(ack) = from.acknowledge(sent_qos, sent_packet_id) {
+                        ack.write(*version, &mut back);
+                    }
+                },
+                MqttRet::Shutdown => return None,
+            }
+
+            let (to_other, to_sender) = if from_client {
+                (injector.to_broker, injector.to_client)
+            } else {
+                (injector.to_client, injector.to_broker)
+            };
+            for mut packet in to_other {
+                to.flows.inject(&mut packet, *version, &mut forward);
+            }
+            for mut packet in to_sender {
+                from.flows.inject(&mut packet, *version, &mut back);
+            }
+        }
+
+        Some((forward, back))
+    }
+}
+
+impl<T: MqttHandler + Clone> Clone for MqttRelay<T> {
+
+    /// Clones the handler into a relay that has not seen any traffic.
+    fn clone(&self) -> Self {
+        MqttRelay::new(self.handler.clone())
+    }
+}
+
+impl<T: MqttHandler> HandlerCallbacks for MqttRelay<T> {
+
+    fn ds_b_callback(&mut self, in_data: Vec<u8>) -> CallbackRet {
+
+        if self.client.passthrough {
+            return CallbackRet::Relay(in_data);
+        }
+
+        match self.process(true, in_data) {
+            Some((relay_data, spoof_data)) => callback_ret(relay_data, spoof_data),
+            None => CallbackRet::Shutdown,
+        }
+    }
+
+    fn us_b_callback(&mut self, in_data: Vec<u8>) -> CallbackRet {
+
+        if self.broker.passthrough {
+            return CallbackRet::Relay(in_data);
+        }
+
+        match self.process(false, in_data) {
+            Some((relay_data, spoof_data)) => callback_ret(relay_data, spoof_data),
+            None => CallbackRet::Shutdown,
+        }
+    }
+
+    fn open_callback(&mut self, session: SessionHandle) {
+        self.handler.open_callback(session);
+    }
+
+    fn close_callback(&mut self, reason: CloseReason) {
+        self.handler.close_callback(reason);
+    }
+}
+
+impl MqttInjector {
+    /// Sends a packet to the client.
+    pub fn to_client(&mut self, packet: MqttPacket) {
+        self.to_client.push(packet);
+    }
+    /// Sends a packet to the broker.
+    pub fn to_broker(&mut self, packet: MqttPacket) {
+        self.to_broker.push(packet);
+    }
+}
+
+impl MqttPublish {
+
+    /// A QoS 0 publish.
+    pub fn new(topic: &str, payload: Vec<u8>) -> Self {
+        MqttPublish {
+            topic: topic.to_string(),
+            payload,
+            qos: 0,
+            retain: false,
+            dup: false,
+            packet_id: None,
+            properties: Vec::new(),
+        }
+    }
+
+    fn topic_alias(&self) -> Option<u16> {
+        self.properties.iter().find_map(|property| match property {
+            MqttProperty::TopicAlias(alias) => Some(*alias),
+            _ => None,
+        })
+    }
+}
+
+impl MqttAck {
+
+    pub fn new(packet_id: u16) -> Self {
+        MqttAck {
+            packet_id,
+            reason_code: 0,
+            properties: Vec::new(),
+        }
+    }
+}
+
+impl Side {
+
+    /// The ack the sender of a publish waits for, when the relay does not pass the publish on.
+    fn acknowledge(&mut self, qos: u8, packet_id: Option<u16>) -> Option<MqttPacket> {
+        match (qos, packet_id) {
+            (1, Some(packet_id)) => Some(MqttPacket::PubAck(MqttAck::new(packet_id))),
+            (2, Some(packet_id)) => {
+                self.dropped.insert(packet_id);
+                Some(MqttPacket::PubRec(MqttAck::new(packet_id)))
+            },
+            _ => None,
+        }
+    }
+}
+
+impl PacketIds {
+
+    /// The packet id a flow of the other side is sent with, starting the flow if it is new.
+    fn forward(&mut self, packet_id: u16) -> u16 {
+
+        if let Some(sent) = self.renumbered.get(&packet_id) {
+            return *sent;
+        }
+        match self.flows.get(&packet_id) {
+            Some(Some(owner)) if *owner == packet_id => packet_id,
+            None => {
+                self.flows.insert(packet_id, Some(packet_id));
+                packet_id
+            },
+            _ => match self.start(Some(packet_id)) {
+                Some(sent) => {
+                    self.renumbered.insert(packet_id, sent);
+                    sent
+                },
+                None => packet_id,
+            },
+        }
+    }
+
+    /// Finds the flow an ack answers. Returns the packet id of the other side's flow,
+    /// or None if the flow is the relay's.
+    fn finish(&mut self, packet_id: u16, done: bool) -> Option<u16> {
+
+        let owner = match self.flows.get(&packet_id) {
+            Some(owner) => *owner,
+            None => return Some(packet_id),
+        };
+        if done {
+            self.flows.remove(&packet_id);
+            if let Some(owner) = owner {
+                self.renumbered.remove(&owner);
+            }
+        }
+        owner
+    }
+
+    /// Writes a packet of the relay, with a packet id of its own if it starts a flow.
+    fn inject(&mut self, packet: &mut MqttPacket, version: Option<u8>, out: &mut Vec<u8>) {
+
+        let packet_id = match packet {
+            MqttPacket::Publish(publish) if publish.qos > 0 => publish.packet_id.get_or_insert(0),
+            MqttPacket::Subscribe{packet_id, ..} | MqttPacket::Unsubscribe{packet_id, ..} => packet_id,
+            _ => {
+                packet.write(version, out);
+                return;
+            },
+        };
+        // Every packet id is in use, nothing could tell the acks apart
+        match self.start(None) {
+            Some(id) => *packet_id = id,
+            None => return,
+        }
+        packet.write(version, out);
+    }
+
+    /// A packet id no flow uses yet.
+    fn start(&mut self, owner: Option<u16>) -> Option<u16> {
+        for _ in 0..u16::MAX {
+            self.next = self.next.checked_add(1).unwrap_or(1);
+            if !self.flows.contains_key(&self.next) {
+                self.flows.insert(self.next, owner);
+                return Some(self.next);
+            }
+        }
+        None
+    }
+}
+
+impl MqttPacket {
+
+    /// The packet as sent with the given protocol level.
+    /// For packets sent with a SessionHandle, which the relay does not see, so they should not carry packet ids.
+    pub fn to_bytes(&self, protocol_level: u8) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.write(Some(protocol_level), &mut out);
+        out
+    }
+
+    fn write(&self, version: Option<u8>, out: &mut Vec<u8>) {
+
+        let v5 = version == Some(5);
+        let mut body = Vec::new();
+
+        let first_byte = match self {
+            MqttPacket::Connect(connect) => {
+                write_string(&mut body, &connect.protocol_name);
+                body.push(connect.protocol_level);
+                let mut flags = 0;
+                if connect.clean_start { flags |= 0x02; }
+                if let Some(will) = &connect.will {
+                    flags |= 0x04 | (will.qos & 0x03) << 3;
+                    if will.retain { flags |= 0x20; }
+                }
+                if connect.password.is_some() { flags |= 0x40; }
+                if connect.username.is_some() { flags |= 0x80; }
+                body.push(flags);
+                body.extend_from_slice(&connect.keep_alive.to_be_bytes());
+                // The protocol level of the packet itself decides, there may not be a session yet
+                let v5 = connect.protocol_level == 5;
+                if v5 {
+                    write_properties(&mut body, &connect.properties);
+                }
+                write_string(&mut body, &connect.client_id);
+                if let Some(will) = &connect.will {
+                    if v5 {
+                        write_properties(&mut body, &will.properties);
+                    }
+                    write_string(&mut body, &will.topic);
+                    write_binary(&mut body, &will.payload);
+                }
+                if let Some(username) = &connect.username {
+                    write_string(&mut body, username);
+                }
+                if let Some(password) = &connect.password {
+                    write_binary(&mut body, password);
+                }
+                CONNECT << 4
+            },
+            MqttPacket::ConnAck{session_present, reason_code, properties} => {
+                body.push(*session_present as u8);
+                body.push(*reason_code);
+                if v5 {
+                    write_properties(&mut body, properties);
+                }
+                CONNACK << 4
+            },
+            MqttPacket::Publish(publish) => {
+                write_string(&mut body, &publish.topic);
+                if publish.qos > 0 {
+                    body.extend_from_slice(&publish.packet_id.unwrap_or(0).to_be_bytes());
+                }
+                if v5 {
+                    write_properties(&mut body, &publish.properties);
+                }
+                body.extend_from_slice(&publish.payload);
+                PUBLISH << 4 | (publish.dup as u8) << 3 | (publish.qos & 0x03) << 1 | publish.retain as u8
+            },
+            MqttPacket::PubAck(ack) => {
+                write_ack(&mut body, ack, v5);
+                PUBACK << 4
+            },
+            MqttPacket::PubRec(ack) => {
+                write_ack(&mut body, ack, v5);
+                PUBREC << 4
+            },
+            MqttPacket::PubRel(ack) => {
+                write_ack(&mut body, ack, v5);
+                PUBREL << 4 | 0x02
+            },
+            MqttPacket::PubComp(ack) => {
+                write_ack(&mut body, ack, v5);
+                PUBCOMP << 4
+            },
+            MqttPacket::Subscribe{packet_id, properties, subscriptions} => {
+                body.extend_from_slice(&packet_id.to_be_bytes());
+                if v5 {
+                    write_properties(&mut body, properties);
+                }
+                for (filter, options) in subscriptions {
+                    write_string(&mut body, filter);
+                    body.push(*options);
+                }
+                SUBSCRIBE << 4 | 0x02
+            },
+            MqttPacket::SubAck{packet_id, properties, reason_codes} => {
+                body.extend_from_slice(&packet_id.to_be_bytes());
+                if v5 {
+                    write_properties(&mut body, properties);
+                }
+                body.extend_from_slice(reason_codes);
+                SUBACK << 4
+            },
+            MqttPacket::Unsubscribe{packet_id, properties, topics} => {
+                body.extend_from_slice(&packet_id.to_be_bytes());
+                if v5 {
+                    write_properties(&mut body, properties);
+                }
+                for topic in topics {
+                    write_string(&mut body, topic);
+                }
+                UNSUBSCRIBE << 4 | 0x02
+            },
+            MqttPacket::UnsubAck{packet_id, properties, reason_codes} => {
+                body.extend_from_slice(&packet_id.to_be_bytes());
+                if v5 {
+                    write_properties(&mut body, properties);
+                    body.extend_from_slice(reason_codes);
+                }
+                UNSUBACK << 4
+            },
+            MqttPacket::PingReq => PINGREQ << 4,
+            MqttPacket::PingResp => PINGRESP << 4,
+            MqttPacket::Disconnect{reason_code, properties} => {
+                if v5 {
+                    write_reason(&mut body, *reason_code, properties);
+                }
+                DISCONNECT << 4
+            },
+            MqttPacket::Auth{reason_code, properties} => {
+                write_reason(&mut body, *reason_code, properties);
+                AUTH << 4
+            },
+        };
+
+        out.push(first_byte);
+        write_variable_int(out, body.len() as u32);
+        out.extend(body);
+    }
+}
+
+impl MqttProperty {
+
+    fn write(&self, out: &mut Vec<u8>) {
+        match self {
+            MqttProperty::PayloadFormatIndicator(value) => out.extend_from_slice(&[0x01, *value]),
+            MqttProperty::MessageExpiryInterval(value) => write_u32_property(out, 0x02, *value),
+            MqttProperty::ContentType(value) => write_string_property(out, 0x03, value),
+            MqttProperty::ResponseTopic(value) => write_string_property(out, 0x08, value),
+            MqttProperty::CorrelationData(value) => {
+                out.push(0x09);
+                write_binary(out, value);
+            },
+            MqttProperty::SubscriptionIdentifier(value) => {
+                out.push(0x0b);
+                write_variable_int(out, *value);
+            },
+            MqttProperty::SessionExpiryInterval(value) => write_u32_property(out, 0x11, *value),
+            MqttProperty::AssignedClientIdentifier(value) => write_string_property(out, 0x12, value),
+            MqttProperty::ServerKeepAlive(value) => write_u16_property(out, 0x13, *value),
+            MqttProperty::AuthenticationMethod(value) => write_string_property(out, 0x15, value),
+            MqttProperty::AuthenticationData(value) => {
+                out.push(0x16);
+                write_binary(out, value);
+            },
+            MqttProperty::RequestProblemInformation(value) => out.extend_from_slice(&[0x17, *value]),
+            MqttProperty::WillDelayInterval(value) => write_u32_property(out, 0x18, *value),
+            MqttProperty::RequestResponseInformation(value) => out.extend_from_slice(&[0x19, *value]),
+            MqttProperty::ResponseInformation(value) => write_string_property(out, 0x1a, value),
+            MqttProperty::ServerReference(value) => write_string_property(out, 0x1c, value),
+            MqttProperty::ReasonString(value) => write_string_property(out, 0x1f, value),
+            MqttProperty::ReceiveMaximum(value) => write_u16_property(out, 0x21, *value),
+            MqttProperty::TopicAliasMaximum(value) => write_u16_property(out, 0x22, *value),
+            MqttProperty::TopicAlias(value) => write_u16_property(out, 0x23, *value),
+            MqttProperty::MaximumQoS(value) => out.extend_from_slice(&[0x24, *value]),
+            MqttProperty::RetainAvailable(value) => out.extend_from_slice(&[0x25, *value]),
+            MqttProperty::UserProperty(name, value) => {
+                out.push(0x26);
+                write_string(out, name);
+                write_string(out, value);
+            },
+            MqttProperty::MaximumPacketSize(value) => write_u32_property(out, 0x27, *value),
+            MqttProperty::WildcardSubscriptionAvailable(value) => out.extend_from_slice(&[0x28, *value]),
+            MqttProperty::SubscriptionIdentifierAvailable(value) => out.extend_from_slice(&[0x29, *value]),
+            MqttProperty::SharedSubscriptionAvailable(value) => out.extend_from_slice(&[0x2a, *value]),
+        }
+    }
+}
+
+/// Length of the control packet at the front of buffer, for Framing::MQTT.
+pub(crate) fn packet_length(buffer: &[u8]) -> Parsed<()> {
+    match fixed_header(buffer) {
+        Parsed::Complete((header, remaining), _) if buffer.len() >= header + remaining => Parsed::Complete((), header + remaining),
+        Parsed::Complete(..) | Parsed::Incomplete => Parsed::Incomplete,
+        Parsed::Invalid => Parsed::Invalid,
+    }
+}
+
+/// Length of the fixed header and the remaining length it announces.
+fn fixed_header(buffer: &[u8]) -> Parsed<(usize, usize)> {
+
+    let mut remaining = 0;
+    // The remaining length takes one to four bytes after the packet type
+    for (index, byte) in buffer.iter().skip(1).take(4).enumerate() {
+        remaining |= ((byte & 0x7f) as usize) << (7 * index);
+        if byte & 0x80 == 0 {
+            return Parsed::Complete((index + 2, remaining), 0);
+        }
+    }
+    if buffer.len() >= 5 {
+        Parsed::Invalid
+    } else {
+        Parsed::Incomplete
+    }
+}
+
+/// Parses the control packet at the front of buffer. Only CONNECT parses before the protocol level is known.
+fn parse_packet(buffer: &[u8], version: Option<u8>) -> Parsed<MqttPacket> {
+
+    let (header, remaining) = match fixed_header(buffer) {
+        Parsed::Complete(lengths, _) => lengths,
+        Parsed::Incomplete => return Parsed::Incomplete,
+        Parsed::Invalid => return Parsed::Invalid,
+    };
+    if buffer.len() < header + remaining {
+        return Parsed::Incomplete;
+    }
+
+    let (kind, flags) = (buffer[0] >> 4, buffer[0] & 0x0f);
+    let mut reader = Reader{data: &buffer[header..header + remaining]};
+
+    let packet = match (kind, version) {
+        (CONNECT, _) if flags == 0 => parse_connect(&mut reader),
+        (_, Some(version)) => parse_body(kind, flags, version == 5, &mut reader),
+        (_, None) => None,
+    };
+
+    match packet {
+        Some(packet) if reader.data.is_empty() => Parsed::Complete(packet, header + remaining),
+        _ => Parsed::Invalid,
+    }
+}
+
+fn parse_connect(reader: &mut Reader) -> Option<MqttPacket> {
+
+    let protocol_name = reader.string()?;
+    let protocol_level = reader.u8()?;
+    if !matches!(protocol_level, 3..=5) {
+        return None;
+    }
+    let flags = reader.u8()?;
+    if flags & 0x01 != 0 {
+        return None;
+    }
+    let keep_alive = reader.u16()?;
+    let v5 = protocol_level == 5;
+    let properties = if v5 { reader.properties()? } else { Vec::new() };
+    let client_id = reader.string()?;
+
+    let will = if flags & 0x04 != 0 {
+        let properties = if v5 { reader.properties()? } else { Vec::new() };
+        Some(MqttWill {
+            topic: reader.string()?,
+            payload: reader.binary()?,
+            qos: (flags >> 3) & 0x03,
+            retain: flags & 0x20 != 0,
+            properties,
+        })
+    } else {
+        None
+    };
+    let username = if flags & 0x80 != 0 { Some(reader.string()?) } else { None };
+    let password = if flags & 0x40 != 0 { Some(reader.binary()?) } else { None };
+
+    Some(MqttPacket::Connect(MqttConnect {
+        protocol_name,
+        protocol_level,
+        clean_start: flags & 0x02 != 0,
+        keep_alive,
+        properties,
+        client_id,
+        will,
+        username,
+        password,
+    }))
+}
+
+fn parse_body(kind: u8, flags: u8, v5: bool, reader: &mut Reader) -> Option<MqttPacket> {
+
+    // Only PUBLISH uses its flags, PUBREL, SUBSCRIBE and UNSUBSCRIBE have them fixed to 2
+    let expected_flags = match kind {
+        PUBLISH => flags,
+        PUBREL | SUBSCRIBE | UNSUBSCRIBE => 0x02,
+        _ => 0,
+    };
+    if flags != expected_flags {
+        return None;
+    }
+
+    let properties = |reader: &mut Reader| if v5 { reader.properties() } else { Some(Vec::new()) };
+
+    let packet = match kind {
+        CONNACK => MqttPacket::ConnAck {
+            session_present: reader.u8()? & 0x01 != 0,
+            reason_code: reader.u8()?,
+            properties: properties(reader)?,
+        },
+        PUBLISH => {
+            let qos = (flags >> 1) & 0x03;
+            if qos == 3 {
+                return None;
+            }
+            let topic = reader.string()?;
+            let packet_id = if qos > 0 { Some(reader.u16()?) } else { None };
+            MqttPacket::Publish(MqttPublish {
+                topic,
+                packet_id,
+                properties: properties(reader)?,
+                payload: reader.rest().to_vec(),
+                qos,
+                retain: flags & 0x01 != 0,
+                dup: flags & 0x08 != 0,
+            })
+        },
+        PUBACK => MqttPacket::PubAck(parse_ack(reader, v5)?),
+        PUBREC => MqttPacket::PubRec(parse_ack(reader, v5)?),
+        PUBREL => MqttPacket::PubRel(parse_ack(reader, v5)?),
+        PUBCOMP => MqttPacket::PubComp(parse_ack(reader, v5)?),
+        SUBSCRIBE => {
+            let packet_id = reader.u16()?;
+            let properties = properties(reader)?;
+            let mut subscriptions = Vec::new();
+            while !reader.data.is_empty() {
+                subscriptions.push((reader.string()?, reader.u8()?));
+            }
+            MqttPacket::Subscribe{packet_id, properties, subscriptions}
+        },
+        SUBACK => MqttPacket::SubAck {
+            packet_id: reader.u16()?,
+            properties: properties(reader)?,
+            reason_codes: reader.rest().to_vec(),
+        },
+        UNSUBSCRIBE => {
+            let packet_id = reader.u16()?;
+            let properties = properties(reader)?;
+            let mut topics = Vec::new();
+            while !reader.data.is_empty() {
+                topics.push(reader.string()?);
+            }
+            MqttPacket::Unsubscribe{packet_id, properties, topics}
+        },
+        UNSUBACK => MqttPacket::UnsubAck {
+            packet_id: reader.u16()?,
+            properties: properties(reader)?,
+            reason_codes: reader.rest().to_vec(),
+        },
+        PINGREQ => MqttPacket::PingReq,
+        PINGRESP => MqttPacket::PingResp,
+        DISCONNECT => {
+            let (reason_code, properties) = parse_reason(reader, v5)?;
+            MqttPacket::Disconnect{reason_code, properties}
+        },
+        AUTH if v5 => {
+            let (reason_code, properties) = parse_reason(reader, v5)?;
+            MqttPacket::Auth{reason_code, properties}
+        },
+        _ => return None,
+    };
+    Some(packet)
+}
+
+/// PUBACK, PUBREC, PUBREL and PUBCOMP. With 5.0 the reason code and properties may be left out.
+fn parse_ack(reader: &mut Reader, v5: bool) -> Option<MqttAck> {
+    let packet_id = reader.u16()?;
+    let (reason_code, properties) = parse_reason(reader, v5)?;
+    Some(MqttAck{packet_id, reason_code, properties})
+}
+
+/// A reason code and properties that may be left out, as in DISCONNECT and AUTH.
+fn parse_reason(reader: &mut Reader, v5: bool) -> Option<(u8, Vec<MqttProperty>)> {
+    if !v5 || reader.data.is_empty() {
+        return Some((0, Vec::new()));
+    }
+    let reason_code = reader.u8()?;
+    if reader.data.is_empty() {
+        return Some((reason_code, Vec::new()));
+    }
+    Some((reason_code, reader.properties()?))
+}
+
+fn write_ack(out: &mut Vec<u8>, ack: &MqttAck, v5: bool) {
+    out.extend_from_slice(&ack.packet_id.to_be_bytes());
+    if v5 {
+        write_reason(out, ack.reason_code, &ack.properties);
+    }
+}
+
+/// Leaves out what a receiver assumes anyway: success without properties.
+fn write_reason(out: &mut Vec<u8>, reason_code: u8, properties: &[MqttProperty]) {
+    if reason_code != 0 || !properties.is_empty() {
+        out.push(reason_code);
+        if !properties.is_empty() {
+            write_properties(out, properties);
+        }
+    }
+}
+
+fn write_properties(out: &mut Vec<u8>, properties: &[MqttProperty]) {
+    let mut data = Vec::new();
+    properties.iter().for_each(|property| property.write(&mut data));
+    write_variable_int(out, data.len() as u32);
+    out.extend(data);
+}
+
+fn write_u16_property(out: &mut Vec<u8>, id: u8, value: u16) {
+    out.push(id);
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+fn write_u32_property(out: &mut Vec<u8>, id: u8, value: u32) {
+    out.push(id);
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+fn write_string_property(out: &mut Vec<u8>, id: u8, value: &str) {
+    out.push(id);
+    write_string(out, value);
+}
+
+fn write_string(out: &mut Vec<u8>, value: &str) {
+    write_binary(out, value.as_bytes());
+}
+
+/// Data longer than a u16 length allows is cut off.
+fn write_binary(out: &mut Vec<u8>, data: &[u8]) {
+    let data = &data[..data.len().min(u16::MAX as usize)];
+    out.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    out.extend_from_slice(data);
+}
+
+fn write_variable_int(out: &mut Vec<u8>, mut value: u32) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+/// Reads the fields of a packet body front to back.
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+
+    fn bytes(&mut self, length: usize) -> Option<&'a [u8]> {
+        if self.data.len() < length {
+            return None;
+        }
+        let (bytes, rest) = self.data.split_at(length);
+        self.data = rest;
+        Some(bytes)
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        std::mem::take(&mut self.data)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|bytes| bytes[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes(2).map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.bytes(4).map(|bytes| u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn variable_int(&mut self) -> Option<u32> {
+        let mut value = 0;
+        for index in 0..4 {
+            let byte = self.u8()?;
+            value |= ((byte & 0x7f) as u32) << (7 * index);
+            if byte & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+        None
+    }
+
+    fn binary(&mut self) -> Option<Vec<u8>> {
+        let length = self.u16()? as usize;
+        self.bytes(length).map(|bytes| bytes.to_vec())
+    }
+
+    fn string(&mut self) -> Option<String> {
+        String::from_utf8(self.binary()?).ok()
+    }
+
+    fn properties(&mut self) -> Option<Vec<MqttProperty>> {
+
+        let length = self.variable_int()? as usize;
+        let mut reader = Reader{data: self.bytes(length)?};
+        let mut properties = Vec::new();
+
+        while !reader.data.is_empty() {
+            let property = match reader.variable_int()? {
+                0x01 => MqttProperty::PayloadFormatIndicator(reader.u8()?),
+                0x02 => MqttProperty::MessageExpiryInterval(reader.u32()?),
+                0x03 => MqttProperty::ContentType(reader.string()?),
+                0x08 => MqttProperty::ResponseTopic(reader.string()?),
+                0x09 => MqttProperty::CorrelationData(reader.binary()?),
+                0x0b => MqttProperty::SubscriptionIdentifier(reader.variable_int()?),
+                0x11 => MqttProperty::SessionExpiryInterval(reader.u32()?),
+                0x12 => MqttProperty::AssignedClientIdentifier(reader.string()?),
+                0x13 => MqttProperty::ServerKeepAlive(reader.u16()?),
+                0x15 => MqttProperty::AuthenticationMethod(reader.string()?),
+                0x16 => MqttProperty::AuthenticationData(reader.binary()?),
+                0x17 => MqttProperty::RequestProblemInformation(reader.u8()?),
+                0x18 => MqttProperty::WillDelayInterval(reader.u32()?),
+                0x19 => MqttProperty::RequestResponseInformation(reader.u8()?),
+                0x1a => MqttProperty::ResponseInformation(reader.string()?),
+                0x1c => MqttProperty::ServerReference(reader.string()?),
+                0x1f => MqttProperty::ReasonString(reader.string()?),
+                0x21 => MqttProperty::ReceiveMaximum(reader.u16()?),
+                0x22 => MqttProperty::TopicAliasMaximum(reader.u16()?),
+                0x23 => MqttProperty::TopicAlias(reader.u16()?),
+                0x24 => MqttProperty::MaximumQoS(reader.u8()?),
+                0x25 => MqttProperty::RetainAvailable(reader.u8()?),
+                0x26 => MqttProperty::UserProperty(reader.string()?, reader.string()?),
+                0x27 => MqttProperty::MaximumPacketSize(reader.u32()?),
+                0x28 => MqttProperty::WildcardSubscriptionAvailable(reader.u8()?),
+                0x29 => MqttProperty::SubscriptionIdentifierAvailable(reader.u8()?),
+                0x2a => MqttProperty::SharedSubscriptionAvailable(reader.u8()?),
+                _ => return None,
+            };
+            properties.push(property);
+        }
+        Some(properties)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    struct Filtering;
+
+    impl MqttHandler for Filtering {
+        fn on_client_packet(&mut self, packet: &mut MqttPacket, inject: &mut MqttInjector) -> MqttRet {
+            match packet {
+                MqttPacket::Publish(publish) if publish.topic.starts_with("blocked/") => MqttRet::Drop,
+                MqttPacket::Publish(publish) if publish.topic == "ping" => {
+                    let mut pong = MqttPublish::new("pong", b"1".to_vec());
+                    pong.qos = 1;
+                    inject.to_client(MqttPacket::Publish(pong));
+                    MqttRet::Relay
+                },
+                _ => MqttRet::Relay,
+            }
+        }
+    }
+
+    fn relayed(callback_ret: CallbackRet) -> Vec<u8> {
+        match callback_ret {
+            CallbackRet::Relay(data) | CallbackRet::RelayAndSpoof(data, _) => data,
+            _ => Vec::new(),
+        }
+    }
+
+    fn spoofed(callback_ret: CallbackRet) -> Vec<u8> {
+        match callback_ret {
+            CallbackRet::Spoof(data) | CallbackRet::RelayAndSpoof(_, data) => data,
+            _ => Vec::new(),
+        }
+    }
+
+    fn connect(protocol_level: u8) -> MqttConnect {
+        MqttConnect {
+            protocol_name: "MQTT".to_string(),
+            protocol_level,
+            clean_start: true,
+            keep_alive: 60,
+            properties: Vec::new(),
+            client_id: "sensor-1".to_string(),
+            will: None,
+            username: None,
+            password: None,
+        }
+    }
+
+    fn publish(topic: &str, qos: u8, packet_id: Option<u16>) -> MqttPacket {
+        let mut publish = MqttPublish::new(topic, b"21.5".to_vec());
+        publish.qos = qos;
+        publish.packet_id = packet_id;
+        MqttPacket::Publish(publish)
+    }
+
+    /// A relay past CONNECT and CONNACK.
+    fn connected(protocol_level: u8) -> MqttRelay<Filtering> {
+        let mut relay = MqttRelay::new(Filtering);
+        let connect = MqttPacket::Connect(connect(protocol_level)).to_bytes(protocol_level);
+        assert_eq!(relayed(relay.ds_b_callback(connect.clone())), connect);
+        let connack = MqttPacket::ConnAck{session_present: false, reason_code: 0, properties: Vec::new()}.to_bytes(protocol_level);
+        assert_eq!(relayed(relay.us_b_callback(connack.clone())), connack);
+        relay
+    }
+
+    fn assert_round_trip(packet: &MqttPacket, version: u8) {
+        let bytes = packet.to_bytes(version);
+        match parse_packet(&bytes, Some(version)) {
+            Parsed::Complete(parsed, length) => {
+                assert_eq!(&parsed, packet);
+                assert_eq!(length, bytes.len());
+            },
+            _ => panic!("{:?} not parsed with version {}", packet, version),
+        }
+    }
+
+    #[test]
+    fn packets_round_trip() {
+        let mut full = connect(4);
+        full.will = Some(MqttWill{topic: "status".to_string(), payload: b"offline".to_vec(), qos: 1, retain: true, properties: Vec::new()});
+        full.username = Some("user".to_string());
+        full.password = Some(b"secret".to_vec());
+
+        let ack = MqttAck::new(7);
+        let packets = [
+            MqttPacket::Connect(full.clone()),
+            MqttPacket::ConnAck{session_present: true, reason_code: 0, properties: Vec::new()},
+            publish("sensors/temperature", 0, None),
+            publish("sensors/temperature", 2, Some(7)),
+            MqttPacket::PubAck(ack.clone()),
+            MqttPacket::PubRec(ack.clone()),
+            MqttPacket::PubRel(ack.clone()),
+            MqttPacket::PubComp(ack),
+            MqttPacket::Subscribe{packet_id: 8, properties: Vec::new(), subscriptions: vec![("sensors/#".to_string(), 1), ("alerts".to_string(), 0)]},
+            MqttPacket::SubAck{packet_id: 8, properties: Vec::new(), reason_codes: vec![1, 0]},
+            MqttPacket::Unsubscribe{packet_id: 9, properties: Vec::new(), topics: vec!["alerts".to_string()]},
+            MqttPacket::UnsubAck{packet_id: 9, properties: Vec::new(), reason_codes: Vec::new()},
+            MqttPacket::PingReq,
+            MqttPacket::PingResp,
+            MqttPacket::Disconnect{reason_code: 0, properties: Vec::new()},
+        ];
+        for packet in &packets {
+            assert_round_trip(packet, 4);
+        }
+
+        let mut full = connect(5);
+        full.properties = vec![MqttProperty::SessionExpiryInterval(3600), MqttProperty::ReceiveMaximum(10)];
+        full.will = Some(MqttWill{topic: "status".to_string(), payload: b"offline".to_vec(), qos: 2, retain: false, properties: vec![MqttProperty::WillDelayInterval(5)]});
+        full.username = Some("user".to_string());
+
+        let mut properties_publish = MqttPublish::new("sensors/temperature", b"21.5".to_vec());
+        properties_publish.qos = 1;
+        properties_publish.packet_id = Some(3);
+        properties_publish.properties = vec![
+            MqttProperty::PayloadFormatIndicator(1),
+            MqttProperty::ContentType("text/plain".to_string()),
+            MqttProperty::CorrelationData(vec![1, 2, 3]),
+            MqttProperty::SubscriptionIdentifier(300_000),
+            MqttProperty::UserProperty("unit".to_string(), "celsius".to_string()),
+        ];
+        let packets = [
+            MqttPacket::Connect(full),
+            MqttPacket::ConnAck{session_present: false, reason_code: 0, properties: vec![MqttProperty::AssignedClientIdentifier("auto-1".to_string()), MqttProperty::MaximumQoS(1)]},
+            MqttPacket::Publish(properties_publish),
+            MqttPacket::PubAck(MqttAck::new(3)),
+            MqttPacket::PubRec(MqttAck{packet_id: 3, reason_code: 0x10, properties: Vec::new()}),
+            MqttPacket::PubRel(MqttAck{packet_id: 3, reason_code: 0x92, properties: vec![MqttProperty::ReasonString("unknown id".to_string())]}),
+            MqttPacket::Subscribe{packet_id: 8, properties: vec![MqttProperty::SubscriptionIdentifier(1)], subscriptions: vec![("sensors/#".to_string(), 0x2e)]},
+            MqttPacket::UnsubAck{packet_id: 9, properties: Vec::new(), reason_codes: vec![0x11]},
+            MqttPacket::Disconnect{reason_code: 0x8e, properties: Vec::new()},
+            MqttPacket::Auth{reason_code: 0x18, properties: vec![MqttProperty::AuthenticationMethod("SCRAM-SHA-1".to_string()), MqttProperty::AuthenticationData(vec![0; 4])]},
+        ];
+        for packet in &packets {
+            assert_round_trip(packet, 5);
+        }
+    }
+
+    #[test]
+    fn remaining_lengths_take_up_to_four_bytes() {
+        let bytes = MqttPacket::Publish(MqttPublish::new("big", vec![0; 20_000])).to_bytes(4);
+        assert!(matches!(fixed_header(&bytes), Parsed::Complete((4, 20_005), _)));
+        assert!(matches!(packet_length(&bytes), Parsed::Complete((), 20_009)));
+        assert!(matches!(packet_length(&bytes[..bytes.len() - 1]), Parsed::Incomplete));
+        assert!(matches!(packet_length(&bytes[..3]), Parsed::Incomplete));
+        assert!(matches!(packet_length(&[0x30, 0xff, 0xff, 0xff, 0xff, 0x01]), Parsed::Invalid));
+    }
+
+    #[test]
+    fn dropped_publishes_are_acknowledged_to_their_sender() {
+        let mut relay = connected(4);
+        let qos0 = publish("blocked/a", 0, None).to_bytes(4);
+        assert!(matches!(relay.ds_b_callback(qos0), CallbackRet::Freeze));
+
+        let qos1 = publish("blocked/a", 1, Some(1)).to_bytes(4);
+        assert_eq!(spoofed(relay.ds_b_callback(qos1)), MqttPacket::PubAck(MqttAck::new(1)).to_bytes(4));
+
+        let qos2 = publish("blocked/a", 2, Some(2)).to_bytes(4);
+        assert_eq!(spoofed(relay.ds_b_callback(qos2)), MqttPacket::PubRec(MqttAck::new(2)).to_bytes(4));
+        let release = MqttPacket::PubRel(MqttAck::new(2)).to_bytes(4);
+        assert_eq!(spoofed(relay.ds_b_callback(release)), MqttPacket::PubComp(MqttAck::new(2)).to_bytes(4));
+    }
+
+    #[test]
+    fn injected_publishes_are_acknowledged_by_the_relay() {
+        let mut relay = connected(4);
+        let ping = publish("ping", 1, Some(1)).to_bytes(4);
+        match relay.ds_b_callback(ping.clone()) {
+            CallbackRet::RelayAndSpoof(forward, back) => {
+                assert_eq!(forward, ping);
+                let mut pong = MqttPublish::new("pong", b"1".to_vec());
+                pong.qos = 1;
+                pong.packet_id = Some(1);
+                assert_eq!(back, MqttPacket::Publish(pong).to_bytes(4));
+            },
+            other => panic!("{:?}", other),
+        }
+
+        // The client's ack is for the relay, the broker's ack is for the client
+        assert!(matches!(relay.ds_b_callback(MqttPacket::PubAck(MqttAck::new(1)).to_bytes(4)), CallbackRet::Freeze));
+        let ack = MqttPacket::PubAck(MqttAck::new(1)).to_bytes(4);
+        assert_eq!(relayed(relay.us_b_callback(ack.clone())), ack);
+    }
+
+    #[test]
+    fn topic_aliases_are_resolved() {
+        let mut relay = connected(5);
+        let mut aliased = MqttPublish::new("sensors/temperature", b"21.5".to_vec());
+        aliased.properties.push(MqttProperty::TopicAlias(3));
+        let expected = publish("sensors/temperature", 0, None).to_bytes(5);
+        assert_eq!(relayed(relay.ds_b_callback(MqttPacket::Publish(aliased.clone()).to_bytes(5))), expected);
+
+        aliased.topic = String::new();
+        assert_eq!(relayed(relay.ds_b_callback(MqttPacket::Publish(aliased).to_bytes(5))), expected);
+    }
+
+    #[test]
+    fn packets_in_pieces_are_put_together() {
+        let mut relay = connected(4);
+        let bytes = publish("sensors/temperature", 1, Some(4)).to_bytes(4);
+        assert!(relayed(relay.ds_b_callback(bytes[..1].to_vec())).is_empty());
+        assert!(relayed(relay.ds_b_callback(bytes[1..10].to_vec())).is_empty());
+        assert_eq!(relayed(relay.ds_b_callback(bytes[10..].to_vec())), bytes);
+    }
+
+    #[test]
+    fn malformed_packets_end_the_interpretation() {
+        let mut bad_level = MqttPacket::Connect(connect(4)).to_bytes(4);
+        bad_level[8] = 7;
+        assert!(matches!(parse_packet(&bad_level, None), Parsed::Invalid));
+        // Nothing but CONNECT parses before the protocol level is known
+        assert!(matches!(parse_packet(&MqttPacket::PingReq.to_bytes(4), None), Parsed::Invalid));
+        // PUBREL with the flags of PUBACK, a QoS 3 publish and a body with bytes left over
+        assert!(matches!(parse_packet(&[0x60, 0x02, 0x00, 0x01], Some(4)), Parsed::Invalid));
+        assert!(matches!(parse_packet(&[0x36, 0x05, 0x00, 0x01, b'a', 0x00, 0x01], Some(4)), Parsed::Invalid));
+        assert!(matches!(parse_packet(&[0x40, 0x03, 0x00, 0x01, 0x00], Some(4)), Parsed::Invalid));
+        // A property id that does not exist
+        assert!(matches!(parse_packet(&[0x40, 0x05, 0x00, 0x01, 0x00, 0x02, 0x7f, 0x00], Some(5)), Parsed::Invalid));
+
+        let mut relay = connected(4);
+        let garbage = b"\x60\x02\x00\x01".to_vec();
+        assert_eq!(relayed(relay.ds_b_callback(garbage.clone())), garbage);
+        assert!(relay.client.passthrough);
+        let blocked = publish("blocked/a", 0, None).to_bytes(4);
+        assert_eq!(relayed(relay.ds_b_callback(blocked.clone())), blocked);
+    }
+}
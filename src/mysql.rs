@@ -0,0 +1,1480 @@
+//! MySQL aware callbacks.
+//!
+//! MySqlRelay wraps a MySqlHandler and implements HandlerCallbacks for it. It follows the
+//! client/server protocol of both legs: the handshake and capability negotiation, COM_QUERY,
+//! prepared statements and the result sets, OK and ERR packets that answer them.
+//! ```ignore
+//! use sslrelay::mysql::{MySqlRelay, MySqlHandler, MySqlRet, ClientMessage, ServerMessage};
+//!
+//! struct Handler;
+//!
+//! impl MySqlHandler for Handler {
+//!     fn on_client_message(&mut self, message: &mut ClientMessage) -> MySqlRet {
+//!         match message {
+//!             ClientMessage::Query(query) | ClientMessage::StmtPrepare(query) if query.contains("DROP") => {
+//!                 MySqlRet::Error(1142, "not through this relay".to_string())
+//!             },
+//!             ClientMessage::Query(query) => {
+//!                 *query = query.replace("FROM users", "FROM users_masked");
+//!                 MySqlRet::Relay
+//!             },
+//!             _ => MySqlRet::Relay,
+//!         }
+//!     }
+//! }
+//!
+//! let mut relay = sslrelay::SSLRelay::new(MySqlRelay::new(Handler), config);
+//! ```
+//! Sequence ids are renumbered when the handler drops, adds or answers packets. A client that asks
+//! for SSL gets both legs switched to TLS (see CallbackRet::RelayAndStartTLS), which needs a
+//! tls_config in RelayConfig. Compression, query attributes and optional result set metadata are
+//! taken out of the capabilities the server offers since the relay can not follow them. Data that
+//! does not parse is relayed untouched from there on.
+
+use crate::{
+    HandlerCallbacks,
+    CallbackRet,
+    CloseReason,
+    SessionHandle,
+    VecDeque,
+    http::callback_ret,
+};
+use std::convert::TryFrom;
+
+/// A message from the client.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ClientMessage {
+    SSLRequest{capabilities: u32, max_packet_size: u32, character_set: u8, mariadb_capabilities: u32},
+    HandshakeResponse(HandshakeResponse),
+    AuthData(Vec<u8>),// Any later packet of the authentication exchange
+    Quit,// COM_QUIT
+    InitDB(String),// COM_INIT_DB
+    Query(String),// COM_QUERY
+    Ping,// COM_PING
+    StmtPrepare(String),// COM_STMT_PREPARE
+    StmtExecute{statement_id: u32, flags: u8, iteration_count: u32, parameters: Vec<u8>},// COM_STMT_EXECUTE, parameters as sent
+    StmtClose(u32),// COM_STMT_CLOSE
+    StmtReset(u32),// COM_STMT_RESET
+    Other(Vec<u8>),// Any other packet, its payload
+}
+
+/// A message from the server.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ServerMessage {
+    Handshake(Handshake),
+    Ok{affected_rows: u64, last_insert_id: u64, status_flags: u16, warnings: u16, info: Vec<u8>},
+    Err{code: u16, sql_state: String, message: String},
+    Eof{warnings: u16, status_flags: u16},// End of columns or rows, sent in the form the client negotiated
+    AuthSwitch{plugin: String, data: Vec<u8>},
+    AuthMoreData(Vec<u8>),
+    LocalInfile(String),// LOAD DATA LOCAL INFILE request for this file
+    ColumnCount(u64),// Start of a result set
+    Column(MySqlColumn),
+    TextRow(Vec<Option<Vec<u8>>>),// Column values, None for NULL
+    BinaryRow(Vec<u8>),// Null bitmap and values of a prepared statement row, as sent
+    StmtPrepareOk{statement_id: u32, columns: u16, params: u16, warnings: u16},
+    Other(Vec<u8>),// Any other packet, its payload
+}
+
+/// The initial handshake packet of the server.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Handshake {
+    pub server_version: String,
+    pub connection_id: u32,
+    pub auth_plugin_data: Vec<u8>,// The scramble
+    pub capabilities: u32,
+    pub character_set: u8,
+    pub status_flags: u16,
+    pub auth_plugin: String,
+    pub mariadb_capabilities: u32,// MariaDB extended capabilities, 0 for MySQL
+}
+
+/// The handshake response of the client.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HandshakeResponse {
+    pub capabilities: u32,
+    pub max_packet_size: u32,
+    pub character_set: u8,
+    pub mariadb_capabilities: u32,// MariaDB extended capabilities, 0 for MySQL
+    pub username: String,
+    pub auth_response: Vec<u8>,
+    pub database: Option<String>,// Sets CLIENT_CONNECT_WITH_DB when relayed
+    pub auth_plugin: Option<String>,
+    pub attributes: Vec<(String, String)>,
+}
+
+/// A column definition of a result set or prepared statement.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MySqlColumn {
+    pub schema: String,
+    pub table: String,
+    pub org_table: String,
+    pub name: String,
+    pub org_name: String,
+    pub character_set: u16,
+    pub column_length: u32,
+    pub column_type: u8,
+    pub flags: u16,
+    pub decimals: u8,
+}
+
+/// What MySqlRelay does with a message after the handler has seen it.
+#[derive(Debug)]
+pub enum MySqlRet {
+    Relay,// Relay the (possibly modified) message
+    Reply(Vec<ServerMessage>),// Client messages: answer the client with these instead of relaying. Server messages: relay these instead
+    Error(u16, String),// Like Reply with an ERR packet of this error code and message
+    Drop,// Dont relay the message. A dropped command leaves the client waiting for an answer
+    Shutdown,// Shutdown TCP connection
+}
+
+/// Callbacks for MySQL traffic, used through MySqlRelay.
+pub trait MySqlHandler {
+    /// Errors and replies to the handshake response end the connection after they are sent.
+    fn on_client_message(&mut self, _message: &mut ClientMessage) -> MySqlRet {MySqlRet::Relay}
+    /// The rest of a result set the handler cut short with an error is dropped.
+    fn on_server_message(&mut self, _message: &mut ServerMessage) -> MySqlRet {MySqlRet::Relay}
+    fn open_callback(&mut self, _session: SessionHandle){}
+    fn close_callback(&mut self, _reason: CloseReason){}
+}
+
+/// Turns a MySqlHandler into HandlerCallbacks.
+pub struct MySqlRelay<T: MySqlHandler> {
+    handler: T,
+    ds_buffer: Vec<u8>,
+    us_buffer: Vec<u8>,
+    ds_passthrough: bool,
+    us_passthrough: bool,
+    // Capabilities of the client's handshake response
+    capabilities: u32,
+    handshake_sent: bool,
+    // Added to the sequence ids of server packets and taken off those of client packets, for the
+    // packets the relay dropped or added in the current exchange
+    shift: u8,
+    // Dropping the rest of a response the handler cut short with an error
+    discard: bool,
+    // The client is sending a file for LOAD DATA LOCAL INFILE
+    infile: bool,
+    // Responses the server owes, in the order the client asked for them
+    pending: VecDeque<Pending>,
+}
+
+enum Pending {
+    Relayed(Expect, u8),// Sent to the server, where its response is at and the shift for it
+    Answered(Vec<u8>),// Answered by the handler, goes out after the responses before it
+}
+
+/// Where a response of the server is at.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Expect {
+    Handshake,
+    Auth,// Until OK or ERR
+    Status,// A single OK, ERR or EOF
+    ResultSet{binary: bool},// OK, ERR, LOCAL INFILE or a column count
+    Columns{remaining: u64, binary: bool},
+    ColumnsEof{binary: bool},
+    Rows{binary: bool},
+    PrepareOk,
+    Params{remaining: u16, columns: u16},
+    ParamsEof{columns: u16},
+    PrepareColumns{remaining: u16},
+    PrepareColumnsEof,
+    Unknown,// A command the relay does not follow, its response lasts until the next command
+}
+
+enum Parsed<T> {
+    Complete(T, usize),// The message and how many bytes of the buffer it took
+    Incomplete,
+    Invalid,
+}
+
+const CLIENT_MYSQL: u32 = 0x1;
+const CLIENT_CONNECT_WITH_DB: u32 = 0x8;
+const CLIENT_COMPRESS: u32 = 0x20;
+const CLIENT_PROTOCOL_41: u32 = 0x200;
+const CLIENT_SSL: u32 = 0x800;
+const CLIENT_SECURE_CONNECTION: u32 = 0x8000;
+const CLIENT_PLUGIN_AUTH: u32 = 0x80000;
+const CLIENT_CONNECT_ATTRS: u32 = 0x100000;
+const CLIENT_PLUGIN_AUTH_LENENC_CLIENT_DATA: u32 = 0x200000;
+const CLIENT_DEPRECATE_EOF: u32 = 0x1000000;
+const CLIENT_OPTIONAL_RESULTSET_METADATA: u32 = 0x2000000;
+const CLIENT_ZSTD_COMPRESSION_ALGORITHM: u32 = 0x4000000;
+const CLIENT_QUERY_ATTRIBUTES: u32 = 0x8000000;
+const MARIADB_CLIENT_CACHE_METADATA: u32 = 0x10;
+
+// Capabilities that change the framing or packet layout in ways the relay does not follow
+const UNSUPPORTED_CAPABILITIES: u32 = CLIENT_COMPRESS | CLIENT_OPTIONAL_RESULTSET_METADATA | CLIENT_ZSTD_COMPRESSION_ALGORITHM | CLIENT_QUERY_ATTRIBUTES;
+
+const SERVER_MORE_RESULTS_EXISTS: u16 = 0x8;
+const SERVER_STATUS_CURSOR_EXISTS: u16 = 0x40;
+
+const COM_QUIT: u8 = 0x01;
+const COM_INIT_DB: u8 = 0x02;
+const COM_QUERY: u8 = 0x03;
+const COM_PING: u8 = 0x0e;
+const COM_CHANGE_USER: u8 = 0x11;
+const COM_BINLOG_DUMP: u8 = 0x12;
+const COM_STMT_PREPARE: u8 = 0x16;
+const COM_STMT_EXECUTE: u8 = 0x17;
+const COM_STMT_SEND_LONG_DATA: u8 = 0x18;
+const COM_STMT_CLOSE: u8 = 0x19;
+const COM_STMT_RESET: u8 = 0x1a;
+const COM_STMT_FETCH: u8 = 0x1c;
+const COM_BINLOG_DUMP_GTID: u8 = 0x1e;
+
+// Payloads this long continue in the next packet
+const MAX_PAYLOAD: usize = 0xffffff;
+// Sanity limit for a single message, the largest max_allowed_packet is 1GB
+const MAX_MESSAGE_SIZE: usize = 1024 * 1024 * 1024;
+
+impl<T: MySqlHandler> MySqlRelay<T> {
+
+    pub fn new(handler: T) -> Self {
+        let mut pending = VecDeque::new();
+        pending.push_back(Pending::Relayed(Expect::Handshake, 0));
+        MySqlRelay {
+            handler,
+            ds_buffer: Vec::new(),
+            us_buffer: Vec::new(),
+            ds_passthrough: false,
+            us_passthrough: false,
+            capabilities: 0,
+            handshake_sent: false,
+            shift: 0,
+            discard: false,
+            infile: false,
+            pending,
+        }
+    }
+
+    fn front(&self) -> Option<Expect> {
+        match self.pending.front() {
+            Some(Pending::Relayed(expect, _)) => Some(*expect),
+            _ => None,
+        }
+    }
+
+    fn push_response(&mut self, expect: Expect, shift: u8) {
+        if self.pending.is_empty() {
+            self.shift = shift;
+        }
+        self.pending.push_back(Pending::Relayed(expect, shift));
+    }
+
+    /// Moves on to the next response, sending the handler's answers that were waiting for this one.
+    fn finish_response(&mut self, relay_data: &mut Vec<u8>) {
+
+        self.pending.pop_front();
+        self.discard = false;
+        self.shift = 0;
+
+        while let Some(Pending::Answered(_)) = self.pending.front() {
+            if let Some(Pending::Answered(answer)) = self.pending.pop_front() {
+                relay_data.extend(answer);
+            }
+        }
+        if let Some(Pending::Relayed(_, shift)) = self.pending.front() {
+            self.shift = *shift;
+        }
+    }
+
+    /// Writes server messages numbered from first, returns how many packets that took.
+    fn write_server(&self, out: &mut Vec<u8>, messages: &[ServerMessage], first: u8) -> usize {
+        messages.iter().fold(0, |count, message| {
+            count + write_packets(out, &message.payload(self.capabilities), first.wrapping_add(count as u8))
+        })
+    }
+}
+
+impl<T: MySqlHandler + Clone> Clone for MySqlRelay<T> {
+
+    /// Clones the handler into a relay that has not seen any traffic.
+    fn clone(&self) -> Self {
+        MySqlRelay::new(self.handler.clone())
+    }
+}
+
+impl<T: MySqlHandler> HandlerCallbacks for MySqlRelay<T> {
+
+    fn ds_b_callback(&mut self, in_data: Vec<u8>) -> CallbackRet {
+
+        if self.ds_passthrough {
+            return CallbackRet::Relay(in_data);
+        }
+        self.ds_buffer.extend(in_data);
+
+        let mut relay_data = Vec::new();
+        let mut spoof_data = Vec::new();
+
+        loop {
+            let (payload, sequence, packets) = match parse_packet(&self.ds_buffer) {
+                Parsed::Complete(packet, length) => {
+                    self.ds_buffer.drain(..length);
+                    packet
+                },
+                Parsed::Incomplete => break,
+                Parsed::Invalid => {
+                    self.ds_passthrough = true;
+                    relay_data.append(&mut self.ds_buffer);
+                    break;
+                },
+            };
+
+            let authenticating = self.front() == Some(Expect::Auth);
+
+            // File contents for LOCAL INFILE, ended by an empty packet, and anything else that is
+            // no message of its own go through as they are
+            if self.infile || (self.handshake_sent && sequence != 0 && !authenticating) {
+                if payload.is_empty() {
+                    self.infile = false;
+                }
+                write_packets(&mut relay_data, &payload, sequence.wrapping_sub(self.shift));
+                continue;
+            }
+
+            let mut message = if !self.handshake_sent {
+
+                let capabilities = read_u32(&payload, 0).unwrap_or(0);
+                let mariadb_capabilities = match capabilities & CLIENT_MYSQL {
+                    0 => read_u32(&payload, 28).unwrap_or(0),
+                    _ => 0,
+                };
+                // Old protocol versions and capabilities the relay can not follow leave both legs to themselves
+                if capabilities & CLIENT_PROTOCOL_41 == 0 || capabilities & UNSUPPORTED_CAPABILITIES != 0 || mariadb_capabilities & MARIADB_CLIENT_CACHE_METADATA != 0 {
+                    self.ds_passthrough = true;
+                    self.us_passthrough = true;
+                    write_packets(&mut relay_data, &payload, sequence);
+                    relay_data.append(&mut self.ds_buffer);
+                    break;
+                }
+
+                match payload.len() == 32 && capabilities & CLIENT_SSL != 0 {
+                    true => ClientMessage::SSLRequest {
+                        capabilities,
+                        max_packet_size: read_u32(&payload, 4).unwrap_or(0),
+                        character_set: payload[8],
+                        mariadb_capabilities,
+                    },
+                    false => parse_handshake_response(&payload)
+                        .map(ClientMessage::HandshakeResponse)
+                        .unwrap_or_else(|| ClientMessage::Other(payload.clone())),
+                }
+            } else if authenticating && sequence != 0 {
+                ClientMessage::AuthData(payload.clone())
+            } else {
+                // A response the relay could not follow ends with the next command
+                if self.front() == Some(Expect::Unknown) {
+                    self.finish_response(&mut spoof_data);
+                }
+                parse_command(&payload)
+            };
+
+            match self.handler.on_client_message(&mut message) {
+                MySqlRet::Relay => {
+
+                    let payload = message.payload();
+
+                    if !self.handshake_sent {
+                        let count = write_packets(&mut relay_data, &payload, sequence.wrapping_sub(self.shift));
+                        if let ClientMessage::SSLRequest{..} = message {
+                            // Both legs continue in TLS, nothing else is sent in the clear
+                            return CallbackRet::RelayAndStartTLS(relay_data, std::mem::take(&mut self.ds_buffer));
+                        }
+                        self.capabilities = read_u32(&payload, 0).unwrap_or(0);
+                        self.handshake_sent = true;
+                        self.push_response(Expect::Auth, self.shift.wrapping_add(packets as u8).wrapping_sub(count as u8));
+                    } else if authenticating && sequence != 0 {
+                        let count = write_packets(&mut relay_data, &payload, sequence.wrapping_sub(self.shift));
+                        self.shift = self.shift.wrapping_add(packets as u8).wrapping_sub(count as u8);
+                    } else {
+                        let count = write_packets(&mut relay_data, &payload, 0);
+                        let shift = (packets as u8).wrapping_sub(count as u8);
+                        match payload.first() {
+                            // The binary log streams until the connection closes
+                            Some(&COM_BINLOG_DUMP) | Some(&COM_BINLOG_DUMP_GTID) => self.us_passthrough = true,
+                            Some(command) => if let Some(expect) = command_response(*command) {
+                                self.push_response(expect, shift);
+                            },
+                            None => {},
+                        }
+                    }
+                },
+                MySqlRet::Drop => {},
+                ret @ MySqlRet::Reply(_) | ret @ MySqlRet::Error(..) => {
+
+                    let replies = match ret {
+                        MySqlRet::Reply(replies) => replies,
+                        MySqlRet::Error(code, error) => vec![ServerMessage::error(code, &error)],
+                        _ => Vec::new(),
+                    };
+                    let mut answer = Vec::new();
+                    self.write_server(&mut answer, &replies, sequence.wrapping_add(packets as u8));
+
+                    if !self.handshake_sent {
+                        spoof_data.extend(answer);
+                        return CallbackRet::SpoofAndHalfClose(spoof_data);
+                    }
+                    if self.pending.is_empty() || (authenticating && sequence != 0) {
+                        spoof_data.extend(answer);
+                    } else {
+                        self.pending.push_back(Pending::Answered(answer));
+                    }
+                },
+                MySqlRet::Shutdown => return CallbackRet::Shutdown,
+            }
+        }
+
+        callback_ret(relay_data, spoof_data)
+    }
+
+    fn us_b_callback(&mut self, in_data: Vec<u8>) -> CallbackRet {
+
+        if self.us_passthrough {
+            return CallbackRet::Relay(in_data);
+        }
+        self.us_buffer.extend(in_data);
+
+        let mut relay_data = Vec::new();
+
+        loop {
+            let (payload, sequence, packets) = match parse_packet(&self.us_buffer) {
+                Parsed::Complete(packet, length) => {
+                    self.us_buffer.drain(..length);
+                    packet
+                },
+                Parsed::Incomplete => break,
+                Parsed::Invalid => {
+                    self.us_passthrough = true;
+                    relay_data.append(&mut self.us_buffer);
+                    break;
+                },
+            };
+
+            let deprecate_eof = self.capabilities & CLIENT_DEPRECATE_EOF != 0;
+            let expect = self.front();
+            let next = expect.and_then(|expect| next_expect(expect, &payload, deprecate_eof));
+
+            let mut count = 0;
+            if !self.discard {
+
+                let mut message = parse_server(expect.unwrap_or(Expect::Unknown), &payload);
+                if let ServerMessage::Handshake(handshake) = &mut message {
+                    handshake.capabilities &= !UNSUPPORTED_CAPABILITIES;
+                    handshake.mariadb_capabilities &= !MARIADB_CLIENT_CACHE_METADATA;
+                }
+
+                let first = sequence.wrapping_add(self.shift);
+                match self.handler.on_server_message(&mut message) {
+                    MySqlRet::Relay => {
+                        if let ServerMessage::LocalInfile(_) = message {
+                            self.infile = true;
+                        }
+                        count = self.write_server(&mut relay_data, &[message], first);
+                    },
+                    MySqlRet::Reply(replies) => count = self.write_server(&mut relay_data, &replies, first),
+                    MySqlRet::Error(code, error) => {
+                        count = self.write_server(&mut relay_data, &[ServerMessage::error(code, &error)], first);
+                        // The client takes the error as the end of the response
+                        self.discard = next.is_some();
+                    },
+                    MySqlRet::Drop => {},
+                    MySqlRet::Shutdown => return CallbackRet::Shutdown,
+                }
+            }
+            self.shift = self.shift.wrapping_add(count as u8).wrapping_sub(packets as u8);
+
+            match (expect, next) {
+                (Some(_), Some(next)) => if let Some(Pending::Relayed(expect, _)) = self.pending.front_mut() {
+                    *expect = next;
+                },
+                (Some(_), None) => self.finish_response(&mut relay_data),
+                (None, _) => {},
+            }
+        }
+
+        callback_ret(relay_data, Vec::new())
+    }
+
+    fn open_callback(&mut self, session: SessionHandle) {
+        self.handler.open_callback(session);
+    }
+
+    fn close_callback(&mut self, reason: CloseReason) {
+        self.handler.close_callback(reason);
+    }
+}
+
+impl ClientMessage {
+
+    fn payload(&self) -> Vec<u8> {
+
+        let mut out = Vec::new();
+
+        match self {
+            ClientMessage::SSLRequest{capabilities, max_packet_size, character_set, mariadb_capabilities} => {
+                out.extend_from_slice(&capabilities.to_le_bytes());
+                out.extend_from_slice(&max_packet_size.to_le_bytes());
+                out.push(*character_set);
+                out.extend_from_slice(&[0; 19]);
+                out.extend_from_slice(&mariadb_capabilities.to_le_bytes());
+            },
+            ClientMessage::HandshakeResponse(response) => response.write(&mut out),
+            ClientMessage::AuthData(data) | ClientMessage::Other(data) => out.extend_from_slice(data),
+            ClientMessage::Quit => out.push(COM_QUIT),
+            ClientMessage::InitDB(schema) => {
+                out.push(COM_INIT_DB);
+                out.extend_from_slice(schema.as_bytes());
+            },
+            ClientMessage::Query(query) => {
+                out.push(COM_QUERY);
+                out.extend_from_slice(query.as_bytes());
+            },
+            ClientMessage::Ping => out.push(COM_PING),
+            ClientMessage::StmtPrepare(query) => {
+                out.push(COM_STMT_PREPARE);
+                out.extend_from_slice(query.as_bytes());
+            },
+            ClientMessage::StmtExecute{statement_id, flags, iteration_count, parameters} => {
+                out.push(COM_STMT_EXECUTE);
+                out.extend_from_slice(&statement_id.to_le_bytes());
+                out.push(*flags);
+                out.extend_from_slice(&iteration_count.to_le_bytes());
+                out.extend_from_slice(parameters);
+            },
+            ClientMessage::StmtClose(statement_id) => {
+                out.push(COM_STMT_CLOSE);
+                out.extend_from_slice(&statement_id.to_le_bytes());
+            },
+            ClientMessage::StmtReset(statement_id) => {
+                out.push(COM_STMT_RESET);
+                out.extend_from_slice(&statement_id.to_le_bytes());
+            },
+        }
+        out
+    }
+}
+
+impl HandshakeResponse {
+
+    fn write(&self, out: &mut Vec<u8>) {
+
+        let capabilities = match self.database {
+            Some(_) => self.capabilities | CLIENT_CONNECT_WITH_DB,
+            None => self.capabilities & !CLIENT_CONNECT_WITH_DB,
+        };
+
+        out.extend_from_slice(&capabilities.to_le_bytes());
+        out.extend_from_slice(&self.max_packet_size.to_le_bytes());
+        out.push(self.character_set);
+        out.extend_from_slice(&[0; 19]);
+        out.extend_from_slice(&self.mariadb_capabilities.to_le_bytes());
+        write_cstring(out, &self.username);
+
+        if capabilities & CLIENT_PLUGIN_AUTH_LENENC_CLIENT_DATA != 0 {
+            write_lenenc_bytes(out, &self.auth_response);
+        } else if capabilities & CLIENT_SECURE_CONNECTION != 0 {
+            out.push(self.auth_response.len() as u8);
+            out.extend_from_slice(&self.auth_response);
+        } else {
+            out.extend_from_slice(&self.auth_response);
+            out.push(0);
+        }
+
+        if let Some(database) = &self.database {
+            write_cstring(out, database);
+        }
+        if capabilities & CLIENT_PLUGIN_AUTH != 0 {
+            write_cstring(out, self.auth_plugin.as_deref().unwrap_or(""));
+        }
+        if capabilities & CLIENT_CONNECT_ATTRS != 0 {
+            let mut attributes = Vec::new();
+            for (name, value) in &self.attributes {
+                write_lenenc_bytes(&mut attributes, name.as_bytes());
+                write_lenenc_bytes(&mut attributes, value.as_bytes());
+            }
+            write_lenenc_bytes(out, &attributes);
+        }
+    }
+}
+
+impl ServerMessage {
+
+    /// An ERR packet with the generic SQL state HY000.
+    pub fn error(code: u16, message: &str) -> ServerMessage {
+        ServerMessage::Err {
+            code,
+            sql_state: "HY000".to_string(),
+            message: message.to_string(),
+        }
+    }
+
+    fn payload(&self, capabilities: u32) -> Vec<u8> {
+
+        let mut out = Vec::new();
+
+        match self {
+            ServerMessage::Handshake(handshake) => handshake.write(&mut out),
+            ServerMessage::Ok{affected_rows, last_insert_id, status_flags, warnings, info} => {
+                out.push(0x00);
+                write_lenenc_int(&mut out, *affected_rows);
+                write_lenenc_int(&mut out, *last_insert_id);
+                out.extend_from_slice(&status_flags.to_le_bytes());
+                out.extend_from_slice(&warnings.to_le_bytes());
+                out.extend_from_slice(info);
+            },
+            ServerMessage::Err{code, sql_state, message} => {
+                out.push(0xff);
+                out.extend_from_slice(&code.to_le_bytes());
+                if !sql_state.is_empty() {
+                    out.push(b'#');
+                    out.extend_from_slice(sql_state.as_bytes());
+                }
+                out.extend_from_slice(message.as_bytes());
+            },
+            ServerMessage::Eof{warnings, status_flags} => {
+                out.push(0xfe);
+                // With CLIENT_DEPRECATE_EOF it is an OK packet with the EOF header
+                if capabilities & CLIENT_DEPRECATE_EOF != 0 {
+                    out.extend_from_slice(&[0, 0]);
+                    out.extend_from_slice(&status_flags.to_le_bytes());
+                    out.extend_from_slice(&warnings.to_le_bytes());
+                } else {
+                    out.extend_from_slice(&warnings.to_le_bytes());
+                    out.extend_from_slice(&status_flags.to_le_bytes());
+                }
+            },
+            ServerMessage::AuthSwitch{plugin, data} => {
+                out.push(0xfe);
+                write_cstring(&mut out, plugin);
+                out.extend_from_slice(data);
+            },
+            ServerMessage::AuthMoreData(data) => {
+                out.push(0x01);
+                out.extend_from_slice(data);
+            },
+            ServerMessage::LocalInfile(file) => {
+                out.push(0xfb);
+                out.extend_from_slice(file.as_bytes());
+            },
+            ServerMessage::ColumnCount(count) => write_lenenc_int(&mut out, *count),
+            ServerMessage::Column(column) => {
+                write_lenenc_bytes(&mut out, b"def");
+                write_lenenc_bytes(&mut out, column.schema.as_bytes());
+                write_lenenc_bytes(&mut out, column.table.as_bytes());
+                write_lenenc_bytes(&mut out, column.org_table.as_bytes());
+                write_lenenc_bytes(&mut out, column.name.as_bytes());
+                write_lenenc_bytes(&mut out, column.org_name.as_bytes());
+                out.push(0x0c);
+                out.extend_from_slice(&column.character_set.to_le_bytes());
+                out.extend_from_slice(&column.column_length.to_le_bytes());
+                out.push(column.column_type);
+                out.extend_from_slice(&column.flags.to_le_bytes());
+                out.push(column.decimals);
+                out.extend_from_slice(&[0, 0]);
+            },
+            ServerMessage::TextRow(values) => {
+                for value in values {
+                    match value {
+                        Some(value) => write_lenenc_bytes(&mut out, value),
+                        None => out.push(0xfb),
+                    }
+                }
+            },
+            ServerMessage::BinaryRow(row) => {
+                out.push(0x00);
+                out.extend_from_slice(row);
+            },
+            ServerMessage::StmtPrepareOk{statement_id, columns, params, warnings} => {
+                out.push(0x00);
+                out.extend_from_slice(&statement_id.to_le_bytes());
+                out.extend_from_slice(&columns.to_le_bytes());
+                out.extend_from_slice(&params.to_le_bytes());
+                out.push(0);
+                out.extend_from_slice(&warnings.to_le_bytes());
+            },
+            ServerMessage::Other(payload) => out.extend_from_slice(payload),
+        }
+        out
+    }
+}
+
+impl Handshake {
+
+    fn write(&self, out: &mut Vec<u8>) {
+
+        let mut data = self.auth_plugin_data.clone();
+        if data.len() < 8 {
+            data.resize(8, 0);
+        }
+
+        out.push(10);
+        write_cstring(out, &self.server_version);
+        out.extend_from_slice(&self.connection_id.to_le_bytes());
+        out.extend_from_slice(&data[..8]);
+        out.push(0);
+        out.extend_from_slice(&(self.capabilities as u16).to_le_bytes());
+        out.push(self.character_set);
+        out.extend_from_slice(&self.status_flags.to_le_bytes());
+        out.extend_from_slice(&((self.capabilities >> 16) as u16).to_le_bytes());
+        out.push(if self.capabilities & CLIENT_PLUGIN_AUTH != 0 { data.len() as u8 } else { 0 });
+        out.extend_from_slice(&[0; 6]);
+        out.extend_from_slice(&self.mariadb_capabilities.to_le_bytes());
+
+        if self.capabilities & CLIENT_SECURE_CONNECTION != 0 {
+            let mut rest = data[8..].to_vec();
+            if rest.len() < 13 {
+                rest.resize(13, 0);
+            }
+            out.extend_from_slice(&rest);
+        }
+        if self.capabilities & CLIENT_PLUGIN_AUTH != 0 {
+            write_cstring(out, &self.auth_plugin);
+        }
+    }
+}
+
+/// Reads packet payloads front to back.
+struct PayloadReader<'a> {
+    payload: &'a [u8],
+    position: usize,
+}
+
+impl<'a> PayloadReader<'a> {
+
+    fn new(payload: &'a [u8]) -> Self {
+        PayloadReader {payload, position: 0}
+    }
+
+    fn done(&self) -> bool {
+        self.position == self.payload.len()
+    }
+
+    fn take(&mut self, length: usize) -> Option<&'a [u8]> {
+        let end = self.position.checked_add(length)?;
+        let bytes = self.payload.get(self.position..end)?;
+        self.position = end;
+        Some(bytes)
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        let rest = &self.payload[self.position..];
+        self.position = self.payload.len();
+        rest
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        let bytes = self.take(2)?;
+        Some(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        let bytes = self.take(4)?;
+        Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// A length encoded integer.
+    fn lenenc(&mut self) -> Option<u64> {
+        let bytes = match self.u8()? {
+            value @ 0..=0xfa => return Some(value as u64),
+            0xfc => self.take(2)?,
+            0xfd => self.take(3)?,
+            0xfe => self.take(8)?,
+            _ => return None,
+        };
+        Some(bytes.iter().rev().fold(0, |value, byte| value << 8 | *byte as u64))
+    }
+
+    fn lenenc_bytes(&mut self) -> Option<&'a [u8]> {
+        let length = self.lenenc()?;
+        self.take(usize::try_from(length).ok()?)
+    }
+
+    fn lenenc_string(&mut self) -> Option<String> {
+        String::from_utf8(self.lenenc_bytes()?.to_vec()).ok()
+    }
+
+    fn cstring(&mut self) -> Option<String> {
+        let length = self.payload.get(self.position..)?.iter().position(|byte| *byte == 0)?;
+        let text = String::from_utf8(self.take(length)?.to_vec()).ok()?;
+        self.position += 1;
+        Some(text)
+    }
+
+    fn string(&mut self) -> Option<String> {
+        String::from_utf8(self.rest().to_vec()).ok()
+    }
+}
+
+/// The payload of the (possibly split) packet at the front of buffer, the sequence id of its first
+/// packet and how many packets it took.
+fn parse_packet(buffer: &[u8]) -> Parsed<(Vec<u8>, u8, usize)> {
+
+    let mut ends = Vec::new();
+    let mut position = 0;
+
+    loop {
+        if buffer.len() < position + 4 {
+            return Parsed::Incomplete;
+        }
+        let length = u32::from_le_bytes([buffer[position], buffer[position + 1], buffer[position + 2], 0]) as usize;
+        if position + length > MAX_MESSAGE_SIZE {
+            return Parsed::Invalid;
+        }
+        position += 4 + length;
+        if buffer.len() < position {
+            return Parsed::Incomplete;
+        }
+        ends.push(position);
+        if length < MAX_PAYLOAD {
+            break;
+        }
+    }
+
+    let mut payload = Vec::with_capacity(position - 4 * ends.len());
+    let mut start = 0;
+    for end in &ends {
+        payload.extend_from_slice(&buffer[start + 4..*end]);
+        start = *end;
+    }
+    Parsed::Complete((payload, buffer[3], ends.len()), position)
+}
+
+fn parse_handshake_response(payload: &[u8]) -> Option<HandshakeResponse> {
+
+    let mut reader = PayloadReader::new(payload);
+    let capabilities = reader.u32()?;
+    let max_packet_size = reader.u32()?;
+    let character_set = reader.u8()?;
+    let reserved = reader.take(23)?;
+    let mariadb_capabilities = match capabilities & CLIENT_MYSQL {
+        0 => read_u32(reserved, 19)?,
+        _ => 0,
+    };
+    let username = reader.cstring()?;
+
+    let auth_response = if capabilities & CLIENT_PLUGIN_AUTH_LENENC_CLIENT_DATA != 0 {
+        reader.lenenc_bytes()?.to_vec()
+    } else if capabilities & CLIENT_SECURE_CONNECTION != 0 {
+        let length = reader.u8()?;
+        reader.take(length as usize)?.to_vec()
+    } else {
+        reader.cstring()?.into_bytes()
+    };
+
+    let database = match capabilities & CLIENT_CONNECT_WITH_DB {
+        0 => None,
+        _ => Some(reader.cstring()?),
+    };
+    let auth_plugin = match capabilities & CLIENT_PLUGIN_AUTH {
+        0 => None,
+        _ => Some(reader.cstring()?),
+    };
+
+    let mut attributes = Vec::new();
+    if capabilities & CLIENT_CONNECT_ATTRS != 0 {
+        let mut attribute_reader = PayloadReader::new(reader.lenenc_bytes()?);
+        while !attribute_reader.done() {
+            attributes.push((attribute_reader.lenenc_string()?, attribute_reader.lenenc_string()?));
+        }
+    }
+
+    match reader.done() {
+        true => Some(HandshakeResponse {
+            capabilities,
+            max_packet_size,
+            character_set,
+            mariadb_capabilities,
+            username,
+            auth_response,
+            database,
+            auth_plugin,
+            attributes,
+        }),
+        false => None,
+    }
+}
+
+fn parse_command(payload: &[u8]) -> ClientMessage {
+
+    let mut reader = PayloadReader::new(payload);
+    let message = match reader.u8() {
+        Some(COM_QUIT) => Some(ClientMessage::Quit),
+        Some(COM_INIT_DB) => reader.string().map(ClientMessage::InitDB),
+        Some(COM_QUERY) => reader.string().map(ClientMessage::Query),
+        Some(COM_PING) => Some(ClientMessage::Ping),
+        Some(COM_STMT_PREPARE) => reader.string().map(ClientMessage::StmtPrepare),
+        Some(COM_STMT_EXECUTE) => (|| {
+            Some(ClientMessage::StmtExecute {
+                statement_id: reader.u32()?,
+                flags: reader.u8()?,
+                iteration_count: reader.u32()?,
+                parameters: reader.rest().to_vec(),
+            })
+        })(),
+        Some(COM_STMT_CLOSE) => reader.u32().map(ClientMessage::StmtClose),
+        Some(COM_STMT_RESET) => reader.u32().map(ClientMessage::StmtReset),
+        _ => None,
+    };
+
+    match message {
+        Some(message) if reader.done() => message,
+        _ => ClientMessage::Other(payload.to_vec()),
+    }
+}
+
+fn parse_server(expect: Expect, payload: &[u8]) -> ServerMessage {
+
+    let mut reader = PayloadReader::new(payload);
+    let header = payload.first().copied();
+
+    let message = match (expect, header) {
+        (_, Some(0xff)) => (|| {
+            reader.u8()?;
+            let code = reader.u16()?;
+            let sql_state = match payload.get(3) {
+                Some(b'#') => {
+                    reader.u8()?;
+                    String::from_utf8(reader.take(5)?.to_vec()).ok()?
+                },
+                _ => String::new(),
+            };
+            Some(ServerMessage::Err{code, sql_state, message: reader.string()?})
+        })(),
+        (Expect::Handshake, Some(10)) => parse_handshake(&mut reader).map(ServerMessage::Handshake),
+        (Expect::Auth, Some(0xfe)) => (|| {
+            reader.u8()?;
+            Some(ServerMessage::AuthSwitch{plugin: reader.cstring()?, data: reader.rest().to_vec()})
+        })(),
+        (Expect::Auth, Some(0x01)) => {
+            reader.u8();
+            Some(ServerMessage::AuthMoreData(reader.rest().to_vec()))
+        },
+        (Expect::ResultSet{..}, Some(0xfb)) => {
+            reader.u8();
+            reader.string().map(ServerMessage::LocalInfile)
+        },
+        (Expect::ResultSet{..}, Some(header)) if header != 0x00 && header != 0xfe => reader.lenenc().map(ServerMessage::ColumnCount),
+        (Expect::Columns{..}, _) | (Expect::Params{..}, _) | (Expect::PrepareColumns{..}, _) => parse_column(&mut reader),
+        (Expect::Rows{..}, Some(0xfe)) if payload.len() < MAX_PAYLOAD => parse_eof(&mut reader),
+        (Expect::Rows{binary: true}, Some(0x00)) => {
+            reader.u8();
+            Some(ServerMessage::BinaryRow(reader.rest().to_vec()))
+        },
+        (Expect::Rows{binary: false}, _) => (|| {
+            let mut values = Vec::new();
+            while !reader.done() {
+                match payload[reader.position] {
+                    0xfb => {
+                        reader.u8()?;
+                        values.push(None);
+                    },
+                    _ => values.push(Some(reader.lenenc_bytes()?.to_vec())),
+                }
+            }
+            Some(ServerMessage::TextRow(values))
+        })(),
+        (Expect::PrepareOk, Some(0x00)) => (|| {
+            reader.u8()?;
+            let statement_id = reader.u32()?;
+            let columns = reader.u16()?;
+            let params = reader.u16()?;
+            reader.u8()?;
+            Some(ServerMessage::StmtPrepareOk{statement_id, columns, params, warnings: reader.u16()?})
+        })(),
+        (_, Some(0x00)) => (|| {
+            reader.u8()?;
+            Some(ServerMessage::Ok {
+                affected_rows: reader.lenenc()?,
+                last_insert_id: reader.lenenc()?,
+                status_flags: reader.u16()?,
+                warnings: reader.u16()?,
+                info: reader.rest().to_vec(),
+            })
+        })(),
+        (_, Some(0xfe)) => parse_eof(&mut reader),
+        _ => None,
+    };
+
+    match message {
+        Some(message) if reader.done() => message,
+        _ => ServerMessage::Other(payload.to_vec()),
+    }
+}
+
+fn parse_handshake(reader: &mut PayloadReader) -> Option<Handshake> {
+
+    reader.u8()?;
+    let server_version = reader.cstring()?;
+    let connection_id = reader.u32()?;
+    let mut auth_plugin_data = reader.take(8)?.to_vec();
+    reader.u8()?;
+    let mut capabilities = reader.u16()? as u32;
+    let character_set = reader.u8()?;
+    let status_flags = reader.u16()?;
+    capabilities |= (reader.u16()? as u32) << 16;
+    let data_length = reader.u8()?;
+    let reserved = reader.take(10)?;
+    let mariadb_capabilities = match capabilities & CLIENT_MYSQL {
+        0 => read_u32(reserved, 6)?,
+        _ => 0,
+    };
+
+    if capabilities & CLIENT_SECURE_CONNECTION != 0 {
+        let length = (data_length as usize).saturating_sub(8).max(13);
+        auth_plugin_data.extend_from_slice(reader.take(length)?);
+    }
+    let auth_plugin = match capabilities & CLIENT_PLUGIN_AUTH {
+        0 => String::new(),
+        _ => reader.cstring()?,
+    };
+
+    Some(Handshake {
+        server_version,
+        connection_id,
+        auth_plugin_data,
+        capabilities,
+        character_set,
+        status_flags,
+        auth_plugin,
+        mariadb_capabilities,
+    })
+}
+
+fn parse_column(reader: &mut PayloadReader) -> Option<ServerMessage> {
+
+    reader.lenenc_bytes()?;
+    let column = MySqlColumn {
+        schema: reader.lenenc_string()?,
+        table: reader.lenenc_string()?,
+        org_table: reader.lenenc_string()?,
+        name: reader.lenenc_string()?,
+        org_name: reader.lenenc_string()?,
+        character_set: {
+            reader.lenenc()?;
+            reader.u16()?
+        },
+        column_length: reader.u32()?,
+        column_type: reader.u8()?,
+        flags: reader.u16()?,
+        decimals: reader.u8()?,
+    };
+    reader.take(2)?;
+    Some(ServerMessage::Column(column))
+}
+
+/// Both the classic EOF packet and an OK packet with the EOF header that carries nothing else.
+fn parse_eof(reader: &mut PayloadReader) -> Option<ServerMessage> {
+
+    reader.u8()?;
+
+    if reader.payload.len() == 5 {
+        return Some(ServerMessage::Eof{warnings: reader.u16()?, status_flags: reader.u16()?});
+    }
+    match (reader.lenenc()?, reader.lenenc()?) {
+        (0, 0) => {
+            let status_flags = reader.u16()?;
+            Some(ServerMessage::Eof{warnings: reader.u16()?, status_flags})
+        },
+        _ => None,
+    }
+}
+
+/// Where the response is at after this packet, None once it is complete.
+fn next_expect(expect: Expect, payload: &[u8], deprecate_eof: bool) -> Option<Expect> {
+
+    let header = payload.first().copied().unwrap_or(0);
+
+    match expect {
+        Expect::Unknown => Some(Expect::Unknown),
+        Expect::Auth => match header {
+            0x00 | 0xff => None,
+            _ => Some(Expect::Auth),
+        },
+        _ if header == 0xff => None,
+        Expect::Handshake | Expect::Status => None,
+        Expect::ResultSet{binary} => match header {
+            0x00 | 0xfe => more_results(payload, binary),
+            0xfb => Some(Expect::ResultSet{binary}),
+            _ => match PayloadReader::new(payload).lenenc() {
+                Some(count) if count > 0 => Some(Expect::Columns{remaining: count, binary}),
+                _ => None,
+            },
+        },
+        Expect::Columns{remaining, binary} if remaining > 1 => Some(Expect::Columns{remaining: remaining - 1, binary}),
+        Expect::Columns{binary, ..} if deprecate_eof => Some(Expect::Rows{binary}),
+        Expect::Columns{binary, ..} => Some(Expect::ColumnsEof{binary}),
+        // A cursor was opened, the rows come with COM_STMT_FETCH
+        Expect::ColumnsEof{..} if status_flags(payload).unwrap_or(0) & SERVER_STATUS_CURSOR_EXISTS != 0 => None,
+        Expect::ColumnsEof{binary} => Some(Expect::Rows{binary}),
+        Expect::Rows{binary} if header == 0xfe && payload.len() < MAX_PAYLOAD => more_results(payload, binary),
+        Expect::Rows{binary} => Some(Expect::Rows{binary}),
+        Expect::PrepareOk => match (header, read_u16(payload, 5), read_u16(payload, 7)) {
+            (0x00, Some(columns), Some(params)) if params > 0 => Some(Expect::Params{remaining: params, columns}),
+            (0x00, Some(columns), _) if columns > 0 => Some(Expect::PrepareColumns{remaining: columns}),
+            _ => None,
+        },
+        Expect::Params{remaining, columns} if remaining > 1 => Some(Expect::Params{remaining: remaining - 1, columns}),
+        Expect::Params{columns, ..} if !deprecate_eof => Some(Expect::ParamsEof{columns}),
+        Expect::Params{columns, ..} | Expect::ParamsEof{columns} => match columns {
+            0 => None,
+            _ => Some(Expect::PrepareColumns{remaining: columns}),
+        },
+        Expect::PrepareColumns{remaining} if remaining > 1 => Some(Expect::PrepareColumns{remaining: remaining - 1}),
+        Expect::PrepareColumns{..} if !deprecate_eof => Some(Expect::PrepareColumnsEof),
+        Expect::PrepareColumns{..} | Expect::PrepareColumnsEof => None,
+    }
+}
+
+/// Another result set follows the one this OK or EOF packet ends.
+fn more_results(payload: &[u8], binary: bool) -> Option<Expect> {
+    match status_flags(payload) {
+        Some(status) if status & SERVER_MORE_RESULTS_EXISTS != 0 => Some(Expect::ResultSet{binary}),
+        _ => None,
+    }
+}
+
+/// The server status of an OK or EOF packet.
+fn status_flags(payload: &[u8]) -> Option<u16> {
+    if payload.first() == Some(&0xfe) && payload.len() == 5 {
+        return read_u16(payload, 3);
+    }
+    let mut reader = PayloadReader::new(payload);
+    reader.u8()?;
+    reader.lenenc()?;
+    reader.lenenc()?;
+    reader.u16()
+}
+
+/// What the server answers a command with, None for commands it does not answer.
+fn command_response(command: u8) -> Option<Expect> {
+    match command {
+        COM_QUIT | COM_STMT_SEND_LONG_DATA | COM_STMT_CLOSE => None,
+        COM_QUERY => Some(Expect::ResultSet{binary: false}),
+        COM_STMT_EXECUTE => Some(Expect::ResultSet{binary: true}),
+        COM_STMT_FETCH => Some(Expect::Rows{binary: true}),
+        COM_STMT_PREPARE => Some(Expect::PrepareOk),
+        COM_CHANGE_USER => Some(Expect::Auth),
+        // COM_PROCESS_KILL, COM_DEBUG, COM_PING, COM_STMT_RESET, COM_SET_OPTION, COM_RESET_CONNECTION
+        COM_INIT_DB | 0x0c | 0x0d | COM_PING | COM_STMT_RESET | 0x1b | 0x1f => Some(Expect::Status),
+        _ => Some(Expect::Unknown),
+    }
+}
+
+/// Writes payload as packets numbered from first, returns how many packets that took.
+fn write_packets(out: &mut Vec<u8>, payload: &[u8], first: u8) -> usize {
+
+    let mut rest = payload;
+    let mut count = 0;
+
+    loop {
+        let length = rest.len().min(MAX_PAYLOAD);
+        out.extend_from_slice(&(length as u32).to_le_bytes()[..3]);
+        out.push(first.wrapping_add(count as u8));
+        out.extend_from_slice(&rest[..length]);
+        rest = &rest[length..];
+        count += 1;
+        // A payload that fills the last packet is ended by an empty one
+        if length < MAX_PAYLOAD {
+            return count;
+        }
+    }
+}
+
+fn read_u16(bytes: &[u8], position: usize) -> Option<u16> {
+    let bytes = bytes.get(position..position + 2)?;
+    Some(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(bytes: &[u8], position: usize) -> Option<u32> {
+    let bytes = bytes.get(position..position + 4)?;
+    Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn write_lenenc_int(out: &mut Vec<u8>, value: u64) {
+    match value {
+        0..=0xfa => out.push(value as u8),
+        0xfb..=0xffff => {
+            out.push(0xfc);
+            out.extend_from_slice(&(value as u16).to_le_bytes());
+        },
+        0x10000..=0xffffff => {
+            out.push(0xfd);
+            out.extend_from_slice(&(value as u32).to_le_bytes()[..3]);
+        },
+        _ => {
+            out.push(0xfe);
+            out.extend_from_slice(&value.to_le_bytes());
+        },
+    }
+}
+
+fn write_lenenc_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    write_lenenc_int(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+fn write_cstring(out: &mut Vec<u8>, text: &str) {
+    out.extend_from_slice(text.as_bytes());
+    out.push(0);
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    struct Blocking;
+
+    impl MySqlHandler for Blocking {
+        fn on_client_message(&mut self, message: &mut ClientMessage) -> MySqlRet {
+            match message {
+                ClientMessage::Query(query) if query.starts_with("DROP") => MySqlRet::Error(1142, "not allowed".to_string()),
+                _ => MySqlRet::Relay,
+            }
+        }
+    }
+
+    const CAPABILITIES: u32 = CLIENT_MYSQL | CLIENT_PROTOCOL_41 | CLIENT_SECURE_CONNECTION | CLIENT_PLUGIN_AUTH;
+
+    fn relayed(callback_ret: CallbackRet) -> Vec<u8> {
+        match callback_ret {
+            CallbackRet::Relay(data) | CallbackRet::RelayAndSpoof(data, _) => data,
+            _ => Vec::new(),
+        }
+    }
+
+    fn spoofed(callback_ret: CallbackRet) -> Vec<u8> {
+        match callback_ret {
+            CallbackRet::Spoof(data) | CallbackRet::RelayAndSpoof(_, data) => data,
+            _ => Vec::new(),
+        }
+    }
+
+    fn packet(payload: &[u8], sequence: u8) -> Vec<u8> {
+        let mut out = Vec::new();
+        write_packets(&mut out, payload, sequence);
+        out
+    }
+
+    fn handshake() -> Handshake {
+        Handshake {
+            server_version: "8.0.36".to_string(),
+            connection_id: 7,
+            auth_plugin_data: (1..=21).collect(),
+            capabilities: CAPABILITIES | CLIENT_CONNECT_WITH_DB | CLIENT_CONNECT_ATTRS,
+            character_set: 255,
+            status_flags: 2,
+            auth_plugin: "caching_sha2_password".to_string(),
+            mariadb_capabilities: 0,
+        }
+    }
+
+    fn handshake_response() -> HandshakeResponse {
+        HandshakeResponse {
+            capabilities: CAPABILITIES | CLIENT_CONNECT_WITH_DB | CLIENT_CONNECT_ATTRS,
+            max_packet_size: 16 * 1024 * 1024,
+            character_set: 255,
+            mariadb_capabilities: 0,
+            username: "root".to_string(),
+            auth_response: vec![0xaa; 32],
+            database: Some("shop".to_string()),
+            auth_plugin: Some("caching_sha2_password".to_string()),
+            attributes: vec![("_client_name".to_string(), "libmysql".to_string())],
+        }
+    }
+
+    fn ok(status_flags: u16) -> ServerMessage {
+        ServerMessage::Ok{affected_rows: 0, last_insert_id: 0, status_flags, warnings: 0, info: Vec::new()}
+    }
+
+    /// A relay past the handshake and authentication.
+    fn connected() -> MySqlRelay<Blocking> {
+        let mut relay = MySqlRelay::new(Blocking);
+
+        let greeting = packet(&ServerMessage::Handshake(handshake()).payload(0), 0);
+        assert_eq!(relayed(relay.us_b_callback(greeting.clone())), greeting);
+        let response = packet(&ClientMessage::HandshakeResponse(handshake_response()).payload(), 1);
+        assert_eq!(relayed(relay.ds_b_callback(response.clone())), response);
+        let ok = packet(&ok(2).payload(CAPABILITIES), 2);
+        assert_eq!(relayed(relay.us_b_callback(ok.clone())), ok);
+        relay
+    }
+
+    #[test]
+    fn client_messages_round_trip() {
+        let messages = [
+            ClientMessage::Quit,
+            ClientMessage::InitDB("shop".to_string()),
+            ClientMessage::Query("SELECT 1".to_string()),
+            ClientMessage::Ping,
+            ClientMessage::StmtPrepare("SELECT ?".to_string()),
+            ClientMessage::StmtExecute{statement_id: 1, flags: 0, iteration_count: 1, parameters: vec![0, 1, 3, 0, 1, 0, 0, 0]},
+            ClientMessage::StmtClose(1),
+            ClientMessage::StmtReset(1),
+            ClientMessage::Other(vec![0x1f]),
+        ];
+        for message in messages {
+            assert_eq!(parse_command(&message.payload()), message);
+        }
+
+        let response = handshake_response();
+        assert_eq!(parse_handshake_response(&ClientMessage::HandshakeResponse(response.clone()).payload()), Some(response));
+    }
+
+    #[test]
+    fn server_messages_round_trip() {
+        let column = MySqlColumn {
+            schema: "shop".to_string(),
+            table: "users".to_string(),
+            org_table: "users".to_string(),
+            name: "id".to_string(),
+            org_name: "id".to_string(),
+            character_set: 63,
+            column_length: 11,
+            column_type: 3,
+            flags: 0x4203,
+            decimals: 0,
+        };
+        let messages = [
+            (Expect::Handshake, ServerMessage::Handshake(handshake())),
+            (Expect::Auth, ServerMessage::AuthSwitch{plugin: "mysql_native_password".to_string(), data: vec![1; 21]}),
+            (Expect::Auth, ServerMessage::AuthMoreData(vec![3])),
+            (Expect::Status, ok(2)),
+            (Expect::Status, ServerMessage::error(1142, "not allowed")),
+            (Expect::ResultSet{binary: false}, ServerMessage::ColumnCount(300)),
+            (Expect::ResultSet{binary: false}, ServerMessage::LocalInfile("/etc/hosts".to_string())),
+            (Expect::Columns{remaining: 1, binary: false}, ServerMessage::Column(column)),
+            (Expect::Rows{binary: false}, ServerMessage::TextRow(vec![Some(b"1".to_vec()), None, Some(vec![b'x'; 300])])),
+            (Expect::Rows{binary: false}, ServerMessage::Eof{warnings: 1, status_flags: 2}),
+            (Expect::Rows{binary: true}, ServerMessage::BinaryRow(vec![0, 1, 0, 0, 0])),
+            (Expect::PrepareOk, ServerMessage::StmtPrepareOk{statement_id: 1, columns: 1, params: 2, warnings: 0}),
+        ];
+        for (expect, message) in messages {
+            assert_eq!(parse_server(expect, &message.payload(CAPABILITIES)), message);
+        }
+
+        // The OK packet that stands in for EOF with CLIENT_DEPRECATE_EOF
+        let eof = ServerMessage::Eof{warnings: 1, status_flags: 2};
+        assert_eq!(parse_server(Expect::Rows{binary: false}, &eof.payload(CAPABILITIES | CLIENT_DEPRECATE_EOF)), eof);
+    }
+
+    #[test]
+    fn long_payloads_are_split_into_packets() {
+        for length in [MAX_PAYLOAD - 1, MAX_PAYLOAD, MAX_PAYLOAD + 10] {
+            let payload = vec![0x42; length];
+            let mut out = Vec::new();
+            let count = write_packets(&mut out, &payload, 3);
+            assert_eq!(count, length / MAX_PAYLOAD + 1);
+
+            match parse_packet(&out) {
+                Parsed::Complete((parsed, sequence, packets), taken) => {
+                    assert!(parsed == payload);
+                    assert_eq!((sequence, packets, taken), (3, count, out.len()));
+                },
+                _ => panic!("{} bytes not parsed", length),
+            }
+            assert!(matches!(parse_packet(&out[..out.len() - 1]), Parsed::Incomplete));
+        }
+    }
+
+    #[test]
+    fn result_sets_are_followed_to_their_end() {
+        let mut relay = connected();
+        let query = packet(&ClientMessage::Query("SELECT 1".to_string()).payload(), 0);
+        assert_eq!(relayed(relay.ds_b_callback(query.clone())), query);
+
+        let response = [
+            packet(&ServerMessage::ColumnCount(1).payload(CAPABILITIES), 1),
+            packet(&ServerMessage::Column(MySqlColumn {
+                schema: String::new(),
+                table: String::new(),
+                org_table: String::new(),
+                name: "1".to_string(),
+                org_name: String::new(),
+                character_set: 63,
+                column_length: 1,
+                column_type: 8,
+                flags: 0x81,
+                decimals: 0,
+            }).payload(CAPABILITIES), 2),
+            packet(&ServerMessage::Eof{warnings: 0, status_flags: 2}.payload(CAPABILITIES), 3),
+            packet(&ServerMessage::TextRow(vec![Some(b"1".to_vec())]).payload(CAPABILITIES), 4),
+            packet(&ServerMessage::Eof{warnings: 0, status_flags: 2}.payload(CAPABILITIES), 5),
+        ].concat();
+        assert_eq!(relayed(relay.us_b_callback(response.clone())), response);
+        assert!(relay.pending.is_empty());
+
+        // Nothing is owed anymore, so the error goes straight back
+        let drop = packet(&ClientMessage::Query("DROP TABLE users".to_string()).payload(), 0);
+        assert_eq!(spoofed(relay.ds_b_callback(drop)), packet(&ServerMessage::error(1142, "not allowed").payload(CAPABILITIES), 1));
+    }
+
+    #[test]
+    fn answers_wait_for_earlier_responses() {
+        let mut relay = connected();
+        let ping = packet(&ClientMessage::Ping.payload(), 0);
+        assert_eq!(relayed(relay.ds_b_callback(ping.clone())), ping);
+        let drop = packet(&ClientMessage::Query("DROP TABLE users".to_string()).payload(), 0);
+        assert!(matches!(relay.ds_b_callback(drop), CallbackRet::Freeze));
+
+        let ok = packet(&ok(2).payload(CAPABILITIES), 1);
+        let error = packet(&ServerMessage::error(1142, "not allowed").payload(CAPABILITIES), 1);
+        assert_eq!(relayed(relay.us_b_callback(ok.clone())), [ok, error].concat());
+    }
+
+    #[test]
+    fn ssl_request_switches_both_sides_to_tls() {
+        let mut relay = MySqlRelay::new(Blocking);
+        let greeting = packet(&ServerMessage::Handshake(handshake()).payload(0), 0);
+        assert_eq!(relayed(relay.us_b_callback(greeting.clone())), greeting);
+
+        let request = ClientMessage::SSLRequest{capabilities: CAPABILITIES | CLIENT_SSL, max_packet_size: 0, character_set: 255, mariadb_capabilities: 0};
+        let request = packet(&request.payload(), 1);
+        match relay.ds_b_callback(request.clone()) {
+            CallbackRet::RelayAndStartTLS(data, read_ahead) => {
+                assert_eq!(data, request);
+                assert!(read_ahead.is_empty());
+            },
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn packets_in_pieces_are_put_together() {
+        let mut relay = connected();
+        let query = packet(&ClientMessage::Query("SELECT 1".to_string()).payload(), 0);
+        assert!(relayed(relay.ds_b_callback(query[..2].to_vec())).is_empty());
+        assert!(relayed(relay.ds_b_callback(query[2..6].to_vec())).is_empty());
+        assert_eq!(relayed(relay.ds_b_callback(query[6..].to_vec())), query);
+    }
+
+    #[test]
+    fn malformed_packets_are_relayed_as_they_are() {
+        // Payloads that do not decode are kept as they are
+        assert_eq!(parse_command(&[COM_STMT_CLOSE, 1]), ClientMessage::Other(vec![COM_STMT_CLOSE, 1]));
+        assert_eq!(parse_server(Expect::Rows{binary: false}, &[0xfc, 1]), ServerMessage::Other(vec![0xfc, 1]));
+        assert_eq!(parse_server(Expect::Status, &[0x00, 0xff]), ServerMessage::Other(vec![0x00, 0xff]));
+        assert_eq!(parse_server(Expect::Handshake, &[10, b'8']), ServerMessage::Other(vec![10, b'8']));
+        let response = ClientMessage::HandshakeResponse(handshake_response()).payload();
+        assert_eq!(parse_handshake_response(&response[..response.len() - 3]), None);
+        assert_eq!(parse_handshake_response(&[response.as_slice(), &[0]].concat()), None);
+
+        let mut relay = connected();
+        let query = packet(&ClientMessage::Query("SELECT 1".to_string()).payload(), 0);
+        assert_eq!(relayed(relay.ds_b_callback(query.clone())), query);
+        let garbage = packet(&[0xfd, 0xff], 1);
+        assert_eq!(relayed(relay.us_b_callback(garbage.clone())), garbage);
+
+        // Clients of the old protocol are relayed without interpretation
+        let mut relay = MySqlRelay::new(Blocking);
+        let old = packet(&[0x8d, 0xa0, 0, 0, 0, 0, 0, b'r', b'o', b'o', b't', 0], 1);
+        assert_eq!(relayed(relay.ds_b_callback(old.clone())), old);
+        assert!(relay.ds_passthrough && relay.us_passthrough);
+        assert_eq!(relayed(relay.ds_b_callback(b"\xff\xff\xff".to_vec())), b"\xff\xff\xff");
+    }
+}
@@ -0,0 +1,91 @@
+use crate::{
+    Arc,
+    Mutex,
+    mpsc,
+    thread,
+    Sender,
+    Receiver,
+};
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Runs accepted sessions for every route of an SSLRelay.
+/// With a worker count the pool keeps that many threads around and queues
+/// sessions that arrive while all of them are busy. Without one every
+/// session gets its own thread.
+pub struct WorkerPool {
+    job_sender: Option<Sender<Job>>,
+    workers: Vec<thread::JoinHandle<()>>,
+}
+
+impl WorkerPool {
+
+    pub fn new(worker_threads: Option<usize>) -> Self {
+
+        let worker_threads = match worker_threads {
+            Some(count) if count > 0 => count,
+            _ => {
+                return WorkerPool {
+                    job_sender: None,
+                    workers: Vec::new(),
+                };
+            }
+        };
+
+        let (job_sender, job_receiver): (Sender<Job>, Receiver<Job>) = mpsc::channel();
+        let job_receiver = Arc::new(Mutex::new(job_receiver));
+        let mut workers = Vec::with_capacity(worker_threads);
+
+        for _ in 0..worker_threads {
+
+            let job_receiver = job_receiver.clone();
+
+            workers.push(thread::spawn(move || {
+                loop {
+                    let job = match job_receiver.lock() {
+                        Ok(receiver) => receiver.recv(),
+                        Err(_e) => return,
+                    };
+                    match job {
+                        Ok(job) => job(),
+                        Err(_e) => return,
+                    }
+                }
+            }));
+        }
+
+        WorkerPool {
+            job_sender: Some(job_sender),
+            workers,
+        }
+    }
+
+    pub fn execute<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        match &self.job_sender {
+            Some(job_sender) => {
+                if let Err(e) = job_sender.send(Box::new(job)) {
+                    tracing::error!(error = %e, "worker pool is closed");
+                }
+            },
+            None => {
+                thread::spawn(job);
+            }
+        }
+    }
+}
+
+impl Drop for WorkerPool {
+
+    fn drop(&mut self) {
+
+        // Closing the job channel lets every worker finish its current session and exit.
+        self.job_sender.take();
+
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
@@ -0,0 +1,854 @@
+//! PostgreSQL aware callbacks.
+//!
+//! PostgresRelay wraps a PostgresHandler and implements HandlerCallbacks for it. It follows the
+//! frontend/backend protocol (version 3) of both legs and hands every message to the handler,
+//! decoded where the handler is likely to care: startup, simple and extended queries, rows and errors.
+//! ```ignore
+//! use sslrelay::postgres::{PostgresRelay, PostgresHandler, PostgresRet, FrontendMessage};
+//!
+//! struct Handler;
+//!
+//! impl PostgresHandler for Handler {
+//!     fn on_frontend_message(&mut self, message: &mut FrontendMessage) -> PostgresRet {
+//!         match message {
+//!             FrontendMessage::Query(query) | FrontendMessage::Parse{query, ..} if query.contains("DROP") => {
+//!                 PostgresRet::Error("42501".to_string(), "not through this relay".to_string())
+//!             },
+//!             FrontendMessage::Query(query) => {
+//!                 *query = query.replace("FROM users", "FROM users_masked");
+//!                 PostgresRet::Relay
+//!             },
+//!             _ => PostgresRet::Relay,
+//!         }
+//!     }
+//! }
+//!
+//! let mut relay = sslrelay::SSLRelay::new(PostgresRelay::new(Handler), config);
+//! ```
+//! An SSLRequest is passed on to the server. If the server agrees both legs switch to TLS
+//! (see CallbackRet::RelayAndStartTLS), which needs a tls_config in RelayConfig. SCRAM channel
+//! binding can not work across the two TLS sessions, so SCRAM-SHA-256-PLUS is taken out of the
+//! mechanisms the server offers. Data that does not parse is relayed untouched from there on.
+
+use crate::{
+    HandlerCallbacks,
+    CallbackRet,
+    CloseReason,
+    SessionHandle,
+    VecDeque,
+    http::callback_ret,
+};
+
+/// A message from the client.
+#[derive(Clone, Debug, PartialEq)]
+pub enum FrontendMessage {
+    Startup{version: u32, parameters: Vec<(String, String)>},// user, database, application_name, ...
+    SSLRequest,
+    GSSENCRequest,// Always answered with 'N', GSSAPI encryption can not be relayed
+    CancelRequest(Vec<u8>),// Process id and secret key
+    Query(String),
+    Parse{name: String, query: String, parameter_types: Vec<u32>},
+    Bind{portal: String, statement: String, parameter_formats: Vec<i16>, parameters: Vec<Option<Vec<u8>>>, result_formats: Vec<i16>},
+    Execute{portal: String, max_rows: i32},
+    Sync,
+    Terminate,
+    Other(u8, Vec<u8>),// Any other message, its type byte and body
+}
+
+/// A message from the server.
+#[derive(Clone, Debug, PartialEq)]
+pub enum BackendMessage {
+    ParameterStatus(String, String),
+    RowDescription(Vec<PostgresField>),
+    DataRow(Vec<Option<Vec<u8>>>),// Column values, None for NULL
+    CommandComplete(String),
+    ErrorResponse(Vec<(u8, String)>),// Field type ('S', 'C', 'M', ...) and value
+    NoticeResponse(Vec<(u8, String)>),
+    ReadyForQuery(u8),// Transaction status 'I', 'T' or 'E'
+    Other(u8, Vec<u8>),// Any other message, its type byte and body
+}
+
+/// A column of a RowDescription.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PostgresField {
+    pub name: String,
+    pub table_oid: u32,
+    pub column: i16,
+    pub type_oid: u32,
+    pub type_size: i16,
+    pub type_modifier: i32,
+    pub format: i16,
+}
+
+/// What PostgresRelay does with a message after the handler has seen it.
+#[derive(Debug)]
+pub enum PostgresRet {
+    Relay,// Relay the (possibly modified) message
+    Drop,// Dont relay the message
+    Error(String, String),// Fail the message with this SQLSTATE and error message, the server never sees it
+    Shutdown,// Shutdown TCP connection
+}
+
+/// Callbacks for PostgreSQL traffic, used through PostgresRelay.
+pub trait PostgresHandler {
+    fn on_frontend_message(&mut self, _message: &mut FrontendMessage) -> PostgresRet {PostgresRet::Relay}
+    /// PostgresRet::Error replaces the message with an ErrorResponse.
+    fn on_backend_message(&mut self, _message: &mut BackendMessage) -> PostgresRet {PostgresRet::Relay}
+    fn open_callback(&mut self, _session: SessionHandle){}
+    fn close_callback(&mut self, _reason: CloseReason){}
+}
+
+/// Turns a PostgresHandler into HandlerCallbacks.
+pub struct PostgresRelay<T: PostgresHandler> {
+    handler: T,
+    ds_buffer: Vec<u8>,
+    us_buffer: Vec<u8>,
+    ds_passthrough: bool,
+    us_passthrough: bool,
+    // The client's next message is a startup message, which has no type byte
+    startup: bool,
+    // The server's next byte answers an SSLRequest
+    ssl_response: bool,
+    // Error for a failed extended query message, the rest of it up to Sync is dropped
+    failed: Option<BackendMessage>,
+    // One entry per ReadyForQuery the server owes, with the error to send in front of it
+    pending_ready: VecDeque<Option<BackendMessage>>,
+}
+
+enum Parsed<T> {
+    Complete(T, usize),// The message and how many bytes of the buffer it took
+    Incomplete,
+    Invalid,
+}
+
+const SSL_REQUEST: u32 = 80877103;
+const GSSENC_REQUEST: u32 = 80877104;
+const CANCEL_REQUEST: u32 = 80877102;
+
+// Sanity limit for a single message, the server's own is 1GB
+const MAX_MESSAGE_SIZE: usize = 1024 * 1024 * 1024;
+
+impl<T: PostgresHandler> PostgresRelay<T> {
+
+    pub fn new(handler: T) -> Self {
+        PostgresRelay {
+            handler,
+            ds_buffer: Vec::new(),
+            us_buffer: Vec::new(),
+            ds_passthrough: false,
+            us_passthrough: false,
+            startup: true,
+            ssl_response: false,
+            failed: None,
+            pending_ready: VecDeque::new(),
+        }
+    }
+}
+
+impl<T: PostgresHandler + Clone> Clone for PostgresRelay<T> {
+
+    /// Clones the handler into a relay that has not seen any traffic.
+    fn clone(&self) -> Self {
+        PostgresRelay::new(self.handler.clone())
+    }
+}
+
+impl<T: PostgresHandler> HandlerCallbacks for PostgresRelay<T> {
+
+    fn ds_b_callback(&mut self, in_data: Vec<u8>) -> CallbackRet {
+
+        if self.ds_passthrough {
+            return CallbackRet::Relay(in_data);
+        }
+        self.ds_buffer.extend(in_data);
+
+        // Direct TLS (PostgreSQL 17 sslnegotiation=direct) starts with a ClientHello
+        if self.startup && self.ds_buffer.first() == Some(&0x16) {
+            self.ds_passthrough = true;
+            self.us_passthrough = true;
+            return CallbackRet::Relay(std::mem::take(&mut self.ds_buffer));
+        }
+
+        let mut relay_data = Vec::new();
+        let mut spoof_data = Vec::new();
+
+        loop {
+            let parsed = match self.startup {
+                true => parse_startup(&self.ds_buffer),
+                false => parse_frontend(&self.ds_buffer),
+            };
+            let mut message = match parsed {
+                Parsed::Complete(message, length) => {
+                    self.ds_buffer.drain(..length);
+                    message
+                },
+                Parsed::Incomplete => break,
+                Parsed::Invalid => {
+                    self.ds_passthrough = true;
+                    relay_data.append(&mut self.ds_buffer);
+                    break;
+                },
+            };
+
+            // The rest of a failed extended query goes nowhere, the Sync ending it is still relayed
+            if self.failed.is_some() && message != FrontendMessage::Sync {
+                continue;
+            }
+
+            let ret = self.handler.on_frontend_message(&mut message);
+
+            match (ret, &message) {
+                (PostgresRet::Relay, FrontendMessage::SSLRequest) => {
+                    message.write(&mut relay_data);
+                    self.ssl_response = true;
+                },
+                (PostgresRet::Relay, FrontendMessage::GSSENCRequest) => spoof_data.push(b'N'),
+                (PostgresRet::Relay, _) => {
+                    if let FrontendMessage::Startup{..} = message {
+                        self.startup = false;
+                    }
+                    if expects_ready(&message) {
+                        self.pending_ready.push_back(self.failed.take());
+                    }
+                    message.write(&mut relay_data);
+                },
+                (PostgresRet::Drop, _) => {},
+                (PostgresRet::Error(code, error), _) => {
+
+                    let error = BackendMessage::error(&code, &error);
+
+                    if self.startup {
+                        // Nothing comes after a failed startup
+                        error.write(&mut spoof_data);
+                        return CallbackRet::SpoofAndHalfClose(spoof_data);
+                    }
+
+                    match message {
+                        // The server still answers the Sync or a stand-in for the query, so the
+                        // error reaches the client in order, right before its ReadyForQuery
+                        FrontendMessage::Query(_) | FrontendMessage::Sync | FrontendMessage::Other(b'F', _) => {
+                            FrontendMessage::Sync.write(&mut relay_data);
+                            self.pending_ready.push_back(Some(error));
+                        },
+                        _ => self.failed = Some(error),
+                    }
+                },
+                (PostgresRet::Shutdown, _) => return CallbackRet::Shutdown,
+            }
+        }
+
+        callback_ret(relay_data, spoof_data)
+    }
+
+    fn us_b_callback(&mut self, in_data: Vec<u8>) -> CallbackRet {
+
+        if self.us_passthrough {
+            return CallbackRet::Relay(in_data);
+        }
+        self.us_buffer.extend(in_data);
+
+        let mut relay_data = Vec::new();
+
+        if self.ssl_response {
+            let answer = match self.us_buffer.first() {
+                Some(answer) => *answer,
+                None => return CallbackRet::Freeze,
+            };
+            self.ssl_response = false;
+            match answer {
+                b'S' => {
+                    self.us_buffer.remove(0);
+                    return CallbackRet::RelayAndStartTLS(vec![b'S'], std::mem::take(&mut self.us_buffer));
+                },
+                b'N' => relay_data.push(self.us_buffer.remove(0)),
+                // An error from a server that does not know SSLRequest
+                _ => {
+                    self.us_passthrough = true;
+                    return CallbackRet::Relay(std::mem::take(&mut self.us_buffer));
+                },
+            }
+        }
+
+        loop {
+            let mut message = match parse_backend(&self.us_buffer) {
+                Parsed::Complete(message, length) => {
+                    self.us_buffer.drain(..length);
+                    message
+                },
+                Parsed::Incomplete => break,
+                Parsed::Invalid => {
+                    self.us_passthrough = true;
+                    relay_data.append(&mut self.us_buffer);
+                    break;
+                },
+            };
+
+            if let BackendMessage::ReadyForQuery(_) = message {
+                if let Some(Some(error)) = self.pending_ready.pop_front() {
+                    error.write(&mut relay_data);
+                }
+            }
+            if let BackendMessage::Other(b'R', body) = &mut message {
+                remove_channel_binding(body);
+            }
+
+            match self.handler.on_backend_message(&mut message) {
+                PostgresRet::Relay => message.write(&mut relay_data),
+                PostgresRet::Drop => {},
+                PostgresRet::Error(code, error) => BackendMessage::error(&code, &error).write(&mut relay_data),
+                PostgresRet::Shutdown => return CallbackRet::Shutdown,
+            }
+        }
+
+        callback_ret(relay_data, Vec::new())
+    }
+
+    fn open_callback(&mut self, session: SessionHandle) {
+        self.handler.open_callback(session);
+    }
+
+    fn close_callback(&mut self, reason: CloseReason) {
+        self.handler.close_callback(reason);
+    }
+}
+
+impl FrontendMessage {
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.write(&mut out);
+        out
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+
+        let mut body = Vec::new();
+
+        let message_type = match self {
+            FrontendMessage::Startup{version, parameters} => {
+                body.extend_from_slice(&version.to_be_bytes());
+                for (name, value) in parameters {
+                    write_cstring(&mut body, name);
+                    write_cstring(&mut body, value);
+                }
+                body.push(0);
+                None
+            },
+            FrontendMessage::SSLRequest => {
+                body.extend_from_slice(&SSL_REQUEST.to_be_bytes());
+                None
+            },
+            FrontendMessage::GSSENCRequest => {
+                body.extend_from_slice(&GSSENC_REQUEST.to_be_bytes());
+                None
+            },
+            FrontendMessage::CancelRequest(key) => {
+                body.extend_from_slice(&CANCEL_REQUEST.to_be_bytes());
+                body.extend_from_slice(key);
+                None
+            },
+            FrontendMessage::Query(query) => {
+                write_cstring(&mut body, query);
+                Some(b'Q')
+            },
+            FrontendMessage::Parse{name, query, parameter_types} => {
+                write_cstring(&mut body, name);
+                write_cstring(&mut body, query);
+                body.extend_from_slice(&(parameter_types.len() as i16).to_be_bytes());
+                parameter_types.iter().for_each(|oid| body.extend_from_slice(&oid.to_be_bytes()));
+                Some(b'P')
+            },
+            FrontendMessage::Bind{portal, statement, parameter_formats, parameters, result_formats} => {
+                write_cstring(&mut body, portal);
+                write_cstring(&mut body, statement);
+                body.extend_from_slice(&(parameter_formats.len() as i16).to_be_bytes());
+                parameter_formats.iter().for_each(|format| body.extend_from_slice(&format.to_be_bytes()));
+                write_values(&mut body, parameters);
+                body.extend_from_slice(&(result_formats.len() as i16).to_be_bytes());
+                result_formats.iter().for_each(|format| body.extend_from_slice(&format.to_be_bytes()));
+                Some(b'B')
+            },
+            FrontendMessage::Execute{portal, max_rows} => {
+                write_cstring(&mut body, portal);
+                body.extend_from_slice(&max_rows.to_be_bytes());
+                Some(b'E')
+            },
+            FrontendMessage::Sync => Some(b'S'),
+            FrontendMessage::Terminate => Some(b'X'),
+            FrontendMessage::Other(message_type, other) => {
+                body.extend_from_slice(other);
+                Some(*message_type)
+            },
+        };
+
+        write_message(out, message_type, &body);
+    }
+}
+
+impl BackendMessage {
+
+    /// An ErrorResponse as the server would send it.
+    pub fn error(code: &str, message: &str) -> BackendMessage {
+        BackendMessage::ErrorResponse(vec![
+            (b'S', "ERROR".to_string()),
+            (b'V', "ERROR".to_string()),
+            (b'C', code.to_string()),
+            (b'M', message.to_string()),
+        ])
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.write(&mut out);
+        out
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+
+        let mut body = Vec::new();
+
+        let message_type = match self {
+            BackendMessage::ParameterStatus(name, value) => {
+                write_cstring(&mut body, name);
+                write_cstring(&mut body, value);
+                b'S'
+            },
+            BackendMessage::RowDescription(fields) => {
+                body.extend_from_slice(&(fields.len() as i16).to_be_bytes());
+                for field in fields {
+                    write_cstring(&mut body, &field.name);
+                    body.extend_from_slice(&field.table_oid.to_be_bytes());
+                    body.extend_from_slice(&field.column.to_be_bytes());
+                    body.extend_from_slice(&field.type_oid.to_be_bytes());
+                    body.extend_from_slice(&field.type_size.to_be_bytes());
+                    body.extend_from_slice(&field.type_modifier.to_be_bytes());
+                    body.extend_from_slice(&field.format.to_be_bytes());
+                }
+                b'T'
+            },
+            BackendMessage::DataRow(values) => {
+                write_values(&mut body, values);
+                b'D'
+            },
+            BackendMessage::CommandComplete(tag) => {
+                write_cstring(&mut body, tag);
+                b'C'
+            },
+            BackendMessage::ErrorResponse(fields) | BackendMessage::NoticeResponse(fields) => {
+                for (field_type, value) in fields {
+                    body.push(*field_type);
+                    write_cstring(&mut body, value);
+                }
+                body.push(0);
+                if let BackendMessage::ErrorResponse(_) = self { b'E' } else { b'N' }
+            },
+            BackendMessage::ReadyForQuery(status) => {
+                body.push(*status);
+                b'Z'
+            },
+            BackendMessage::Other(message_type, other) => {
+                body.extend_from_slice(other);
+                *message_type
+            },
+        };
+
+        write_message(out, Some(message_type), &body);
+    }
+}
+
+/// Reads message bodies front to back.
+struct BodyReader<'a> {
+    body: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BodyReader<'a> {
+
+    fn new(body: &'a [u8]) -> Self {
+        BodyReader {body, position: 0}
+    }
+
+    fn done(&self) -> bool {
+        self.position == self.body.len()
+    }
+
+    fn take(&mut self, length: usize) -> Option<&'a [u8]> {
+        let end = self.position.checked_add(length)?;
+        let bytes = self.body.get(self.position..end)?;
+        self.position = end;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn i16(&mut self) -> Option<i16> {
+        let bytes = self.take(2)?;
+        Some(i16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn i32(&mut self) -> Option<i32> {
+        let bytes = self.take(4)?;
+        Some(i32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.i32().map(|value| value as u32)
+    }
+
+    fn cstring(&mut self) -> Option<String> {
+        let length = self.body.get(self.position..)?.iter().position(|byte| *byte == 0)?;
+        let text = String::from_utf8(self.take(length)?.to_vec()).ok()?;
+        self.position += 1;
+        Some(text)
+    }
+
+    /// A count followed by that many length prefixed values, -1 being NULL.
+    fn values(&mut self) -> Option<Vec<Option<Vec<u8>>>> {
+        let count = self.i16()?;
+        let mut values = Vec::with_capacity(count.max(0) as usize);
+        for _ in 0..count {
+            match self.i32()? {
+                -1 => values.push(None),
+                length if length >= 0 => values.push(Some(self.take(length as usize)?.to_vec())),
+                _ => return None,
+            }
+        }
+        Some(values)
+    }
+
+    fn i16_list(&mut self) -> Option<Vec<i16>> {
+        let count = self.i16()?;
+        (0..count).map(|_| self.i16()).collect()
+    }
+}
+
+/// A message without a type byte, the first one on a connection or the one after SSLRequest.
+fn parse_startup(buffer: &[u8]) -> Parsed<FrontendMessage> {
+
+    let (body, length) = match split_message(buffer, 0) {
+        Parsed::Complete(body, length) => (body, length),
+        Parsed::Incomplete => return Parsed::Incomplete,
+        Parsed::Invalid => return Parsed::Invalid,
+    };
+
+    let mut reader = BodyReader::new(body);
+    let code = match reader.u32() {
+        Some(code) => code,
+        None => return Parsed::Invalid,
+    };
+
+    let message = match code {
+        SSL_REQUEST if reader.done() => FrontendMessage::SSLRequest,
+        GSSENC_REQUEST if reader.done() => FrontendMessage::GSSENCRequest,
+        CANCEL_REQUEST => FrontendMessage::CancelRequest(body[4..].to_vec()),
+        version if version >> 16 == 3 => {
+            let mut parameters = Vec::new();
+            loop {
+                match reader.cstring() {
+                    Some(name) if name.is_empty() => break,
+                    Some(name) => match reader.cstring() {
+                        Some(value) => parameters.push((name, value)),
+                        None => return Parsed::Invalid,
+                    },
+                    None => return Parsed::Invalid,
+                }
+            }
+            FrontendMessage::Startup{version, parameters}
+        },
+        _ => return Parsed::Invalid,
+    };
+    Parsed::Complete(message, length)
+}
+
+fn parse_frontend(buffer: &[u8]) -> Parsed<FrontendMessage> {
+
+    let (body, length) = match split_message(buffer, 1) {
+        Parsed::Complete(body, length) => (body, length),
+        Parsed::Incomplete => return Parsed::Incomplete,
+        Parsed::Invalid => return Parsed::Invalid,
+    };
+    let message_type = buffer[0];
+
+    let mut reader = BodyReader::new(body);
+    let message = match message_type {
+        b'Q' => reader.cstring().map(FrontendMessage::Query),
+        b'P' => (|| {
+            let name = reader.cstring()?;
+            let query = reader.cstring()?;
+            let count = reader.i16()?;
+            let parameter_types = (0..count).map(|_| reader.u32()).collect::<Option<Vec<u32>>>()?;
+            Some(FrontendMessage::Parse{name, query, parameter_types})
+        })(),
+        b'B' => (|| {
+            Some(FrontendMessage::Bind{
+                portal: reader.cstring()?,
+                statement: reader.cstring()?,
+                parameter_formats: reader.i16_list()?,
+                parameters: reader.values()?,
+                result_formats: reader.i16_list()?,
+            })
+        })(),
+        b'E' => (|| {
+            Some(FrontendMessage::Execute{portal: reader.cstring()?, max_rows: reader.i32()?})
+        })(),
+        b'S' => Some(FrontendMessage::Sync),
+        b'X' => Some(FrontendMessage::Terminate),
+        _ => None,
+    };
+
+    // Messages that do not decode cleanly are still passed on as they are
+    match message {
+        Some(message) if reader.done() => Parsed::Complete(message, length),
+        _ => Parsed::Complete(FrontendMessage::Other(message_type, body.to_vec()), length),
+    }
+}
+
+fn parse_backend(buffer: &[u8]) -> Parsed<BackendMessage> {
+
+    let (body, length) = match split_message(buffer, 1) {
+        Parsed::Complete(body, length) => (body, length),
+        Parsed::Incomplete => return Parsed::Incomplete,
+        Parsed::Invalid => return Parsed::Invalid,
+    };
+    let message_type = buffer[0];
+
+    let mut reader = BodyReader::new(body);
+    let message = match message_type {
+        b'S' => (|| Some(BackendMessage::ParameterStatus(reader.cstring()?, reader.cstring()?)))(),
+        b'T' => (|| {
+            let count = reader.i16()?;
+            let fields = (0..count).map(|_| {
+                Some(PostgresField {
+                    name: reader.cstring()?,
+                    table_oid: reader.u32()?,
+                    column: reader.i16()?,
+                    type_oid: reader.u32()?,
+                    type_size: reader.i16()?,
+                    type_modifier: reader.i32()?,
+                    format: reader.i16()?,
+                })
+            }).collect::<Option<Vec<PostgresField>>>()?;
+            Some(BackendMessage::RowDescription(fields))
+        })(),
+        b'D' => reader.values().map(BackendMessage::DataRow),
+        b'C' => reader.cstring().map(BackendMessage::CommandComplete),
+        b'E' | b'N' => (|| {
+            let mut fields = Vec::new();
+            loop {
+                match reader.u8()? {
+                    0 => break,
+                    field_type => fields.push((field_type, reader.cstring()?)),
+                }
+            }
+            Some(if message_type == b'E' { BackendMessage::ErrorResponse(fields) } else { BackendMessage::NoticeResponse(fields) })
+        })(),
+        b'Z' => reader.u8().map(BackendMessage::ReadyForQuery),
+        _ => None,
+    };
+
+    match message {
+        Some(message) if reader.done() => Parsed::Complete(message, length),
+        _ => Parsed::Complete(BackendMessage::Other(message_type, body.to_vec()), length),
+    }
+}
+
+/// Splits off the body of the message at the front of buffer, `type_length` being 1 for typed messages.
+/// The length field counts itself but not the type byte.
+fn split_message(buffer: &[u8], type_length: usize) -> Parsed<&[u8]> {
+
+    if buffer.len() < type_length + 4 {
+        return Parsed::Incomplete;
+    }
+    let length_field = &buffer[type_length..type_length + 4];
+    let length = u32::from_be_bytes([length_field[0], length_field[1], length_field[2], length_field[3]]) as usize;
+    if !(4..=MAX_MESSAGE_SIZE).contains(&length) {
+        return Parsed::Invalid;
+    }
+
+    let end = type_length + length;
+    if buffer.len() < end {
+        return Parsed::Incomplete;
+    }
+    Parsed::Complete(&buffer[type_length + 4..end], end)
+}
+
+/// Messages the server answers with a ReadyForQuery.
+fn expects_ready(message: &FrontendMessage) -> bool {
+    matches!(message, FrontendMessage::Query(_) | FrontendMessage::Sync | FrontendMessage::Other(b'F', _))
+}
+
+/// Takes the channel binding variants out of an AuthenticationSASL mechanism list.
+fn remove_channel_binding(body: &mut Vec<u8>) {
+
+    // Authentication code 10, then a list of mechanism names ended by an empty one
+    if body.len() < 4 || body[..4] != 10u32.to_be_bytes() {
+        return;
+    }
+    let mechanisms: Vec<&[u8]> = body[4..].split(|byte| *byte == 0).filter(|name| !name.is_empty()).collect();
+    if !mechanisms.iter().any(|name| name.ends_with(b"-PLUS")) {
+        return;
+    }
+
+    let mut stripped = 10u32.to_be_bytes().to_vec();
+    for name in mechanisms.into_iter().filter(|name| !name.ends_with(b"-PLUS")) {
+        stripped.extend_from_slice(name);
+        stripped.push(0);
+    }
+    stripped.push(0);
+    *body = stripped;
+}
+
+fn write_message(out: &mut Vec<u8>, message_type: Option<u8>, body: &[u8]) {
+    if let Some(message_type) = message_type {
+        out.push(message_type);
+    }
+    out.extend_from_slice(&(body.len() as u32 + 4).to_be_bytes());
+    out.extend_from_slice(body);
+}
+
+fn write_cstring(out: &mut Vec<u8>, text: &str) {
+    out.extend_from_slice(text.as_bytes());
+    out.push(0);
+}
+
+fn write_values(out: &mut Vec<u8>, values: &[Option<Vec<u8>>]) {
+    out.extend_from_slice(&(values.len() as i16).to_be_bytes());
+    for value in values {
+        match value {
+            Some(value) => {
+                out.extend_from_slice(&(value.len() as i32).to_be_bytes());
+                out.extend_from_slice(value);
+            },
+            None => out.extend_from_slice(&(-1i32).to_be_bytes()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    struct Blocking;
+
+    impl PostgresHandler for Blocking {
+        fn on_frontend_message(&mut self, message: &mut FrontendMessage) -> PostgresRet {
+            match message {
+                FrontendMessage::Query(query) if query.starts_with("DROP") => PostgresRet::Error("42501".to_string(), "not allowed".to_string()),
+                _ => PostgresRet::Relay,
+            }
+        }
+    }
+
+    fn relayed(callback_ret: CallbackRet) -> Vec<u8> {
+        match callback_ret {
+            CallbackRet::Relay(data) | CallbackRet::RelayAndSpoof(data, _) => data,
+            _ => Vec::new(),
+        }
+    }
+
+    fn started() -> PostgresRelay<Blocking> {
+        let mut relay = PostgresRelay::new(Blocking);
+        let startup = FrontendMessage::Startup{version: 196608, parameters: vec![("user".to_string(), "postgres".to_string())]};
+        assert_eq!(relayed(relay.ds_b_callback(startup.to_bytes())), startup.to_bytes());
+        relay
+    }
+
+    #[test]
+    fn frontend_messages_round_trip() {
+        let messages = [
+            FrontendMessage::Query("SELECT 1".to_string()),
+            FrontendMessage::Parse{name: "s1".to_string(), query: "SELECT $1".to_string(), parameter_types: vec![23]},
+            FrontendMessage::Bind{portal: String::new(), statement: "s1".to_string(), parameter_formats: vec![0], parameters: vec![Some(b"1".to_vec()), None], result_formats: vec![]},
+            FrontendMessage::Execute{portal: String::new(), max_rows: 0},
+            FrontendMessage::Sync,
+            FrontendMessage::Terminate,
+            FrontendMessage::Other(b'H', Vec::new()),
+        ];
+        for message in messages {
+            match parse_frontend(&message.to_bytes()) {
+                Parsed::Complete(parsed, length) => {
+                    assert_eq!(parsed, message);
+                    assert_eq!(length, message.to_bytes().len());
+                },
+                _ => panic!("{:?} not parsed", message),
+            }
+        }
+
+        let startup = FrontendMessage::Startup{version: 196608, parameters: vec![("user".to_string(), "postgres".to_string())]};
+        for message in [startup, FrontendMessage::SSLRequest, FrontendMessage::GSSENCRequest, FrontendMessage::CancelRequest(vec![0, 0, 0, 1, 0, 0, 0, 2])] {
+            assert!(matches!(parse_startup(&message.to_bytes()), Parsed::Complete(parsed, _) if parsed == message));
+        }
+    }
+
+    #[test]
+    fn backend_messages_round_trip() {
+        let field = PostgresField{name: "id".to_string(), table_oid: 0, column: 0, type_oid: 23, type_size: 4, type_modifier: -1, format: 0};
+        let messages = [
+            BackendMessage::ParameterStatus("server_version".to_string(), "16.0".to_string()),
+            BackendMessage::RowDescription(vec![field]),
+            BackendMessage::DataRow(vec![Some(b"1".to_vec()), None]),
+            BackendMessage::CommandComplete("SELECT 1".to_string()),
+            BackendMessage::error("42501", "not allowed"),
+            BackendMessage::ReadyForQuery(b'I'),
+        ];
+        for message in messages {
+            assert!(matches!(parse_backend(&message.to_bytes()), Parsed::Complete(parsed, _) if parsed == message));
+        }
+    }
+
+    #[test]
+    fn errors_come_right_before_their_ready_for_query() {
+        let mut relay = started();
+        let query = FrontendMessage::Query("DROP TABLE users".to_string()).to_bytes();
+        assert_eq!(relayed(relay.ds_b_callback(query)), FrontendMessage::Sync.to_bytes());
+
+        let ready = BackendMessage::ReadyForQuery(b'I').to_bytes();
+        let expected = [BackendMessage::error("42501", "not allowed").to_bytes(), ready.clone()].concat();
+        assert_eq!(relayed(relay.us_b_callback(ready)), expected);
+    }
+
+    #[test]
+    fn ssl_request_switches_both_sides_to_tls() {
+        let mut relay = PostgresRelay::new(Blocking);
+        assert_eq!(relayed(relay.ds_b_callback(FrontendMessage::SSLRequest.to_bytes())), FrontendMessage::SSLRequest.to_bytes());
+        match relay.us_b_callback(b"S".to_vec()) {
+            CallbackRet::RelayAndStartTLS(data, read_ahead) => {
+                assert_eq!(data, b"S");
+                assert!(read_ahead.is_empty());
+            },
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn channel_binding_is_not_offered() {
+        let mut body = 10u32.to_be_bytes().to_vec();
+        body.extend_from_slice(b"SCRAM-SHA-256-PLUS\0SCRAM-SHA-256\0\0");
+        remove_channel_binding(&mut body);
+        assert_eq!(&body[4..], b"SCRAM-SHA-256\0\0");
+    }
+
+    #[test]
+    fn messages_in_pieces_are_put_together() {
+        let mut relay = started();
+        let query = FrontendMessage::Query("SELECT 1".to_string()).to_bytes();
+        assert!(relayed(relay.ds_b_callback(query[..3].to_vec())).is_empty());
+        assert_eq!(relayed(relay.ds_b_callback(query[3..].to_vec())), query);
+    }
+
+    #[test]
+    fn malformed_messages_end_the_interpretation() {
+        assert!(matches!(split_message(b"Q\0\0\0\x03", 1), Parsed::Invalid));
+        assert!(matches!(split_message(b"Q\x7f\xff\xff\xff", 1), Parsed::Invalid));
+        assert!(matches!(parse_startup(b"\0\0\0\x08\0\x09\0\0"), Parsed::Invalid));
+        // Bodies that do not decode are kept as they are
+        assert!(matches!(parse_backend(b"Z\0\0\0\x06II"), Parsed::Complete(BackendMessage::Other(b'Z', _), 7)));
+
+        let mut relay = started();
+        assert_eq!(relayed(relay.us_b_callback(b"Z\0\0\0\x02".to_vec())), b"Z\0\0\0\x02");
+        assert_eq!(relayed(relay.us_b_callback(b"anything".to_vec())), b"anything");
+    }
+}
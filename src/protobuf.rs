@@ -0,0 +1,763 @@
+//! Schema aware protobuf decoding, used by the grpc module.
+//!
+//! A DescriptorSet is read from the file protoc writes with
+//! `protoc --include_imports --descriptor_set_out=services.pb service.proto`.
+//! It turns encoded messages into a ProtoMessage, a list of named fields that can be
+//! inspected, changed and encoded again.
+//! ```ignore
+//! let descriptors = DescriptorSet::from_file("services.pb")?;
+//! if let Some(mut message) = descriptors.decode("helloworld.HelloRequest", &data) {
+//!     if let Some(ProtoValue::String(name)) = message.get_mut("name") {
+//!         name.push_str(" (relayed)");
+//!     }
+//!     data = message.encode();
+//! }
+//! ```
+
+use std::{
+    convert::{
+        TryFrom,
+        TryInto,
+    },
+    fs,
+    io,
+    path::Path,
+};
+
+use crate::{
+    HashMap,
+};
+
+/// Message and service definitions from a protobuf FileDescriptorSet.
+#[derive(Clone, Debug, Default)]
+pub struct DescriptorSet {
+    // Fully qualified message name without the leading dot
+    messages: HashMap<String, MessageDescriptor>,
+    // "package.Service/Method" to its input and output message names
+    methods: HashMap<String, (String, String)>,
+}
+
+/// A decoded message. Fields keep their order on the wire, repeated fields show up once per value.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProtoMessage {
+    pub type_name: String,
+    pub fields: Vec<ProtoField>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProtoField {
+    pub number: u32,
+    pub name: String,
+    pub value: ProtoValue,
+}
+
+/// Value of a single field. The variant decides how the field is encoded again.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ProtoValue {
+    Double(f64),
+    Float(f32),
+    Int32(i32),
+    Int64(i64),
+    UInt32(u32),
+    UInt64(u64),
+    SInt32(i32),
+    SInt64(i64),
+    Fixed32(u32),
+    Fixed64(u64),
+    SFixed32(i32),
+    SFixed64(i64),
+    Bool(bool),
+    Enum(i32),
+    String(String),
+    Bytes(Vec<u8>),
+    Message(ProtoMessage),
+    Unknown(Vec<u8>),// Field the descriptors do not describe, kept encoded (key included) and written back as it is
+}
+
+#[derive(Clone, Debug)]
+struct MessageDescriptor {
+    fields: HashMap<u32, FieldDescriptor>,
+}
+
+#[derive(Clone, Debug)]
+struct FieldDescriptor {
+    name: String,
+    kind: FieldKind,
+    // Message type of Message fields
+    type_name: String,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum FieldKind {
+    Double,
+    Float,
+    Int64,
+    UInt64,
+    Int32,
+    Fixed64,
+    Fixed32,
+    Bool,
+    String,
+    Group,
+    Message,
+    Bytes,
+    UInt32,
+    Enum,
+    SFixed32,
+    SFixed64,
+    SInt32,
+    SInt64,
+}
+
+const WIRE_VARINT: u8 = 0;
+const WIRE_FIXED64: u8 = 1;
+const WIRE_LENGTH: u8 = 2;
+const WIRE_START_GROUP: u8 = 3;
+const WIRE_END_GROUP: u8 = 4;
+const WIRE_FIXED32: u8 = 5;
+
+// Nested messages deeper than this are kept as Unknown
+const MAX_DEPTH: usize = 64;
+
+/// One field as it was read off the wire.
+enum WireValue<'a> {
+    Varint(u64),
+    Fixed64([u8; 8]),
+    Length(&'a [u8]),
+    Group,
+    Fixed32([u8; 4]),
+}
+
+/// Reads the fields of an encoded message one after another.
+struct WireReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl DescriptorSet {
+
+    /// Reads a FileDescriptorSet as written by protoc --descriptor_set_out.
+    /// Pass --include_imports as well so the messages of imported files can be decoded too.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<DescriptorSet> {
+        DescriptorSet::from_bytes(&fs::read(path)?)
+    }
+
+    pub fn from_bytes(data: &[u8]) -> io::Result<DescriptorSet> {
+
+        let mut descriptors = DescriptorSet::default();
+
+        let mut reader = WireReader::new(data);
+        while let Some(field) = reader.next_field() {
+            if let (1, WireValue::Length(file)) = field {
+                descriptors.add_file(file).ok_or_else(malformed)?;
+            }
+        }
+        if !reader.done() {
+            return Err(malformed());
+        }
+        Ok(descriptors)
+    }
+
+    /// Decodes `data` as the message `type_name` ("package.Message").
+    /// None if the type is not known or the data is not a valid encoding of it.
+    pub fn decode(&self, type_name: &str, data: &[u8]) -> Option<ProtoMessage> {
+        self.decode_message(type_name.trim_start_matches('.'), data, 0)
+    }
+
+    /// Input and output message names of a method, service being "package.Service".
+    pub fn method(&self, service: &str, method: &str) -> Option<(&str, &str)> {
+        self.methods.get(&format!("{}/{}", service, method))
+            .map(|(input, output)| (input.as_str(), output.as_str()))
+    }
+
+    fn add_file(&mut self, file: &[u8]) -> Option<()> {
+
+        let mut package = String::new();
+        let mut messages = Vec::new();
+        let mut services = Vec::new();
+
+        let mut reader = WireReader::new(file);
+        while let Some(field) = reader.next_field() {
+            match field {
+                (2, WireValue::Length(name)) => package = String::from_utf8(name.to_vec()).ok()?,
+                (4, WireValue::Length(message)) => messages.push(message),
+                (6, WireValue::Length(service)) => services.push(service),
+                _ => {},
+            }
+        }
+        if !reader.done() {
+            return None;
+        }
+
+        let prefix = if package.is_empty() { String::new() } else { format!("{}.", package) };
+        for message in messages {
+            self.add_message(&prefix, message)?;
+        }
+        for service in services {
+            self.add_service(&prefix, service)?;
+        }
+        Some(())
+    }
+
+    fn add_message(&mut self, prefix: &str, message: &[u8]) -> Option<()> {
+
+        let mut name = String::new();
+        let mut fields = HashMap::new();
+        let mut nested = Vec::new();
+
+        let mut reader = WireReader::new(message);
+        while let Some(field) = reader.next_field() {
+            match field {
+                (1, WireValue::Length(value)) => name = String::from_utf8(value.to_vec()).ok()?,
+                (2, WireValue::Length(value)) => {
+                    let (number, field) = parse_field(value)?;
+                    fields.insert(number, field);
+                },
+                (3, WireValue::Length(value)) => nested.push(value),
+                _ => {},
+            }
+        }
+        if !reader.done() {
+            return None;
+        }
+
+        let full_name = format!("{}{}", prefix, name);
+        let nested_prefix = format!("{}.", full_name);
+        for message in nested {
+            self.add_message(&nested_prefix, message)?;
+        }
+        self.messages.insert(full_name, MessageDescriptor {fields});
+        Some(())
+    }
+
+    fn add_service(&mut self, prefix: &str, service: &[u8]) -> Option<()> {
+
+        let mut name = String::new();
+        let mut methods = Vec::new();
+
+        let mut reader = WireReader::new(service);
+        while let Some(field) = reader.next_field() {
+            match field {
+                (1, WireValue::Length(value)) => name = String::from_utf8(value.to_vec()).ok()?,
+                (2, WireValue::Length(value)) => methods.push(value),
+                _ => {},
+            }
+        }
+        if !reader.done() {
+            return None;
+        }
+
+        for method in methods {
+
+            let mut method_name = String::new();
+            let mut input = String::new();
+            let mut output = String::new();
+
+            let mut reader = WireReader::new(method);
+            while let Some(field) = reader.next_field() {
+                match field {
+                    (1, WireValue::Length(value)) => method_name = String::from_utf8(value.to_vec()).ok()?,
+                    (2, WireValue::Length(value)) => input = String::from_utf8(value.to_vec()).ok()?,
+                    (3, WireValue::Length(value)) => output = String::from_utf8(value.to_vec()).ok()?,
+                    _ => {},
+                }
+            }
+            if !reader.done() {
+                return None;
+            }
+
+            self.methods.insert(
+                format!("{}{}/{}", prefix, name, method_name),
+                (input.trim_start_matches('.').to_string(), output.trim_start_matches('.').to_string())
+            );
+        }
+        Some(())
+    }
+
+    fn decode_message(&self, type_name: &str, data: &[u8], depth: usize) -> Option<ProtoMessage> {
+
+        let descriptor = self.messages.get(type_name)?;
+        let mut fields = Vec::new();
+
+        let mut reader = WireReader::new(data);
+        loop {
+            let start = reader.position;
+            let (number, value) = match reader.next_field() {
+                Some(field) => field,
+                None => break,
+            };
+            let unknown = || ProtoField {
+                number,
+                name: String::new(),
+                value: ProtoValue::Unknown(data[start..reader.position].to_vec()),
+            };
+
+            let field = match descriptor.fields.get(&number) {
+                Some(field) => field,
+                None => {
+                    fields.push(unknown());
+                    continue;
+                },
+            };
+
+            match value {
+                // Packed repeated scalars
+                WireValue::Length(packed) if field.kind.is_packable() => {
+                    let mut packed_reader = WireReader::new(packed);
+                    let mut values = Vec::new();
+                    while !packed_reader.done() {
+                        values.push(packed_reader.scalar(field.kind)?);
+                    }
+                    fields.extend(values.into_iter().map(|value| ProtoField {number, name: field.name.clone(), value}));
+                },
+                value => match self.decode_value(field, value, depth) {
+                    Some(value) => fields.push(ProtoField {number, name: field.name.clone(), value}),
+                    None => fields.push(unknown()),
+                },
+            }
+        }
+        if !reader.done() {
+            return None;
+        }
+
+        Some(ProtoMessage {type_name: type_name.to_string(), fields})
+    }
+
+    fn decode_value(&self, field: &FieldDescriptor, value: WireValue, depth: usize) -> Option<ProtoValue> {
+
+        let value = match (field.kind, value) {
+            (FieldKind::Double, WireValue::Fixed64(bytes)) => ProtoValue::Double(f64::from_le_bytes(bytes)),
+            (FieldKind::Fixed64, WireValue::Fixed64(bytes)) => ProtoValue::Fixed64(u64::from_le_bytes(bytes)),
+            (FieldKind::SFixed64, WireValue::Fixed64(bytes)) => ProtoValue::SFixed64(i64::from_le_bytes(bytes)),
+            (FieldKind::Float, WireValue::Fixed32(bytes)) => ProtoValue::Float(f32::from_le_bytes(bytes)),
+            (FieldKind::Fixed32, WireValue::Fixed32(bytes)) => ProtoValue::Fixed32(u32::from_le_bytes(bytes)),
+            (FieldKind::SFixed32, WireValue::Fixed32(bytes)) => ProtoValue::SFixed32(i32::from_le_bytes(bytes)),
+            (kind, WireValue::Varint(value)) => varint_value(kind, value)?,
+            (FieldKind::String, WireValue::Length(bytes)) => ProtoValue::String(String::from_utf8(bytes.to_vec()).ok()?),
+            (FieldKind::Bytes, WireValue::Length(bytes)) => ProtoValue::Bytes(bytes.to_vec()),
+            (FieldKind::Message, WireValue::Length(bytes)) if depth < MAX_DEPTH => {
+                ProtoValue::Message(self.decode_message(&field.type_name, bytes, depth + 1)?)
+            },
+            _ => return None,
+        };
+        Some(value)
+    }
+}
+
+impl ProtoMessage {
+
+    /// First value of the field called `name`.
+    pub fn get(&self, name: &str) -> Option<&ProtoValue> {
+        self.fields.iter().find(|field| field.name == name).map(|field| &field.value)
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut ProtoValue> {
+        self.fields.iter_mut().find(|field| field.name == name).map(|field| &mut field.value)
+    }
+
+    /// Every value of the repeated field called `name`.
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a ProtoValue> + 'a {
+        self.fields.iter().filter(move |field| field.name == name).map(|field| &field.value)
+    }
+
+    /// Encodes the message. Repeated scalars are written unpacked, which every parser accepts.
+    pub fn encode(&self) -> Vec<u8> {
+
+        let mut out = Vec::new();
+
+        for field in &self.fields {
+
+            let key = |out: &mut Vec<u8>, wire_type: u8| write_varint(out, ((field.number as u64) << 3) | wire_type as u64);
+
+            match &field.value {
+                ProtoValue::Double(value) => {key(&mut out, WIRE_FIXED64); out.extend_from_slice(&value.to_le_bytes())},
+                ProtoValue::Fixed64(value) => {key(&mut out, WIRE_FIXED64); out.extend_from_slice(&value.to_le_bytes())},
+                ProtoValue::SFixed64(value) => {key(&mut out, WIRE_FIXED64); out.extend_from_slice(&value.to_le_bytes())},
+                ProtoValue::Float(value) => {key(&mut out, WIRE_FIXED32); out.extend_from_slice(&value.to_le_bytes())},
+                ProtoValue::Fixed32(value) => {key(&mut out, WIRE_FIXED32); out.extend_from_slice(&value.to_le_bytes())},
+                ProtoValue::SFixed32(value) => {key(&mut out, WIRE_FIXED32); out.extend_from_slice(&value.to_le_bytes())},
+                // Negative int32 and enum values are sign extended to ten bytes
+                ProtoValue::Int32(value) => {key(&mut out, WIRE_VARINT); write_varint(&mut out, *value as i64 as u64)},
+                ProtoValue::Int64(value) => {key(&mut out, WIRE_VARINT); write_varint(&mut out, *value as u64)},
+                ProtoValue::UInt32(value) => {key(&mut out, WIRE_VARINT); write_varint(&mut out, *value as u64)},
+                ProtoValue::UInt64(value) => {key(&mut out, WIRE_VARINT); write_varint(&mut out, *value)},
+                ProtoValue::SInt32(value) => {key(&mut out, WIRE_VARINT); write_varint(&mut out, ((*value << 1) ^ (*value >> 31)) as u32 as u64)},
+                ProtoValue::SInt64(value) => {key(&mut out, WIRE_VARINT); write_varint(&mut out, ((*value << 1) ^ (*value >> 63)) as u64)},
+                ProtoValue::Bool(value) => {key(&mut out, WIRE_VARINT); write_varint(&mut out, *value as u64)},
+                ProtoValue::Enum(value) => {key(&mut out, WIRE_VARINT); write_varint(&mut out, *value as i64 as u64)},
+                ProtoValue::String(value) => {key(&mut out, WIRE_LENGTH); write_bytes(&mut out, value.as_bytes())},
+                ProtoValue::Bytes(value) => {key(&mut out, WIRE_LENGTH); write_bytes(&mut out, value)},
+                ProtoValue::Message(value) => {key(&mut out, WIRE_LENGTH); write_bytes(&mut out, &value.encode())},
+                ProtoValue::Unknown(encoded) => out.extend_from_slice(encoded),
+            }
+        }
+        out
+    }
+}
+
+impl FieldKind {
+
+    fn from_type(field_type: u64) -> Option<FieldKind> {
+        let kind = match field_type {
+            1 => FieldKind::Double,
+            2 => FieldKind::Float,
+            3 => FieldKind::Int64,
+            4 => FieldKind::UInt64,
+            5 => FieldKind::Int32,
+            6 => FieldKind::Fixed64,
+            7 => FieldKind::Fixed32,
+            8 => FieldKind::Bool,
+            9 => FieldKind::String,
+            10 => FieldKind::Group,
+            11 => FieldKind::Message,
+            12 => FieldKind::Bytes,
+            13 => FieldKind::UInt32,
+            14 => FieldKind::Enum,
+            15 => FieldKind::SFixed32,
+            16 => FieldKind::SFixed64,
+            17 => FieldKind::SInt32,
+            18 => FieldKind::SInt64,
+            _ => return None,
+        };
+        Some(kind)
+    }
+
+    fn is_packable(self) -> bool {
+        !matches!(self, FieldKind::String | FieldKind::Bytes | FieldKind::Message | FieldKind::Group)
+    }
+}
+
+impl<'a> WireReader<'a> {
+
+    fn new(data: &'a [u8]) -> Self {
+        WireReader {data, position: 0}
+    }
+
+    /// Whether all the data was read. A reader that stopped early hit malformed data.
+    fn done(&self) -> bool {
+        self.position == self.data.len()
+    }
+
+    fn next_field(&mut self) -> Option<(u32, WireValue<'a>)> {
+
+        if self.done() {
+            return None;
+        }
+        let start = self.position;
+        let field = self.read_field();
+        if field.is_none() {
+            self.position = start;
+        }
+        field
+    }
+
+    fn read_field(&mut self) -> Option<(u32, WireValue<'a>)> {
+
+        let key = self.varint()?;
+        let number = u32::try_from(key >> 3).ok().filter(|number| *number > 0)?;
+
+        let value = match (key & 0x7) as u8 {
+            WIRE_VARINT => WireValue::Varint(self.varint()?),
+            WIRE_FIXED64 => WireValue::Fixed64(self.take(8)?.try_into().ok()?),
+            WIRE_LENGTH => {
+                let length = usize::try_from(self.varint()?).ok()?;
+                WireValue::Length(self.take(length)?)
+            },
+            WIRE_START_GROUP => {
+                self.skip_group(number, 0)?;
+                WireValue::Group
+            },
+            WIRE_FIXED32 => WireValue::Fixed32(self.take(4)?.try_into().ok()?),
+            _ => return None,
+        };
+        Some((number, value))
+    }
+
+    /// Skips past the end of a group, which has no length of its own.
+    fn skip_group(&mut self, number: u32, depth: usize) -> Option<()> {
+
+        if depth >= MAX_DEPTH {
+            return None;
+        }
+        loop {
+            let key = self.varint()?;
+            let field_number = (key >> 3) as u32;
+            match (key & 0x7) as u8 {
+                WIRE_VARINT => {self.varint()?;},
+                WIRE_FIXED64 => {self.take(8)?;},
+                WIRE_LENGTH => {
+                    let length = usize::try_from(self.varint()?).ok()?;
+                    self.take(length)?;
+                },
+                WIRE_START_GROUP => self.skip_group(field_number, depth + 1)?,
+                WIRE_END_GROUP if field_number == number => return Some(()),
+                WIRE_FIXED32 => {self.take(4)?;},
+                _ => return None,
+            }
+        }
+    }
+
+    /// One element of a packed repeated field.
+    fn scalar(&mut self, kind: FieldKind) -> Option<ProtoValue> {
+        let value = match kind {
+            FieldKind::Double | FieldKind::Fixed64 | FieldKind::SFixed64 => {
+                let bytes: [u8; 8] = self.take(8)?.try_into().ok()?;
+                match kind {
+                    FieldKind::Double => ProtoValue::Double(f64::from_le_bytes(bytes)),
+                    FieldKind::Fixed64 => ProtoValue::Fixed64(u64::from_le_bytes(bytes)),
+                    _ => ProtoValue::SFixed64(i64::from_le_bytes(bytes)),
+                }
+            },
+            FieldKind::Float | FieldKind::Fixed32 | FieldKind::SFixed32 => {
+                let bytes: [u8; 4] = self.take(4)?.try_into().ok()?;
+                match kind {
+                    FieldKind::Float => ProtoValue::Float(f32::from_le_bytes(bytes)),
+                    FieldKind::Fixed32 => ProtoValue::Fixed32(u32::from_le_bytes(bytes)),
+                    _ => ProtoValue::SFixed32(i32::from_le_bytes(bytes)),
+                }
+            },
+            kind => varint_value(kind, self.varint()?)?,
+        };
+        Some(value)
+    }
+
+    fn varint(&mut self) -> Option<u64> {
+        let mut value = 0u64;
+        for shift in (0..70).step_by(7) {
+            let byte = *self.data.get(self.position)?;
+            self.position += 1;
+            value |= ((byte & 0x7f) as u64).checked_shl(shift).unwrap_or(0);
+            if byte & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+        None
+    }
+
+    fn take(&mut self, length: usize) -> Option<&'a [u8]> {
+        let end = self.position.checked_add(length)?;
+        let bytes = self.data.get(self.position..end)?;
+        self.position = end;
+        Some(bytes)
+    }
+}
+
+fn parse_field(data: &[u8]) -> Option<(u32, FieldDescriptor)> {
+
+    let mut name = String::new();
+    let mut number = 0;
+    let mut kind = None;
+    let mut type_name = String::new();
+
+    let mut reader = WireReader::new(data);
+    while let Some(field) = reader.next_field() {
+        match field {
+            (1, WireValue::Length(value)) => name = String::from_utf8(value.to_vec()).ok()?,
+            (3, WireValue::Varint(value)) => number = u32::try_from(value).ok()?,
+            (5, WireValue::Varint(value)) => kind = FieldKind::from_type(value),
+            (6, WireValue::Length(value)) => type_name = String::from_utf8(value.to_vec()).ok()?,
+            _ => {},
+        }
+    }
+    if !reader.done() {
+        return None;
+    }
+
+    let field = FieldDescriptor {
+        name,
+        kind: kind?,
+        type_name: type_name.trim_start_matches('.').to_string(),
+    };
+    Some((number, field))
+}
+
+fn varint_value(kind: FieldKind, value: u64) -> Option<ProtoValue> {
+    let value = match kind {
+        FieldKind::Int32 => ProtoValue::Int32(value as i32),
+        FieldKind::Int64 => ProtoValue::Int64(value as i64),
+        FieldKind::UInt32 => ProtoValue::UInt32(value as u32),
+        FieldKind::UInt64 => ProtoValue::UInt64(value),
+        FieldKind::SInt32 => ProtoValue::SInt32(((value as u32) >> 1) as i32 ^ -((value & 1) as i32)),
+        FieldKind::SInt64 => ProtoValue::SInt64((value >> 1) as i64 ^ -((value & 1) as i64)),
+        FieldKind::Bool => ProtoValue::Bool(value != 0),
+        FieldKind::Enum => ProtoValue::Enum(value as i32),
+        _ => return None,
+    };
+    Some(value)
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn write_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    write_varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+fn malformed() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "malformed FileDescriptorSet")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn varint_field(number: u32, value: u64) -> Vec<u8> {
+        let mut out = Vec::new();
+        write_varint(&mut out, (number as u64) << 3 | WIRE_VARINT as u64);
+        write_varint(&mut out, value);
+        out
+    }
+
+    fn length_field(number: u32, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        write_varint(&mut out, (number as u64) << 3 | WIRE_LENGTH as u64);
+        write_bytes(&mut out, data);
+        out
+    }
+
+    fn field_descriptor(name: &str, number: u64, field_type: u64, type_name: &str) -> Vec<u8> {
+        [
+            length_field(1, name.as_bytes()),
+            varint_field(3, number),
+            varint_field(5, field_type),
+            length_field(6, type_name.as_bytes()),
+        ].concat()
+    }
+
+    /// package test; message Request {...} message Inner {uint64 count = 1;} service Greeter {rpc Hello(Request) returns (Inner);}
+    fn descriptors() -> DescriptorSet {
+        let request = [
+            length_field(1, b"Request"),
+            length_field(2, &field_descriptor("name", 1, 9, "")),
+            length_field(2, &field_descriptor("delta", 2, 17, "")),
+            length_field(2, &field_descriptor("ids", 3, 5, "")),
+            length_field(2, &field_descriptor("inner", 4, 11, ".test.Inner")),
+            length_field(2, &field_descriptor("ratio", 5, 1, "")),
+            length_field(2, &field_descriptor("offset", 6, 5, "")),
+            length_field(2, &field_descriptor("blob", 7, 12, "")),
+            length_field(2, &field_descriptor("checksum", 8, 7, "")),
+            length_field(2, &field_descriptor("flag", 9, 8, "")),
+        ].concat();
+        let inner = [
+            length_field(1, b"Inner"),
+            length_field(2, &field_descriptor("count", 1, 4, "")),
+        ].concat();
+        let method = [
+            length_field(1, b"Hello"),
+            length_field(2, b".test.Request"),
+            length_field(3, b".test.Inner"),
+        ].concat();
+        let service = [length_field(1, b"Greeter"), length_field(2, &method)].concat();
+        let file = [
+            length_field(2, b"test"),
+            length_field(4, &request),
+            length_field(4, &inner),
+            length_field(6, &service),
+        ].concat();
+        DescriptorSet::from_bytes(&length_field(1, &file)).unwrap()
+    }
+
+    fn field(number: u32, name: &str, value: ProtoValue) -> ProtoField {
+        ProtoField {number, name: name.to_string(), value}
+    }
+
+    #[test]
+    fn varints_round_trip() {
+        let mut encoded = Vec::new();
+        write_varint(&mut encoded, 300);
+        assert_eq!(encoded, [0xac, 0x02]);
+
+        for value in [0, 1, 127, 128, 300, u32::MAX as u64, u64::MAX] {
+            let mut encoded = Vec::new();
+            write_varint(&mut encoded, value);
+            let mut reader = WireReader::new(&encoded);
+            assert_eq!(reader.varint(), Some(value));
+            assert!(reader.done());
+        }
+    }
+
+    #[test]
+    fn malformed_varints_are_rejected() {
+        assert_eq!(WireReader::new(&[0x80]).varint(), None);
+        assert_eq!(WireReader::new(&[0xff; 11]).varint(), None);
+    }
+
+    #[test]
+    fn methods_are_looked_up() {
+        let descriptors = descriptors();
+        assert_eq!(descriptors.method("test.Greeter", "Hello"), Some(("test.Request", "test.Inner")));
+        assert_eq!(descriptors.method("test.Greeter", "Goodbye"), None);
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        let message = ProtoMessage {
+            type_name: "test.Request".to_string(),
+            fields: vec![
+                field(1, "name", ProtoValue::String("relay".to_string())),
+                field(2, "delta", ProtoValue::SInt32(-3)),
+                field(3, "ids", ProtoValue::Int32(300)),
+                field(3, "ids", ProtoValue::Int32(1)),
+                field(4, "inner", ProtoValue::Message(ProtoMessage {
+                    type_name: "test.Inner".to_string(),
+                    fields: vec![field(1, "count", ProtoValue::UInt64(u64::MAX))],
+                })),
+                field(5, "ratio", ProtoValue::Double(0.25)),
+                field(6, "offset", ProtoValue::Int32(-1)),
+                field(7, "blob", ProtoValue::Bytes(vec![0, 0xff])),
+                field(8, "checksum", ProtoValue::Fixed32(0xdeadbeef)),
+                field(9, "flag", ProtoValue::Bool(true)),
+            ],
+        };
+        let encoded = message.encode();
+        // The zigzag encoding of -3 and a sign extended -1
+        assert_eq!(&encoded[7..9], [0x10, 0x05]);
+        assert!(encoded.windows(11).any(|window| window == [0x30, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01]));
+
+        let decoded = descriptors().decode("test.Request", &encoded).unwrap();
+        assert_eq!(decoded, message);
+        assert_eq!(decoded.encode(), encoded);
+        assert_eq!(decoded.get_all("ids").count(), 2);
+    }
+
+    #[test]
+    fn packed_fields_are_written_unpacked() {
+        let encoded = length_field(3, &[0x01, 0x02, 0xac, 0x02]);
+        let decoded = descriptors().decode(".test.Request", &encoded).unwrap();
+        let ids: Vec<&ProtoValue> = decoded.get_all("ids").collect();
+        assert_eq!(ids, [&ProtoValue::Int32(1), &ProtoValue::Int32(2), &ProtoValue::Int32(300)]);
+        assert_eq!(decoded.encode(), [varint_field(3, 1), varint_field(3, 2), varint_field(3, 300)].concat());
+    }
+
+    #[test]
+    fn unknown_fields_keep_their_bytes() {
+        let encoded = [varint_field(15, 7), length_field(1, b"a"), length_field(1, &[0xff])].concat();
+        let decoded = descriptors().decode("test.Request", &encoded).unwrap();
+        assert_eq!(decoded.fields[0].value, ProtoValue::Unknown(varint_field(15, 7)));
+        // Not UTF-8, so not a string either
+        assert_eq!(decoded.fields[2].value, ProtoValue::Unknown(length_field(1, &[0xff])));
+        assert_eq!(decoded.encode(), encoded);
+    }
+
+    #[test]
+    fn malformed_messages_are_rejected() {
+        let descriptors = descriptors();
+        assert_eq!(descriptors.decode("test.Missing", b""), None);
+        // A length past the end, a field number of 0, wire type 7 and a fixed32 cut short
+        assert_eq!(descriptors.decode("test.Request", &[0x0a, 0x05, b'a']), None);
+        assert_eq!(descriptors.decode("test.Request", &[0x00, 0x01]), None);
+        assert_eq!(descriptors.decode("test.Request", &[0x0f, 0x01]), None);
+        assert_eq!(descriptors.decode("test.Request", &[0x45, 0x01, 0x02]), None);
+        // An unterminated group
+        assert_eq!(descriptors.decode("test.Request", &[0x53, 0x08, 0x01]), None);
+
+        assert!(DescriptorSet::from_bytes(&[0x0a, 0x10]).is_err());
+        assert!(DescriptorSet::from_bytes(&length_field(1, &[0x22, 0x02, 0x12, 0x7f])).is_err());
+    }
+}
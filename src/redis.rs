@@ -0,0 +1,718 @@
+//! Redis aware callbacks.
+//!
+//! RedisRelay wraps a RedisHandler and implements HandlerCallbacks for it. It parses the RESP2
+//! and RESP3 traffic of both legs into RespValues, hands commands and replies to the handler and
+//! writes them out again. Replies are matched to the commands they answer, including pipelined ones.
+//! ```ignore
+//! use sslrelay::redis::{RedisRelay, RedisHandler, RedisRet, RespValue};
+//!
+//! struct Handler;
+//!
+//! impl RedisHandler for Handler {
+//!     fn on_command(&mut self, command: &mut RespValue) -> RedisRet {
+//!         match command.command_name().as_deref() {
+//!             Some("FLUSHALL") => RedisRet::Reply(RespValue::error("ERR not through this relay")),
+//!             Some("GET") => {
+//!                 command.set_arg(1, b"prefixed:key".to_vec());
+//!                 RedisRet::Relay
+//!             },
+//!             _ => RedisRet::Relay,
+//!         }
+//!     }
+//! }
+//!
+//! let mut relay = sslrelay::SSLRelay::new(RedisRelay::new(Handler), config);
+//! ```
+//! Values the handler relays unchanged keep the bytes they arrived as, inline commands included. Modified
+//! ones are written out in RESP, an inline command as an array of bulk strings.
+//! Data that is not RESP ends the interpretation of that leg, the rest of it is relayed untouched.
+//! So does a value larger than RedisOptions::max_value_size.
+//! Framing::RESP splits a leg into RESP values for plain HandlerCallbacks.
+
+use crate::{
+    HandlerCallbacks,
+    CallbackRet,
+    CloseReason,
+    SessionHandle,
+    VecDeque,
+    http::callback_ret,
+};
+
+/// A RESP2 or RESP3 value.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RespValue {
+    SimpleString(String),// +
+    Error(String),// -
+    Integer(i64),// :
+    BulkString(Option<Vec<u8>>),// $, None is the RESP2 null bulk string
+    Array(Option<Vec<RespValue>>),// *, None is the RESP2 null array
+    Null,// _
+    Boolean(bool),// #
+    Double(f64),// ,
+    BigNumber(String),// (
+    BulkError(Vec<u8>),// !
+    VerbatimString(String, Vec<u8>),// = with its three letter format
+    Map(Vec<(RespValue, RespValue)>),// %
+    Set(Vec<RespValue>),// ~
+    Push(Vec<RespValue>),// >
+    Attribute(Vec<(RespValue, RespValue)>, Box<RespValue>),// | with the value it belongs to
+}
+
+/// What RedisRelay does with a command, reply or push message after the handler has seen it.
+#[derive(Debug)]
+pub enum RedisRet {
+    Relay,// Relay the (possibly modified) value
+    Reply(RespValue),// Commands: answer the client with this instead of relaying. Replies and pushes: relay this instead
+    Drop,// Dont relay the value. A dropped reply leaves the client waiting for one
+    Shutdown,// Shutdown TCP connection
+}
+
+/// Callbacks for Redis traffic, used through RedisRelay.
+pub trait RedisHandler {
+    fn on_command(&mut self, _command: &mut RespValue) -> RedisRet {RedisRet::Relay}
+    /// Called with the command the reply answers.
+    fn on_reply(&mut self, _command: &RespValue, _reply: &mut RespValue) -> RedisRet {RedisRet::Relay}
+    /// Called for RESP3 push messages, and for anything the server sends that answers no command
+    /// (pub/sub messages and subscription replies in RESP2, MONITOR output).
+    fn on_push(&mut self, _push: &mut RespValue) -> RedisRet {RedisRet::Relay}
+    fn open_callback(&mut self, _session: SessionHandle){}
+    fn close_callback(&mut self, _reason: CloseReason){}
+}
+
+/// Options for RedisRelay.
+#[derive(Clone, Debug)]
+pub struct RedisOptions {
+    /// A command or reply larger than this is relayed untouched along with the rest of the leg.
+    pub max_value_size: usize,
+}
+
+impl Default for RedisOptions {
+
+    fn default() -> Self {
+        RedisOptions {
+            max_value_size: 16 * 1024 * 1024,
+        }
+    }
+}
+
+/// Turns a RedisHandler into HandlerCallbacks.
+pub struct RedisRelay<T: RedisHandler> {
+    handler: T,
+    options: RedisOptions,
+    ds_buffer: Vec<u8>,
+    us_buffer: Vec<u8>,
+    // The buffers are not parsed again before they hold this many bytes
+    ds_needed: usize,
+    us_needed: usize,
+    ds_passthrough: bool,
+    us_passthrough: bool,
+    // Commands waiting for their reply, in the order the client sent them
+    pending: VecDeque<Pending>,
+}
+
+enum Pending {
+    Relayed(RespValue),// Sent to the server
+    Answered(RespValue),// Answered by the handler, the reply goes out after the replies before it
+}
+
+pub(crate) enum Parsed<T> {
+    Complete(T, usize),// The value and how many bytes of the buffer it took
+    Incomplete(usize),// At least this many bytes of the buffer are needed
+    Invalid,
+}
+
+// Aggregates nested deeper than this are not accepted
+const MAX_DEPTH: usize = 128;
+
+impl<T: RedisHandler> RedisRelay<T> {
+
+    pub fn new(handler: T) -> Self {
+        Self::with_options(handler, RedisOptions::default())
+    }
+
+    pub fn with_options(handler: T, options: RedisOptions) -> Self {
+        RedisRelay {
+            handler,
+            options,
+            ds_buffer: Vec::new(),
+            us_buffer: Vec::new(),
+            ds_needed: 0,
+            us_needed: 0,
+            ds_passthrough: false,
+            us_passthrough: false,
+            pending: VecDeque::new(),
+        }
+    }
+}
+
+impl<T: RedisHandler + Clone> Clone for RedisRelay<T> {
+
+    /// Clones the handler into a relay that has not seen any traffic.
+    fn clone(&self) -> Self {
+        RedisRelay::with_options(self.handler.clone(), self.options.clone())
+    }
+}
+
+impl<T: RedisHandler> HandlerCallbacks for RedisRelay<T> {
+
+    fn ds_b_callback(&mut self, in_data: Vec<u8>) -> CallbackRet {
+
+        if self.ds_passthrough {
+            return CallbackRet::Relay(in_data);
+        }
+        self.ds_buffer.extend(in_data);
+
+        let mut relay_data = Vec::new();
+        let mut spoof_data = Vec::new();
+
+        // A value arriving in pieces is parsed again only once it can be complete
+        while self.ds_buffer.len() >= self.ds_needed {
+            let (mut command, bytes) = match parse_command(&self.ds_buffer) {
+                Parsed::Complete(command, length) => {
+                    self.ds_needed = 0;
+                    (command, self.ds_buffer.drain(..length).collect::<Vec<u8>>())
+                },
+                Parsed::Incomplete(needed) if needed > self.options.max_value_size => {
+                    self.ds_passthrough = true;
+                    relay_data.append(&mut self.ds_buffer);
+                    break;
+                },
+                Parsed::Incomplete(needed) => {
+                    self.ds_needed = needed;
+                    break;
+                },
+                Parsed::Invalid => {
+                    self.ds_passthrough = true;
+                    relay_data.append(&mut self.ds_buffer);
+                    break;
+                },
+            };
+
+            // Empty lines and arrays are no command to the server, nothing answers them
+            if matches!(&command, RespValue::Array(None)) || matches!(&command, RespValue::Array(Some(args)) if args.is_empty()) {
+                continue;
+            }
+
+            let original = command.clone();
+
+            match self.handler.on_command(&mut command) {
+                RedisRet::Relay => {
+                    match command == original {
+                        true => relay_data.extend(bytes),
+                        false => command.write(&mut relay_data),
+                    }
+                    // Subscription replies do not answer one command each, they go to on_push
+                    if !is_subscription(&command) {
+                        self.pending.push_back(Pending::Relayed(command));
+                    }
+                },
+                RedisRet::Reply(reply) => {
+                    if self.pending.is_empty() {
+                        reply.write(&mut spoof_data);
+                    } else {
+                        self.pending.push_back(Pending::Answered(reply));
+                    }
+                },
+                RedisRet::Drop => {},
+                RedisRet::Shutdown => return CallbackRet::Shutdown,
+            }
+        }
+
+        callback_ret(relay_data, spoof_data)
+    }
+
+    fn us_b_callback(&mut self, in_data: Vec<u8>) -> CallbackRet {
+
+        if self.us_passthrough {
+            return CallbackRet::Relay(in_data);
+        }
+        self.us_buffer.extend(in_data);
+
+        let mut relay_data = Vec::new();
+
+        while self.us_buffer.len() >= self.us_needed {
+            let (mut reply, bytes) = match parse(&self.us_buffer, 0, 0) {
+                Parsed::Complete(reply, length) => {
+                    self.us_needed = 0;
+                    (reply, self.us_buffer.drain(..length).collect::<Vec<u8>>())
+                },
+                Parsed::Incomplete(needed) if needed > self.options.max_value_size => {
+                    self.us_passthrough = true;
+                    relay_data.append(&mut self.us_buffer);
+                    break;
+                },
+                Parsed::Incomplete(needed) => {
+                    self.us_needed = needed;
+                    break;
+                },
+                Parsed::Invalid => {
+                    self.us_passthrough = true;
+                    relay_data.append(&mut self.us_buffer);
+                    break;
+                },
+            };
+
+            let original = reply.clone();
+
+            let command = match self.pending.front() {
+                Some(Pending::Relayed(_)) if !matches!(reply, RespValue::Push(_)) => self.pending.pop_front(),
+                _ => None,
+            };
+            let ret = match command {
+                Some(Pending::Relayed(command)) => self.handler.on_reply(&command, &mut reply),
+                _ => self.handler.on_push(&mut reply),
+            };
+
+            match ret {
+                RedisRet::Relay if reply == original => relay_data.extend(bytes),
+                RedisRet::Relay => reply.write(&mut relay_data),
+                RedisRet::Reply(replacement) => replacement.write(&mut relay_data),
+                RedisRet::Drop => {},
+                RedisRet::Shutdown => return CallbackRet::Shutdown,
+            }
+
+            // Replies of the handler that were waiting for this one
+            while let Some(Pending::Answered(_)) = self.pending.front() {
+                if let Some(Pending::Answered(answer)) = self.pending.pop_front() {
+                    answer.write(&mut relay_data);
+                }
+            }
+        }
+
+        callback_ret(relay_data, Vec::new())
+    }
+
+    fn open_callback(&mut self, session: SessionHandle) {
+        self.handler.open_callback(session);
+    }
+
+    fn close_callback(&mut self, reason: CloseReason) {
+        self.handler.close_callback(reason);
+    }
+}
+
+impl RespValue {
+
+    pub fn error(message: &str) -> RespValue {
+        RespValue::Error(message.to_string())
+    }
+
+    pub fn bulk(data: &[u8]) -> RespValue {
+        RespValue::BulkString(Some(data.to_vec()))
+    }
+
+    /// A command as clients send it, an array of bulk strings.
+    pub fn command(args: &[&str]) -> RespValue {
+        RespValue::Array(Some(args.iter().map(|arg| RespValue::bulk(arg.as_bytes())).collect()))
+    }
+
+    /// Upper case name of a command, the first element of an array.
+    pub fn command_name(&self) -> Option<String> {
+        self.arg(0).map(|name| String::from_utf8_lossy(name).to_ascii_uppercase())
+    }
+
+    /// Element `index` of an array, if it is a bulk or simple string.
+    pub fn arg(&self, index: usize) -> Option<&[u8]> {
+        match self {
+            RespValue::Array(Some(values)) | RespValue::Push(values) => match values.get(index)? {
+                RespValue::BulkString(Some(data)) => Some(data),
+                RespValue::SimpleString(data) => Some(data.as_bytes()),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Replaces element `index` of an array with a bulk string.
+    /// Returns false if the value is not an array that long.
+    pub fn set_arg(&mut self, index: usize, data: Vec<u8>) -> bool {
+        match self {
+            RespValue::Array(Some(values)) | RespValue::Push(values) => match values.get_mut(index) {
+                Some(value) => {
+                    *value = RespValue::BulkString(Some(data));
+                    true
+                },
+                None => false,
+            },
+            _ => false,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.write(&mut out);
+        out
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        match self {
+            RespValue::SimpleString(value) => write_line(out, b'+', value.as_bytes()),
+            RespValue::Error(value) => write_line(out, b'-', value.as_bytes()),
+            RespValue::Integer(value) => write_line(out, b':', value.to_string().as_bytes()),
+            RespValue::BulkString(None) => out.extend_from_slice(b"$-1\r\n"),
+            RespValue::BulkString(Some(data)) => write_blob(out, b'$', data),
+            RespValue::Array(None) => out.extend_from_slice(b"*-1\r\n"),
+            RespValue::Array(Some(values)) => {
+                write_line(out, b'*', values.len().to_string().as_bytes());
+                values.iter().for_each(|value| value.write(out));
+            },
+            RespValue::Null => out.extend_from_slice(b"_\r\n"),
+            RespValue::Boolean(value) => out.extend_from_slice(if *value { b"#t\r\n" } else { b"#f\r\n" }),
+            RespValue::Double(value) => {
+                let value = match value {
+                    value if value.is_nan() => "nan".to_string(),
+                    value if value.is_infinite() => if *value > 0.0 { "inf".to_string() } else { "-inf".to_string() },
+                    value => value.to_string(),
+                };
+                write_line(out, b',', value.as_bytes());
+            },
+            RespValue::BigNumber(value) => write_line(out, b'(', value.as_bytes()),
+            RespValue::BulkError(data) => write_blob(out, b'!', data),
+            RespValue::VerbatimString(format, data) => {
+                let mut blob = format.as_bytes().to_vec();
+                blob.push(b':');
+                blob.extend_from_slice(data);
+                write_blob(out, b'=', &blob);
+            },
+            RespValue::Map(entries) => {
+                write_line(out, b'%', entries.len().to_string().as_bytes());
+                entries.iter().for_each(|(key, value)| {key.write(out); value.write(out)});
+            },
+            RespValue::Set(values) => {
+                write_line(out, b'~', values.len().to_string().as_bytes());
+                values.iter().for_each(|value| value.write(out));
+            },
+            RespValue::Push(values) => {
+                write_line(out, b'>', values.len().to_string().as_bytes());
+                values.iter().for_each(|value| value.write(out));
+            },
+            RespValue::Attribute(entries, value) => {
+                write_line(out, b'|', entries.len().to_string().as_bytes());
+                entries.iter().for_each(|(key, value)| {key.write(out); value.write(out)});
+                value.write(out);
+            },
+        }
+    }
+}
+
+/// Length of the RESP value at the front of buffer, for Framing::RESP.
+/// Inline commands count as values too.
+pub(crate) fn value_length(buffer: &[u8]) -> Parsed<()> {
+    match parse_command(buffer) {
+        Parsed::Complete(_, length) => Parsed::Complete((), length),
+        Parsed::Incomplete(needed) => Parsed::Incomplete(needed),
+        Parsed::Invalid => Parsed::Invalid,
+    }
+}
+
+/// Parses a command, which may also be an inline command ("PING\r\n") as typed into telnet.
+fn parse_command(buffer: &[u8]) -> Parsed<RespValue> {
+
+    match buffer.first() {
+        None => Parsed::Incomplete(1),
+        Some(byte) if is_type_byte(*byte) => parse(buffer, 0, 0),
+        Some(_) => {
+            let end = match buffer.iter().position(|byte| *byte == b'\n') {
+                Some(end) => end,
+                None => return Parsed::Incomplete(buffer.len() + 1),
+            };
+            let line = &buffer[..end];
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            let args = line.split(|byte| *byte == b' ' || *byte == b'\t')
+                .filter(|arg| !arg.is_empty())
+                .map(RespValue::bulk)
+                .collect();
+            Parsed::Complete(RespValue::Array(Some(args)), end + 1)
+        },
+    }
+}
+
+/// Parses the value at `start`. Incomplete counts the bytes needed from the start of buffer.
+fn parse(buffer: &[u8], start: usize, depth: usize) -> Parsed<RespValue> {
+
+    if depth > MAX_DEPTH {
+        return Parsed::Invalid;
+    }
+
+    let (line, mut position) = match read_line(buffer, start) {
+        Some(line) => line,
+        None => return Parsed::Incomplete(buffer.len() + 1),
+    };
+    if line.is_empty() {
+        return Parsed::Invalid;
+    }
+    let (kind, text) = (line[0], &line[1..]);
+    let text_string = || String::from_utf8(text.to_vec()).ok();
+    let number = || std::str::from_utf8(text).ok().and_then(|text| text.parse::<i64>().ok());
+
+    let value = match kind {
+        b'+' => match text_string() {
+            Some(text) => RespValue::SimpleString(text),
+            None => return Parsed::Invalid,
+        },
+        b'-' => match text_string() {
+            Some(text) => RespValue::Error(text),
+            None => return Parsed::Invalid,
+        },
+        b':' => match number() {
+            Some(number) => RespValue::Integer(number),
+            None => return Parsed::Invalid,
+        },
+        b'_' if text.is_empty() => RespValue::Null,
+        b'#' => match text {
+            b"t" => RespValue::Boolean(true),
+            b"f" => RespValue::Boolean(false),
+            _ => return Parsed::Invalid,
+        },
+        b',' => match std::str::from_utf8(text).ok().and_then(|text| text.parse::<f64>().ok()) {
+            Some(number) => RespValue::Double(number),
+            None => return Parsed::Invalid,
+        },
+        b'(' => match text_string() {
+            Some(text) if !text.is_empty() => RespValue::BigNumber(text),
+            _ => return Parsed::Invalid,
+        },
+        b'$' | b'!' | b'=' => {
+            let length = match number() {
+                Some(-1) if kind == b'$' => return Parsed::Complete(RespValue::BulkString(None), position - start),
+                Some(length) if length >= 0 => length as usize,
+                _ => return Parsed::Invalid,
+            };
+            let end = match position.checked_add(length) {
+                Some(end) if end < usize::MAX - 1 => end,
+                _ => return Parsed::Invalid,
+            };
+            if buffer.len() < end + 2 {
+                return Parsed::Incomplete(end + 2);
+            }
+            if &buffer[end..end + 2] != b"\r\n" {
+                return Parsed::Invalid;
+            }
+            let data = buffer[position..end].to_vec();
+            position = end + 2;
+            match kind {
+                b'$' => RespValue::BulkString(Some(data)),
+                b'!' => RespValue::BulkError(data),
+                _ => {
+                    if data.len() < 4 || data[3] != b':' {
+                        return Parsed::Invalid;
+                    }
+                    RespValue::VerbatimString(String::from_utf8_lossy(&data[..3]).into_owned(), data[4..].to_vec())
+                },
+            }
+        },
+        b'*' | b'~' | b'>' | b'%' | b'|' => {
+            let count = match number() {
+                Some(-1) if kind == b'*' => return Parsed::Complete(RespValue::Array(None), position - start),
+                Some(count) if count >= 0 => count as usize,
+                _ => return Parsed::Invalid,
+            };
+            // Maps and attributes hold key value pairs
+            let elements = match kind {
+                b'%' | b'|' => count.saturating_mul(2),
+                _ => count,
+            };
+            // Every element takes at least three bytes, a bogus count can not make us allocate much
+            let mut values = Vec::with_capacity(elements.min((buffer.len() - position) / 3));
+            // An attribute is followed by the value it describes
+            let following = if kind == b'|' { 1 } else { 0 };
+            for index in 0..elements {
+                match parse(buffer, position, depth + 1) {
+                    Parsed::Complete(value, length) => {
+                        values.push(value);
+                        position += length;
+                    },
+                    Parsed::Incomplete(needed) => {
+                        let remaining = (elements - index - 1).saturating_add(following);
+                        return Parsed::Incomplete(needed.saturating_add(remaining.saturating_mul(3)));
+                    },
+                    Parsed::Invalid => return Parsed::Invalid,
+                }
+            }
+            match kind {
+                b'*' => RespValue::Array(Some(values)),
+                b'~' => RespValue::Set(values),
+                b'>' => RespValue::Push(values),
+                _ => {
+                    let mut entries = Vec::with_capacity(count);
+                    let mut values = values.into_iter();
+                    while let (Some(key), Some(value)) = (values.next(), values.next()) {
+                        entries.push((key, value));
+                    }
+                    if kind == b'%' {
+                        RespValue::Map(entries)
+                    } else {
+                        // The attribute comes before the value it describes
+                        match parse(buffer, position, depth + 1) {
+                            Parsed::Complete(value, length) => {
+                                position += length;
+                                RespValue::Attribute(entries, Box::new(value))
+                            },
+                            other => return other,
+                        }
+                    }
+                },
+            }
+        },
+        _ => return Parsed::Invalid,
+    };
+
+    Parsed::Complete(value, position - start)
+}
+
+/// The line starting at `start` without its CRLF, and where the next one starts.
+fn read_line(buffer: &[u8], start: usize) -> Option<(&[u8], usize)> {
+    let end = start + buffer.get(start..)?.windows(2).position(|window| window == b"\r\n")?;
+    Some((&buffer[start..end], end + 2))
+}
+
+fn is_type_byte(byte: u8) -> bool {
+    b"+-:$*_#,(!=%~>|".contains(&byte)
+}
+
+fn is_subscription(command: &RespValue) -> bool {
+    matches!(
+        command.command_name().as_deref(),
+        Some("SUBSCRIBE") | Some("PSUBSCRIBE") | Some("SSUBSCRIBE") | Some("UNSUBSCRIBE") | Some("PUNSUBSCRIBE") | Some("SUNSUBSCRIBE")
+    )
+}
+
+fn write_line(out: &mut Vec<u8>, kind: u8, line: &[u8]) {
+    out.push(kind);
+    out.extend_from_slice(line);
+    out.extend_from_slice(b"\r\n");
+}
+
+fn write_blob(out: &mut Vec<u8>, kind: u8, data: &[u8]) {
+    write_line(out, kind, data.len().to_string().as_bytes());
+    out.extend_from_slice(data);
+    out.extend_from_slice(b"\r\n");
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    struct Answering;
+
+    impl RedisHandler for Answering {
+        fn on_command(&mut self, command: &mut RespValue) -> RedisRet {
+            match command.command_name().as_deref() {
+                Some("PING") => RedisRet::Reply(RespValue::SimpleString("PONG".to_string())),
+                _ => RedisRet::Relay,
+            }
+        }
+    }
+
+    struct Prefixing;
+
+    impl RedisHandler for Prefixing {
+        fn on_command(&mut self, command: &mut RespValue) -> RedisRet {
+            if let Some(key) = command.arg(1).map(|key| [&b"p:"[..], key].concat()) {
+                command.set_arg(1, key);
+            }
+            RedisRet::Relay
+        }
+    }
+
+    fn relayed(callback_ret: CallbackRet) -> Vec<u8> {
+        match callback_ret {
+            CallbackRet::Relay(data) | CallbackRet::RelayAndSpoof(data, _) => data,
+            _ => Vec::new(),
+        }
+    }
+
+    fn spoofed(callback_ret: CallbackRet) -> Vec<u8> {
+        match callback_ret {
+            CallbackRet::Spoof(data) | CallbackRet::RelayAndSpoof(_, data) => data,
+            _ => Vec::new(),
+        }
+    }
+
+    #[test]
+    fn values_round_trip() {
+        let values: [&[u8]; 7] = [
+            b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$-1\r\n",
+            b"%1\r\n+key\r\n~2\r\n:1\r\n#t\r\n",
+            b">2\r\n$7\r\nmessage\r\n_\r\n",
+            b"|1\r\n+ttl\r\n:3\r\n,1.5\r\n",
+            b"=8\r\ntxt:text\r\n",
+            b"!3\r\nERR\r\n",
+            b"(12345678901234567890\r\n",
+        ];
+        for value in values {
+            match parse(value, 0, 0) {
+                Parsed::Complete(parsed, length) => {
+                    assert_eq!(length, value.len());
+                    assert_eq!(parsed.to_bytes(), value);
+                },
+                _ => panic!("{:?} not parsed", String::from_utf8_lossy(value)),
+            }
+        }
+    }
+
+    #[test]
+    fn incomplete_values_need_what_they_announce() {
+        assert!(matches!(parse(b"$5\r\nhel", 0, 0), Parsed::Incomplete(11)));
+        assert!(matches!(parse(b"*3\r\n$5\r\nhel", 0, 0), Parsed::Incomplete(21)));
+        assert!(matches!(parse(b"|1\r\n+ttl\r\n", 0, 0), Parsed::Incomplete(14)));
+        assert!(matches!(parse_command(b"GET ke"), Parsed::Incomplete(7)));
+    }
+
+    #[test]
+    fn values_in_pieces_are_put_together() {
+        let mut relay = RedisRelay::new(Answering);
+        assert!(relayed(relay.ds_b_callback(b"*2\r\n$3\r\nGET\r\n$5\r\nhe".to_vec())).is_empty());
+        assert!(relayed(relay.ds_b_callback(b"l".to_vec())).is_empty());
+        assert_eq!(relayed(relay.ds_b_callback(b"lo\r\n".to_vec())), b"*2\r\n$3\r\nGET\r\n$5\r\nhello\r\n");
+    }
+
+    #[test]
+    fn answers_wait_for_earlier_replies() {
+        let mut relay = RedisRelay::new(Answering);
+        let ret = relay.ds_b_callback(b"GET a\r\nPING\r\n".to_vec());
+        assert_eq!(relayed(ret), b"GET a\r\n");
+        assert_eq!(relayed(relay.us_b_callback(b"$1\r\nb\r\n".to_vec())), b"$1\r\nb\r\n+PONG\r\n");
+        assert_eq!(spoofed(relay.ds_b_callback(b"PING\r\n".to_vec())), b"+PONG\r\n");
+    }
+
+    #[test]
+    fn unchanged_values_keep_their_bytes() {
+        let mut relay = RedisRelay::new(Answering);
+        assert_eq!(relayed(relay.ds_b_callback(b"ECHO  hi\r\n".to_vec())), b"ECHO  hi\r\n");
+        assert_eq!(relayed(relay.us_b_callback(b",1.50\r\n".to_vec())), b",1.50\r\n");
+    }
+
+    #[test]
+    fn modified_values_are_written_in_resp() {
+        let mut relay = RedisRelay::new(Prefixing);
+        assert_eq!(relayed(relay.ds_b_callback(b"GET k\r\n".to_vec())), b"*2\r\n$3\r\nGET\r\n$3\r\np:k\r\n");
+        assert_eq!(relayed(relay.ds_b_callback(b"PING\r\n".to_vec())), b"PING\r\n");
+    }
+
+    #[test]
+    fn oversized_values_are_passed_through() {
+        let mut relay = RedisRelay::with_options(Answering, RedisOptions { max_value_size: 64 });
+        assert_eq!(relayed(relay.us_b_callback(b"$9999999999\r\nab".to_vec())), b"$9999999999\r\nab");
+        assert_eq!(relayed(relay.us_b_callback(b"cd".to_vec())), b"cd");
+        assert_eq!(relayed(relay.ds_b_callback(b"GET k".to_vec())), b"");
+        assert_eq!(relayed(relay.ds_b_callback(vec![b'x'; 64])), [&b"GET k"[..], &[b'x'; 64][..]].concat());
+        assert_eq!(relayed(relay.ds_b_callback(b"PING\r\n".to_vec())), b"PING\r\n");
+    }
+
+    #[test]
+    fn malformed_values_are_invalid() {
+        assert!(matches!(parse(b"?\r\n", 0, 0), Parsed::Invalid));
+        assert!(matches!(parse(b":x\r\n", 0, 0), Parsed::Invalid));
+        assert!(matches!(parse(b"$3\r\nabcd\r\n", 0, 0), Parsed::Invalid));
+        assert!(matches!(parse(b"$-2\r\n", 0, 0), Parsed::Invalid));
+        assert!(matches!(parse(b"$18446744073709551615\r\n", 0, 0), Parsed::Invalid));
+        assert!(matches!(parse(b"=2\r\nab\r\n", 0, 0), Parsed::Invalid));
+        assert!(matches!(parse(&b"*1\r\n".repeat(MAX_DEPTH + 2), 0, 0), Parsed::Invalid));
+
+        let mut relay = RedisRelay::new(Answering);
+        assert_eq!(relayed(relay.us_b_callback(b"?\r\n".to_vec())), b"?\r\n");
+        assert_eq!(relayed(relay.us_b_callback(b"+OK\r\n".to_vec())), b"+OK\r\n");
+    }
+}
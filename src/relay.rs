@@ -2,15 +2,25 @@
 
 use crate::{
     SSLRelay,
+    RelayHandle,
+    RelayRoute,
     HandlerCallbacks,
+    HandlerFactory,
     InnerHandlers,
+    ConnectionInfo,
     TCPDataType,
+    DetectedProtocol,
     TcpListener,
     thread,
     FullDuplexTcp,
     DataStreamType,
     RelayConfig,
     Arc,
+    AtomicBool,
+    AtomicU64,
+    Ordering,
+    Duration,
+    io,
     SslAcceptor,
     Path,
     SslMethod,
@@ -18,97 +28,210 @@ use crate::{
     TLSConfig,
     PKey,
     X509,
+    AlpnError,
+    WorkerPool,
+    UpstreamPool,
+    LoadBalancing,
+    OverflowPolicy,
+    Framing,
+    Shutdown,
+    tls,
+    sniff,
+    SessionMetrics,
 };
 
-impl<H: HandlerCallbacks + std::marker::Sync + std::marker::Send + Clone + 'static> SSLRelay<H> {
+impl<H: HandlerFactory + std::marker::Sync + std::marker::Send + 'static> SSLRelay<H> {
     /// Creates new SSLRelay instance.
+    /// handlers is either a handler that gets cloned for every connection or a HandlerFactory.
     pub fn new(handlers: H, config: RelayConfig) -> Self {
 
         SSLRelay {
-            config,
-            handlers: Some(InnerHandlers{cb: handlers}),
+            routes: vec![RelayRoute{config, factory: Arc::new(handlers)}],
+            worker_threads: None,
+            shutdown: Arc::new(AtomicBool::new(false)),
+            next_connection_id: Arc::new(AtomicU64::new(0)),
+        }
+    }
+    /// Adds another listener -> upstream route to this SSLRelay instance.
+    /// Every route uses its own RelayConfig and handler.
+    pub fn add_route(&mut self, handlers: H, config: RelayConfig) {
+        self.routes.push(RelayRoute{config, factory: Arc::new(handlers)});
+    }
+    /// Limits how many TCP sessions are handled at the same time across all routes.
+    /// Connections accepted while every worker is busy wait for a free worker.
+    /// By default every connection gets its own thread.
+    pub fn set_worker_threads(&mut self, worker_threads: usize) {
+        self.worker_threads = Some(worker_threads);
+    }
+    /// Returns a handle that can shut this relay down from another thread.
+    pub fn handle(&self) -> RelayHandle {
+        RelayHandle {
+            shutdown: self.shutdown.clone(),
         }
     }
     /// Starts the SSLRelay connection handling.
+    /// Blocks until the relay is shut down through its RelayHandle.
     pub fn start(&mut self) {
 
-        let rhost = self.config.remote_host.clone();
-        let rport = self.config.remote_port.clone();
-        let upstream_data_stream_type = self.config.upstream_data_type;
-
-        let listener = TcpListener::bind(format!("{}:{}", self.config.bind_host.clone(), self.config.bind_port.clone())).unwrap();
-
-        match self.config.downstream_data_type {
-
-            TCPDataType::TLS => {
-    
-                let acceptor = self.setup_ssl_config(self.config.tls_config.clone());
-
-                for stream in listener.incoming() {
-        
-                    match stream {
-                        Ok(stream) => {
-        
-                            let acceptor = acceptor.clone();
-                            let handler_clone = self.handlers.as_ref().unwrap().clone();
-        
-                            let r_host = rhost.clone();
-                            let r_port = rport.clone();
-        
-                            thread::spawn(move || {
-        
-                                match acceptor.accept(stream) {
-                                    Ok(stream) => {
-                                        // FULL DUPLEX OBJECT CREATION HERE
-                                       match FullDuplexTcp::new(DataStreamType::TLS(stream), upstream_data_stream_type, r_host, r_port, handler_clone) {
-                                           Ok(mut fdtcp) => fdtcp.handle(),
-                                           Err(_ec) => {println!("[SSLRelay Error] Failed to handle TCP(TLS) connection: {}", _ec)}
-                                       }
-                                    },
-                                    Err(e) => {
-        
-                                        println!("[Error] {}", e);
-                                    }
-                                }
-                            });
-                        },
-                        Err(e) => {println!("[Error] Tcp Connection Failed: {}", e)}
+        let pool = Arc::new(WorkerPool::new(self.worker_threads));
+        let mut route_threads = Vec::new();
+
+        for (route_index, route) in self.routes.iter().enumerate() {
+
+            let listener = match TcpListener::bind(format!("{}:{}", route.config.bind_host, route.config.bind_port)) {
+                Ok(listener) => listener,
+                Err(e) => panic!("[SSLRelay Error] Failed to bind [{}:{}]: {}", route.config.bind_host, route.config.bind_port, e),
+            };
+            if let Err(e) = listener.set_nonblocking(true) {
+                panic!("[SSLRelay Error] Failed to set listener [{}:{}] non blocking: {}", route.config.bind_host, route.config.bind_port, e);
+            }
+
+            // RAW downstreams with a tls_config keep the acceptor for STARTTLS
+            let acceptor = match (route.config.downstream_data_type, &route.config.tls_config) {
+                (TCPDataType::RAW, TLSConfig::NONE) | (TCPDataType::AUTO, TLSConfig::NONE) => None,
+                _ => Some(self.setup_ssl_config(route.config.tls_config.clone(), route.config.alpn_wire_format())),
+            };
+
+            let upstreams = Arc::new(UpstreamPool::new(&route.config));
+            let config = Arc::new(route.config.clone());
+            let factory = route.factory.clone();
+            let pool = pool.clone();
+            let shutdown = self.shutdown.clone();
+            let next_connection_id = self.next_connection_id.clone();
+
+            route_threads.push(thread::spawn(move || {
+                Self::accept_connections(listener, acceptor, route_index, config, factory, upstreams, pool, shutdown, next_connection_id);
+            }));
+        }
+
+        for route_thread in route_threads {
+            let _ = route_thread.join();
+        }
+
+        for route in &self.routes {
+            if let Some(recorder) = &route.config.har {
+                recorder.save_on_shutdown();
+            }
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn accept_connections(listener: TcpListener, acceptor: Option<Arc<SslAcceptor>>, route_index: usize, config: Arc<RelayConfig>, factory: Arc<H>, upstreams: Arc<UpstreamPool>, pool: Arc<WorkerPool>, shutdown: Arc<AtomicBool>, next_connection_id: Arc<AtomicU64>) {
+
+        let metrics = SessionMetrics::new(&config);
+
+        loop {
+
+            if shutdown.load(Ordering::Relaxed) {
+                return;
+            }
+
+            match listener.accept() {
+                Ok((stream, peer_addr)) => {
+
+                    if let Err(e) = stream.set_nonblocking(false) {
+                        tracing::error!(peer = %peer_addr, error = %e, "failed to set TCP stream blocking");
+                        continue;
                     }
-                }
-            },
 
-            TCPDataType::RAW => {
-
-                for stream in listener.incoming() {
-        
-                    match stream {
-                        Ok(stream) => {
-        
-                            let handler_clone = self.handlers.as_ref().unwrap().clone();
-        
-                            let r_host = rhost.clone();
-                            let r_port = rport.clone();
-        
-                            thread::spawn(move || {
-    
-                                // FULL DUPLEX OBJECT CREATION HERE
-                                match FullDuplexTcp::new(DataStreamType::RAW(stream), upstream_data_stream_type, r_host, r_port, handler_clone) {
-                                    Ok(mut fdtcp) => fdtcp.handle(),
-                                    Err(_ec) => println!("[SSLRelay Error] Failed to handle TCP connection: {}", _ec),
+                    let mut connection_info = ConnectionInfo {
+                        connection_id: next_connection_id.fetch_add(1, Ordering::Relaxed),
+                        route: route_index,
+                        client_addr: peer_addr,
+                        local_addr: stream.local_addr().ok(),
+                        protocol: None,
+                        proxy_client_addr: None,
+                        proxy_local_addr: None,
+                    };
+
+                    let acceptor = acceptor.clone();
+                    let factory = factory.clone();
+                    let metrics = metrics.clone();
+                    let active = metrics.as_ref().map(SessionMetrics::connection_accepted);
+
+                    let config = config.clone();
+                    let upstreams = upstreams.clone();
+                    let shutdown = shutdown.clone();
+
+                    pool.execute(move || {
+
+                        let _active = active;
+
+                        // Everything logged for this connection, on any of its threads, falls under this span
+                        let span = tracing::info_span!("session", connection_id = connection_info.connection_id, route = route_index, peer = %peer_addr, proxy_client = tracing::field::Empty);
+                        let _entered = span.enter();
+
+                        let sniffed = match config.downstream_data_type {
+                            TCPDataType::AUTO => {
+                                let proxy_trusted = config.trusted_proxies.contains(&peer_addr.ip());
+                                sniff::detect_protocol(&stream, config.sniff_timeout, proxy_trusted, &mut connection_info)
+                            },
+                            _ => Ok(()),
+                        };
+
+                        if let Some(proxy_client_addr) = connection_info.proxy_client_addr {
+                            span.record("proxy_client", tracing::field::display(proxy_client_addr));
+                        }
+
+                        let inner_handlers = InnerHandlers::new(factory.create(&connection_info), factory.create_nb(&connection_info));
+
+                        if let Err(reason) = sniffed {
+                            tracing::debug!(reason = ?reason, "protocol detection failed");
+                            let _ = stream.shutdown(Shutdown::Both);
+                            if let Some(metrics) = &metrics {
+                                metrics.closed(reason);
+                            }
+                            inner_handlers.lock().close_callback(reason);
+                            return;
+                        }
+
+                        let ds_stream = match (config.downstream_data_type, connection_info.protocol, &acceptor) {
+                            (TCPDataType::TLS, _, Some(acceptor)) | (TCPDataType::AUTO, Some(DetectedProtocol::TLS), Some(acceptor)) => {
+                                match tls::tls_accept(acceptor, stream, Vec::new(), config.handshake_timeout) {
+                                    Ok(stream) => DataStreamType::TLS(stream),
+                                    Err(reason) => {
+                                        tracing::debug!(reason = ?reason, "downstream TLS handshake failed");
+                                        if let Some(metrics) = &metrics {
+                                            metrics.handshake_failed(reason);
+                                            metrics.closed(reason);
+                                        }
+                                        inner_handlers.lock().close_callback(reason);
+                                        return;
+                                    }
                                 }
-                            });
-                        },
-                        Err(e) => {println!("[Error] Tcp Connection Failed: {}", e)}
-                    }
-                }
+                            },
+                            _ => DataStreamType::RAW(stream),
+                        };
+
+                        // FULL DUPLEX OBJECT CREATION HERE
+                        match FullDuplexTcp::new(ds_stream, acceptor, config, upstreams, Some(peer_addr), inner_handlers, shutdown) {
+                            Ok(mut fdtcp) => fdtcp.handle(),
+                            Err(reason) => tracing::debug!(reason = ?reason, "session closed before relaying"),
+                        }
+                    });
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    thread::sleep(Duration::from_millis(50));
+                },
+                Err(e) => tracing::error!(route = route_index, error = %e, "failed to accept a connection"),
             }
         }
     }
 
-    fn setup_ssl_config(&self, tls_config: TLSConfig) -> Arc<SslAcceptor> {
+    fn setup_ssl_config(&self, tls_config: TLSConfig, alpn_protocols: Vec<u8>) -> Arc<SslAcceptor> {
 
         let mut acceptor = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
 
+        if !alpn_protocols.is_empty() {
+            // Pick our most preferred protocol the client also offers, or go on without ALPN
+            acceptor.set_alpn_select_callback(move |_ssl, client_protocols| {
+                let offered = alpn_protocol_list(client_protocols);
+                alpn_protocol_list(&alpn_protocols).into_iter()
+                    .find_map(|ours| offered.iter().find(|theirs| **theirs == ours).copied())
+                    .ok_or(AlpnError::NOACK)
+            });
+        }
+
         match tls_config {
             TLSConfig::FILE{certificate_path, private_key_path} => {
 
@@ -134,4 +257,82 @@ impl<H: HandlerCallbacks + std::marker::Sync + std::marker::Send + Clone + 'stat
         }
         Arc::new(acceptor.build())
     }
+}
+
+impl Default for RelayConfig {
+
+    fn default() -> Self {
+
+        RelayConfig {
+            downstream_data_type: TCPDataType::RAW,
+            upstream_data_type: TCPDataType::RAW,
+            bind_host: "0.0.0.0".to_string(),
+            bind_port: String::new(),
+            remote_host: String::new(),
+            remote_port: String::new(),
+            tls_config: TLSConfig::NONE,
+            alpn_protocols: Vec::new(),
+            extra_remotes: Vec::new(),
+            load_balancing: LoadBalancing::RoundRobin,
+            connect_timeout: Some(Duration::from_secs(10)),
+            max_fails: 3,
+            fail_timeout: Duration::from_secs(30),
+            handshake_timeout: Some(Duration::from_secs(10)),
+            idle_timeout: None,
+            max_session_lifetime: None,
+            half_close: true,
+            high_watermark: 1024 * 1024,
+            low_watermark: 256 * 1024,
+            max_session_memory: None,
+            nb_callback_backlog: 1024,
+            nb_callback_overflow: OverflowPolicy::Block,
+            sniff_timeout: Duration::from_secs(1),
+            trusted_proxies: Vec::new(),
+            capture: None,
+            har: None,
+            session_recorder: None,
+            metrics: None,
+            downstream_framing: Framing::NONE,
+            upstream_framing: Framing::NONE,
+            max_frame_size: 1024 * 1024,
+        }
+    }
+}
+
+impl RelayConfig {
+    /// alpn_protocols as length prefixed protocol names, the way OpenSSL takes them.
+    pub(crate) fn alpn_wire_format(&self) -> Vec<u8> {
+        let mut wire_format = Vec::new();
+        for protocol in self.alpn_protocols.iter().filter(|protocol| !protocol.is_empty() && protocol.len() < 256) {
+            wire_format.push(protocol.len() as u8);
+            wire_format.extend_from_slice(protocol.as_bytes());
+        }
+        wire_format
+    }
+}
+
+/// Splits length prefixed ALPN protocol names.
+fn alpn_protocol_list(wire_format: &[u8]) -> Vec<&[u8]> {
+    let mut protocols = Vec::new();
+    let mut rest = wire_format;
+    while let Some((length, tail)) = rest.split_first() {
+        if tail.len() < *length as usize {
+            break;
+        }
+        let (protocol, tail) = tail.split_at(*length as usize);
+        protocols.push(protocol);
+        rest = tail;
+    }
+    protocols
+}
+
+impl RelayHandle {
+    /// Stops accepting connections on every route and ends all running sessions.
+    pub fn shutdown(&self) {
+        self.shutdown.store(true, Ordering::Relaxed);
+    }
+    /// Returns true once shutdown() has been called.
+    pub fn is_shutdown(&self) -> bool {
+        self.shutdown.load(Ordering::Relaxed)
+    }
 }// SSLRelay
\ No newline at end of file
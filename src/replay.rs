@@ -0,0 +1,577 @@
+//! Recording sessions to a file and replaying them.
+//!
+//! A SessionRecorder set as RelayConfig::session_recorder writes every chunk of every session, with its
+//! direction and when it arrived, as it was read before any callback changed it. A Recording read back
+//! from that file can stand in for either end of a session:
+//! ```ignore
+//! use sslrelay::replay::{Recording, ReplayOptions, ReplayTiming};
+//!
+//! let recording = Recording::open("bug.rec")?;
+//! let options = ReplayOptions { timing: ReplayTiming::Scaled(10.0), ..Default::default() };
+//!
+//! // A fake upstream: every connection made to the listener gets the next recorded session.
+//! let reports = sslrelay::replay::serve_upstream(&TcpListener::bind("127.0.0.1:8443")?, &recording, &options)?;
+//!
+//! // A fake client: sends what the client sent to a live upstream.
+//! let report = sslrelay::replay::replay_client_to("upstream:443", &recording.sessions[0], &options)?;
+//! ```
+//! Replaying waits for the live end to send as many bytes as the recording has from it before going on,
+//! so both ends take turns in the recorded order. What the live end sent differently shows up in the ReplayReport.
+//! TLS is up to the caller: replay_client and replay_upstream take any stream, an SslStream included.
+//! Such a stream needs a read timeout of its own (TcpStream::set_read_timeout on the socket underneath),
+//! shorter than ReplayOptions::read_timeout, or a live end that stays silent blocks the replay for good.
+//!
+//! The file is a header followed by records, numbers are LEB128 varints:
+//! ```text
+//! "SSLRREC1"
+//! 1 connection started(unix micros) client_len client upstream_len upstream   Session opened
+//! 2 connection origin(0 client, 1 upstream) offset(micros) length data       Chunk
+//! 3 connection offset(micros)                                                 Session closed
+//! ```
+
+use std::io::{
+    BufReader,
+    BufWriter,
+};
+
+use std::net::{
+    TcpListener,
+    TcpStream,
+    ToSocketAddrs,
+};
+
+use crate::{
+    Arc,
+    Mutex,
+    AtomicU64,
+    Ordering,
+    HashMap,
+    SocketAddr,
+    Duration,
+    Instant,
+    SystemTime,
+    Path,
+    Read,
+    Write,
+    io,
+    thread,
+};
+
+const MAGIC: &[u8; 8] = b"SSLRREC1";
+
+const RECORD_OPEN: u8 = 1;
+const RECORD_CHUNK: u8 = 2;
+const RECORD_CLOSE: u8 = 3;
+
+/// Which end of a session sent a chunk.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Origin {
+    Client,// Read from DownStream
+    Upstream,// Read from UpStream
+}
+
+/// Writes every session it is set for to one file, sessions interleaved as they happen.
+pub struct SessionRecorder {
+    writer: Mutex<Box<dyn Write + std::marker::Send>>,
+    connections: AtomicU64,
+}
+
+/// One session in a SessionRecorder, written as closed when dropped.
+pub(crate) struct RecordedConnection {
+    recorder: Arc<SessionRecorder>,
+    connection: u64,
+    opened: Instant,
+}
+
+/// Every session of a recording file.
+#[derive(Clone, Debug, Default)]
+pub struct Recording {
+    /// Sessions in the order they were opened.
+    pub sessions: Vec<RecordedSession>,
+}
+
+/// One recorded session.
+#[derive(Clone, Debug)]
+pub struct RecordedSession {
+    /// Numbers sessions in the order the recorder saw them.
+    pub connection: u64,
+    pub started: SystemTime,
+    pub client_addr: Option<SocketAddr>,
+    /// Host and port of the upstream the session was relayed to.
+    pub upstream: String,
+    pub chunks: Vec<RecordedChunk>,
+    /// Time from opening to closing, None when the recording ended first.
+    pub duration: Option<Duration>,
+}
+
+/// Data one end sent, one message when that direction is framed (RelayConfig::downstream_framing and
+/// upstream_framing), otherwise as it was read.
+#[derive(Clone, Debug)]
+pub struct RecordedChunk {
+    pub origin: Origin,
+    /// Time since the session was opened.
+    pub offset: Duration,
+    pub data: Vec<u8>,
+}
+
+/// How the gaps between recorded chunks are kept when replaying.
+#[derive(Copy, Clone, Debug)]
+pub enum ReplayTiming {
+    Preserve,// Wait as long as the recording did
+    Scaled(f64),// Divide every gap by this factor, 10.0 plays ten times as fast
+    MaxGap(Duration),// Keep gaps up to this long, shorten longer ones to it
+    Immediate,// Send without waiting
+}
+
+/// Options for replaying a RecordedSession.
+#[derive(Clone, Debug)]
+pub struct ReplayOptions {
+    pub timing: ReplayTiming,
+    /// How long to wait for the live end to send what the recording has from it.
+    /// Replaying goes on without the rest of it once this has passed.
+    pub read_timeout: Duration,
+}
+
+/// How a replay went.
+#[derive(Clone, Debug, Default)]
+pub struct ReplayReport {
+    pub bytes_sent: usize,
+    pub bytes_received: usize,
+    /// Chunks of the live end (indexes into RecordedSession::chunks) that came back different or not at all.
+    pub mismatched_chunks: Vec<usize>,
+    /// The live end closed the connection before the recording was through.
+    pub closed_early: bool,
+}
+
+impl SessionRecorder {
+
+    /// Creates (or truncates) a recording file at path.
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::new(BufWriter::new(std::fs::File::create(path)?))
+    }
+
+    /// Writes the recording to any writer.
+    pub fn new<W: Write + std::marker::Send + 'static>(mut writer: W) -> io::Result<Self> {
+        writer.write_all(MAGIC)?;
+        writer.flush()?;
+        Ok(SessionRecorder {
+            writer: Mutex::new(Box::new(writer)),
+            connections: AtomicU64::new(0),
+        })
+    }
+
+    pub(crate) fn open_connection(recorder: &Arc<SessionRecorder>, client_addr: Option<SocketAddr>, upstream: &str) -> RecordedConnection {
+
+        let connection = recorder.connections.fetch_add(1, Ordering::Relaxed);
+        let started = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+        let client_addr = client_addr.map(|addr| addr.to_string()).unwrap_or_default();
+
+        let mut record = vec![RECORD_OPEN];
+        write_varint(&mut record, connection);
+        write_varint(&mut record, started.as_micros() as u64);
+        write_bytes(&mut record, client_addr.as_bytes());
+        write_bytes(&mut record, upstream.as_bytes());
+        recorder.write_record(&record);
+
+        RecordedConnection {
+            recorder: recorder.clone(),
+            connection,
+            opened: Instant::now(),
+        }
+    }
+
+    fn write_record(&self, record: &[u8]) {
+        // A recording that can not be written to is not worth ending sessions over
+        let mut writer = match self.writer.lock() {
+            Ok(writer) => writer,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let _ = writer.write_all(record).and_then(|_| writer.flush());
+    }
+}
+
+impl RecordedConnection {
+
+    /// Writes a chunk one end sent.
+    pub(crate) fn record(&self, origin: Origin, data: &[u8]) {
+        let mut record = Vec::with_capacity(data.len() + 24);
+        record.push(RECORD_CHUNK);
+        write_varint(&mut record, self.connection);
+        record.push(match origin {
+            Origin::Client => 0,
+            Origin::Upstream => 1,
+        });
+        write_varint(&mut record, self.opened.elapsed().as_micros() as u64);
+        write_bytes(&mut record, data);
+        self.recorder.write_record(&record);
+    }
+}
+
+impl Drop for RecordedConnection {
+
+    fn drop(&mut self) {
+        let mut record = vec![RECORD_CLOSE];
+        write_varint(&mut record, self.connection);
+        write_varint(&mut record, self.opened.elapsed().as_micros() as u64);
+        self.recorder.write_record(&record);
+    }
+}
+
+impl Recording {
+
+    /// Reads a file written by a SessionRecorder.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::read(BufReader::new(std::fs::File::open(path)?))
+    }
+
+    /// Reads what a SessionRecorder wrote. A recording cut off in the middle of a record or
+    /// holding anything but records is an InvalidData or UnexpectedEof error.
+    pub fn read<R: Read>(mut reader: R) -> io::Result<Self> {
+
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a session recording"));
+        }
+
+        let mut sessions: Vec<RecordedSession> = Vec::new();
+        let mut by_connection: HashMap<u64, usize> = HashMap::new();
+
+        loop {
+            let mut record_type = [0; 1];
+            if reader.read(&mut record_type)? == 0 {
+                break;
+            }
+
+            match read_record(&mut reader, record_type[0])? {
+                Record::Open(session) => {
+                    by_connection.insert(session.connection, sessions.len());
+                    sessions.push(session);
+                },
+                Record::Chunk(connection, chunk) => {
+                    if let Some(index) = by_connection.get(&connection) {
+                        sessions[*index].chunks.push(chunk);
+                    }
+                },
+                Record::Close(connection, duration) => {
+                    if let Some(index) = by_connection.get(&connection) {
+                        sessions[*index].duration = Some(duration);
+                    }
+                },
+            }
+        }
+
+        Ok(Recording{sessions})
+    }
+}
+
+impl RecordedSession {
+
+    /// Everything one end sent, in one piece.
+    pub fn data_from(&self, origin: Origin) -> Vec<u8> {
+        self.chunks.iter()
+            .filter(|chunk| chunk.origin == origin)
+            .flat_map(|chunk| chunk.data.iter().copied())
+            .collect()
+    }
+}
+
+impl Default for ReplayOptions {
+
+    fn default() -> Self {
+        ReplayOptions {
+            timing: ReplayTiming::Preserve,
+            read_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+impl ReplayTiming {
+
+    fn gap(&self, gap: Duration) -> Duration {
+        match *self {
+            ReplayTiming::Preserve => gap,
+            ReplayTiming::Scaled(factor) if factor > 0.0 => gap.div_f64(factor),
+            ReplayTiming::Scaled(_) => gap,
+            ReplayTiming::MaxGap(max_gap) => gap.min(max_gap),
+            ReplayTiming::Immediate => Duration::ZERO,
+        }
+    }
+}
+
+/// Plays the upstream of session to a live client on stream: sends what upstream sent and
+/// waits for what the client sent in between. The stream needs a read timeout, see the module docs.
+pub fn replay_upstream<S: Read + Write>(stream: &mut S, session: &RecordedSession, options: &ReplayOptions) -> io::Result<ReplayReport> {
+    replay(stream, session, Origin::Upstream, options)
+}
+
+/// Plays the client of session to a live upstream on stream: sends what the client sent and
+/// waits for what upstream sent in between. The stream needs a read timeout, see the module docs.
+pub fn replay_client<S: Read + Write>(stream: &mut S, session: &RecordedSession, options: &ReplayOptions) -> io::Result<ReplayReport> {
+    replay(stream, session, Origin::Client, options)
+}
+
+/// Connects to addr and plays the client of session to it over plain TCP.
+pub fn replay_client_to<A: ToSocketAddrs>(addr: A, session: &RecordedSession, options: &ReplayOptions) -> io::Result<ReplayReport> {
+    let mut stream = TcpStream::connect(addr)?;
+    stream.set_read_timeout(Some(poll_interval(options)))?;
+    replay_client(&mut stream, session, options)
+}
+
+/// Plays the upstream of one recorded session after another to the connections accepted on listener,
+/// until every session was played.
+pub fn serve_upstream(listener: &TcpListener, recording: &Recording, options: &ReplayOptions) -> io::Result<Vec<ReplayReport>> {
+    let mut reports = Vec::with_capacity(recording.sessions.len());
+    for session in &recording.sessions {
+        let (mut stream, _) = listener.accept()?;
+        stream.set_read_timeout(Some(poll_interval(options)))?;
+        reports.push(replay_upstream(&mut stream, session, options)?);
+    }
+    Ok(reports)
+}
+
+/// A socket read timeout short enough for read_timeout to be kept.
+fn poll_interval(options: &ReplayOptions) -> Duration {
+    options.read_timeout.min(Duration::from_millis(100)).max(Duration::from_millis(1))
+}
+
+fn replay<S: Read + Write>(stream: &mut S, session: &RecordedSession, playing: Origin, options: &ReplayOptions) -> io::Result<ReplayReport> {
+
+    let mut report = ReplayReport::default();
+    let mut last_offset = Duration::ZERO;
+    let mut last_event = Instant::now();
+
+    for (index, chunk) in session.chunks.iter().enumerate() {
+
+        if chunk.origin == playing {
+            // Keep the gap to whatever happened before this chunk in the recording
+            let due = last_event + options.timing.gap(chunk.offset.saturating_sub(last_offset));
+            let now = Instant::now();
+            if due > now {
+                thread::sleep(due - now);
+            }
+            stream.write_all(&chunk.data)?;
+            stream.flush()?;
+            report.bytes_sent += chunk.data.len();
+        } else {
+            let (received, closed) = read_deadline(stream, chunk.data.len(), options.read_timeout)?;
+            report.bytes_received += received.len();
+            if received != chunk.data {
+                report.mismatched_chunks.push(index);
+            }
+            if closed {
+                report.closed_early = true;
+                return Ok(report);
+            }
+        }
+
+        last_offset = chunk.offset;
+        last_event = Instant::now();
+    }
+    Ok(report)
+}
+
+/// Reads up to length bytes, giving up after timeout. Also tells whether the stream closed.
+/// Streams with a read timeout of their own are checked against the deadline whenever it runs out.
+fn read_deadline<S: Read>(stream: &mut S, length: usize, timeout: Duration) -> io::Result<(Vec<u8>, bool)> {
+
+    let deadline = Instant::now() + timeout;
+    let mut received = vec![0; length];
+    let mut filled = 0;
+
+    while filled < length && Instant::now() < deadline {
+        match stream.read(&mut received[filled..]) {
+            Ok(0) => {
+                received.truncate(filled);
+                return Ok((received, true));
+            },
+            Ok(read) => filled += read,
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut | io::ErrorKind::Interrupted) => {},
+            Err(e) => return Err(e),
+        }
+    }
+    received.truncate(filled);
+    Ok((received, false))
+}
+
+enum Record {
+    Open(RecordedSession),
+    Chunk(u64, RecordedChunk),
+    Close(u64, Duration),
+}
+
+fn read_record<R: Read>(reader: &mut R, record_type: u8) -> io::Result<Record> {
+
+    let connection = read_varint(reader)?;
+
+    match record_type {
+        RECORD_OPEN => {
+            let started = SystemTime::UNIX_EPOCH.checked_add(Duration::from_micros(read_varint(reader)?))
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "session start out of range"))?;
+            let client_addr = String::from_utf8_lossy(&read_bytes(reader)?).parse().ok();
+            let upstream = String::from_utf8_lossy(&read_bytes(reader)?).to_string();
+            Ok(Record::Open(RecordedSession{connection, started, client_addr, upstream, chunks: Vec::new(), duration: None}))
+        },
+        RECORD_CHUNK => {
+            let mut origin = [0; 1];
+            reader.read_exact(&mut origin)?;
+            let origin = match origin[0] {
+                0 => Origin::Client,
+                1 => Origin::Upstream,
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "unknown chunk origin")),
+            };
+            let offset = Duration::from_micros(read_varint(reader)?);
+            let data = read_bytes(reader)?;
+            Ok(Record::Chunk(connection, RecordedChunk{origin, offset, data}))
+        },
+        RECORD_CLOSE => Ok(Record::Close(connection, Duration::from_micros(read_varint(reader)?))),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "unknown record type")),
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn write_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    write_varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+fn read_varint<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut value = 0u64;
+    let mut byte = [0; 1];
+    for shift in (0..64).step_by(7) {
+        reader.read_exact(&mut byte)?;
+        value |= ((byte[0] & 0x7f) as u64) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::InvalidData, "varint too long"))
+}
+
+fn read_bytes<R: Read>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let length = read_varint(reader)? as usize;
+    let mut bytes = Vec::new();
+    // take keeps a corrupt length from allocating more than the file holds
+    (&mut *reader).take(length as u64).read_to_end(&mut bytes)?;
+    if bytes.len() < length {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    /// A writer the test can still read once the recorder has it.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(data);
+            Ok(data.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Two interleaved sessions, the second still open when the recording ends.
+    fn recorded() -> Vec<u8> {
+        let buffer = SharedBuffer::default();
+        let recorder = Arc::new(SessionRecorder::new(buffer.clone()).unwrap());
+
+        let first = SessionRecorder::open_connection(&recorder, Some(SocketAddr::from(([127, 0, 0, 1], 50000))), "example.test:443");
+        let second = SessionRecorder::open_connection(&recorder, None, "[::1]:6379");
+        first.record(Origin::Client, b"GET / HTTP/1.1\r\n\r\n");
+        second.record(Origin::Client, b"PING\r\n");
+        first.record(Origin::Upstream, &vec![b'x'; 300]);
+        second.record(Origin::Upstream, b"+PONG\r\n");
+        first.record(Origin::Client, b"");
+        drop(first);
+        // Never closed, as when the relay was stopped with the session open
+        std::mem::forget(second);
+
+        let bytes = buffer.0.lock().unwrap().clone();
+        bytes
+    }
+
+    #[test]
+    fn sessions_round_trip() {
+        let recording = Recording::read(recorded().as_slice()).unwrap();
+        assert_eq!(recording.sessions.len(), 2);
+
+        let first = &recording.sessions[0];
+        assert_eq!((first.connection, first.client_addr, first.upstream.as_str()), (0, Some(SocketAddr::from(([127, 0, 0, 1], 50000))), "example.test:443"));
+        assert_eq!(first.chunks.iter().map(|chunk| chunk.origin).collect::<Vec<_>>(), [Origin::Client, Origin::Upstream, Origin::Client]);
+        assert_eq!(first.data_from(Origin::Client), b"GET / HTTP/1.1\r\n\r\n");
+        assert_eq!(first.data_from(Origin::Upstream), vec![b'x'; 300]);
+        assert!(first.chunks.windows(2).all(|chunks| chunks[0].offset <= chunks[1].offset));
+        assert!(first.duration.is_some_and(|duration| duration >= first.chunks[2].offset));
+        assert!(first.started <= SystemTime::now());
+
+        let second = &recording.sessions[1];
+        assert_eq!((second.connection, second.client_addr, second.upstream.as_str()), (1, None, "[::1]:6379"));
+        assert_eq!(second.data_from(Origin::Client), b"PING\r\n");
+        assert_eq!(second.data_from(Origin::Upstream), b"+PONG\r\n");
+        assert_eq!(second.duration, None);
+    }
+
+    #[test]
+    fn varints_round_trip() {
+        for value in [0, 1, 0x7f, 0x80, 300, u32::MAX as u64, u64::MAX] {
+            let mut out = Vec::new();
+            write_varint(&mut out, value);
+            assert_eq!(read_varint(&mut out.as_slice()).unwrap(), value);
+        }
+        assert_eq!(read_varint(&mut [0xff; 11].as_slice()).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn truncated_recordings_are_errors() {
+        let bytes = recorded();
+        let empty = Recording::read(&MAGIC[..]).unwrap();
+        assert!(empty.sessions.is_empty());
+
+        // Every cut that is not between two records
+        let mut boundaries = vec![MAGIC.len(), bytes.len()];
+        let mut reader = &bytes[MAGIC.len()..];
+        while let Some((&record_type, mut rest)) = reader.split_first() {
+            read_record(&mut rest, record_type).unwrap();
+            boundaries.push(bytes.len() - rest.len());
+            reader = rest;
+        }
+        for length in 0..bytes.len() {
+            let read = Recording::read(&bytes[..length]);
+            assert_eq!(read.is_ok(), boundaries.contains(&length), "cut at {}", length);
+        }
+    }
+
+    #[test]
+    fn corrupt_recordings_are_errors() {
+        let invalid = |bytes: &[u8]| Recording::read(bytes).unwrap_err().kind();
+
+        assert_eq!(invalid(b"SSLRREC2"), io::ErrorKind::InvalidData);
+        // Record type 9 and a chunk from origin 2
+        assert_eq!(invalid(&[&MAGIC[..], &[9, 0]].concat()), io::ErrorKind::InvalidData);
+        assert_eq!(invalid(&[&MAGIC[..], &[RECORD_CHUNK, 0, 2, 0, 0]].concat()), io::ErrorKind::InvalidData);
+        // The latest session start a varint holds, which some platforms' SystemTime does not
+        let mut open = vec![RECORD_OPEN, 0];
+        write_varint(&mut open, u64::MAX);
+        open.extend_from_slice(&[0, 0]);
+        let _ = Recording::read([&MAGIC[..], &open].concat().as_slice());
+        // A length far past the end of the file
+        let mut chunk = vec![RECORD_CHUNK, 0, 0, 0];
+        write_varint(&mut chunk, u64::MAX);
+        assert_eq!(invalid(&[&MAGIC[..], &chunk].concat()), io::ErrorKind::UnexpectedEof);
+    }
+}
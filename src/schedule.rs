@@ -0,0 +1,156 @@
+use crate::{
+    WriteSchedule,
+    StreamSide,
+    SessionBuffers,
+    DataPipeSender,
+    DataPipe,
+    CallbackRet,
+    CloseReason,
+    VecDeque,
+    Arc,
+    AtomicBool,
+    AtomicUsize,
+    Ordering,
+    Duration,
+    Instant,
+};
+
+impl WriteSchedule {
+
+    pub fn new(buffers: Arc<SessionBuffers>) -> Self {
+        WriteSchedule {
+            ds_writes: VecDeque::new(),
+            us_writes: VecDeque::new(),
+            tls_started: false,
+            buffers,
+        }
+    }
+
+    /// Queues what a blocking callback returned for data on its way to the side `to`.
+    /// Returns false if the callback asked for the session to be shut down.
+    pub fn callback_ret(&mut self, callback_ret: CallbackRet, to: StreamSide) -> bool {
+
+        let back = match to {
+            StreamSide::DownStream => StreamSide::UpStream,
+            StreamSide::UpStream => StreamSide::DownStream,
+        };
+        let now = Instant::now();
+
+        match callback_ret {
+            CallbackRet::Relay(data) => {
+                self.push(to, DataPipe::DataWrite(data), now);
+            },
+            CallbackRet::Spoof(data) => {
+                self.push(back, DataPipe::DataWrite(data), now);
+            },
+            CallbackRet::Freeze => {},
+            CallbackRet::Shutdown => return false,
+            CallbackRet::RelayAndSpoof(relay_data, spoof_data) => {
+                self.push(to, DataPipe::DataWrite(relay_data), now);
+                self.push(back, DataPipe::DataWrite(spoof_data), now);
+            },
+            CallbackRet::Split(chunks, pause) => {
+                let mut due = now;
+                for chunk in chunks {
+                    due = self.push(to, DataPipe::DataWrite(chunk), due) + pause;
+                }
+            },
+            CallbackRet::Delay(data, delay) => {
+                self.push(to, DataPipe::DataWrite(data), now + delay);
+            },
+            CallbackRet::RelayAndHalfClose(data) => {
+                self.push(to, DataPipe::DataWrite(data), now);
+                self.push(to, DataPipe::HalfClose, now);
+            },
+            CallbackRet::SpoofAndHalfClose(data) => {
+                self.push(back, DataPipe::DataWrite(data), now);
+                self.push(back, DataPipe::HalfClose, now);
+            },
+            // Once a switch is under way another one is only a write
+            CallbackRet::RelayAndStartTLS(data, _) if self.tls_started => {
+                self.push(to, DataPipe::DataWrite(data), now);
+            },
+            CallbackRet::RelayAndStartTLS(data, _) => {
+                self.tls_started = true;
+                self.push(to, DataPipe::StartTLS(data), now);
+                self.push(back, DataPipe::StartTLS(Vec::new()), now);
+            },
+        }
+        true
+    }
+
+    /// Queues a write for the side `to`, never ahead of writes already queued for it.
+    /// Returns when the write is due.
+    pub fn push(&mut self, to: StreamSide, data_pipe: DataPipe, due: Instant) -> Instant {
+
+        let (writes, scheduled) = match to {
+            StreamSide::DownStream => (&mut self.ds_writes, &self.buffers.ds_scheduled),
+            StreamSide::UpStream => (&mut self.us_writes, &self.buffers.us_scheduled),
+        };
+
+        let due = match writes.back() {
+            Some((last_due, _)) if *last_due > due => *last_due,
+            _ => due,
+        };
+
+        if let DataPipe::DataWrite(data) | DataPipe::StartTLS(data) = &data_pipe {
+            scheduled.fetch_add(data.len(), Ordering::Relaxed);
+        }
+        writes.push_back((due, data_pipe));
+        due
+    }
+
+    /// Hands every write that is due to its stream thread.
+    pub fn flush(&mut self, ds_data_pipe_sender: &DataPipeSender, us_data_pipe_sender: &DataPipeSender) -> Result<(), CloseReason> {
+
+        let now = Instant::now();
+
+        Self::flush_side(&mut self.ds_writes, &self.buffers.ds_write_closed, &self.buffers.ds_scheduled, ds_data_pipe_sender, now)
+            .map_err(|_| CloseReason::DownStreamClosed)?;
+        Self::flush_side(&mut self.us_writes, &self.buffers.us_write_closed, &self.buffers.us_scheduled, us_data_pipe_sender, now)
+            .map_err(|_| CloseReason::UpStreamClosed)
+    }
+
+    fn flush_side(writes: &mut VecDeque<(Instant, DataPipe)>, write_closed: &AtomicBool, scheduled: &AtomicUsize, data_pipe_sender: &DataPipeSender, now: Instant) -> Result<(), ()> {
+
+        while writes.front().is_some_and(|(due, _)| *due <= now) {
+
+            let (_, data_pipe) = match writes.pop_front() {
+                Some(write) => write,
+                None => break,
+            };
+
+            match &data_pipe {
+                DataPipe::DataWrite(data) | DataPipe::StartTLS(data) => {
+                    scheduled.fetch_sub(data.len(), Ordering::Relaxed);
+                    // Nothing can be written once this side was half closed
+                    if write_closed.load(Ordering::Relaxed) {
+                        continue;
+                    }
+                },
+                DataPipe::HalfClose => {
+                    if write_closed.swap(true, Ordering::Relaxed) {
+                        continue;
+                    }
+                },
+                DataPipe::Shutdown | DataPipe::Handshake(_) => {},
+            }
+
+            if data_pipe_sender.send(data_pipe).is_err() {
+                return Err(());
+            }
+        }
+        Ok(())
+    }
+
+    /// How long the master thread may wait for stream events before the next write is due.
+    pub fn poll_timeout(&self, max: Duration) -> Duration {
+
+        let now = Instant::now();
+
+        [self.ds_writes.front(), self.us_writes.front()].iter()
+            .flatten()
+            .map(|(due, _)| due.saturating_duration_since(now))
+            .fold(max, Duration::min)
+    }
+}
@@ -0,0 +1,39 @@
+use crate::{
+    SessionHandle,
+    SessionBuffers,
+    DataPipeSender,
+    DataPipe,
+    FullDuplexTcpState,
+    StreamSide,
+    Sender,
+    Arc,
+    Ordering,
+};
+
+impl SessionHandle {
+
+    pub(crate) fn new(ds_data_pipe_sender: DataPipeSender, us_data_pipe_sender: DataPipeSender, state_sender: Sender<FullDuplexTcpState>, buffers: Arc<SessionBuffers>) -> Self {
+        SessionHandle {
+            ds_data_pipe_sender,
+            us_data_pipe_sender,
+            state_sender,
+            buffers,
+        }
+    }
+    /// Writes data to the DownStream client. Returns false once the session has ended or DownStream was half closed.
+    pub fn send_downstream(&self, data: Vec<u8>) -> bool {
+        !self.buffers.ds_write_closed.load(Ordering::Relaxed)
+            && self.state_sender.send(FullDuplexTcpState::Inject(StreamSide::DownStream, data)).is_ok()
+    }
+    /// Writes data to the UpStream server. Returns false once the session has ended or UpStream was half closed.
+    pub fn send_upstream(&self, data: Vec<u8>) -> bool {
+        !self.buffers.us_write_closed.load(Ordering::Relaxed)
+            && self.state_sender.send(FullDuplexTcpState::Inject(StreamSide::UpStream, data)).is_ok()
+    }
+    /// Closes both sides of the session. close_callback gets CloseReason::SessionHandleShutdown.
+    pub fn shutdown(&self) {
+        let _ = self.ds_data_pipe_sender.send(DataPipe::Shutdown);
+        let _ = self.us_data_pipe_sender.send(DataPipe::Shutdown);
+        let _ = self.state_sender.send(FullDuplexTcpState::SessionHandleShutDown);
+    }
+}
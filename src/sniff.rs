@@ -0,0 +1,450 @@
+use crate::{
+    ConnectionInfo,
+    DetectedProtocol,
+    CloseReason,
+    TcpStream,
+    SocketAddr,
+    Duration,
+    Instant,
+    Read,
+    io,
+    thread,
+};
+use std::net::{
+    IpAddr,
+    Ipv4Addr,
+    Ipv6Addr,
+};
+
+/// What the first bytes of a connection start with.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Sniffed {
+    Protocol(DetectedProtocol),
+    ProxyV1,// "PROXY " text header
+    ProxyV2,// Binary header
+    NeedMore,// Could still become one of the signatures
+}
+
+// Enough for every signature, the HTTP/2 preface being the longest
+const SNIFF_LENGTH: usize = 24;
+
+const PROXY_V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+
+// A PROXY protocol v1 header is at most this long, CRLF included
+const PROXY_V1_MAX_LENGTH: usize = 107;
+
+const SIGNATURES: &[(&[u8], Sniffed)] = &[
+    (b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n", Sniffed::Protocol(DetectedProtocol::HTTP2)),
+    (b"SSH-", Sniffed::Protocol(DetectedProtocol::SSH)),
+    (b"PROXY ", Sniffed::ProxyV1),
+    (PROXY_V2_SIGNATURE, Sniffed::ProxyV2),
+    (b"GET ", Sniffed::Protocol(DetectedProtocol::HTTP1)),
+    (b"POST ", Sniffed::Protocol(DetectedProtocol::HTTP1)),
+    (b"PUT ", Sniffed::Protocol(DetectedProtocol::HTTP1)),
+    (b"HEAD ", Sniffed::Protocol(DetectedProtocol::HTTP1)),
+    (b"DELETE ", Sniffed::Protocol(DetectedProtocol::HTTP1)),
+    (b"OPTIONS ", Sniffed::Protocol(DetectedProtocol::HTTP1)),
+    (b"PATCH ", Sniffed::Protocol(DetectedProtocol::HTTP1)),
+    (b"CONNECT ", Sniffed::Protocol(DetectedProtocol::HTTP1)),
+    (b"TRACE ", Sniffed::Protocol(DetectedProtocol::HTTP1)),
+];
+
+/// Peeks at what the client sends first and sets info.protocol. If proxy_trusted, a PROXY protocol header is
+/// read off the stream into info.proxy_client_addr and info.proxy_local_addr, and detection goes on after it.
+/// Waits at most timeout for the client, clients of protocols where the server speaks first send nothing.
+pub fn detect_protocol(stream: &TcpStream, timeout: Duration, proxy_trusted: bool, info: &mut ConnectionInfo) -> Result<(), CloseReason> {
+
+    let deadline = Instant::now() + timeout;
+    let mut proxy_header_read = false;
+
+    let protocol = loop {
+        match peek(stream, deadline) {
+            Sniffed::Protocol(protocol) => break protocol,
+            Sniffed::ProxyV1 if proxy_trusted && !proxy_header_read => read_proxy_v1(stream, deadline, info)?,
+            Sniffed::ProxyV2 if proxy_trusted && !proxy_header_read => read_proxy_v2(stream, deadline, info)?,
+            // A PROXY header from an untrusted client, a second one or a signature cut short
+            _ => break DetectedProtocol::Unknown,
+        }
+        proxy_header_read = true;
+    };
+
+    let _ = stream.set_read_timeout(None);
+    info.protocol = Some(protocol);
+    Ok(())
+}
+
+/// Peeks until the data matches a signature, matches none or the deadline passed.
+fn peek(stream: &TcpStream, deadline: Instant) -> Sniffed {
+
+    let mut buffer = [0; SNIFF_LENGTH];
+
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() || stream.set_read_timeout(Some(remaining)).is_err() {
+            return Sniffed::Protocol(DetectedProtocol::Unknown);
+        }
+
+        match stream.peek(&mut buffer) {
+            // The client closed without sending anything, the session finds out soon enough
+            Ok(0) | Err(_) => return Sniffed::Protocol(DetectedProtocol::Unknown),
+            Ok(length) => match classify(&buffer[..length]) {
+                // peek returns right away once there is some data, give the rest time to arrive
+                Sniffed::NeedMore if length < SNIFF_LENGTH => thread::sleep(Duration::from_millis(5)),
+                Sniffed::NeedMore => return Sniffed::Protocol(DetectedProtocol::Unknown),
+                sniffed => return sniffed,
+            },
+        }
+    }
+}
+
+fn classify(data: &[u8]) -> Sniffed {
+
+    let (first, second) = match data {
+        [] => return Sniffed::NeedMore,
+        [first] => (*first, None),
+        [first, second, ..] => (*first, Some(*second)),
+    };
+
+    match (first, second) {
+        // A handshake record of TLS 1.0 to 1.3
+        (0x16, Some(0x03)) => return Sniffed::Protocol(DetectedProtocol::TLS),
+        // Version and the CONNECT or BIND command
+        (0x04, Some(0x01)) | (0x04, Some(0x02)) => return Sniffed::Protocol(DetectedProtocol::SOCKS4),
+        // Version and the number of authentication methods that follow
+        (0x05, Some(methods)) if methods > 0 => return Sniffed::Protocol(DetectedProtocol::SOCKS5),
+        (0x16, None) | (0x04, None) | (0x05, None) => return Sniffed::NeedMore,
+        _ => {},
+    }
+
+    let mut need_more = false;
+    for (signature, sniffed) in SIGNATURES {
+        if data.starts_with(signature) {
+            return *sniffed;
+        }
+        need_more |= signature.starts_with(data);
+    }
+
+    if need_more {
+        Sniffed::NeedMore
+    } else {
+        Sniffed::Protocol(DetectedProtocol::Unknown)
+    }
+}
+
+/// "PROXY TCP4 192.0.2.1 192.0.2.2 51234 443\r\n", or "PROXY UNKNOWN ..." without usable addresses.
+fn read_proxy_v1(stream: &TcpStream, deadline: Instant, info: &mut ConnectionInfo) -> Result<(), CloseReason> {
+
+    let mut header = Vec::new();
+    let mut byte = [0; 1];
+
+    // Read a byte at a time, nothing past the header may be taken off the stream
+    while !header.ends_with(b"\r\n") {
+        if header.len() >= PROXY_V1_MAX_LENGTH {
+            return Err(CloseReason::InvalidProxyHeader);
+        }
+        read_before(stream, &mut byte, deadline)?;
+        header.push(byte[0]);
+    }
+
+    parse_proxy_v1(&header[..header.len() - 2], info)
+}
+
+fn parse_proxy_v1(header: &[u8], info: &mut ConnectionInfo) -> Result<(), CloseReason> {
+
+    let header = std::str::from_utf8(header).map_err(|_| CloseReason::InvalidProxyHeader)?;
+    let fields: Vec<&str> = header.split(' ').collect();
+
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(()),
+        ["PROXY", "TCP4", source, destination, source_port, destination_port]
+        | ["PROXY", "TCP6", source, destination, source_port, destination_port] => {
+            let address = |ip: &str, port: &str| -> Option<SocketAddr> {
+                Some(SocketAddr::new(ip.parse().ok()?, port.parse().ok()?))
+            };
+            match (address(source, source_port), address(destination, destination_port)) {
+                (Some(client), Some(local)) => {
+                    info.proxy_client_addr = Some(client);
+                    info.proxy_local_addr = Some(local);
+                    Ok(())
+                },
+                _ => Err(CloseReason::InvalidProxyHeader),
+            }
+        },
+        _ => Err(CloseReason::InvalidProxyHeader),
+    }
+}
+
+/// The signature, version and command, address family, length and then the addresses and TLVs.
+fn read_proxy_v2(stream: &TcpStream, deadline: Instant, info: &mut ConnectionInfo) -> Result<(), CloseReason> {
+
+    let mut header = [0; 16];
+    read_before(stream, &mut header, deadline)?;
+
+    let (version, command) = (header[12] >> 4, header[12] & 0x0f);
+    if version != 2 || command > 1 {
+        return Err(CloseReason::InvalidProxyHeader);
+    }
+
+    let mut body = vec![0; u16::from_be_bytes([header[14], header[15]]) as usize];
+    read_before(stream, &mut body, deadline)?;
+
+    parse_proxy_v2(command, header[13], &body, info)
+}
+
+fn parse_proxy_v2(command: u8, family: u8, body: &[u8], info: &mut ConnectionInfo) -> Result<(), CloseReason> {
+
+    // LOCAL connections (health checks of the proxy) carry no addresses worth keeping
+    if command == 0 {
+        return Ok(());
+    }
+
+    let port = |offset: usize| u16::from_be_bytes([body[offset], body[offset + 1]]);
+
+    match family {
+        // TCP over IPv4
+        0x11 if body.len() >= 12 => {
+            let ip = |offset: usize| IpAddr::V4(Ipv4Addr::new(body[offset], body[offset + 1], body[offset + 2], body[offset + 3]));
+            info.proxy_client_addr = Some(SocketAddr::new(ip(0), port(8)));
+            info.proxy_local_addr = Some(SocketAddr::new(ip(4), port(10)));
+        },
+        // TCP over IPv6
+        0x21 if body.len() >= 36 => {
+            let ip = |offset: usize| {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(&body[offset..offset + 16]);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            };
+            info.proxy_client_addr = Some(SocketAddr::new(ip(0), port(32)));
+            info.proxy_local_addr = Some(SocketAddr::new(ip(16), port(34)));
+        },
+        0x11 | 0x21 => return Err(CloseReason::InvalidProxyHeader),
+        // UNSPEC, UDP and unix sockets
+        _ => {},
+    }
+    Ok(())
+}
+
+/// Fills buffer, each read only gets what is left until the deadline so a header sent a byte at a time
+/// cannot hold the session past it.
+fn read_before(mut stream: &TcpStream, buffer: &mut [u8], deadline: Instant) -> Result<(), CloseReason> {
+
+    let mut filled = 0;
+    while filled < buffer.len() {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() || stream.set_read_timeout(Some(remaining)).is_err() {
+            return Err(CloseReason::InvalidProxyHeader);
+        }
+        match stream.read(&mut buffer[filled..]) {
+            Ok(0) => return Err(CloseReason::DownStreamClosed),
+            Ok(length) => filled += length,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(read_error(e)),
+        }
+    }
+    Ok(())
+}
+
+/// A header that stops coming is as bad as one that does not parse.
+fn read_error(e: io::Error) -> CloseReason {
+    match e.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => CloseReason::InvalidProxyHeader,
+        _ => CloseReason::DownStreamClosed,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::net::TcpListener;
+
+    fn info() -> ConnectionInfo {
+        ConnectionInfo {
+            connection_id: 0,
+            route: 0,
+            client_addr: "127.0.0.1:1".parse().unwrap(),
+            local_addr: None,
+            protocol: None,
+            proxy_client_addr: None,
+            proxy_local_addr: None,
+        }
+    }
+
+    /// The client side to write to and the accepted side to read from.
+    fn connection() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (client, server)
+    }
+
+    fn v2_header(command: u8, family: u8, body: &[u8]) -> Vec<u8> {
+        let mut header = PROXY_V2_SIGNATURE.to_vec();
+        header.extend([0x20 | command, family]);
+        header.extend((body.len() as u16).to_be_bytes());
+        header.extend(body);
+        header
+    }
+
+    #[test]
+    fn protocols_are_classified() {
+        assert_eq!(classify(&[0x16, 0x03, 0x01]), Sniffed::Protocol(DetectedProtocol::TLS));
+        assert_eq!(classify(&[0x04, 0x01, 0x00, 0x50]), Sniffed::Protocol(DetectedProtocol::SOCKS4));
+        assert_eq!(classify(&[0x04, 0x02]), Sniffed::Protocol(DetectedProtocol::SOCKS4));
+        assert_eq!(classify(&[0x05, 0x01, 0x00]), Sniffed::Protocol(DetectedProtocol::SOCKS5));
+
+        for (signature, sniffed) in SIGNATURES {
+            let mut data = signature.to_vec();
+            data.extend(b"rest");
+            assert_eq!(classify(&data), *sniffed);
+            assert_eq!(classify(&signature[..signature.len() - 1]), Sniffed::NeedMore);
+        }
+    }
+
+    #[test]
+    fn prefixes_need_more() {
+        assert_eq!(classify(b""), Sniffed::NeedMore);
+        assert_eq!(classify(&[0x16]), Sniffed::NeedMore);
+        assert_eq!(classify(&[0x04]), Sniffed::NeedMore);
+        assert_eq!(classify(&[0x05]), Sniffed::NeedMore);
+        assert_eq!(classify(b"PR"), Sniffed::NeedMore);
+        assert_eq!(classify(b"\r\n\r\n"), Sniffed::NeedMore);
+    }
+
+    #[test]
+    fn anything_else_is_unknown() {
+        assert_eq!(classify(b"HELLO"), Sniffed::Protocol(DetectedProtocol::Unknown));
+        assert_eq!(classify(b"get / HTTP/1.1"), Sniffed::Protocol(DetectedProtocol::Unknown));
+        assert_eq!(classify(&[0x16, 0x02]), Sniffed::Protocol(DetectedProtocol::Unknown));
+        assert_eq!(classify(&[0x04, 0x03]), Sniffed::Protocol(DetectedProtocol::Unknown));
+        assert_eq!(classify(&[0x05, 0x00]), Sniffed::Protocol(DetectedProtocol::Unknown));
+    }
+
+    #[test]
+    fn proxy_v1_addresses_are_read() {
+        let mut tcp4 = info();
+        parse_proxy_v1(b"PROXY TCP4 192.0.2.1 192.0.2.2 51234 443", &mut tcp4).unwrap();
+        assert_eq!(tcp4.proxy_client_addr, Some("192.0.2.1:51234".parse().unwrap()));
+        assert_eq!(tcp4.proxy_local_addr, Some("192.0.2.2:443".parse().unwrap()));
+
+        let mut tcp6 = info();
+        parse_proxy_v1(b"PROXY TCP6 2001:db8::1 2001:db8::2 51234 443", &mut tcp6).unwrap();
+        assert_eq!(tcp6.proxy_client_addr, Some("[2001:db8::1]:51234".parse().unwrap()));
+        assert_eq!(tcp6.proxy_local_addr, Some("[2001:db8::2]:443".parse().unwrap()));
+
+        let mut unknown = info();
+        parse_proxy_v1(b"PROXY UNKNOWN ffff::1 ffff::2 1 2", &mut unknown).unwrap();
+        assert_eq!(unknown.proxy_client_addr, None);
+        assert_eq!(unknown.proxy_local_addr, None);
+    }
+
+    #[test]
+    fn bad_proxy_v1_headers_are_rejected() {
+        for header in [
+            &b"PROXY TCP4 192.0.2.300 192.0.2.2 51234 443"[..],
+            b"PROXY TCP4 192.0.2.1 192.0.2.2 51234 65536",
+            b"PROXY TCP4 192.0.2.1 192.0.2.2 51234",
+            b"PROXY UDP4 192.0.2.1 192.0.2.2 51234 443",
+            b"PROXY TCP4 192.0.2.1 192.0.2.2 51234 443\xff",
+        ] {
+            assert_eq!(parse_proxy_v1(header, &mut info()), Err(CloseReason::InvalidProxyHeader));
+        }
+    }
+
+    #[test]
+    fn proxy_v1_headers_stop_at_crlf() {
+        let (mut client, server) = connection();
+        client.write_all(b"PROXY TCP4 192.0.2.1 192.0.2.2 51234 443\r\nGET / HTTP/1.1\r\n").unwrap();
+
+        let mut info = info();
+        read_proxy_v1(&server, Instant::now() + Duration::from_secs(5), &mut info).unwrap();
+        assert_eq!(info.proxy_client_addr, Some("192.0.2.1:51234".parse().unwrap()));
+
+        let mut rest = [0; 4];
+        (&server).read_exact(&mut rest).unwrap();
+        assert_eq!(&rest, b"GET ");
+    }
+
+    #[test]
+    fn overlong_proxy_v1_headers_are_rejected() {
+        let (mut client, server) = connection();
+        let mut header = b"PROXY TCP4 ".to_vec();
+        header.resize(PROXY_V1_MAX_LENGTH + 10, b'1');
+        client.write_all(&header).unwrap();
+
+        let result = read_proxy_v1(&server, Instant::now() + Duration::from_secs(5), &mut info());
+        assert_eq!(result, Err(CloseReason::InvalidProxyHeader));
+    }
+
+    #[test]
+    fn proxy_v1_headers_are_bounded_by_the_deadline() {
+        let (mut client, server) = connection();
+        let writer = thread::spawn(move || {
+            // A byte well within the timeout each time, but never the whole header
+            for byte in b"PROXY TCP4 192.0.2.1 192.0.2.2" {
+                if client.write_all(&[*byte]).is_err() {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(20));
+            }
+        });
+
+        let start = Instant::now();
+        let result = read_proxy_v1(&server, start + Duration::from_millis(100), &mut info());
+        assert_eq!(result, Err(CloseReason::InvalidProxyHeader));
+        assert!(start.elapsed() < Duration::from_millis(400));
+        drop(server);
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn proxy_v2_addresses_are_read() {
+        let mut ipv4 = info();
+        let body = [192, 0, 2, 1, 192, 0, 2, 2, 0xc8, 0x22, 0x01, 0xbb];
+        parse_proxy_v2(1, 0x11, &body, &mut ipv4).unwrap();
+        assert_eq!(ipv4.proxy_client_addr, Some("192.0.2.1:51234".parse().unwrap()));
+        assert_eq!(ipv4.proxy_local_addr, Some("192.0.2.2:443".parse().unwrap()));
+
+        let mut body = vec![0; 36];
+        body[..16].copy_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        body[16..32].copy_from_slice(&"2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        body[32..].copy_from_slice(&[0xc8, 0x22, 0x01, 0xbb]);
+
+        let (mut client, server) = connection();
+        client.write_all(&v2_header(1, 0x21, &body)).unwrap();
+        let mut ipv6 = info();
+        read_proxy_v2(&server, Instant::now() + Duration::from_secs(5), &mut ipv6).unwrap();
+        assert_eq!(ipv6.proxy_client_addr, Some("[2001:db8::1]:51234".parse().unwrap()));
+        assert_eq!(ipv6.proxy_local_addr, Some("[2001:db8::2]:443".parse().unwrap()));
+    }
+
+    #[test]
+    fn proxy_v2_local_carries_no_addresses() {
+        let (mut client, server) = connection();
+        client.write_all(&v2_header(0, 0x11, &[0; 12])).unwrap();
+
+        let mut info = info();
+        read_proxy_v2(&server, Instant::now() + Duration::from_secs(5), &mut info).unwrap();
+        assert_eq!(info.proxy_client_addr, None);
+        assert_eq!(info.proxy_local_addr, None);
+    }
+
+    #[test]
+    fn short_proxy_v2_bodies_are_rejected() {
+        assert_eq!(parse_proxy_v2(1, 0x11, &[0; 11], &mut info()), Err(CloseReason::InvalidProxyHeader));
+        assert_eq!(parse_proxy_v2(1, 0x21, &[0; 35], &mut info()), Err(CloseReason::InvalidProxyHeader));
+        // Families without addresses to keep need none
+        assert_eq!(parse_proxy_v2(1, 0x00, &[], &mut info()), Ok(()));
+    }
+
+    #[test]
+    fn bad_proxy_v2_versions_are_rejected() {
+        let (mut client, server) = connection();
+        let mut header = v2_header(1, 0x11, &[0; 12]);
+        header[12] = 0x11;
+        client.write_all(&header).unwrap();
+
+        let result = read_proxy_v2(&server, Instant::now() + Duration::from_secs(5), &mut info());
+        assert_eq!(result, Err(CloseReason::InvalidProxyHeader));
+    }
+}
@@ -9,6 +9,8 @@ use crate::{
     DownStreamInner,
     UpStreamInner,
     InnerHandlers,
+    AtomicBool,
+    Ordering,
     Shutdown,
     Sender,
     Receiver,
@@ -16,72 +18,308 @@ use crate::{
     DataPipe,
     mpsc,
     thread,
-    CallbackRet,
+    StreamSide,
+    WriteSchedule,
+    FrameBuffer,
     TcpStream,
-    SslVerifyMode,
-    SslConnector,
-    SslMethod,
+    SocketAddr,
+    ToSocketAddrs,
+    UpstreamPool,
+    SessionBuffers,
+    DataPipeSender,
+    SessionHandle,
+    PcapngCapture,
+    CaptureConnection,
+    capture,
+    ConnectTimings,
+    RemoteEndpoint,
+    har::{
+        HarRecorder,
+        HarSession,
+    },
+    replay::{
+        SessionRecorder,
+        Origin,
+    },
+    SessionMetrics,
+    CallbackQueue,
+    NbCallbackJob,
+    RelayConfig,
+    CloseReason,
+    Instant,
+    SslAcceptor,
+    CallbackRet,
+    tls,
 };
 
-impl<H: HandlerCallbacks + std::marker::Sync + std::marker::Send + Clone + 'static> FullDuplexTcp<H> {
+/// What the master goes on with after the callbacks for a message ran.
+enum CallbackFlow {
+    Continue,
+    StartTLS(Vec<u8>),// Both sides switch to TLS, the callback already got this much of the handshake
+    Shutdown,
+}
+
+/// What a side read while it switches to TLS is handshake data, not for the callbacks.
+/// It goes back to the stream thread once that stopped reading in the clear.
+#[derive(Default)]
+struct TlsSwitch {
+    started: bool,
+    ds_handshake: Option<Vec<u8>>,
+    us_handshake: Option<Vec<u8>>,
+}
+
+impl<H: HandlerCallbacks + std::marker::Send + 'static> FullDuplexTcp<H> {
 
-    pub fn new(ds_tcp_stream: DataStreamType, us_tcp_stream_type: TCPDataType, remote_host: String, remote_port: String, handlers: InnerHandlers<H>) -> Result<Self, i8> {
+    pub fn new(ds_tcp_stream: DataStreamType, tls_acceptor: Option<Arc<SslAcceptor>>, config: Arc<RelayConfig>, upstreams: Arc<UpstreamPool>, client_addr: Option<SocketAddr>, handlers: InnerHandlers<H>, shutdown: Arc<AtomicBool>) -> Result<Self, CloseReason> {
 
         match ds_tcp_stream {
             DataStreamType::RAW(ref s) => { let _ = s.set_read_timeout(Some(Duration::from_millis(50))); },
             DataStreamType::TLS(ref s) => { let _ = s.get_ref().set_read_timeout(Some(Duration::from_millis(50))); },
         }
 
-        let us_tcp_stream = match Self::connect_endpoint(us_tcp_stream_type, remote_host.clone(), remote_port.clone()) {
-            Ok(s) => s,
-            Err(ec) => {
+        let metrics = SessionMetrics::new(&config);
+        let upstream_alpn = Self::upstream_alpn(&config, &ds_tcp_stream);
+
+        // Try every upstream endpoint in load balancing order until one connects
+        let mut last_error = CloseReason::UpStreamConnectFailed;
+        let mut upstream = None;
+
+        for index in upstreams.connect_order(client_addr) {
+
+            let remote = upstreams.remote(index);
+
+            match Self::connect_endpoint(config.upstream_data_type, remote.host.clone(), remote.port.clone(), upstreams.connect_timeout(), config.handshake_timeout, &upstream_alpn) {
+                Ok((s, timings)) => {
+                    tracing::debug!(upstream = %format_args!("{}:{}", remote.host, remote.port), "connected to upstream");
+                    upstream = Some((s, index, timings));
+                    break;
+                },
+                Err(ec) => {
+                    upstreams.mark_failure(index);
+                    if let Some(metrics) = &metrics {
+                        metrics.upstream_failed(&format!("{}:{}", remote.host, remote.port), ec);
+                    }
+                    last_error = ec;
+                }
+            }
+        }
+
+        let (us_tcp_stream, upstream_index, connect_timings) = match upstream {
+            Some(upstream) => upstream,
+            None => {
                 match ds_tcp_stream {
                     DataStreamType::RAW(s) => { let _ = s.shutdown(Shutdown::Both); },
                     DataStreamType::TLS(mut s) => { let _ = s.shutdown(); },
                 }
-                return Err(ec);
+                if let Some(metrics) = &metrics {
+                    metrics.closed(last_error);
+                }
+                handlers.lock().close_callback(last_error);
+                return Err(last_error);
             }
         };
 
+        if !Self::alpn_agrees(&ds_tcp_stream, &us_tcp_stream) {
+            tracing::warn!("upstream did not agree to the ALPN protocol of the client");
+            for stream in [ds_tcp_stream, us_tcp_stream] {
+                match stream {
+                    DataStreamType::RAW(s) => { let _ = s.shutdown(Shutdown::Both); },
+                    DataStreamType::TLS(mut s) => { let _ = s.shutdown(); },
+                }
+            }
+            if let Some(metrics) = &metrics {
+                metrics.closed(CloseReason::AlpnMismatch);
+            }
+            handlers.lock().close_callback(CloseReason::AlpnMismatch);
+            return Err(CloseReason::AlpnMismatch);
+        }
+
+        let remote = upstreams.remote(upstream_index).clone();
+        let buffers = Arc::new(SessionBuffers::new(&config));
+        let ds_raw = matches!(ds_tcp_stream, DataStreamType::RAW(_));
+        let capture = config.capture.as_ref().map(|capture| Self::open_capture(capture, &ds_tcp_stream, &us_tcp_stream, client_addr));
+        let recording = config.session_recorder.as_ref().map(|recorder| SessionRecorder::open_connection(recorder, client_addr, &format!("{}:{}", remote.host, remote.port)));
+        let har = config.har.as_ref().map(|recorder| Self::open_har(recorder, ds_raw, &remote, &us_tcp_stream, connect_timings));
+
         Ok(
             FullDuplexTcp {
-            remote_host,
-            remote_port,
-            ds_inner_m: Arc::new(Mutex::new(Some(DownStreamInner{ds_stream: ds_tcp_stream, internal_data_buffer: Vec::<u8>::new()}))),
-            us_inner_m: Arc::new(Mutex::new(Some(UpStreamInner{us_stream: us_tcp_stream, internal_data_buffer: Vec::<u8>::new()}))),
+            remote_host: remote.host.clone(),
+            remote_port: remote.port,
+            ds_inner_m: Arc::new(Mutex::new(Some(DownStreamInner{
+                ds_stream: ds_tcp_stream,
+                internal_data_buffer: Vec::<u8>::new(),
+                half_close: config.half_close,
+                buffers: buffers.clone(),
+                tls_acceptor,
+                handshake_timeout: config.handshake_timeout,
+            }))),
+            us_inner_m: Arc::new(Mutex::new(Some(UpStreamInner{
+                us_stream: us_tcp_stream,
+                internal_data_buffer: Vec::<u8>::new(),
+                half_close: config.half_close,
+                buffers: buffers.clone(),
+                remote_host: remote.host.clone(),
+                alpn_protocols: config.alpn_wire_format(),
+                handshake_timeout: config.handshake_timeout,
+            }))),
             inner_handlers: handlers,
+            ds_raw,
+            capture,
+            har,
+            recording,
+            metrics,
+            config,
+            buffers,
+            shutdown,
+            _upstream_lease: UpstreamPool::mark_success(&upstreams, upstream_index),
         })
     }
 
+    /// The ALPN protocols to offer upstream. Behind a TLS client only the one it negotiated, or none,
+    /// so both legs end up speaking the same protocol.
+    fn upstream_alpn(config: &RelayConfig, ds_stream: &DataStreamType) -> Vec<u8> {
+        match ds_stream {
+            DataStreamType::TLS(s) => match s.ssl().selected_alpn_protocol() {
+                Some(protocol) => [&[protocol.len() as u8][..], protocol].concat(),
+                None => Vec::new(),
+            },
+            DataStreamType::RAW(_) => config.alpn_wire_format(),
+        }
+    }
+
+    /// Whether upstream settled on the protocol the client negotiated. An upstream without ALPN is taken
+    /// to speak it, except for h2 which is only ever spoken over TLS after ALPN picked it.
+    fn alpn_agrees(ds_stream: &DataStreamType, us_stream: &DataStreamType) -> bool {
+        match (ds_stream, us_stream) {
+            (DataStreamType::TLS(ds), DataStreamType::TLS(us)) => match (ds.ssl().selected_alpn_protocol(), us.ssl().selected_alpn_protocol()) {
+                (Some(ds_protocol), Some(us_protocol)) => ds_protocol == us_protocol,
+                (Some(ds_protocol), None) => ds_protocol != b"h2",
+                (None, _) => true,
+            },
+            _ => true,
+        }
+    }
+
+    /// Starts the session in the capture as a connection from the client to the upstream it was relayed to.
+    fn open_capture(capture: &Arc<PcapngCapture>, ds_stream: &DataStreamType, us_stream: &DataStreamType, client_addr: Option<SocketAddr>) -> CaptureConnection {
+
+        let unspecified = SocketAddr::from(([0, 0, 0, 0], 0));
+        let server_addr = match us_stream {
+            DataStreamType::RAW(s) => s.peer_addr(),
+            DataStreamType::TLS(s) => s.get_ref().peer_addr(),
+        };
+        let comment = format!(
+            "DownStream: {}, UpStream: {}",
+            capture::stream_details(ds_stream),
+            capture::stream_details(us_stream),
+        );
+        PcapngCapture::open_connection(capture, client_addr.unwrap_or(unspecified), server_addr.unwrap_or(unspecified), comment)
+    }
+
+    /// Follows the session's HTTP traffic for a HAR log.
+    fn open_har(recorder: &Arc<HarRecorder>, ds_raw: bool, remote: &RemoteEndpoint, us_stream: &DataStreamType, connect_timings: ConnectTimings) -> HarSession {
+
+        let us_tcp_stream = match us_stream {
+            DataStreamType::RAW(s) => s,
+            DataStreamType::TLS(s) => s.get_ref(),
+        };
+        // The local port tells connections apart, like browsers do
+        let connection = us_tcp_stream.local_addr().map(|addr| addr.port().to_string()).unwrap_or_default();
+        HarSession::new(recorder.clone(), !ds_raw, format!("{}:{}", remote.host, remote.port), us_tcp_stream.peer_addr().ok().map(|addr| addr.ip()), connection, connect_timings)
+    }
+
     pub fn handle(&mut self) {
 
+        let close_reason = self.relay_data();
+        tracing::debug!(reason = ?close_reason, "session closed");
+        if let Some(metrics) = &self.metrics {
+            metrics.closed(close_reason);
+        }
+        self.inner_handlers.lock().close_callback(close_reason);
+    }
+
+    fn relay_data(&mut self) -> CloseReason {
+
         let (state_sender, state_receiver): (Sender<FullDuplexTcpState>, Receiver<FullDuplexTcpState>) = mpsc::channel();
         let (ds_data_pipe_sender, ds_data_pipe_receiver): (Sender<DataPipe>, Receiver<DataPipe>) = mpsc::channel();
         let (us_data_pipe_sender, us_data_pipe_receiver): (Sender<DataPipe>, Receiver<DataPipe>) = mpsc::channel();
 
+        let ds_data_pipe_sender = DataPipeSender::new(ds_data_pipe_sender, self.buffers.ds_pending.clone());
+        let us_data_pipe_sender = DataPipeSender::new(us_data_pipe_sender, self.buffers.us_pending.clone());
+
         let ds_method_pointer = self.ds_inner_m.clone();
         let ds_state_bc = state_sender.clone();
 
         let us_method_pointer = self.us_inner_m.clone();
         let us_state_bc = state_sender.clone();
 
+        // Stream threads log under the session span of this one
+        let ds_span = tracing::info_span!("stream", side = "downstream");
+        let us_span = tracing::info_span!("stream", side = "upstream");
+
         thread::spawn(move || {
+            let _entered = ds_span.enter();
             ds_method_pointer.lock().unwrap().take().unwrap().ds_handler(ds_state_bc, ds_data_pipe_receiver);
         });
 
         thread::spawn(move || {
+            let _entered = us_span.enter();
             us_method_pointer.lock().unwrap().take().unwrap().us_handler(us_state_bc, us_data_pipe_receiver);
         });
 
+        self.inner_handlers.lock().open_callback(SessionHandle::new(ds_data_pipe_sender.clone(), us_data_pipe_sender.clone(), state_sender.clone(), self.buffers.clone()));
+
+        let nb_callbacks = CallbackQueue::new(self.inner_handlers.clone(), self.config.nb_callback_backlog, self.config.nb_callback_overflow);
+
+        let session_start = Instant::now();
+        let mut last_activity = Instant::now();
+
+        // Set once a side has half closed, holds the reason for whichever side closed first
+        let mut half_closed: Option<CloseReason> = None;
+
+        let mut schedule = WriteSchedule::new(self.buffers.clone());
+        // Reading pauses at the high watermark, a message held back that long would never complete
+        let max_frame_size = self.config.max_frame_size.min(self.buffers.read_limit() - 1);
+        let mut ds_frames = FrameBuffer::new(&self.config.downstream_framing, max_frame_size);
+        let mut us_frames = FrameBuffer::new(&self.config.upstream_framing, max_frame_size);
+
+        let mut tls = TlsSwitch::default();
+
         loop {
 
-            match state_receiver.recv() {
+            if let Some(max_session_lifetime) = self.config.max_session_lifetime {
+                if session_start.elapsed() >= max_session_lifetime {
+                    Self::shutdown_pipes(&ds_data_pipe_sender, &us_data_pipe_sender);
+                    return CloseReason::SessionLifetime;
+                }
+            }
+
+            if let Some(max_session_memory) = self.config.max_session_memory {
+                if self.buffers.total() > max_session_memory {
+                    Self::shutdown_pipes(&ds_data_pipe_sender, &us_data_pipe_sender);
+                    return CloseReason::MemoryLimit;
+                }
+            }
+
+            // Delayed writes that came due
+            if let Err(reason) = schedule.flush(&ds_data_pipe_sender, &us_data_pipe_sender) {
+                Self::shutdown_pipes(&ds_data_pipe_sender, &us_data_pipe_sender);
+                return reason;
+            }
+
+            match state_receiver.recv_timeout(schedule.poll_timeout(Duration::from_millis(50))) {
                 Ok(state_request) => {
                     match state_request {
 
                         // DownStream Write Request
                         FullDuplexTcpState::DownStreamWrite(data) => {
 
+                            last_activity = Instant::now();
+                            self.buffers.us_unprocessed.fetch_sub(data.len(), Ordering::Relaxed);
+                            if let Some(metrics) = &self.metrics {
+                                metrics.received(StreamSide::UpStream, data.len());
+                            }
+
                             /*
                                 Callbacks that work with data from UpStream go here
                                 Add callback return types for blocking callback subroutines
@@ -91,169 +329,377 @@ impl<H: HandlerCallbacks + std::marker::Sync + std::marker::Send + Clone + 'stat
                                 Freeze - Freeze data (dont relay and destroy data)
                             */
 
-                            let inner_handlers_clone = self.inner_handlers.clone();
-                            let in_data = data.clone();
-
-                            thread::spawn(move || {
-                                inner_handlers_clone.cb.us_nb_callback(in_data);
-                            });
-
-                            match self.inner_handlers.cb.us_b_callback(data) {
-                                CallbackRet::Relay(retdata) => {
-                                    match ds_data_pipe_sender.send(DataPipe::DataWrite(retdata)) {
-                                        Ok(()) => {},
-                                        Err(e) => {
-                                            Self::handle_error(format!("Failed to send data write to DownStream thread: {}", e).as_str());
-                                            return;
-                                        }
-                                    }
-                                },
-                                CallbackRet::Spoof(retdata) => {
-                                    match us_data_pipe_sender.send(DataPipe::DataWrite(retdata)) {
-                                        Ok(()) => {},
-                                        Err(e) => {
-                                            Self::handle_error(format!("Failed to send data write to DownStream thread: {}", e).as_str());
-                                            return;
-                                        }
-                                    }
-                                },
-                                CallbackRet::Freeze => {},
-                                CallbackRet::Shutdown => {
-                                    if let Err(e) = us_data_pipe_sender.send(DataPipe::Shutdown) {
-                                        Self::handle_error(format!("Failed to send Shutdown signal to UpStream thread: {}", e).as_str());
-                                    }
-                                    if let Err(e) = ds_data_pipe_sender.send(DataPipe::Shutdown) {
-                                        Self::handle_error(format!("Failed to send Shutdown signal to DownStream thread: {}", e).as_str());
-                                    }
-                                    return;
+                            if let Some(handshake) = tls.us_handshake.as_mut() {
+                                handshake.extend(data);
+                                continue;
+                            }
+
+                            let messages = us_frames.push(data);
+                            self.buffers.us_framing.store(us_frames.buffered(), Ordering::Relaxed);
+
+                            for message in messages {
+                                if let Some(handshake) = tls.us_handshake.as_mut() {
+                                    handshake.extend(message);
+                                    continue;
                                 }
+                                if let Err(reason) = self.relay_message(StreamSide::UpStream, message, &nb_callbacks, &mut schedule, &mut tls) {
+                                    Self::shutdown_pipes(&us_data_pipe_sender, &ds_data_pipe_sender);
+                                    return reason;
+                                }
+                            }
+                            // Whatever the framing held back belongs to the handshake as well
+                            if let Some(handshake) = tls.us_handshake.as_mut() {
+                                handshake.extend(us_frames.take_remainder().unwrap_or_default());
+                                self.buffers.us_framing.store(0, Ordering::Relaxed);
+                            }
+                            if let Err(reason) = schedule.flush(&ds_data_pipe_sender, &us_data_pipe_sender) {
+                                Self::shutdown_pipes(&ds_data_pipe_sender, &us_data_pipe_sender);
+                                return reason;
                             }
                         },
                         // UpStream Write Request
                         FullDuplexTcpState::UpStreamWrite(data) => {
 
+                            last_activity = Instant::now();
+                            self.buffers.ds_unprocessed.fetch_sub(data.len(), Ordering::Relaxed);
+                            if let Some(metrics) = &self.metrics {
+                                metrics.received(StreamSide::DownStream, data.len());
+                            }
+
                             /*
                                 Callbacks that work with data from DownStream go here
                             */
 
-                            let inner_handlers_clone = self.inner_handlers.clone();
-                            let in_data = data.clone();
-
-                            thread::spawn(move || {
-                                inner_handlers_clone.cb.ds_nb_callback(in_data);
-                            });
-
-                            match self.inner_handlers.cb.ds_b_callback(data) {
-                                CallbackRet::Relay(retdata) => {
-                                    match us_data_pipe_sender.send(DataPipe::DataWrite(retdata)) {
-                                        Ok(()) => {},
-                                        Err(e) => {
-                                            Self::handle_error(format!("Failed to send data write to UpStream thread: {}", e).as_str());
-                                            return;
-                                        }
-                                    }
-                                },
-                                CallbackRet::Spoof(retdata) => {
-                                    match ds_data_pipe_sender.send(DataPipe::DataWrite(retdata)) {
-                                        Ok(()) => {},
-                                        Err(e) => {
-                                            Self::handle_error(format!("Failed to send data write to DownStream thread: {}", e).as_str());
-                                            return;
-                                        }
-                                    }
-                                },
-                                CallbackRet::Freeze => {},
-                                CallbackRet::Shutdown => {
-                                    if let Err(e) = ds_data_pipe_sender.send(DataPipe::Shutdown) {
-                                        Self::handle_error(format!("Failed to send Shutdown signal to DownStream thread: {}", e).as_str());
-                                    }
-                                    if let Err(e) = us_data_pipe_sender.send(DataPipe::Shutdown) {
-                                        Self::handle_error(format!("Failed to send Shutdown signal to UpStream thread: {}", e).as_str());
-                                    }
-                                    return;
+                            if let Some(handshake) = tls.ds_handshake.as_mut() {
+                                handshake.extend(data);
+                                continue;
+                            }
+
+                            let messages = ds_frames.push(data);
+                            self.buffers.ds_framing.store(ds_frames.buffered(), Ordering::Relaxed);
+
+                            for message in messages {
+                                if let Some(handshake) = tls.ds_handshake.as_mut() {
+                                    handshake.extend(message);
+                                    continue;
                                 }
+                                if let Err(reason) = self.relay_message(StreamSide::DownStream, message, &nb_callbacks, &mut schedule, &mut tls) {
+                                    Self::shutdown_pipes(&ds_data_pipe_sender, &us_data_pipe_sender);
+                                    return reason;
+                                }
+                            }
+                            // Whatever the framing held back belongs to the handshake as well
+                            if let Some(handshake) = tls.ds_handshake.as_mut() {
+                                handshake.extend(ds_frames.take_remainder().unwrap_or_default());
+                                self.buffers.ds_framing.store(0, Ordering::Relaxed);
+                            }
+                            if let Err(reason) = schedule.flush(&ds_data_pipe_sender, &us_data_pipe_sender) {
+                                Self::shutdown_pipes(&ds_data_pipe_sender, &us_data_pipe_sender);
+                                return reason;
                             }
                         },
                         // DownStreamShutDown Request
                         FullDuplexTcpState::DownStreamShutDown => {
 
+                            // Whatever message was left still goes out ahead of the Shutdown
+                            let flushed = self.relay_remainder(StreamSide::DownStream, &mut ds_frames, &nb_callbacks, &mut schedule, &mut tls)
+                                .and_then(|_| schedule.flush(&ds_data_pipe_sender, &us_data_pipe_sender));
+                            if let Err(reason) = flushed {
+                                Self::shutdown_pipes(&ds_data_pipe_sender, &us_data_pipe_sender);
+                                return reason;
+                            }
+
                             if let Err(e) = us_data_pipe_sender.send(DataPipe::Shutdown) {
-                                Self::handle_error(format!("Failed to send Shutdown signal to UpStream thread: {}", e).as_str());
-                                return;
+                                tracing::warn!(error = %e, "failed to send Shutdown to the UpStream thread");
                             }
-                            return;
+                            return CloseReason::DownStreamClosed;
                         },
                         // UpStreamShutDown Request
                         FullDuplexTcpState::UpStreamShutDown => {
 
+                            // Whatever message was left still goes out ahead of the Shutdown
+                            let flushed = self.relay_remainder(StreamSide::UpStream, &mut us_frames, &nb_callbacks, &mut schedule, &mut tls)
+                                .and_then(|_| schedule.flush(&ds_data_pipe_sender, &us_data_pipe_sender));
+                            if let Err(reason) = flushed {
+                                Self::shutdown_pipes(&ds_data_pipe_sender, &us_data_pipe_sender);
+                                return reason;
+                            }
+
                             if let Err(e) = ds_data_pipe_sender.send(DataPipe::Shutdown) {
-                                Self::handle_error(format!("Failed to send Shutdown signal to DownStream thread: {}", e).as_str());
-                                return;
+                                tracing::warn!(error = %e, "failed to send Shutdown to the DownStream thread");
+                            }
+                            return CloseReason::UpStreamClosed;
+                        },
+                        // DownStream stopped sending, pass the FIN/close_notify on to UpStream
+                        FullDuplexTcpState::DownStreamHalfClose => {
+
+                            // The end of the stream completes whatever message was left
+                            if let Err(reason) = self.relay_remainder(StreamSide::DownStream, &mut ds_frames, &nb_callbacks, &mut schedule, &mut tls) {
+                                Self::shutdown_pipes(&ds_data_pipe_sender, &us_data_pipe_sender);
+                                return reason;
                             }
-                            return;
+
+                            // Queued behind any delayed write still on its way to UpStream
+                            schedule.push(StreamSide::UpStream, DataPipe::HalfClose, Instant::now());
+                            if let Err(reason) = schedule.flush(&ds_data_pipe_sender, &us_data_pipe_sender) {
+                                tracing::warn!(reason = ?reason, "failed to send HalfClose to the UpStream thread");
+                                Self::shutdown_pipes(&ds_data_pipe_sender, &us_data_pipe_sender);
+                                return reason;
+                            }
+
+                            match half_closed {
+                                Some(first_closed) => {
+                                    Self::shutdown_pipes(&ds_data_pipe_sender, &us_data_pipe_sender);
+                                    return first_closed;
+                                },
+                                None => half_closed = Some(CloseReason::DownStreamClosed),
+                            }
+                        },
+                        // UpStream stopped sending, pass the FIN/close_notify on to DownStream
+                        FullDuplexTcpState::UpStreamHalfClose => {
+
+                            // The end of the stream completes whatever message was left
+                            if let Err(reason) = self.relay_remainder(StreamSide::UpStream, &mut us_frames, &nb_callbacks, &mut schedule, &mut tls) {
+                                Self::shutdown_pipes(&ds_data_pipe_sender, &us_data_pipe_sender);
+                                return reason;
+                            }
+
+                            // Queued behind any delayed write still on its way to DownStream
+                            schedule.push(StreamSide::DownStream, DataPipe::HalfClose, Instant::now());
+                            if let Err(reason) = schedule.flush(&ds_data_pipe_sender, &us_data_pipe_sender) {
+                                tracing::warn!(reason = ?reason, "failed to send HalfClose to the DownStream thread");
+                                Self::shutdown_pipes(&ds_data_pipe_sender, &us_data_pipe_sender);
+                                return reason;
+                            }
+
+                            match half_closed {
+                                Some(first_closed) => {
+                                    Self::shutdown_pipes(&ds_data_pipe_sender, &us_data_pipe_sender);
+                                    return first_closed;
+                                },
+                                None => half_closed = Some(CloseReason::UpStreamClosed),
+                            }
+                        },
+                        // Written through a SessionHandle, behind anything the callbacks still hold back for that side
+                        FullDuplexTcpState::Inject(to, data) => {
+                            schedule.push(to, DataPipe::DataWrite(data), Instant::now());
+                            if let Err(reason) = schedule.flush(&ds_data_pipe_sender, &us_data_pipe_sender) {
+                                Self::shutdown_pipes(&ds_data_pipe_sender, &us_data_pipe_sender);
+                                return reason;
+                            }
+                        },
+                        // SessionHandle::shutdown() was called, the stream threads got their Shutdown already
+                        FullDuplexTcpState::SessionHandleShutDown => {
+                            return CloseReason::SessionHandleShutdown;
                         },
+                        // A side that was switched to TLS failed its handshake
+                        FullDuplexTcpState::StartTLSFailed(reason) => {
+                            if let Some(metrics) = &self.metrics {
+                                metrics.handshake_failed(reason);
+                            }
+                            Self::shutdown_pipes(&ds_data_pipe_sender, &us_data_pipe_sender);
+                            return reason;
+                        },
+                        FullDuplexTcpState::StartTLSDone(side, details) => {
+                            if let Some(capture) = self.capture.as_mut() {
+                                capture.start_tls(side, details);
+                            }
+                        },
+                        // A side switching to TLS stopped reading in the clear, hand it what it read of the handshake
+                        FullDuplexTcpState::DownStreamStartTLS => {
+                            let _ = ds_data_pipe_sender.send(DataPipe::Handshake(tls.ds_handshake.take().unwrap_or_default()));
+                        },
+                        FullDuplexTcpState::UpStreamStartTLS => {
+                            let _ = us_data_pipe_sender.send(DataPipe::Handshake(tls.us_handshake.take().unwrap_or_default()));
+                        },
+                    }
+                },
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+
+                    // Relay is shutting down
+                    if self.shutdown.load(Ordering::Relaxed) {
+                        Self::shutdown_pipes(&ds_data_pipe_sender, &us_data_pipe_sender);
+                        return CloseReason::RelayShutdown;
+                    }
+
+                    // No data in either direction for too long
+                    if let Some(idle_timeout) = self.config.idle_timeout {
+                        if last_activity.elapsed() >= idle_timeout {
+                            Self::shutdown_pipes(&ds_data_pipe_sender, &us_data_pipe_sender);
+                            return CloseReason::IdleTimeout;
+                        }
                     }
                 },
-                Err(_e) => {
-                    Self::handle_error("State receiver communication channel has closed!");
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    tracing::error!("state receiver channel has closed");
                     if let Err(e) = ds_data_pipe_sender.send(DataPipe::Shutdown) {
-                        Self::handle_error(format!("Failed to send Shutdown signal to DownStream thread: {}", e).as_str());
+                        tracing::warn!(error = %e, "failed to send Shutdown to the DownStream thread");
                     }
                     if let Err(e) = us_data_pipe_sender.send(DataPipe::Shutdown) {
-                        Self::handle_error(format!("Failed to send Shutdown signal to UpStream thread: {}", e).as_str());
+                        tracing::warn!(error = %e, "failed to send Shutdown to the UpStream thread");
                     }
-                    return;
+                    return CloseReason::RelayError;
                 }
             }// State Receiver
         }
     }
     
-    fn connect_endpoint(stream_data_type: TCPDataType, remote_host: String, remote_port: String) -> Result<DataStreamType, i8> {
+    /// Records one message received from the side `from` in the capture, HAR and session recording,
+    /// then runs the callbacks for it. Err once a callback ended the session.
+    fn relay_message(&mut self, from: StreamSide, message: Vec<u8>, nb_callbacks: &CallbackQueue, schedule: &mut WriteSchedule, tls: &mut TlsSwitch) -> Result<(), CloseReason> {
+
+        let from_client = matches!(from, StreamSide::DownStream);
+
+        if let Some(capture) = self.capture.as_mut() {
+            capture.record(from_client, &message);
+        }
+        if let Some(har) = self.har.as_mut() {
+            har.record(from_client, &message);
+        }
+        if let Some(recording) = &self.recording {
+            recording.record(if from_client { Origin::Client } else { Origin::Upstream }, &message);
+        }
+
+        match self.run_callbacks(from, message, nb_callbacks, schedule) {
+            CallbackFlow::Continue => Ok(()),
+            CallbackFlow::StartTLS(_) if tls.started => Ok(()),
+            CallbackFlow::StartTLS(read_ahead) => {
+                // The callback got the start of the handshake from the side that sent the message
+                let (ds_read_ahead, us_read_ahead) = match from {
+                    StreamSide::DownStream => (read_ahead, Vec::new()),
+                    StreamSide::UpStream => (Vec::new(), read_ahead),
+                };
+                tls.started = true;
+                tls.ds_handshake = self.switches_to_tls(StreamSide::DownStream).then_some(ds_read_ahead);
+                tls.us_handshake = self.switches_to_tls(StreamSide::UpStream).then_some(us_read_ahead);
+                Ok(())
+            },
+            CallbackFlow::Shutdown => Err(CloseReason::CallbackShutdown),
+        }
+    }
+
+    /// Passes on the message the framing of `from` still held when that side stopped sending.
+    fn relay_remainder(&mut self, from: StreamSide, frames: &mut FrameBuffer, nb_callbacks: &CallbackQueue, schedule: &mut WriteSchedule, tls: &mut TlsSwitch) -> Result<(), CloseReason> {
+
+        let message = match frames.take_remainder() {
+            Some(message) => message,
+            None => return Ok(()),
+        };
+        match from {
+            StreamSide::DownStream => self.buffers.ds_framing.store(0, Ordering::Relaxed),
+            StreamSide::UpStream => self.buffers.us_framing.store(0, Ordering::Relaxed),
+        }
+        self.relay_message(from, message, nb_callbacks, schedule, tls)
+    }
+
+    /// Runs the callbacks for one message received from the side `from` and queues what they returned.
+    fn run_callbacks(&self, from: StreamSide, message: Vec<u8>, nb_callbacks: &CallbackQueue, schedule: &mut WriteSchedule) -> CallbackFlow {
+
+        // Only the blocking callback is timed, not the wait for room in the non blocking backlog
+        // or for the handler
+        let started;
+        let (callback_ret, to) = match from {
+            StreamSide::DownStream => {
+                nb_callbacks.push(NbCallbackJob::DownStream(message.clone()));
+                let mut handler = self.inner_handlers.lock();
+                started = Instant::now();
+                (handler.ds_b_callback(message), StreamSide::UpStream)
+            },
+            StreamSide::UpStream => {
+                nb_callbacks.push(NbCallbackJob::UpStream(message.clone()));
+                let mut handler = self.inner_handlers.lock();
+                started = Instant::now();
+                (handler.us_b_callback(message), StreamSide::DownStream)
+            },
+        };
+
+        if let Some(metrics) = &self.metrics {
+            metrics.callback(from, started.elapsed(), &callback_ret);
+        }
+
+        let flow = match &callback_ret {
+            CallbackRet::RelayAndStartTLS(_, read_ahead) => CallbackFlow::StartTLS(read_ahead.clone()),
+            _ => CallbackFlow::Continue,
+        };
+        match schedule.callback_ret(callback_ret, to) {
+            true => flow,
+            false => CallbackFlow::Shutdown,
+        }
+    }
+
+    /// Only RAW sides switch on CallbackRet::RelayAndStartTLS, TLS ones take its data as a plain write.
+    fn switches_to_tls(&self, side: StreamSide) -> bool {
+        match side {
+            StreamSide::DownStream => self.ds_raw,
+            StreamSide::UpStream => !matches!(self.config.upstream_data_type, TCPDataType::TLS),
+        }
+    }
+
+    fn shutdown_pipes(ds_data_pipe_sender: &DataPipeSender, us_data_pipe_sender: &DataPipeSender) {
+        let _ = ds_data_pipe_sender.send(DataPipe::Shutdown);
+        let _ = us_data_pipe_sender.send(DataPipe::Shutdown);
+    }
+
+    fn connect_endpoint(stream_data_type: TCPDataType, remote_host: String, remote_port: String, connect_timeout: Option<Duration>, handshake_timeout: Option<Duration>, alpn_protocols: &[u8]) -> Result<(DataStreamType, ConnectTimings), CloseReason> {
+
+        let started = Instant::now();
 
         match stream_data_type {
 
-            TCPDataType::RAW => {
-                let s = match TcpStream::connect(format!("{}:{}", remote_host, remote_port)) {
+            TCPDataType::RAW | TCPDataType::AUTO => {
+                let s = match Self::connect_tcp(&remote_host, &remote_port, connect_timeout) {
                     Ok(s) => s,
                     Err(e) => {
-                        Self::handle_error(format!("Can't connect to remote host: {}\nErr: {}", format!("{}:{}", remote_host, remote_port), e).as_str());
-                        return Result::Err(-1);
+                        tracing::warn!(upstream = %format_args!("{}:{}", remote_host, remote_port), error = %e, "can't connect to upstream");
+                        return Result::Err(Self::connect_error_reason(&e));
                     }
                 };
                 let _ = s.set_read_timeout(Some(Duration::from_millis(50)));
-                return Ok(DataStreamType::RAW(s));
+                Ok((DataStreamType::RAW(s), ConnectTimings{connect: started.elapsed(), tls_handshake: None}))
 
             },
             TCPDataType::TLS => {
 
-                let mut sslbuilder = SslConnector::builder(SslMethod::tls()).unwrap();
-                sslbuilder.set_verify(SslVerifyMode::NONE);
-        
-                let connector = sslbuilder.build();
-        
-                let s = match TcpStream::connect(format!("{}:{}", remote_host, remote_port)) {
+                let s = match Self::connect_tcp(&remote_host, &remote_port, connect_timeout) {
                     Ok(s) => s,
                     Err(e) => {
-                        Self::handle_error(format!("Can't connect to remote host: {}\nErr: {}", format!("{}:{}", remote_host, remote_port), e).as_str());
-                        return Result::Err(-1);
+                        tracing::warn!(upstream = %format_args!("{}:{}", remote_host, remote_port), error = %e, "can't connect to upstream");
+                        return Result::Err(Self::connect_error_reason(&e));
                     }
                 };
+                let handshake_started = Instant::now();
         
-                let s = match connector.connect(remote_host.as_str(), s) {
-                    Ok(s) => s,
-                    Err(e) => {
-                        Self::handle_error(format!("Failed to accept TLS/SSL handshake: {}", e).as_str());
-                        return Result::Err(-2);
-                    }
-                };
-
+                let s = tls::tls_connect(s, Vec::new(), &remote_host, handshake_timeout, alpn_protocols)?;
                 let _ = s.get_ref().set_read_timeout(Some(Duration::from_millis(50)));
-                return Ok(DataStreamType::TLS(s));
+                Ok((DataStreamType::TLS(s), ConnectTimings{connect: started.elapsed(), tls_handshake: Some(handshake_started.elapsed())}))
             }
         }
     }
 
-    fn handle_error(error_description: &str) {
-        println!("[SSLRelay Master Thread Error]: {}", error_description);
+    fn connect_error_reason(e: &std::io::Error) -> CloseReason {
+
+        match e.kind() {
+            std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock => CloseReason::UpStreamConnectTimeout,
+            _ => CloseReason::UpStreamConnectFailed,
+        }
+    }
+
+    fn connect_tcp(remote_host: &str, remote_port: &str, connect_timeout: Option<Duration>) -> std::io::Result<TcpStream> {
+
+        let connect_timeout = match connect_timeout {
+            Some(connect_timeout) => connect_timeout,
+            None => return TcpStream::connect(format!("{}:{}", remote_host, remote_port)),
+        };
+
+        // connect_timeout() only takes resolved addresses, so try each one like connect() does
+        let mut last_error = std::io::Error::new(std::io::ErrorKind::InvalidInput, "Remote host resolved to no addresses");
+
+        for addr in format!("{}:{}", remote_host, remote_port).to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, connect_timeout) {
+                Ok(s) => return Ok(s),
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
     }
 }
\ No newline at end of file
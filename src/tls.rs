@@ -0,0 +1,141 @@
+use crate::{
+    SslVerifyMode,
+    SslConnector,
+    SslAcceptor,
+    SslStream,
+    HandshakeError,
+    SslMethod,
+    TcpStream,
+    CloseReason,
+    Duration,
+    Instant,
+    Read,
+    Write,
+    io,
+};
+use std::ops::Deref;
+
+/// The TcpStream under a TLS session. Data that was read from the socket in the clear before a
+/// switch to TLS (STARTTLS) belongs to the handshake and is handed out first.
+pub struct TlsTransport {
+    stream: TcpStream,
+    read_ahead: Vec<u8>,
+}
+
+impl TlsTransport {
+
+    pub fn new(stream: TcpStream, read_ahead: Vec<u8>) -> Self {
+        TlsTransport {stream, read_ahead}
+    }
+}
+
+impl Read for TlsTransport {
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+
+        if self.read_ahead.is_empty() {
+            return self.stream.read(buf);
+        }
+        let length = buf.len().min(self.read_ahead.len());
+        buf[..length].copy_from_slice(&self.read_ahead[..length]);
+        self.read_ahead.drain(..length);
+        Ok(length)
+    }
+}
+
+impl Write for TlsTransport {
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl Deref for TlsTransport {
+
+    type Target = TcpStream;
+
+    fn deref(&self) -> &TcpStream {
+        &self.stream
+    }
+}
+
+/// Accepts a downstream TLS handshake, giving up once handshake_timeout has passed.
+/// read_ahead is the part of the handshake that was already read from the stream.
+pub fn tls_accept(acceptor: &SslAcceptor, stream: TcpStream, read_ahead: Vec<u8>, handshake_timeout: Option<Duration>) -> Result<SslStream<TlsTransport>, CloseReason> {
+
+    let _ = stream.set_read_timeout(handshake_timeout);
+    let _ = stream.set_write_timeout(handshake_timeout);
+    let deadline = handshake_timeout.map(|t| Instant::now() + t);
+
+    let s = finish_handshake(acceptor.accept(TlsTransport::new(stream, read_ahead)), deadline, CloseReason::DownStreamHandshakeFailed, CloseReason::DownStreamHandshakeTimeout)?;
+    let _ = s.get_ref().set_write_timeout(None);
+    Ok(s)
+}
+
+/// Runs the upstream TLS handshake on a connected stream, giving up once handshake_timeout has passed.
+/// read_ahead is the part of the handshake that was already read from the stream.
+pub fn tls_connect(stream: TcpStream, read_ahead: Vec<u8>, remote_host: &str, handshake_timeout: Option<Duration>, alpn_protocols: &[u8]) -> Result<SslStream<TlsTransport>, CloseReason> {
+
+    let mut sslbuilder = SslConnector::builder(SslMethod::tls()).unwrap();
+    sslbuilder.set_verify(SslVerifyMode::NONE);
+    if !alpn_protocols.is_empty() {
+        let _ = sslbuilder.set_alpn_protos(alpn_protocols);
+    }
+
+    let connector = sslbuilder.build();
+
+    let _ = stream.set_read_timeout(handshake_timeout);
+    let _ = stream.set_write_timeout(handshake_timeout);
+    let deadline = handshake_timeout.map(|t| Instant::now() + t);
+
+    let s = finish_handshake(connector.connect(remote_host, TlsTransport::new(stream, read_ahead)), deadline, CloseReason::UpStreamHandshakeFailed, CloseReason::UpStreamHandshakeTimeout)?;
+    let _ = s.get_ref().set_write_timeout(None);
+    Ok(s)
+}
+
+fn finish_handshake(mut result: Result<SslStream<TlsTransport>, HandshakeError<TlsTransport>>, deadline: Option<Instant>, failed: CloseReason, timed_out: CloseReason) -> Result<SslStream<TlsTransport>, CloseReason> {
+
+    loop {
+        match result {
+            Ok(s) => return Ok(s),
+            // The socket timeout fired mid handshake, keep going until the deadline
+            Err(HandshakeError::WouldBlock(mid_handshake)) => {
+
+                let now = Instant::now();
+                let deadline = match deadline {
+                    Some(deadline) if deadline > now => deadline,
+                    _ => {
+                        tracing::warn!(reason = ?timed_out, "TLS handshake timed out");
+                        return Err(timed_out);
+                    }
+                };
+
+                let _ = mid_handshake.get_ref().set_read_timeout(Some(deadline - now));
+                let _ = mid_handshake.get_ref().set_write_timeout(Some(deadline - now));
+                result = mid_handshake.handshake();
+            },
+            Err(HandshakeError::Failure(mid_handshake)) => {
+
+                let is_timeout = match mid_handshake.error().io_error() {
+                    Some(e) => e.kind() == std::io::ErrorKind::WouldBlock || e.kind() == std::io::ErrorKind::TimedOut,
+                    None => false,
+                };
+                tracing::warn!(error = %mid_handshake.error(), "TLS handshake failed");
+
+                return if is_timeout {
+                    Err(timed_out)
+                } else {
+                    Err(failed)
+                };
+            },
+            Err(HandshakeError::SetupFailure(e)) => {
+                tracing::error!(error = %e, "failed to set up TLS handshake");
+                return Err(failed);
+            }
+        }
+    }
+}
@@ -0,0 +1,154 @@
+use crate::{
+    UpstreamPool,
+    UpstreamEndpointState,
+    UpstreamHealth,
+    UpstreamLease,
+    RemoteEndpoint,
+    RelayConfig,
+    LoadBalancing,
+    Arc,
+    Mutex,
+    AtomicUsize,
+    AtomicU64,
+    Ordering,
+    Instant,
+    SystemTime,
+    SocketAddr,
+};
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+impl UpstreamPool {
+
+    pub fn new(config: &RelayConfig) -> Self {
+
+        let mut remotes = vec![RemoteEndpoint{host: config.remote_host.clone(), port: config.remote_port.clone()}];
+        remotes.extend(config.extra_remotes.iter().cloned());
+
+        let endpoints = remotes.into_iter().map(|remote| {
+            UpstreamEndpointState {
+                remote,
+                active_connections: AtomicUsize::new(0),
+                health: Mutex::new(UpstreamHealth{consecutive_fails: 0, ejected_until: None}),
+            }
+        }).collect();
+
+        let seed = match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+            Ok(d) => d.as_nanos() as u64,
+            Err(_e) => 0,
+        };
+
+        UpstreamPool {
+            endpoints,
+            load_balancing: config.load_balancing,
+            connect_timeout: config.connect_timeout,
+            max_fails: config.max_fails,
+            fail_timeout: config.fail_timeout,
+            round_robin_next: AtomicUsize::new(0),
+            // xorshift must never be seeded with 0
+            random_state: AtomicU64::new(seed | 1),
+        }
+    }
+
+    pub fn remote(&self, index: usize) -> &RemoteEndpoint {
+        &self.endpoints[index].remote
+    }
+
+    pub fn connect_timeout(&self) -> Option<std::time::Duration> {
+        self.connect_timeout
+    }
+
+    /// Order in which endpoints should be tried for a new connection.
+    /// Healthy endpoints come first in load balancing order, ejected ones are
+    /// only tried as a last resort.
+    pub fn connect_order(&self, client_addr: Option<SocketAddr>) -> Vec<usize> {
+
+        let endpoint_count = self.endpoints.len();
+
+        let mut order: Vec<usize> = match self.load_balancing {
+            LoadBalancing::RoundRobin => {
+                let start = self.round_robin_next.fetch_add(1, Ordering::Relaxed) % endpoint_count;
+                (0..endpoint_count).map(|i| (start + i) % endpoint_count).collect()
+            },
+            LoadBalancing::Random => {
+                let start = (self.next_random() % endpoint_count as u64) as usize;
+                (0..endpoint_count).map(|i| (start + i) % endpoint_count).collect()
+            },
+            LoadBalancing::ClientIpHash => {
+                let start = match client_addr {
+                    Some(addr) => {
+                        let mut hasher = DefaultHasher::new();
+                        addr.ip().hash(&mut hasher);
+                        (hasher.finish() % endpoint_count as u64) as usize
+                    },
+                    None => 0,
+                };
+                (0..endpoint_count).map(|i| (start + i) % endpoint_count).collect()
+            },
+            LoadBalancing::LeastConnections => {
+                let mut order: Vec<usize> = (0..endpoint_count).collect();
+                order.sort_by_key(|i| self.endpoints[*i].active_connections.load(Ordering::Relaxed));
+                order
+            },
+        };
+
+        // Stable sort keeps the load balancing order within healthy and ejected endpoints
+        let now = Instant::now();
+        order.sort_by_key(|i| self.is_ejected(*i, now));
+        order
+    }
+
+    pub fn mark_success(pool: &Arc<Self>, index: usize) -> UpstreamLease {
+
+        if let Ok(mut health) = pool.endpoints[index].health.lock() {
+            health.consecutive_fails = 0;
+            health.ejected_until = None;
+        }
+        pool.endpoints[index].active_connections.fetch_add(1, Ordering::Relaxed);
+
+        UpstreamLease {
+            pool: pool.clone(),
+            index,
+        }
+    }
+
+    pub fn mark_failure(&self, index: usize) {
+
+        if let Ok(mut health) = self.endpoints[index].health.lock() {
+            health.consecutive_fails = health.consecutive_fails.saturating_add(1);
+            if self.max_fails > 0 && health.consecutive_fails >= self.max_fails {
+                health.ejected_until = Some(Instant::now() + self.fail_timeout);
+            }
+        }
+    }
+
+    fn is_ejected(&self, index: usize, now: Instant) -> bool {
+
+        match self.endpoints[index].health.lock() {
+            Ok(health) => match health.ejected_until {
+                Some(ejected_until) => ejected_until > now,
+                None => false,
+            },
+            Err(_e) => false,
+        }
+    }
+
+    fn next_random(&self) -> u64 {
+
+        // xorshift64, good enough for spreading connections
+        let mut x = self.random_state.load(Ordering::Relaxed);
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.random_state.store(x, Ordering::Relaxed);
+        x
+    }
+}
+
+impl Drop for UpstreamLease {
+
+    fn drop(&mut self) {
+        self.pool.endpoints[self.index].active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
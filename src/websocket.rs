@@ -0,0 +1,596 @@
+//! WebSocket aware callbacks.
+//!
+//! When http::HttpRelay sees a connection upgraded to WebSocket it keeps following it frame by frame.
+//! Masking, fragmentation and permessage-deflate are undone before HttpHandler::on_websocket_message
+//! gets a message, and redone when the message is written out again.
+//! ```ignore
+//! impl HttpHandler for Handler {
+//!     fn on_websocket_message(&mut self, message: &mut WebSocketMessage, inject: &mut WebSocketInjector) -> WebSocketRet {
+//!         if message.from_client && message.data == b"ping?" {
+//!             inject.to_client(WebSocketMessage::text("pong!"));
+//!             return WebSocketRet::Drop;
+//!         }
+//!         WebSocketRet::Relay
+//!     }
+//! }
+//! ```
+
+use flate2::{
+    Compress,
+    Compression,
+    Decompress,
+    FlushCompress,
+    FlushDecompress,
+    Status,
+};
+
+use crate::{
+    SystemTime,
+};
+
+/// Kind of a WebSocket message. Control messages (Close, Ping, Pong) are passed to the handler too.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum WebSocketOpcode {
+    Text,
+    Binary,
+    Close,
+    Ping,
+    Pong,
+}
+
+/// A complete WebSocket message, reassembled from its fragments and decompressed.
+#[derive(Clone, Debug)]
+pub struct WebSocketMessage {
+    pub opcode: WebSocketOpcode,
+    pub data: Vec<u8>,
+    /// Whether the message was sent by the client. Ignored for injected messages.
+    pub from_client: bool,
+}
+
+/// What HttpRelay does with a WebSocket message after the handler has seen it.
+#[derive(Debug)]
+pub enum WebSocketRet {
+    Relay,// Relay the (possibly modified) message
+    Drop,// Dont relay the message
+    Shutdown,// Shutdown TCP connection
+}
+
+/// Collects messages the handler wants to send in addition to the one it was given.
+/// They go out right after that message.
+pub struct WebSocketInjector {
+    to_client: Vec<WebSocketMessage>,
+    to_server: Vec<WebSocketMessage>,
+}
+
+impl WebSocketMessage {
+
+    pub fn text(text: &str) -> Self {
+        WebSocketMessage {
+            opcode: WebSocketOpcode::Text,
+            data: text.as_bytes().to_vec(),
+            from_client: false,
+        }
+    }
+
+    pub fn binary(data: Vec<u8>) -> Self {
+        WebSocketMessage {
+            opcode: WebSocketOpcode::Binary,
+            data,
+            from_client: false,
+        }
+    }
+}
+
+impl WebSocketInjector {
+    /// Sends a message to the client.
+    pub fn to_client(&mut self, message: WebSocketMessage) {
+        self.to_client.push(message);
+    }
+    /// Sends a message to the server.
+    pub fn to_server(&mut self, message: WebSocketMessage) {
+        self.to_server.push(message);
+    }
+}
+
+/// Both directions of an upgraded connection.
+pub(crate) struct WebSocketSession {
+    client: WebSocketReader,
+    server: WebSocketReader,
+    to_client: WebSocketWriter,
+    to_server: WebSocketWriter,
+    max_message_size: usize,
+}
+
+/// Frames read from one side.
+struct WebSocketReader {
+    buffer: Vec<u8>,
+    inflater: Option<Decompress>,
+    no_context_takeover: bool,
+    // Opcode, compressed flag and data of a fragmented message that is not complete yet
+    fragments: Option<(WebSocketOpcode, bool, Vec<u8>)>,
+}
+
+/// Frames written to one side.
+struct WebSocketWriter {
+    deflater: Option<Compress>,
+    mask: bool,
+    mask_state: u32,
+}
+
+/// The permessage-deflate parameters the server accepted.
+struct DeflateParameters {
+    server_no_context_takeover: bool,
+    client_no_context_takeover: bool,
+    server_max_window_bits: u8,
+    client_max_window_bits: u8,
+}
+
+impl WebSocketSession {
+
+    /// Starts following the connection, with the Sec-WebSocket-Extensions of the 101 response.
+    pub fn new(extensions: Option<&str>, max_message_size: usize) -> Self {
+
+        let deflate = extensions.and_then(DeflateParameters::parse);
+
+        let (client_inflater, server_inflater) = match &deflate {
+            Some(deflate) => (
+                Some((Decompress::new(false), deflate.client_no_context_takeover)),
+                Some((Decompress::new(false), deflate.server_no_context_takeover)),
+            ),
+            None => (None, None),
+        };
+
+        // Messages are compressed again only where the receiver takes a full sized window,
+        // anything else goes out uncompressed which permessage-deflate always allows
+        let to_client_deflater = deflate.as_ref()
+            .filter(|deflate| deflate.server_max_window_bits == 15)
+            .map(|_| Compress::new(Compression::default(), false));
+        let to_server_deflater = deflate.as_ref()
+            .filter(|deflate| deflate.client_max_window_bits == 15)
+            .map(|_| Compress::new(Compression::default(), false));
+
+        let seed = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|since| since.subsec_nanos())
+            .unwrap_or(0);
+
+        WebSocketSession {
+            client: WebSocketReader::new(client_inflater),
+            server: WebSocketReader::new(server_inflater),
+            to_client: WebSocketWriter{deflater: to_client_deflater, mask: false, mask_state: 0},
+            to_server: WebSocketWriter{deflater: to_server_deflater, mask: true, mask_state: seed | 1},
+            max_message_size,
+        }
+    }
+
+    /// Reads the frames received from one side and runs every complete message through on_message.
+    /// Returns the bytes for the server and the bytes for the client, or None if the session can not go on.
+    pub fn process<F>(&mut self, from_client: bool, in_data: Vec<u8>, on_message: &mut F) -> Option<(Vec<u8>, Vec<u8>)>
+    where
+        F: FnMut(&mut WebSocketMessage, &mut WebSocketInjector) -> WebSocketRet,
+    {
+        let mut to_server = Vec::new();
+        let mut to_client = Vec::new();
+
+        let reader = if from_client { &mut self.client } else { &mut self.server };
+        reader.buffer.extend(in_data);
+
+        loop {
+            let reader = if from_client { &mut self.client } else { &mut self.server };
+            let mut message = match reader.next_message(from_client, self.max_message_size) {
+                Ok(Some(message)) => message,
+                Ok(None) => break,
+                Err(()) => return None,
+            };
+
+            let mut injector = WebSocketInjector {
+                to_client: Vec::new(),
+                to_server: Vec::new(),
+            };
+
+            match on_message(&mut message, &mut injector) {
+                WebSocketRet::Relay => {
+                    if from_client {
+                        self.to_server.write(&message, &mut to_server);
+                    } else {
+                        self.to_client.write(&message, &mut to_client);
+                    }
+                },
+                WebSocketRet::Drop => {},
+                WebSocketRet::Shutdown => return None,
+            }
+
+            for message in injector.to_server {
+                self.to_server.write(&message, &mut to_server);
+            }
+            for message in injector.to_client {
+                self.to_client.write(&message, &mut to_client);
+            }
+        }
+
+        Some((to_server, to_client))
+    }
+}
+
+impl WebSocketReader {
+
+    fn new(inflater: Option<(Decompress, bool)>) -> Self {
+        let (inflater, no_context_takeover) = match inflater {
+            Some((inflater, no_context_takeover)) => (Some(inflater), no_context_takeover),
+            None => (None, false),
+        };
+        WebSocketReader {
+            buffer: Vec::new(),
+            inflater,
+            no_context_takeover,
+            fragments: None,
+        }
+    }
+
+    /// Takes frames off the buffer until a message is complete.
+    fn next_message(&mut self, from_client: bool, max_message_size: usize) -> Result<Option<WebSocketMessage>, ()> {
+
+        loop {
+            let (header, payload_length) = match frame_length(&self.buffer) {
+                Some(lengths) => lengths,
+                None => return Ok(None),
+            };
+            if payload_length > max_message_size as u64 {
+                return Err(());
+            }
+            let frame_end = header + payload_length as usize;
+            if self.buffer.len() < frame_end {
+                return Ok(None);
+            }
+
+            let first = self.buffer[0];
+            let masked = self.buffer[1] & 0x80 != 0;
+            let mut payload = self.buffer[header..frame_end].to_vec();
+            if masked {
+                let key = [self.buffer[header - 4], self.buffer[header - 3], self.buffer[header - 2], self.buffer[header - 1]];
+                apply_mask(&mut payload, key);
+            }
+            self.buffer.drain(..frame_end);
+
+            let fin = first & 0x80 != 0;
+            let compressed = first & 0x40 != 0;
+
+            let opcode = match first & 0x0f {
+                0x0 => None,
+                0x1 => Some(WebSocketOpcode::Text),
+                0x2 => Some(WebSocketOpcode::Binary),
+                0x8 => Some(WebSocketOpcode::Close),
+                0x9 => Some(WebSocketOpcode::Ping),
+                0xa => Some(WebSocketOpcode::Pong),
+                _ => return Err(()),
+            };
+
+            let (opcode, compressed, data) = match opcode {
+                // Control frames are never fragmented and may come between fragments
+                Some(opcode @ (WebSocketOpcode::Close | WebSocketOpcode::Ping | WebSocketOpcode::Pong)) => (opcode, false, payload),
+                Some(opcode) => {
+                    if self.fragments.is_some() {
+                        return Err(());
+                    }
+                    if !fin {
+                        self.fragments = Some((opcode, compressed, payload));
+                        continue;
+                    }
+                    (opcode, compressed, payload)
+                },
+                None => {
+                    let (opcode, compressed, mut data) = self.fragments.take().ok_or(())?;
+                    data.extend(payload);
+                    if data.len() > max_message_size {
+                        return Err(());
+                    }
+                    if !fin {
+                        self.fragments = Some((opcode, compressed, data));
+                        continue;
+                    }
+                    (opcode, compressed, data)
+                },
+            };
+
+            let data = if compressed {
+                self.inflate(data, max_message_size)?
+            } else {
+                data
+            };
+
+            return Ok(Some(WebSocketMessage {
+                opcode,
+                data,
+                from_client,
+            }));
+        }
+    }
+
+    fn inflate(&mut self, mut data: Vec<u8>, max_message_size: usize) -> Result<Vec<u8>, ()> {
+
+        let inflater = self.inflater.as_mut().ok_or(())?;
+
+        // The sender removed the empty block that ends every compressed message
+        data.extend_from_slice(&[0x00, 0x00, 0xff, 0xff]);
+
+        let mut inflated = Vec::with_capacity(data.len() * 4);
+        let start_in = inflater.total_in();
+
+        loop {
+            if inflated.len() == inflated.capacity() {
+                inflated.reserve(inflated.len().max(1024));
+            }
+            let consumed = (inflater.total_in() - start_in) as usize;
+            let produced = inflated.len();
+            let status = inflater.decompress_vec(&data[consumed..], &mut inflated, FlushDecompress::Sync).map_err(|_| ())?;
+            let progress = (inflater.total_in() - start_in) as usize != consumed || inflated.len() != produced;
+
+            if inflated.len() > max_message_size {
+                return Err(());
+            }
+            // With all input taken, a full buffer may still leave output inside the inflater
+            let consumed = (inflater.total_in() - start_in) as usize;
+            if consumed >= data.len() && (inflated.len() < inflated.capacity() || !progress) {
+                break;
+            }
+            // No progress while input remains means the data is broken
+            if !progress {
+                return Err(());
+            }
+            if let Status::StreamEnd = status {
+                break;
+            }
+        }
+
+        if self.no_context_takeover {
+            inflater.reset(false);
+        }
+        Ok(inflated)
+    }
+}
+
+impl WebSocketWriter {
+
+    fn write(&mut self, message: &WebSocketMessage, out: &mut Vec<u8>) {
+
+        let opcode = match message.opcode {
+            WebSocketOpcode::Text => 0x1,
+            WebSocketOpcode::Binary => 0x2,
+            WebSocketOpcode::Close => 0x8,
+            WebSocketOpcode::Ping => 0x9,
+            WebSocketOpcode::Pong => 0xa,
+        };
+        let control = opcode >= 0x8;
+
+        let (mut payload, compressed) = match self.deflater.as_mut() {
+            Some(deflater) if !control => match deflate(deflater, &message.data) {
+                Some(payload) => (payload, true),
+                None => (message.data.clone(), false),
+            },
+            _ => (message.data.clone(), false),
+        };
+
+        // Every message goes out as a single frame
+        out.push(0x80 | if compressed { 0x40 } else { 0x00 } | opcode);
+
+        let mask_bit = if self.mask { 0x80 } else { 0x00 };
+        match payload.len() {
+            length if length < 126 => out.push(mask_bit | length as u8),
+            length if length <= 0xffff => {
+                out.push(mask_bit | 126);
+                out.extend_from_slice(&(length as u16).to_be_bytes());
+            },
+            length => {
+                out.push(mask_bit | 127);
+                out.extend_from_slice(&(length as u64).to_be_bytes());
+            },
+        }
+
+        if self.mask {
+            let key = self.next_mask().to_be_bytes();
+            out.extend_from_slice(&key);
+            apply_mask(&mut payload, key);
+        }
+        out.extend(payload);
+    }
+
+    fn next_mask(&mut self) -> u32 {
+        // xorshift32, a fresh mask for every frame
+        self.mask_state ^= self.mask_state << 13;
+        self.mask_state ^= self.mask_state >> 17;
+        self.mask_state ^= self.mask_state << 5;
+        self.mask_state
+    }
+}
+
+/// Compresses one message on its own, so the receiver never needs an earlier message's window.
+fn deflate(deflater: &mut Compress, data: &[u8]) -> Option<Vec<u8>> {
+
+    deflater.reset();
+
+    let mut deflated = Vec::with_capacity(data.len() / 2 + 64);
+    loop {
+        if deflated.len() == deflated.capacity() {
+            deflated.reserve(deflated.len().max(64));
+        }
+        let consumed = deflater.total_in() as usize;
+        let produced = deflated.len();
+        deflater.compress_vec(&data[consumed..], &mut deflated, FlushCompress::Sync).ok()?;
+        let progress = deflater.total_in() as usize != consumed || deflated.len() != produced;
+        if deflater.total_in() as usize >= data.len() && (deflated.len() < deflated.capacity() || !progress) {
+            break;
+        }
+        if !progress {
+            return None;
+        }
+    }
+
+    // The sync flush ends with an empty block the receiver adds back itself
+    if deflated.ends_with(&[0x00, 0x00, 0xff, 0xff]) {
+        deflated.truncate(deflated.len() - 4);
+    }
+    Some(deflated)
+}
+
+/// Header length and payload length of the frame at the front of buffer.
+fn frame_length(buffer: &[u8]) -> Option<(usize, u64)> {
+
+    if buffer.len() < 2 {
+        return None;
+    }
+    let mask_length = if buffer[1] & 0x80 != 0 { 4 } else { 0 };
+
+    let (header, payload_length) = match buffer[1] & 0x7f {
+        126 => {
+            if buffer.len() < 4 {
+                return None;
+            }
+            (4, u16::from_be_bytes([buffer[2], buffer[3]]) as u64)
+        },
+        127 => {
+            if buffer.len() < 10 {
+                return None;
+            }
+            let mut length = [0u8; 8];
+            length.copy_from_slice(&buffer[2..10]);
+            (10, u64::from_be_bytes(length))
+        },
+        length => (2, length as u64),
+    };
+    Some((header + mask_length, payload_length))
+}
+
+fn apply_mask(data: &mut [u8], key: [u8; 4]) {
+    for (i, byte) in data.iter_mut().enumerate() {
+        *byte ^= key[i % 4];
+    }
+}
+
+impl DeflateParameters {
+
+    /// Finds permessage-deflate in a Sec-WebSocket-Extensions value.
+    fn parse(extensions: &str) -> Option<Self> {
+
+        for extension in extensions.split(',') {
+
+            let mut parameters = extension.split(';').map(|parameter| parameter.trim());
+            if parameters.next() != Some("permessage-deflate") {
+                continue;
+            }
+
+            let mut deflate = DeflateParameters {
+                server_no_context_takeover: false,
+                client_no_context_takeover: false,
+                server_max_window_bits: 15,
+                client_max_window_bits: 15,
+            };
+
+            for parameter in parameters {
+                let (name, value) = match parameter.split_once('=') {
+                    Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+                    None => (parameter, None),
+                };
+                let window_bits = value.and_then(|value| value.parse::<u8>().ok()).unwrap_or(15);
+                match name {
+                    "server_no_context_takeover" => deflate.server_no_context_takeover = true,
+                    "client_no_context_takeover" => deflate.client_no_context_takeover = true,
+                    "server_max_window_bits" => deflate.server_max_window_bits = window_bits,
+                    "client_max_window_bits" => deflate.client_max_window_bits = window_bits,
+                    _ => {},
+                }
+            }
+            return Some(deflate);
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn relay(session: &mut WebSocketSession, from_client: bool, frames: Vec<u8>) -> Option<Vec<WebSocketMessage>> {
+        let mut messages = Vec::new();
+        session.process(from_client, frames, &mut |message, _| {
+            messages.push(message.clone());
+            WebSocketRet::Relay
+        })?;
+        Some(messages)
+    }
+
+    // Compressible data that is not just one byte repeated
+    fn sample(length: usize) -> Vec<u8> {
+        (0..length).map(|i| b"abcdefgh"[(i * i / 7) % 8]).collect()
+    }
+
+    #[test]
+    fn compressed_messages_round_trip() {
+        for length in (0..4096).step_by(7).chain([64 * 1024, 1024 * 1024]) {
+            let mut session = WebSocketSession::new(Some("permessage-deflate"), 16 * 1024 * 1024);
+            let message = WebSocketMessage::binary(sample(length));
+            let mut frames = Vec::new();
+            session.to_server.write(&message, &mut frames);
+            assert_eq!(frames[0] & 0x40, 0x40);
+
+            let messages = relay(&mut session, true, frames).expect("message not inflated");
+            assert_eq!(messages.len(), 1);
+            assert_eq!(messages[0].data, message.data, "length {}", length);
+        }
+    }
+
+    #[test]
+    fn inflating_exactly_to_the_buffer_size_ends() {
+        // Random letters out of three compress to about a fifth. Inflate starts out with room for four
+        // times the compressed size, somewhere a message fills exactly that
+        let mut state = 1u32;
+        let letters: Vec<u8> = (0..64 * 1024).map(|_| {
+            state = state.wrapping_mul(1103515245).wrapping_add(12345);
+            b"abc"[(state >> 16) as usize % 3]
+        }).collect();
+        let mut deflater = Compress::new(Compression::default(), false);
+        let mut room_left = |length: usize| {
+            let deflated = deflate(&mut deflater, &letters[..length]).unwrap();
+            ((deflated.len() + 4) * 4) as isize - length as isize
+        };
+
+        // Room left only ever shrinks by one byte, so it is zero somewhere after the last length with room
+        let start = (1..256).map(|step| step * 256).take_while(|length| room_left(*length) > 0).last().unwrap_or(1);
+        let length = (start..letters.len()).find(|length| room_left(*length) == 0).unwrap();
+
+        let deflated = deflate(&mut deflater, &letters[..length]).unwrap();
+        let mut reader = WebSocketReader::new(Some((Decompress::new(false), false)));
+        assert_eq!(reader.inflate(deflated, 1024 * 1024), Ok(letters[..length].to_vec()));
+    }
+
+    #[test]
+    fn fragmented_messages_are_put_together() {
+        let mut session = WebSocketSession::new(None, 1024);
+        let frames = [&b"\x01\x03hel"[..], b"\x89\x00", b"\x80\x02lo"].concat();
+        let messages = relay(&mut session, false, frames).unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].opcode, WebSocketOpcode::Ping);
+        assert_eq!(messages[1].opcode, WebSocketOpcode::Text);
+        assert_eq!(messages[1].data, b"hello");
+    }
+
+    #[test]
+    fn malformed_frames_end_the_session() {
+        // Not deflate data
+        let mut session = WebSocketSession::new(Some("permessage-deflate"), 1024);
+        assert!(relay(&mut session, false, b"\xc2\x04\xff\xff\xff\xff".to_vec()).is_none());
+        // Inflates to more than the largest message
+        let mut session = WebSocketSession::new(Some("permessage-deflate"), 1024);
+        let mut frames = Vec::new();
+        session.to_client.write(&WebSocketMessage::binary(vec![0; 4096]), &mut frames);
+        assert!(relay(&mut session, false, frames).is_none());
+        // Reserved opcode, and a continuation without a start
+        let mut session = WebSocketSession::new(None, 1024);
+        assert!(relay(&mut session, false, b"\x83\x00".to_vec()).is_none());
+        let mut session = WebSocketSession::new(None, 1024);
+        assert!(relay(&mut session, false, b"\x80\x00".to_vec()).is_none());
+        // Announces more than the largest message
+        let mut session = WebSocketSession::new(None, 1024);
+        assert!(relay(&mut session, false, b"\x82\x7f\x00\x00\x00\x00\x00\x01\x00\x00".to_vec()).is_none());
+    }
+}